@@ -15,7 +15,7 @@ pub fn run() {
 
     loop {
         let mut cmd = String::new();
-        io::stdout().write("> ".as_bytes()).unwrap();
+        io::stdout().write_all("> ".as_bytes()).unwrap();
         io::stdout().flush().unwrap();
 
         io::stdin()
@@ -32,7 +32,7 @@ pub fn run() {
                 process::exit(0);
             }
             "help" => help(),
-            "set" | "get" | "ls" | "rm" | "merge" => {
+            "set" | "get" | "ls" | "rm" | "merge" | "lineage" => {
                 process_db_command(&mut db, &cmds);
             }
             "" => empty(),
@@ -43,7 +43,7 @@ pub fn run() {
     }
 }
 
-fn process_db_command(db: &mut lsm::Lsm, cmds: &Vec<&str>) {
+fn process_db_command(db: &mut lsm::Lsm, cmds: &[&str]) {
     match cmds[0] {
         "set" => {
             db.put(cmds[1].as_bytes().to_vec(), cmds[2].as_bytes().to_vec())
@@ -64,9 +64,20 @@ fn process_db_command(db: &mut lsm::Lsm, cmds: &Vec<&str>) {
         "rm" => {
             db.delete(cmds[1].as_bytes()).unwrap();
         }
+        "lineage" => match cmds[1].parse() {
+            Ok(id) => match db.sstable_lineage(id) {
+                Ok(lineage) => println!("{}", lineage),
+                Err(e) => println!("{}", e),
+            },
+            Err(_) => println!("invalid sstable id: {}", cmds[1]),
+        },
         "merge" => {
-            todo!()
-            // db.merge().unwrap();
+            let outcome = db.compact_now().unwrap();
+            println!(
+                "merged {} sstables, {} bytes reclaimed",
+                outcome.inputs.len(),
+                outcome.bytes_reclaimed
+            );
         }
         &_ => todo!(),
     };
@@ -80,6 +91,8 @@ fn help() {
     println!("set  -- set key value, by: <key> <value>");
     println!("ls   -- list keys");
     println!("rm   -- remove key value, by: <key>");
+    println!("merge -- flush, then merge every sstable into one");
+    println!("lineage -- show where an sstable comes from, by: <id>");
     println!("exit -- exit command");
 }
 
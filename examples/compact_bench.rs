@@ -0,0 +1,57 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use slmlib::lsm::{self, keys, CompactionGate, KVStore};
+
+const SSTABLES: u64 = 16;
+
+/// Leave every merge to `Lsm::compact`.
+struct ManualOnly;
+
+impl CompactionGate for ManualOnly {
+    fn allow(&self, _candidate_ids: &[u64]) -> bool {
+        false
+    }
+}
+
+/// Time a full compaction of `SSTABLES` sstables holding `KEYS` (first
+/// argument, default 2M) small entries, interleaved so every sstable
+/// spans the whole key range, the newer ones holding the smaller keys.
+fn main() {
+    env_logger::init();
+
+    let total: u64 = std::env::args()
+        .nth(1)
+        .map(|n| n.parse().expect("number of keys"))
+        .unwrap_or(2_000_000);
+
+    let path = "compact_bench";
+    let _ = std::fs::remove_dir_all(path);
+
+    let mut lsm = lsm::OpenOptions::new()
+        .max_log_length(u64::MAX)
+        .compaction_gate(Arc::new(ManualOnly))
+        .open(path)
+        .unwrap();
+    for sstable in 0..SSTABLES {
+        for i in (SSTABLES - 1 - sstable..total).step_by(SSTABLES as usize) {
+            lsm.put(keys::encode_u64(i).to_vec(), [0; 16].to_vec())
+                .unwrap();
+        }
+        lsm.flush().unwrap();
+    }
+
+    let start = Instant::now();
+    let outcome = lsm.compact().unwrap();
+    let elapsed = start.elapsed();
+    println!(
+        "merged {} sstables, {} keys in {} ms, {:.0} keys/s",
+        outcome.inputs.len(),
+        total,
+        elapsed.as_millis(),
+        total as f64 / elapsed.as_secs_f64()
+    );
+
+    drop(lsm);
+    std::fs::remove_dir_all(path).unwrap();
+}
@@ -0,0 +1,39 @@
+use slmlib::lsm::{self, keys, KVStore};
+
+const FLUSHES: u64 = 500;
+const KEYS_PER_FLUSH: u64 = 1_000;
+
+fn main() {
+    env_logger::init();
+
+    for hints_on_flush in [true, false] {
+        let path = "flush_bench";
+        let _ = std::fs::remove_dir_all(path);
+
+        let mut lsm = lsm::OpenOptions::new()
+            .max_log_length(u64::MAX)
+            .hints_on_flush(hints_on_flush)
+            .open(path)
+            .unwrap();
+
+        let mut flush_time = std::time::Duration::ZERO;
+        for flush in 0..FLUSHES {
+            for i in 0..KEYS_PER_FLUSH {
+                let key = keys::encode_u64(flush * KEYS_PER_FLUSH + i);
+                lsm.put(key.to_vec(), [0; 100].to_vec()).unwrap();
+            }
+            flush_time += lsm.flush().unwrap().duration;
+        }
+
+        println!(
+            "hints_on_flush: {}, {} flushes of {} keys, {:.0} us/flush",
+            hints_on_flush,
+            FLUSHES,
+            KEYS_PER_FLUSH,
+            flush_time.as_micros() as f64 / FLUSHES as f64
+        );
+
+        drop(lsm);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
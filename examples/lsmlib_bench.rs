@@ -1,4 +1,4 @@
-use slmlib::lsm::{self, KVStore};
+use slmlib::lsm::{self, keys, KVStore};
 
 fn main() {
     env_logger::init();
@@ -10,27 +10,25 @@ fn main() {
         .unwrap();
     dbg!(before_recovery.elapsed());
 
-    /*
-    if let Some((k, _v)) = lsm.iter().next_back() {
-        println!("max key recovered: {:?}", u64::from_le_bytes(*k));
+    if let Some((k, _v)) = lsm.iter().unwrap().next_back().transpose().unwrap() {
+        println!("max key recovered: {:?}", keys::decode_u64(&k));
     } else {
         println!("starting from scratch");
     }
-    */
 
     let before_writes = std::time::Instant::now();
     for i in 1_u64..1_000_000_000 {
-        lsm.put(i.to_le_bytes().to_vec(), [0; 100].to_vec())
+        lsm.put(keys::encode_u64(i).to_vec(), [0; 100].to_vec())
             .unwrap();
         if i % 1_000_000 == 0 {
             log::info!(
-                "{:.2} million wps - stats: xxx",
+                "{:.2} million wps - stats: {:?}",
                 i as f64 / (before_writes.elapsed().as_micros() + 1) as f64,
-                // lsm.stats(),
+                lsm.stats(),
             )
         }
     }
-    // lsm.flush().unwrap();
+    lsm.flush().unwrap();
     dbg!(before_writes.elapsed());
 
     std::thread::sleep(std::time::Duration::from_secs(200));
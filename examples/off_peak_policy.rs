@@ -0,0 +1,48 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use chrono::Timelike;
+use slmlib::lsm::{
+    self, keys, CandidateKind, CandidateRun, CompactionPolicy, DefaultCompactionPolicy, KVStore,
+};
+
+/// Defer size tiered merges to off-peak hours, when they cost the
+/// application least, still merging small files and reclaiming garbage
+/// of a nearly full store meanwhile.
+struct OffPeakPolicy {
+    /// peak hours of the day, UTC.
+    peak: Range<u32>,
+}
+
+impl CompactionPolicy for OffPeakPolicy {
+    fn select(&self, candidates: &[CandidateRun]) -> Option<usize> {
+        if !self.peak.contains(&chrono::Utc::now().hour()) {
+            return DefaultCompactionPolicy.select(candidates);
+        }
+        candidates
+            .iter()
+            .position(|run| run.kind != CandidateKind::SizeTiered)
+    }
+}
+
+/// Write a few sstables under a policy deferring merges around the clock.
+fn main() {
+    env_logger::init();
+
+    let path = "off_peak_policy";
+    let _ = std::fs::remove_dir_all(path);
+
+    let mut lsm = lsm::OpenOptions::new()
+        .max_log_length(64 * 1024)
+        .merge_window(2)
+        .compaction_policy(Arc::new(OffPeakPolicy { peak: 0..24 }))
+        .open(path)
+        .unwrap();
+    for i in 0..100_000u64 {
+        lsm.put(keys::encode_u64(i).to_vec(), [0; 16].to_vec())
+            .unwrap();
+    }
+    lsm.flush().unwrap();
+
+    println!("{:?}", lsm.stats());
+}
@@ -0,0 +1,49 @@
+use std::time::Instant;
+
+use slmlib::lsm::{self, keys, KVStore};
+
+const KEYS_PER_FLUSH: u64 = 100_000;
+
+/// Time opens of a store of `KEYS` (first argument, default 2M) small
+/// entries, keydir built from hint files or from the sstables.
+fn main() {
+    env_logger::init();
+
+    let total: u64 = std::env::args()
+        .nth(1)
+        .map(|n| n.parse().expect("number of keys"))
+        .unwrap_or(2_000_000);
+
+    for hints_on_flush in [true, false] {
+        let path = "open_bench";
+        let _ = std::fs::remove_dir_all(path);
+
+        let mut lsm = lsm::OpenOptions::new()
+            .max_log_length(u64::MAX)
+            .hints_on_flush(hints_on_flush)
+            .open(path)
+            .unwrap();
+        for i in 0..total {
+            lsm.put(keys::encode_u64(i).to_vec(), [0; 16].to_vec())
+                .unwrap();
+            if (i + 1) % KEYS_PER_FLUSH == 0 {
+                lsm.flush().unwrap();
+            }
+        }
+        lsm.flush().unwrap();
+        drop(lsm);
+
+        let start = Instant::now();
+        let lsm = lsm::Lsm::open(path).unwrap();
+        let elapsed = start.elapsed();
+        println!(
+            "hints_on_flush: {}, {} keys, open in {} ms",
+            hints_on_flush,
+            total,
+            elapsed.as_millis()
+        );
+
+        drop(lsm);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+use slmlib::lsm::{self, keys, KVStore};
+
+const KEYS: u64 = 100_000;
+const GETS: u64 = 1_000_000;
+const BATCH: u64 = 100;
+
+fn main() {
+    env_logger::init();
+
+    let path = "point_get_bench";
+    let _ = std::fs::remove_dir_all(path);
+
+    let mut lsm = lsm::OpenOptions::new()
+        .max_log_length(4 * 1024 * 1024)
+        .merge_window(5)
+        .open(path)
+        .unwrap();
+
+    for i in 0..KEYS {
+        lsm.put(keys::encode_u64(i).to_vec(), [0; 100].to_vec())
+            .unwrap();
+    }
+    drop(lsm);
+
+    // reopen so every get is served from sstables.
+    let lsm = lsm::OpenOptions::new().open(path).unwrap();
+
+    let before_gets = std::time::Instant::now();
+    let mut key = 0;
+    for _ in 0..GETS {
+        // visit keys in a scattered order.
+        key = (key + 7919) % KEYS;
+        assert!(lsm.get(&keys::encode_u64(key)).unwrap().is_some());
+    }
+    let elapsed = before_gets.elapsed();
+
+    println!(
+        "{} point gets in {:?}, {:.0} ns/get",
+        GETS,
+        elapsed,
+        elapsed.as_nanos() as f64 / GETS as f64
+    );
+
+    // the same keys in batches, the store locked once per batch.
+    let before_batches = std::time::Instant::now();
+    let mut key = 0;
+    for _ in 0..GETS / BATCH {
+        let batch: Vec<_> = (0..BATCH)
+            .map(|_| {
+                key = (key + 7919) % KEYS;
+                keys::encode_u64(key)
+            })
+            .collect();
+        let batch: Vec<&[u8]> = batch.iter().map(|k| &k[..]).collect();
+        let values = lsm.get_many(&batch).unwrap();
+        assert!(values.iter().all(Option::is_some));
+    }
+    let elapsed = before_batches.elapsed();
+
+    println!(
+        "{} batched gets of {} in {:?}, {:.0} ns/get",
+        GETS,
+        BATCH,
+        elapsed,
+        elapsed.as_nanos() as f64 / GETS as f64
+    );
+
+    drop(lsm);
+    std::fs::remove_dir_all(path).unwrap();
+}
@@ -1,3 +1,87 @@
 //! BloomFilter Module.
 
-pub struct BloomFilter {}
+/// Stable 64 bits hash of `key` with `seed`, FNV-1a with a final mix.
+///
+/// Digests are read by other processes, so this must never change.
+pub(crate) fn hash(key: &[u8], seed: u64) -> u64 {
+    let mut h = 0xcbf2_9ce4_8422_2325 ^ seed;
+    for b in key {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    mix(h)
+}
+
+/// splitmix64 finalizer.
+fn mix(mut h: u64) -> u64 {
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+#[derive(Debug)]
+pub struct BloomFilter {
+    /// bit array.
+    words: Vec<u64>,
+
+    /// number of probed bits per key.
+    hashes: u32,
+
+    /// seed of the key hash.
+    seed: u64,
+}
+
+impl BloomFilter {
+    /// Filter sized for `keys` keys with `bits_per_key` bits each.
+    pub fn new(keys: u64, bits_per_key: u8, seed: u64) -> Self {
+        let bits = (keys * bits_per_key.max(1) as u64).max(64);
+        // k = ln2 * bits per key minimizes false positives.
+        let hashes = ((bits_per_key as f64 * std::f64::consts::LN_2).round() as u32).clamp(1, 30);
+
+        Self::from_parts(vec![0; bits.div_ceil(64) as usize], hashes, seed)
+    }
+
+    pub fn from_parts(words: Vec<u64>, hashes: u32, seed: u64) -> Self {
+        Self {
+            words,
+            hashes,
+            seed,
+        }
+    }
+
+    pub fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    pub fn hashes(&self) -> u32 {
+        self.hashes
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        self.insert_hash(hash(key, self.seed));
+    }
+
+    /// `insert` of a key by its `hash` under the filter seed.
+    pub fn insert_hash(&mut self, h: u64) {
+        for bit in self.probes(h) {
+            self.words[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.probes(hash(key, self.seed))
+            .all(|bit| self.words[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Bits of the key hashing to `h1`, by double hashing.
+    fn probes(&self, h1: u64) -> impl Iterator<Item = u64> {
+        let bits = self.words.len() as u64 * 64;
+        let h2 = mix(h1) | 1;
+
+        (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bits)
+    }
+}
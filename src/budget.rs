@@ -0,0 +1,133 @@
+//! IO Budget Module.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::{LSMLibError, Result};
+
+/// What foreground reads and writes do once the budget is exhausted.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum ThrottleMode {
+    /// Sleep until the budget allows the operation.
+    #[default]
+    Sleep,
+
+    /// Fail with `LSMLibError::Throttled`.
+    Error,
+}
+
+/// Caps of foreground `get` and `put` bytes per second, 0 means no cap.
+///
+/// Budgets are token buckets holding at most one second worth of
+/// bytes, starting empty. Compaction is not accounted.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct IoBudget {
+    /// key and value bytes read per second.
+    pub read_bytes_per_sec: u64,
+
+    /// key and value bytes written per second.
+    pub write_bytes_per_sec: u64,
+
+    pub mode: ThrottleMode,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    /// bytes per second.
+    rate: f64,
+
+    /// available bytes, negative when in debt.
+    tokens: f64,
+
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: 0.0,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    /// Time until the bucket is out of debt, `None` if it is not.
+    fn debt(&mut self) -> Option<Duration> {
+        self.refill();
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / self.rate))
+    }
+
+    fn charge(&mut self, bytes: u64) {
+        self.refill();
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// Enforces an `IoBudget`.
+///
+/// An operation may start while its bucket is not in debt, and is
+/// charged its bytes once known, so a large operation borrows from
+/// the next seconds instead of never fitting the bucket.
+#[derive(Debug)]
+pub(crate) struct IoLimiter {
+    read: Option<Mutex<TokenBucket>>,
+    write: Option<Mutex<TokenBucket>>,
+    mode: ThrottleMode,
+}
+
+impl IoLimiter {
+    pub(crate) fn new(budget: IoBudget) -> Self {
+        let bucket = |rate| (rate > 0).then(|| Mutex::new(TokenBucket::new(rate)));
+        Self {
+            read: bucket(budget.read_bytes_per_sec),
+            write: bucket(budget.write_bytes_per_sec),
+            mode: budget.mode,
+        }
+    }
+
+    fn acquire(&self, bucket: &Option<Mutex<TokenBucket>>) -> Result<()> {
+        let bucket = match bucket {
+            Some(bucket) => bucket,
+            None => return Ok(()),
+        };
+
+        loop {
+            // never sleep holding the bucket.
+            let debt = bucket.lock().unwrap().debt();
+            match (debt, self.mode) {
+                (None, _) => return Ok(()),
+                (Some(wait), ThrottleMode::Sleep) => std::thread::sleep(wait),
+                (Some(retry_after), ThrottleMode::Error) => {
+                    return Err(LSMLibError::Throttled { retry_after })
+                }
+            }
+        }
+    }
+
+    /// Wait for, or fail without, read budget.
+    pub(crate) fn acquire_read(&self) -> Result<()> {
+        self.acquire(&self.read)
+    }
+
+    pub(crate) fn charge_read(&self, bytes: u64) {
+        if let Some(bucket) = &self.read {
+            bucket.lock().unwrap().charge(bytes);
+        }
+    }
+
+    /// Wait for, or fail without, write budget, then charge `bytes`.
+    pub(crate) fn acquire_write(&self, bytes: u64) -> Result<()> {
+        self.acquire(&self.write)?;
+        if let Some(bucket) = &self.write {
+            bucket.lock().unwrap().charge(bytes);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! Cache Module.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::bloomfilter;
+use crate::stats::NegativeCacheStats;
+
+/// Fixed size cache of keys known to be absent from the store,
+/// one key per slot picked by key hash.
+#[derive(Debug)]
+pub(crate) struct NegativeCache {
+    /// absent keys, full keys so a hash collision can not hide a key.
+    slots: Mutex<Vec<Option<Vec<u8>>>>,
+
+    /// lookups answered by the cache.
+    hits: AtomicU64,
+
+    /// all lookups.
+    lookups: AtomicU64,
+}
+
+impl NegativeCache {
+    pub(crate) fn new(entries: usize) -> Self {
+        Self {
+            slots: Mutex::new(vec![None; entries.max(1)]),
+            hits: AtomicU64::new(0),
+            lookups: AtomicU64::new(0),
+        }
+    }
+
+    fn slot(slots: &[Option<Vec<u8>>], key: &[u8]) -> usize {
+        (bloomfilter::hash(key, 0) % slots.len() as u64) as usize
+    }
+
+    /// `true` if `key` is known to be absent.
+    pub(crate) fn is_absent(&self, key: &[u8]) -> bool {
+        let slots = self.slots.lock().unwrap();
+        let absent = slots[Self::slot(&slots, key)].as_deref() == Some(key);
+
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        if absent {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        absent
+    }
+
+    /// Remember `key` is absent, evicting the key of its slot.
+    pub(crate) fn insert(&self, key: &[u8]) {
+        let mut slots = self.slots.lock().unwrap();
+        let slot = Self::slot(&slots, key);
+        slots[slot] = Some(key.to_vec());
+    }
+
+    /// Forget `key`, about to be written.
+    pub(crate) fn invalidate(&self, key: &[u8]) {
+        let mut slots = self.slots.lock().unwrap();
+        let slot = Self::slot(&slots, key);
+        if slots[slot].as_deref() == Some(key) {
+            slots[slot] = None;
+        }
+    }
+
+    /// Forget all keys.
+    pub(crate) fn clear(&self) {
+        self.slots.lock().unwrap().fill(None);
+    }
+
+    pub(crate) fn stats(&self) -> NegativeCacheStats {
+        NegativeCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            lookups: self.lookups.load(Ordering::Relaxed),
+        }
+    }
+}
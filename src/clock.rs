@@ -0,0 +1,85 @@
+//! Clock Module.
+//!
+//! Source of the entry timestamps, see `OpenOptions::clock`.
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// Seconds since the unix epoch, see `OpenOptions::clock`.
+pub type ClockFn = Arc<dyn Fn() -> u32 + Send + Sync>;
+
+/// How far, in seconds, the clock may go back below the newest
+/// timestamp of the store before its readings are distrusted.
+const MAX_CLOCK_STEP_BACK: u32 = 24 * 3600;
+
+/// Clock of the entry timestamps of a store, guarding against clocks
+/// reading far in the past, like an RTC starting at the epoch until
+/// NTP syncs.
+pub(crate) struct StoreClock {
+    clock: ClockFn,
+
+    /// newest timestamp the store holds or the clock read.
+    high_water: AtomicU32,
+
+    /// whether a distrusted reading was logged.
+    warned: AtomicBool,
+}
+
+impl StoreClock {
+    pub(crate) fn new(clock: ClockFn, high_water: u32) -> Self {
+        Self {
+            clock,
+            high_water: AtomicU32::new(high_water),
+            warned: AtomicBool::new(false),
+        }
+    }
+
+    /// Clock the readings come from.
+    pub(crate) fn source(&self) -> ClockFn {
+        Arc::clone(&self.clock)
+    }
+
+    /// Current time in seconds since the unix epoch.
+    ///
+    /// A reading more than `MAX_CLOCK_STEP_BACK` below the high-water
+    /// mark is replaced by the mark plus one, logged once.
+    pub(crate) fn now(&self) -> u32 {
+        let now = (self.clock)();
+        let high_water = self.high_water.load(Ordering::Relaxed);
+        if now.saturating_add(MAX_CLOCK_STEP_BACK) < high_water {
+            if !self.warned.swap(true, Ordering::Relaxed) {
+                log::warn!(
+                    "clock reads {}, far behind the newest timestamp {} of the store, \
+                    using {} until it catches up",
+                    now,
+                    high_water,
+                    high_water.saturating_add(1)
+                );
+            }
+            return high_water.saturating_add(1);
+        }
+
+        self.high_water.fetch_max(now, Ordering::Relaxed);
+        now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_step_back() {
+        let clock = StoreClock::new(Arc::new(|| 5), 1_000_000);
+        assert_eq!(clock.now(), 1_000_001);
+        assert_eq!(clock.now(), 1_000_001);
+
+        // within the tolerated step back.
+        let clock = StoreClock::new(Arc::new(|| 999_000), 1_000_000);
+        assert_eq!(clock.now(), 999_000);
+
+        let clock = StoreClock::new(Arc::new(|| 2_000_000), 1_000_000);
+        assert_eq!(clock.now(), 2_000_000);
+        assert_eq!(clock.high_water.load(Ordering::Relaxed), 2_000_000);
+    }
+}
@@ -1,16 +1,73 @@
 //! Config and Default Constants Definitions Module.
 
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::error::{LSMLibError, Result};
+
 pub(crate) const DATA_FILE_SUFFIX: &str = ".data";
 pub(crate) const HINT_FILE_SUFFIX: &str = ".hint";
 pub(crate) const WAL_FILE_SUFFIX: &str = ".wal";
+pub(crate) const LINEAGE_FILE_SUFFIX: &str = ".lineage";
+pub(crate) const BLOOM_FILE_SUFFIX: &str = ".bloom";
+pub(crate) const RETIRED_FILE_SUFFIX: &str = ".retired";
 pub(crate) const DEFAULT_MAX_LOG_LENGTH: u64 = 32 * 1024 * 1024; // 32MB
 pub(crate) const DEFAULT_MAX_KEY_SIZE: u64 = 64;
 pub(crate) const DEFAULT_MAX_VALUE_SIZE: u64 = 65536;
+pub(crate) const READ_ONLY_OPEN_ATTEMPTS: u32 = 5;
+
+pub(crate) const VERSION_FILE: &str = "VERSION";
+pub(crate) const MIGRATION_FILE: &str = "MIGRATION";
+pub(crate) const REPLICATION_SLOT_PREFIX: &str = "REPLICATION-";
+pub(crate) const SNAPSHOT_MANIFEST_FILE: &str = "SNAPSHOT.json";
+pub(crate) const KEY_TRANSFORM_FILE: &str = "KEY_TRANSFORM";
+pub(crate) const SSTABLE_ID_FILE: &str = "SSTABLE_ID";
+pub(crate) const IDENTITY_FILE: &str = "IDENTITY";
+pub(crate) const CLEAN_SHUTDOWN_FILE: &str = "CLEAN_SHUTDOWN";
+pub(crate) const QUOTAS_FILE: &str = "QUOTAS";
 
 pub(crate) const SSTABLE_DIR: &str = "sstables";
 pub(crate) const U64_SZ: usize = std::mem::size_of::<u64>();
 
-#[derive(Debug, Copy, Clone)]
+/// Which sstables are checked entry by entry when the store opens.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum VerifyOnOpen {
+    /// Trust every sstable.
+    #[default]
+    None,
+
+    /// Check the `n` newest sstables, the ones a crash may have torn.
+    NewestFiles(u32),
+
+    /// Check every sstable, reading the whole store.
+    All,
+}
+
+/// What a write does when the memtable would outgrow
+/// `Config::memtable_hard_limit_bytes`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum MemtableFullPolicy {
+    /// Flush the memtable first, blocking the write meanwhile.
+    #[default]
+    Flush,
+
+    /// Fail the write with `MemtableFull`.
+    Reject,
+}
+
+/// What a read missing the keydir does while a partial open indexes
+/// older sstables, see `Config::partial_open_sstables`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum PartialOpenReadPolicy {
+    /// Wait for every sstable to be indexed, then look the key up again.
+    #[default]
+    Wait,
+
+    /// Fail the read with `IndexingInProgress`.
+    Fail,
+}
+
+#[derive(Debug, Clone)]
 pub struct Config {
     /// If on-disk uncompressed sstable data exceeds in-memory usage
     /// by this proportion, a full-compaction of all sstables will occur.
@@ -36,15 +93,184 @@ pub struct Config {
 
     /// When the background compactor thread looks for ranges of
     /// sstables to merge, it will require ranges to be at least
-    /// this long.
+    /// this long, or 2 long while the store holds fewer sstables.
+    /// The oldest such range is merged, extended as far as its
+    /// sstables qualify.
     pub merge_window: u8,
 
+    /// sstables smaller than this many bytes are small files.
+    pub small_file_size: u64,
+
+    /// When there are more small files than this, the background
+    /// compactor merges runs of them regardless of `merge_ratio`.
+    pub small_file_merge_threshold: u32,
+
+    /// Maximum bytes of small files merged in one run.
+    pub small_file_merge_max_bytes: u64,
+
     /// All inserts go directly to a `BufWriter` wrapping the log
     /// file. This option determines how large that in-memory buffer is.
     pub log_bufwriter_size: u32,
 
     /// The level of compression to use for the sstables with zstd.
+    ///
+    /// Each value is compressed on its own and kept so only if smaller.
+    /// 0 writes new values uncompressed; above 0 the store is stamped
+    /// with format version 5, which older readers refuse.
     pub zstd_sstable_compression_level: u8,
+
+    /// Align every sstable entry to a multiple of this many bytes
+    /// (e.g. 512 or 4096), padding the gap with a padding record.
+    /// 0 disables alignment.
+    pub sstable_block_alignment: u64,
+
+    /// Syncs taking longer than this are logged as a warning
+    /// and counted in the slow syncs stats.
+    pub slow_sync_warn_threshold: Duration,
+
+    /// Tombstones younger than this are kept by compaction even where
+    /// dropping them is safe, so a lagging import or backfill cannot
+    /// bring a deleted key back. Zero drops them as soon as safe.
+    pub tombstone_grace: Duration,
+
+    /// A put syncs the WAL when its oldest unsynced entry is older than
+    /// this, bounding the writes a power failure may lose. `None` syncs
+    /// only on flush.
+    pub max_unsynced_age: Option<Duration>,
+
+    /// Open the store read only: no lock is taken and nothing is ever
+    /// written, so any number of processes may read it. Writes fail
+    /// with `ReadOnly`, the WAL is replayed but kept as is, and the
+    /// compactor stays idle.
+    ///
+    /// The store may be open by a live writer meanwhile: an open whose
+    /// sstables changed under it, by a flush or a compaction, is retried
+    /// a few times before failing with `ConcurrentCompaction`.
+    pub read_only: bool,
+
+    /// Read back every flushed sstable before truncating the WAL,
+    /// checking entry crc and that it holds exactly the memtable keys.
+    /// A failed check fails the flush and keeps the WAL intact.
+    ///
+    /// Costs a full read of each new sstable, roughly doubling flush IO.
+    pub paranoid_flush_checks: bool,
+
+    /// sstables whose entry crc is checked at open, before the keydir is
+    /// built. A torn tail is cut off, dropping the hint file if it points
+    /// past the cut, while a damaged entry followed by intact ones fails
+    /// the open with `VerificationFailed`, as does a torn tail of a read
+    /// only store.
+    pub verify_on_open: VerifyOnOpen,
+
+    /// Whether `Lsm::repair_key` deletes a key left without any intact
+    /// version, so readers get `None` instead of recurring errors.
+    pub repair_writes_tombstone: bool,
+
+    /// Most sstables a read scanning several files, e.g. the search of
+    /// an older version by `Lsm::repair_key`, may read. Past it the read
+    /// fails with `ReadAmplificationExceeded`, or scans the newest files
+    /// only with `clamp_files_per_read`, and `IoStats` counts it. Reads
+    /// through the keydir read a single file.
+    pub max_files_per_read: Option<u32>,
+
+    /// Scan the newest `max_files_per_read` files rather than fail.
+    pub clamp_files_per_read: bool,
+
+    /// Permissions of created files (e.g. `0o600`), whatever the
+    /// process umask. `None` leaves them to the umask, unix only.
+    pub file_mode: Option<u32>,
+
+    /// Permissions of the created store directory (e.g. `0o700`),
+    /// whatever the process umask. `None` leaves them to the umask,
+    /// unix only.
+    pub dir_mode: Option<u32>,
+
+    /// Number of keys the negative lookup cache remembers as absent,
+    /// sparing gets of missing keys the store lock. 0 disables it.
+    pub negative_cache_entries: u32,
+
+    /// Nice value of the compactor thread, e.g. `10` to let foreground
+    /// threads go first on busy hosts. Linux only, where the nice value
+    /// is per thread; elsewhere it would apply to the whole process and
+    /// is ignored. Lowering it below the process value needs privileges.
+    pub compactor_nice: Option<i32>,
+
+    /// Bytes the store may take on disk, sstables, hint files and WAL
+    /// included. Writes which would take it past the limit once flushed
+    /// fail with `DatabaseFull`, deletes still go through. `None` sets
+    /// no limit.
+    pub max_database_bytes: Option<u64>,
+
+    /// Percentage of `max_database_bytes` past which the store is
+    /// flagged as nearly full and compaction favours the runs holding
+    /// the most overwritten and deleted data.
+    pub database_soft_limit_percent: u8,
+
+    /// Write a hint file along with each flushed sstable. Without it
+    /// flushes write one file less, and the keydir of the sstable is
+    /// rebuilt from its data at open until compaction merges it, or
+    /// `Lsm::write_missing_hints` is called. Merged sstables always
+    /// get a hint.
+    pub hints_on_flush: bool,
+
+    /// Deliver the mutations recovered from the WAL at open to the
+    /// write observer again, marked `recovered`, see `observer`.
+    pub replay_writes_on_open: bool,
+
+    /// Smallest id given to a new sstable. Ids only grow, the highest
+    /// one ever given is kept in the `SSTABLE_ID` file.
+    pub sstable_id_start: u64,
+
+    /// sstable ids never given to flushed sstables, kept for sstables
+    /// ingested with `Lsm::ingest_sstable_keep_id`. Compaction output
+    /// takes the id of its newest input, never a new one. Ranges must
+    /// be non-empty, above 0 and disjoint.
+    pub reserved_id_ranges: Vec<Range<u64>>,
+
+    /// Keep the first N bytes of every flushed value in memory, for
+    /// `Lsm::scan_by_value_prefix` to skip values which cannot match
+    /// without reading them. Costs N bytes plus a copy of the key per
+    /// key, `None` keeps no index.
+    pub value_prefix_index_bytes: Option<u8>,
+
+    /// Bytes of keys and values the memtable never exceeds, whatever
+    /// `max_log_length` says, see `memtable_full_policy`. A write
+    /// larger than the limit on its own fails with `MemtableFull`.
+    pub memtable_hard_limit_bytes: Option<u64>,
+
+    pub memtable_full_policy: MemtableFullPolicy,
+
+    /// Number of sstables a flush splits the memtable into, each holding
+    /// a run of consecutive keys of about the same bytes, so flushed
+    /// sstables span narrower key ranges.
+    pub flush_partitions: u8,
+
+    /// Index only the newest this many sstables before `Lsm::open`
+    /// returns, the older ones in the background, newest first. `None`
+    /// indexes every sstable first.
+    ///
+    /// Reads the indexed sstables answer, or the bloom filters of the
+    /// others settle, are served meanwhile, others follow
+    /// `partial_open_read_policy`. Iterations, snapshots and
+    /// compactions wait for the indexing to finish.
+    pub partial_open_sstables: Option<u32>,
+
+    pub partial_open_read_policy: PartialOpenReadPolicy,
+
+    /// Write a bloom filter of the keys of every new sstable with this
+    /// many bits per key, `None` writes none. Filters let a partial open
+    /// answer misses before indexing the older sstables, and compaction
+    /// drop tombstones no older sstable may hold a version under.
+    pub bloom_bits_per_key: Option<u8>,
+
+    /// Values are exported in chunks of at most this many bytes, the
+    /// export holding only one chunk of a value in memory at a time,
+    /// see `Lsm::export_range`. Below 1 GiB.
+    pub export_chunk_size: u32,
+
+    /// Keep the keydir in key order, so ranges and prefixes visit only
+    /// the keys they hold, for slower point lookups.
+    pub ordered_keydir: bool,
 }
 
 impl Default for Config {
@@ -56,8 +282,109 @@ impl Default for Config {
             max_value_size: DEFAULT_MAX_VALUE_SIZE,
             merge_ratio: 3,
             merge_window: 10,
+            small_file_size: 64 * 1024,
+            small_file_merge_threshold: 32,
+            small_file_merge_max_bytes: 64 * 1024 * 1024,
             log_bufwriter_size: 32 * 1024,
             zstd_sstable_compression_level: 3,
+            sstable_block_alignment: 0,
+            slow_sync_warn_threshold: Duration::from_secs(1),
+            tombstone_grace: Duration::ZERO,
+            max_unsynced_age: None,
+            read_only: false,
+            paranoid_flush_checks: false,
+            verify_on_open: VerifyOnOpen::None,
+            repair_writes_tombstone: false,
+            max_files_per_read: None,
+            clamp_files_per_read: false,
+            file_mode: None,
+            dir_mode: None,
+            negative_cache_entries: 0,
+            compactor_nice: None,
+            max_database_bytes: None,
+            database_soft_limit_percent: 90,
+            hints_on_flush: true,
+            replay_writes_on_open: false,
+            sstable_id_start: 1,
+            reserved_id_ranges: Vec::new(),
+            value_prefix_index_bytes: None,
+            memtable_hard_limit_bytes: None,
+            memtable_full_policy: MemtableFullPolicy::Flush,
+            flush_partitions: 1,
+            partial_open_sstables: None,
+            partial_open_read_policy: PartialOpenReadPolicy::Wait,
+            bloom_bits_per_key: None,
+            export_chunk_size: 1 << 20,
+            ordered_keydir: false,
         }
     }
 }
+
+impl Config {
+    /// Check the options make sense together, failing with `InvalidConfig`.
+    pub(crate) fn validate(&self) -> Result<()> {
+        let invalid = |reason: String| Err(LSMLibError::InvalidConfig(reason));
+
+        if self.sstable_id_start == 0 {
+            return invalid("sstable_id_start must be above 0".to_string());
+        }
+
+        if self.flush_partitions == 0 {
+            return invalid("flush_partitions must be above 0".to_string());
+        }
+
+        if self.partial_open_sstables == Some(0) {
+            return invalid("partial_open_sstables must be above 0".to_string());
+        }
+
+        if self.bloom_bits_per_key == Some(0) {
+            return invalid("bloom_bits_per_key must be above 0".to_string());
+        }
+
+        if self.export_chunk_size == 0 || self.export_chunk_size >= 1 << 30 {
+            return invalid("export_chunk_size must be above 0 and below 1 GiB".to_string());
+        }
+
+        if self.value_prefix_index_bytes == Some(0) {
+            return invalid("value_prefix_index_bytes must be above 0".to_string());
+        }
+
+        let mut ranges = self.reserved_id_ranges.clone();
+        ranges.sort_by_key(|r| r.start);
+        for (i, range) in ranges.iter().enumerate() {
+            if range.is_empty() || range.start == 0 {
+                return invalid(format!("reserved id range {:?} is empty or holds 0", range));
+            }
+            if let Some(next) = ranges.get(i + 1).filter(|next| next.start < range.end) {
+                return invalid(format!(
+                    "reserved id ranges {:?} and {:?} overlap",
+                    range, next
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether sstable `id` lies in `reserved_id_ranges`.
+    pub(crate) fn is_reserved_id(&self, id: u64) -> bool {
+        self.reserved_id_ranges.iter().any(|r| r.contains(&id))
+    }
+
+    /// Smallest sstable id above `high_water` which may be given
+    /// to a new sstable.
+    pub(crate) fn next_sstable_id(&self, high_water: u64) -> u64 {
+        let mut id = high_water.saturating_add(1).max(self.sstable_id_start);
+        while let Some(range) = self.reserved_id_ranges.iter().find(|r| r.contains(&id)) {
+            id = range.end;
+        }
+        id
+    }
+
+    /// Bytes past which the store is nearly full, see
+    /// `database_soft_limit_percent`.
+    pub(crate) fn database_soft_limit(&self) -> Option<u64> {
+        self.max_database_bytes
+            .map(|max| max / 100 * u64::from(self.database_soft_limit_percent))
+    }
+}
@@ -0,0 +1,74 @@
+//! SSTable Bloom Filter Module.
+//!
+//! Bloom filter of the keys of an sstable, tombstones included, kept in
+//! a `<id>.bloom` metadata file next to it, see `MetaFileWriter`: a
+//! first record of the number of probed bits per key as u32 and the key
+//! hash seed as u64, then a record of the bit array as u64 words, all
+//! little endian.
+//!
+//! Filters are optional, see `Config::bloom_bits_per_key`: an sstable
+//! without one, or with a damaged one, may hold any key.
+
+use std::path::Path;
+
+use crate::bloomfilter::BloomFilter;
+use crate::disk::format::{MetaFileReader, MetaFileWriter};
+use crate::error::{LSMLibError, Result};
+
+/// Seed of the key hash of sstable filters.
+pub(crate) const BLOOM_SEED: u64 = 0;
+
+const BLOOM_MAGIC: &[u8; 4] = b"BLOM";
+const BLOOM_VERSION: u32 = 1;
+
+/// Read the filter at `path`, `None` if there is none or it is damaged.
+pub(crate) fn read(path: &Path) -> Result<Option<BloomFilter>> {
+    let mut reader = match MetaFileReader::open(path, BLOOM_MAGIC, BLOOM_VERSION) {
+        Ok(reader) => reader,
+        Err(LSMLibError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e @ LSMLibError::CorruptMetaFile { .. }) => {
+            log::warn!("ignoring bloom filter: {}", e);
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let filter = decode(reader.next_record(), reader.next_record());
+    if filter.is_none() {
+        log::warn!("ignoring invalid bloom filter {}", path.display());
+    }
+    Ok(filter)
+}
+
+fn decode(params: Option<Vec<u8>>, words: Option<Vec<u8>>) -> Option<BloomFilter> {
+    let (params, words) = (params?, words?);
+    if params.len() != 12 || words.is_empty() || !words.len().is_multiple_of(8) {
+        return None;
+    }
+
+    let hashes = u32::from_le_bytes(params[..4].try_into().unwrap());
+    let seed = u64::from_le_bytes(params[4..].try_into().unwrap());
+    let words = words
+        .chunks_exact(8)
+        .map(|w| u64::from_le_bytes(w.try_into().unwrap()))
+        .collect();
+    Some(BloomFilter::from_parts(words, hashes, seed))
+}
+
+/// Write `filter` to `path`, replacing any file there.
+pub(crate) fn write(filter: &BloomFilter, path: &Path, file_mode: Option<u32>) -> Result<()> {
+    let mut writer = MetaFileWriter::create(path, BLOOM_MAGIC, BLOOM_VERSION, file_mode)?;
+
+    let mut params = filter.hashes().to_le_bytes().to_vec();
+    params.extend_from_slice(&filter.seed().to_le_bytes());
+    writer.append(&params)?;
+
+    let words: Vec<u8> = filter
+        .words()
+        .iter()
+        .flat_map(|w| w.to_le_bytes())
+        .collect();
+    writer.append(&words)?;
+
+    writer.finish()
+}
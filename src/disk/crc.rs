@@ -10,6 +10,38 @@ pub(super) fn hash(k: &[u8], v: &[u8]) -> u32 {
     hasher.finalize() ^ 0xFF
 }
 
+/// `hash` of an entry expiring at `expiry`, which the crc covers too.
+pub(super) fn hash_expiring(k: &[u8], v: &[u8], expiry: u32) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(k);
+    hasher.update(v);
+    hasher.update(&expiry.to_le_bytes());
+
+    hasher.finalize() ^ 0xFF
+}
+
+/// `hash` or `hash_expiring` of an entry whose value is fed in chunks.
+pub(super) struct EntryHasher(crc32fast::Hasher);
+
+impl EntryHasher {
+    pub(super) fn new(k: &[u8]) -> Self {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(k);
+        Self(hasher)
+    }
+
+    pub(super) fn update(&mut self, chunk: &[u8]) {
+        self.0.update(chunk);
+    }
+
+    pub(super) fn finalize(mut self, expiry: u32) -> u32 {
+        if expiry != 0 {
+            self.0.update(&expiry.to_le_bytes());
+        }
+        self.0.finalize() ^ 0xFF
+    }
+}
+
 #[inline]
 pub(super) fn hash_batch_len(len: usize) -> u32 {
     let mut hasher = crc32fast::Hasher::new();
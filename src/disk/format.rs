@@ -2,11 +2,31 @@
 
 use std::{
     fmt::Display,
-    io::{Read, Seek, SeekFrom, Write},
+    fs,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
 };
 
-use crate::disk::crc::hash;
-use crate::error::Result;
+use crate::disk::crc::{hash, hash_batch_len, hash_expiring};
+use crate::error::{LSMLibError, MetaFileDamage, Result};
+use crate::utils;
+
+/// Read a full header into `buf`.
+///
+/// Returns `false` on a clean end of file, and an `UnexpectedEof`
+/// error when only part of the header is present (a torn write).
+fn read_header<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match r.read(&mut buf[read..])? {
+            0 if read == 0 => return Ok(false),
+            0 => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+            n => read += n,
+        }
+    }
+
+    Ok(true)
+}
 
 /// EntryIO trait.
 pub trait EntryIO {
@@ -21,7 +41,123 @@ pub trait EntryIO {
         W: Write + Seek;
 }
 
-pub const HEADER_SIZE: usize = 16;
+/// Version of the on-disk format written by this crate.
+///
+/// - 1: entries without sequence number.
+/// - 2: entries and hints carry the global sequence number.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Format version of stores which may hold range tombstones.
+///
+/// Version 2 but for range tombstone entries, a store is stamped with it
+/// by its first `Lsm::delete_range` so older readers refuse it rather
+/// than read deleted keys back.
+pub const RANGE_TOMBSTONE_FORMAT_VERSION: u32 = 3;
+
+/// Format version of stores which may hold expiring entries.
+///
+/// Version 3 but for entries flagged with `EXPIRY_FLAG`, a store is
+/// stamped with it by its first `Lsm::put_with_ttl` so older readers
+/// refuse it rather than misread the entries.
+pub const EXPIRY_FORMAT_VERSION: u32 = 4;
+
+/// Format version of stores whose sstables may hold compressed values.
+///
+/// Version 4 but for entries flagged with `COMPRESSED_FLAG`, a store is
+/// stamped with it by its first flush or compaction with
+/// `Config::zstd_sstable_compression_level` above 0, so older readers
+/// refuse it rather than misread the entries.
+pub const COMPRESSION_FORMAT_VERSION: u32 = 5;
+
+/// Format version of stores which may hold touch entries.
+///
+/// Version 5 but for entries flagged with `TOUCH_FLAG`, a store is
+/// stamped with it by its first `Lsm::touch` so older readers refuse it
+/// rather than read touches as tombstones.
+pub const TOUCH_FORMAT_VERSION: u32 = 6;
+
+/// Format version of stores which may hold merge operands.
+///
+/// Version 6 but for entries flagged with `MERGE_FLAG`, a store is
+/// stamped with it by its first `Lsm::merge` so older readers refuse it
+/// rather than read operands as values.
+pub const MERGE_FORMAT_VERSION: u32 = 7;
+
+pub const HEADER_SIZE: usize = 24;
+
+/// Header size of format version 1, without sequence number.
+pub const HEADER_SIZE_V1: usize = 16;
+
+/// `key_sz` of a padding record, never a valid key size.
+///
+/// A padding record is a header with this key size followed by
+/// `value_sz` zero bytes, readers skip over it.
+pub const PADDING_KEY_SZ: u32 = u32::MAX;
+
+/// Size of a batch header record, see `DiskEntry::write_batch_header`.
+pub const BATCH_HEADER_SIZE: u64 = HEADER_SIZE as u64 + 8;
+
+/// Bit of `value_sz` flagging an entry with an expiry, in data and hint
+/// files alike.
+///
+/// The expiry, u32 seconds since the unix epoch, follows the value of a
+/// data entry or the key of a hint entry. Data entries count it in
+/// `value_sz` and in their crc.
+pub const EXPIRY_FLAG: u32 = 1 << 31;
+
+/// Size of the expiry of a flagged entry.
+pub(crate) const EXPIRY_SIZE: usize = 4;
+
+/// Bit of `value_sz` flagging a data entry whose value is stored as a
+/// zstd frame, see `DiskEntry::compress`. Hint entries never carry it.
+///
+/// The crc covers the value, not the frame, and `value_sz` counts the
+/// frame.
+pub const COMPRESSED_FLAG: u32 = 1 << 30;
+
+/// Bit of `value_sz` flagging a touch, in data and hint files alike: an
+/// entry without value moving the expiry of the older version of its
+/// key to its own, see `Lsm::touch`.
+pub const TOUCH_FLAG: u32 = 1 << 29;
+
+/// Bit of `value_sz` flagging merge operands, in data and hint files
+/// alike: an entry whose value holds operands still to apply to the
+/// value of its key, see `Lsm::merge`.
+pub const MERGE_FLAG: u32 = 1 << 28;
+
+/// `value_sz` field of a value of `len` bytes expiring at `expiry`.
+fn encode_value_sz(len: usize, expiry: u32) -> u32 {
+    match expiry {
+        0 => len as u32,
+        _ => (len + EXPIRY_SIZE) as u32 | EXPIRY_FLAG,
+    }
+}
+
+/// Split the expiry off the value region of a flagged entry.
+fn split_expiry(mut value: Vec<u8>, flagged: bool) -> Result<(Vec<u8>, u32)> {
+    if !flagged {
+        return Ok((value, 0));
+    }
+
+    let len = value
+        .len()
+        .checked_sub(EXPIRY_SIZE)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "expiry missing"))?;
+    let expiry = u32::from_le_bytes(value[len..].try_into().unwrap());
+    value.truncate(len);
+
+    Ok((value, expiry))
+}
+
+/// Value of a flagged entry and its zstd frame, see `COMPRESSED_FLAG`.
+fn decompress(stored: Vec<u8>, flagged: bool) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+    if !flagged {
+        return Ok((stored, None));
+    }
+
+    let value = zstd::stream::decode_all(stored.as_slice())?;
+    Ok((value, Some(stored)))
+}
 
 /// Entry Header
 ///
@@ -29,19 +165,22 @@ pub const HEADER_SIZE: usize = 16;
 /// - crc: u32
 /// - timestamp: u32
 /// - key_sz: u32
-/// - value_sz: u32
+/// - value_sz: u32, top bits `EXPIRY_FLAG`, `COMPRESSED_FLAG`, `TOUCH_FLAG`
+///   and `MERGE_FLAG`
+/// - seq: u64
 ///
 #[derive(Debug, Clone)]
 pub struct Header([u8; HEADER_SIZE]);
 
 impl Header {
-    pub fn new(crc: u32, timestamp: u32, key_sz: u32, value_sz: u32) -> Self {
+    pub fn new(crc: u32, timestamp: u32, key_sz: u32, value_sz: u32, seq: u64) -> Self {
         let mut buf = [0u8; HEADER_SIZE];
 
         buf[0..4].copy_from_slice(&crc.to_le_bytes());
         buf[4..8].copy_from_slice(&timestamp.to_le_bytes());
         buf[8..12].copy_from_slice(&key_sz.to_le_bytes());
         buf[12..16].copy_from_slice(&value_sz.to_le_bytes());
+        buf[16..24].copy_from_slice(&seq.to_le_bytes());
 
         Self(buf)
     }
@@ -58,8 +197,38 @@ impl Header {
         u32::from_le_bytes(self.0[8..12].try_into().unwrap())
     }
 
+    /// Size of the value as stored, expiry included, flags masked out.
     pub fn value_sz(&self) -> u32 {
         u32::from_le_bytes(self.0[12..16].try_into().unwrap())
+            & !(EXPIRY_FLAG | COMPRESSED_FLAG | TOUCH_FLAG | MERGE_FLAG)
+    }
+
+    pub fn has_expiry(&self) -> bool {
+        u32::from_le_bytes(self.0[12..16].try_into().unwrap()) & EXPIRY_FLAG != 0
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        u32::from_le_bytes(self.0[12..16].try_into().unwrap()) & COMPRESSED_FLAG != 0
+    }
+
+    pub fn is_touch(&self) -> bool {
+        u32::from_le_bytes(self.0[12..16].try_into().unwrap()) & TOUCH_FLAG != 0
+    }
+
+    pub fn is_merge(&self) -> bool {
+        u32::from_le_bytes(self.0[12..16].try_into().unwrap()) & MERGE_FLAG != 0
+    }
+
+    pub fn seq(&self) -> u64 {
+        u64::from_le_bytes(self.0[16..24].try_into().unwrap())
+    }
+
+    fn set_seq(&mut self, seq: u64) {
+        self.0[16..24].copy_from_slice(&seq.to_le_bytes());
+    }
+
+    fn set_timestamp(&mut self, timestamp: u32) {
+        self.0[4..8].copy_from_slice(&timestamp.to_le_bytes());
     }
 }
 
@@ -82,25 +251,40 @@ pub struct DiskEntry {
     header: Header,
 
     /// key of the disk entry.
-    pub key: Vec<u8>,
+    pub(crate) key: Vec<u8>,
 
     /// value of the disk entry.
-    pub value: Vec<u8>,
+    pub(crate) value: Vec<u8>,
 
     /// offset of the disk entry in the disk file.
-    pub offset: Option<u64>,
+    pub(crate) offset: Option<u64>,
 
     /// file id of the disk entry may stored.
-    pub file_id: Option<u64>,
+    pub(crate) file_id: Option<u64>,
+
+    /// expiry in seconds since the unix epoch, 0 if never.
+    expiry: u32,
+
+    /// zstd frame of the value as stored, if compressed.
+    compressed: Option<Vec<u8>>,
 }
 
 impl DiskEntry {
+    /// Entry of `key` and `value`, an empty value is a tombstone,
+    /// stamped by the system clock.
+    ///
+    /// Stable.
     pub fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
+        Self::stamped(key, value, utils::now_secs())
+    }
+
+    /// Entry of `key` and `value` stamped with `timestamp`, as the
+    /// store writes them by its clock.
+    pub(crate) fn stamped(key: Vec<u8>, value: Vec<u8>, timestamp: u32) -> Self {
         let crc = hash(&key, &value);
-        let timestamp = chrono::Utc::now().timestamp().try_into().unwrap();
         let key_sz = key.len() as u32;
         let value_sz = value.len() as u32;
-        let header = Header::new(crc, timestamp, key_sz, value_sz);
+        let header = Header::new(crc, timestamp, key_sz, value_sz, 0);
 
         Self {
             header,
@@ -108,37 +292,218 @@ impl DiskEntry {
             value,
             offset: None,
             file_id: None,
+            expiry: 0,
+            compressed: None,
         }
     }
 
+    /// Touch of `key` at `timestamp` moving its expiry to `expiry`, 0
+    /// for never, see `Lsm::touch`.
+    pub(crate) fn touch(key: Vec<u8>, expiry: u32, timestamp: u32) -> Self {
+        let mut entry = Self::stamped(key, Vec::new(), timestamp);
+        entry.header = Header::new(
+            entry.crc(),
+            entry.timestamp(),
+            entry.key.len() as u32,
+            TOUCH_FLAG,
+            0,
+        );
+        entry.with_expiry(expiry)
+    }
+
+    /// Merge operands of `key` encoded in `value` at `timestamp`, see
+    /// `Lsm::merge`.
+    pub(crate) fn merge(key: Vec<u8>, value: Vec<u8>, timestamp: u32) -> Self {
+        let mut entry = Self::stamped(key, value, timestamp);
+        entry.header = Header::new(
+            entry.crc(),
+            entry.timestamp(),
+            entry.key.len() as u32,
+            entry.value.len() as u32 | MERGE_FLAG,
+            0,
+        );
+        entry
+    }
+
+    /// Stable.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Stable.
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// Whether the entry deletes its key.
+    ///
+    /// Stable.
+    pub fn is_tombstone(&self) -> bool {
+        self.value.is_empty() && !self.is_touch()
+    }
+
+    /// Whether the entry is a touch, holding no value, see `touch`.
+    pub fn is_touch(&self) -> bool {
+        self.header.is_touch()
+    }
+
+    /// Whether the entry holds merge operands, see `merge`.
+    pub fn is_merge(&self) -> bool {
+        self.header.is_merge()
+    }
+
     pub fn crc(&self) -> u32 {
         self.header.crc()
     }
 
+    /// Write time in seconds since the unix epoch.
+    ///
+    /// Stable.
     pub fn timestamp(&self) -> u32 {
         self.header.timestamp()
     }
 
+    /// Global sequence number of the write.
+    ///
+    /// Experimental, may change with the format version.
+    pub fn seq(&self) -> u64 {
+        self.header.seq()
+    }
+
+    /// Expiry in seconds since the unix epoch, 0 if the entry never
+    /// expires.
+    ///
+    /// Experimental, may change with the format version.
+    pub fn expiry(&self) -> u32 {
+        self.expiry
+    }
+
+    /// Whether the entry has expired at `now`, seconds since the unix epoch.
+    pub fn is_expired(&self, now: u32) -> bool {
+        self.expiry != 0 && self.expiry <= now
+    }
+
+    /// Entry expiring at `expiry`, 0 for never.
+    pub(crate) fn with_expiry(mut self, expiry: u32) -> Self {
+        self.expiry = expiry;
+        self.header = Header::new(
+            self.crc_actual(),
+            self.timestamp(),
+            self.key.len() as u32,
+            self.encode_value_sz(),
+            self.seq(),
+        );
+        self
+    }
+
+    /// Entry storing its value as a zstd frame compressed at `level`,
+    /// unless the frame is no smaller. Tombstones, range tombstones and
+    /// entries already compressed are left as they are.
+    pub(crate) fn compress(mut self, level: i32) -> Result<Self> {
+        if self.key.is_empty() || self.value.is_empty() || self.compressed.is_some() {
+            return Ok(self);
+        }
+
+        let frame = zstd::bulk::compress(&self.value, level)?;
+        if frame.len() >= self.value.len() {
+            return Ok(self);
+        }
+
+        self.compressed = Some(frame);
+        self.header = Header::new(
+            self.crc(),
+            self.timestamp(),
+            self.key.len() as u32,
+            self.encode_value_sz(),
+            self.seq(),
+        );
+        Ok(self)
+    }
+
+    /// Whether the value is stored compressed, see `compress`.
+    pub fn is_compressed(&self) -> bool {
+        self.compressed.is_some()
+    }
+
+    /// Value as stored, the zstd frame of a compressed entry.
+    fn stored_value(&self) -> &[u8] {
+        self.compressed.as_deref().unwrap_or(&self.value)
+    }
+
+    /// `value_sz` field of the entry, flags included.
+    fn encode_value_sz(&self) -> u32 {
+        let mut value_sz = encode_value_sz(self.stored_value().len(), self.expiry);
+        if self.compressed.is_some() {
+            value_sz |= COMPRESSED_FLAG;
+        }
+        if self.is_touch() {
+            value_sz |= TOUCH_FLAG;
+        }
+        if self.is_merge() {
+            value_sz |= MERGE_FLAG;
+        }
+        value_sz
+    }
+
+    pub(crate) fn with_seq(mut self, seq: u64) -> Self {
+        self.header.set_seq(seq);
+        self
+    }
+
+    /// Size of the entry on disk, header included.
+    ///
+    /// Stable.
     pub fn size(&self) -> u64 {
-        (HEADER_SIZE + self.key.len() + self.value.len()) as u64
+        let expiry = if self.expiry == 0 { 0 } else { EXPIRY_SIZE };
+        (HEADER_SIZE + self.key.len() + self.stored_value().len() + expiry) as u64
+    }
+
+    /// Parse the entry filling exactly `buf`,
+    /// `None` if the header disagrees with the buffer length.
+    pub(crate) fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let mut header = [0u8; HEADER_SIZE];
+        header.copy_from_slice(&buf[..HEADER_SIZE]);
+        let header = Header::from(header);
+
+        let (key_sz, value_sz) = (header.key_sz() as usize, header.value_sz() as usize);
+        if header.key_sz() == PADDING_KEY_SZ || HEADER_SIZE + key_sz + value_sz != buf.len() {
+            return None;
+        }
+
+        let (key, value) = buf[HEADER_SIZE..].split_at(key_sz);
+        let (value, expiry) = split_expiry(value.to_vec(), header.has_expiry()).ok()?;
+        let (value, compressed) = decompress(value, header.is_compressed()).ok()?;
+        Some(Self {
+            header,
+            key: key.to_vec(),
+            value,
+            offset: None,
+            file_id: None,
+            expiry,
+            compressed,
+        })
     }
 
     pub fn entry_size(k: &[u8], v: &[u8]) -> u64 {
         (HEADER_SIZE + k.len() + v.len()) as u64
     }
 
-    pub fn offset(mut self, offset: u64) -> Self {
+    pub(crate) fn offset(mut self, offset: u64) -> Self {
         self.offset = Some(offset);
         self
     }
 
-    pub fn file_id(mut self, file_id: u64) -> Self {
+    pub(crate) fn file_id(mut self, file_id: u64) -> Self {
         self.file_id = Some(file_id);
         self
     }
 
     pub fn is_validate(&self) -> bool {
-        self.header.crc() == hash(&self.key, &self.value)
+        self.header.crc() == self.crc_actual()
     }
 
     pub fn crc_expected(&self) -> u32 {
@@ -146,10 +511,207 @@ impl DiskEntry {
     }
 
     pub fn crc_actual(&self) -> u32 {
-        hash(&self.key, &self.value)
+        match self.expiry {
+            0 => hash(&self.key, &self.value),
+            expiry => hash_expiring(&self.key, &self.value, expiry),
+        }
+    }
+
+    /// Size of the padding record needed before an entry at `offset`
+    /// to start it on a multiple of `alignment`, 0 if none.
+    pub(crate) fn padding_size(offset: u64, alignment: u64) -> u64 {
+        if alignment == 0 {
+            return 0;
+        }
+
+        let mut padding = (alignment - offset % alignment) % alignment;
+        // padding record needs room for its own header.
+        while padding != 0 && padding < HEADER_SIZE as u64 {
+            padding += alignment;
+        }
+
+        padding
+    }
+
+    pub(crate) fn with_timestamp(mut self, timestamp: u32) -> Self {
+        self.header.set_timestamp(timestamp);
+        self
+    }
+
+    /// Range tombstone the entry records, if it is one.
+    ///
+    /// A range tombstone is an entry with an empty key, never the key of
+    /// a put, and the bounds as value: u32 length of the start, the
+    /// start, then the end.
+    pub fn range_tombstone(&self) -> Option<RangeTombstone> {
+        if !self.key.is_empty() || self.value.len() < 4 {
+            return None;
+        }
+
+        let start_sz = u32::from_le_bytes(self.value[..4].try_into().unwrap()) as usize;
+        let bounds = &self.value[4..];
+        if start_sz > bounds.len() {
+            return None;
+        }
+
+        Some(RangeTombstone {
+            start: bounds[..start_sz].to_vec(),
+            end: bounds[start_sz..].to_vec(),
+            seq: self.seq(),
+            timestamp: self.timestamp(),
+        })
+    }
+
+    /// Write a padding record of `size` bytes (header included).
+    pub(crate) fn write_padding<W>(w: &mut W, size: u64) -> Result<()>
+    where
+        W: Write,
+    {
+        let value_sz = size - HEADER_SIZE as u64;
+        let header = Header::new(0, 0, PADDING_KEY_SZ, value_sz as u32, 0);
+
+        w.write_all(header.as_ref())?;
+        w.write_all(&vec![0u8; value_sz as usize])?;
+
+        Ok(())
+    }
+
+    /// Write the header of a batch whose entries take the next `len`
+    /// bytes: a padding record holding `len`, its crc checking it.
+    ///
+    /// Readers unaware of batches skip it and read the entries one by one.
+    pub(crate) fn write_batch_header<W>(w: &mut W, len: u64) -> Result<()>
+    where
+        W: Write,
+    {
+        let header = Header::new(hash_batch_len(len as usize), 0, PADDING_KEY_SZ, 8, 0);
+
+        w.write_all(header.as_ref())?;
+        w.write_all(&len.to_le_bytes())?;
+
+        Ok(())
+    }
+
+    /// Length of the entries of the batch whose header is at `offset`,
+    /// `None` if no batch header is there.
+    pub(crate) fn read_batch_header<R>(r: &mut R, offset: u64) -> Result<Option<u64>>
+    where
+        R: Read + Seek,
+    {
+        r.seek(SeekFrom::Start(offset))?;
+
+        let mut buf = [0u8; BATCH_HEADER_SIZE as usize];
+        if !read_header(r, &mut buf)? {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; HEADER_SIZE];
+        header.copy_from_slice(&buf[..HEADER_SIZE]);
+        let header = Header::from(header);
+        let len = u64::from_le_bytes(buf[HEADER_SIZE..].try_into().unwrap());
+
+        // alignment padding is zero filled.
+        let batch = header.key_sz() == PADDING_KEY_SZ
+            && header.value_sz() == 8
+            && len > 0
+            && header.crc() == hash_batch_len(len as usize);
+        Ok(batch.then_some(len))
+    }
+}
+
+/// Deletion of every key in `[start, end)` written before `seq`.
+///
+/// Experimental, may change with the format version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub(crate) start: Vec<u8>,
+    pub(crate) end: Vec<u8>,
+    pub(crate) seq: u64,
+    pub(crate) timestamp: u32,
+}
+
+impl RangeTombstone {
+    pub(crate) fn new(start: Vec<u8>, end: Vec<u8>, seq: u64, timestamp: u32) -> Self {
+        Self {
+            start,
+            end,
+            seq,
+            timestamp,
+        }
+    }
+
+    pub fn start(&self) -> &[u8] {
+        &self.start
+    }
+
+    pub fn end(&self) -> &[u8] {
+        &self.end
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    /// Whether `key` lies within the deleted range.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.start.as_slice() <= key && key < self.end.as_slice()
+    }
+
+    /// Whether the tombstone deletes the version of `key` written at `seq`.
+    pub fn covers(&self, key: &[u8], seq: u64) -> bool {
+        seq < self.seq && self.contains(key)
+    }
+
+    /// Entry recording the tombstone, see `DiskEntry::range_tombstone`.
+    pub(crate) fn to_entry(&self) -> DiskEntry {
+        let mut value = Vec::with_capacity(4 + self.start.len() + self.end.len());
+        value.extend_from_slice(&(self.start.len() as u32).to_le_bytes());
+        value.extend_from_slice(&self.start);
+        value.extend_from_slice(&self.end);
+
+        DiskEntry::stamped(Vec::new(), value, self.timestamp).with_seq(self.seq)
     }
 }
 
+/// Read an entry written in format version 1 at `offset`.
+///
+/// The entry gets a zero sequence number, and its size is the
+/// version 1 size: `HEADER_SIZE_V1 + key + value`.
+pub fn read_entry_v1<R>(r: &mut R, offset: u64) -> Result<Option<DiskEntry>>
+where
+    R: Read + Seek,
+{
+    r.seek(SeekFrom::Start(offset))?;
+
+    let mut buf = [0u8; HEADER_SIZE_V1];
+    if !read_header(r, &mut buf)? {
+        return Ok(None);
+    }
+
+    let field = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+    let (crc, timestamp, key_sz, value_sz) = (field(0), field(4), field(8), field(12));
+
+    let mut key = vec![0u8; key_sz as usize];
+    r.read_exact(&mut key)?;
+
+    let mut value = vec![0u8; value_sz as usize];
+    r.read_exact(&mut value)?;
+
+    Ok(Some(DiskEntry {
+        header: Header::new(crc, timestamp, key_sz, value_sz, 0),
+        key,
+        value,
+        offset: Some(offset),
+        file_id: None,
+        expiry: 0,
+        compressed: None,
+    }))
+}
+
 impl Display for DiskEntry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -170,27 +732,41 @@ impl EntryIO for DiskEntry {
     where
         R: Read + Seek,
     {
+        let mut offset = offset;
         r.seek(SeekFrom::Start(offset))?;
 
-        let mut buf = [0u8; HEADER_SIZE];
-        if r.read(&mut buf)? == 0 {
-            return Ok(None);
-        }
+        let header = loop {
+            let mut buf = [0u8; HEADER_SIZE];
+            if !read_header(r, &mut buf)? {
+                return Ok(None);
+            }
+
+            let header = Header::from(buf);
+            if header.key_sz() != PADDING_KEY_SZ {
+                break header;
+            }
 
-        let header = Header::from(buf);
+            // skip padding record.
+            offset += HEADER_SIZE as u64 + header.value_sz() as u64;
+            r.seek(SeekFrom::Start(offset))?;
+        };
 
         let mut key = vec![0u8; header.key_sz() as usize];
         r.read_exact(&mut key)?;
 
         let mut value = vec![0u8; header.value_sz() as usize];
         r.read_exact(&mut value)?;
+        let (value, expiry) = split_expiry(value, header.has_expiry())?;
+        let (value, compressed) = decompress(value, header.is_compressed())?;
 
         Ok(Some(Self {
             header,
             key,
             value,
-            offset: None,
+            offset: Some(offset),
             file_id: None,
+            expiry,
+            compressed,
         }))
     }
 
@@ -202,33 +778,38 @@ impl EntryIO for DiskEntry {
 
         w.write_all(self.header.as_ref())?;
         w.write_all(self.key.as_ref())?;
-        w.write_all(self.value.as_ref())?;
+        w.write_all(self.stored_value())?;
+        if self.expiry != 0 {
+            w.write_all(&self.expiry.to_le_bytes())?;
+        }
 
         Ok(offset)
     }
 }
 
-pub const HINT_HEADER_SIZE: usize = 20;
+pub const HINT_HEADER_SIZE: usize = 28;
 
 /// Hint Entry Header Structure.
 ///
 /// # fields:
 /// - offset: u64
 /// - key_sz: u32
-/// - value_sz: u32
+/// - value_sz: u32, top bits `EXPIRY_FLAG`, `TOUCH_FLAG` and `MERGE_FLAG`
 /// - timestamp: u32
+/// - seq: u64
 ///
 #[derive(Debug)]
 pub struct HintHeader([u8; HINT_HEADER_SIZE]);
 
 impl HintHeader {
-    pub fn new(offset: u64, key_sz: u32, value_sz: u32, timestamp: u32) -> Self {
+    pub fn new(offset: u64, key_sz: u32, value_sz: u32, timestamp: u32, seq: u64) -> Self {
         let mut buf = [0u8; HINT_HEADER_SIZE];
 
         buf[0..8].copy_from_slice(&offset.to_le_bytes());
         buf[8..12].copy_from_slice(&key_sz.to_le_bytes());
         buf[12..16].copy_from_slice(&value_sz.to_le_bytes());
         buf[16..20].copy_from_slice(&timestamp.to_le_bytes());
+        buf[20..28].copy_from_slice(&seq.to_le_bytes());
 
         Self(buf)
     }
@@ -241,13 +822,32 @@ impl HintHeader {
         u32::from_le_bytes(self.0[8..12].try_into().unwrap()) as usize
     }
 
+    /// `value_sz` of the data entry, `EXPIRY_FLAG`, `TOUCH_FLAG` and
+    /// `MERGE_FLAG` masked out.
     pub fn value_sz(&self) -> usize {
-        u32::from_le_bytes(self.0[12..16].try_into().unwrap()) as usize
+        (u32::from_le_bytes(self.0[12..16].try_into().unwrap())
+            & !(EXPIRY_FLAG | TOUCH_FLAG | MERGE_FLAG)) as usize
+    }
+
+    pub fn has_expiry(&self) -> bool {
+        u32::from_le_bytes(self.0[12..16].try_into().unwrap()) & EXPIRY_FLAG != 0
+    }
+
+    pub fn is_touch(&self) -> bool {
+        u32::from_le_bytes(self.0[12..16].try_into().unwrap()) & TOUCH_FLAG != 0
+    }
+
+    pub fn is_merge(&self) -> bool {
+        u32::from_le_bytes(self.0[12..16].try_into().unwrap()) & MERGE_FLAG != 0
     }
 
     pub fn timestamp(&self) -> u32 {
         u32::from_le_bytes(self.0[16..20].try_into().unwrap())
     }
+
+    pub fn seq(&self) -> u64 {
+        u64::from_le_bytes(self.0[20..28].try_into().unwrap())
+    }
 }
 
 impl AsRef<[u8; HINT_HEADER_SIZE]> for HintHeader {
@@ -263,30 +863,66 @@ impl From<[u8; HINT_HEADER_SIZE]> for HintHeader {
 }
 
 /// Entry in the hint file.
+///
+/// Experimental, hint files may change with the format version.
 #[derive(Debug)]
 pub struct HintEntry {
     /// header of hint entry.
     header: HintHeader,
 
     /// key of disk entry.
-    pub key: Vec<u8>,
+    pub(crate) key: Vec<u8>,
 
     /// file_id of hint entry, also is disk entry.
-    pub file_id: Option<u64>,
+    pub(crate) file_id: Option<u64>,
+
+    /// expiry of the disk entry, 0 if never.
+    expiry: u32,
 }
 
 impl HintEntry {
-    pub fn new(key: Vec<u8>, offset: u64, size: u64, timestamp: u32) -> Self {
+    /// Read the entry at the current position of `r`, without seeking,
+    /// so a buffered reader keeps its buffer across entries.
+    pub(crate) fn read_next<R: Read>(r: &mut R) -> Result<Option<Self>> {
+        let mut buf = [0u8; HINT_HEADER_SIZE];
+        if !read_header(r, &mut buf)? {
+            return Ok(None);
+        }
+
+        let header = HintHeader::from(buf);
+
+        let mut key = vec![0u8; header.key_sz()];
+        r.read_exact(&mut key)?;
+
+        let mut expiry = [0u8; EXPIRY_SIZE];
+        if header.has_expiry() {
+            r.read_exact(&mut expiry)?;
+        }
+
+        Ok(Some(Self {
+            header,
+            key,
+            file_id: None,
+            expiry: u32::from_le_bytes(expiry),
+        }))
+    }
+
+    pub fn new(key: Vec<u8>, offset: u64, size: u64, timestamp: u32, seq: u64) -> Self {
         let key_sz = key.len() as u32;
         let value_sz = size as u32 - HEADER_SIZE as u32 - key_sz;
-        let header = HintHeader::new(offset, key_sz, value_sz, timestamp);
+        let header = HintHeader::new(offset, key_sz, value_sz, timestamp, seq);
         Self {
             header,
             key,
             file_id: None,
+            expiry: 0,
         }
     }
 
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
     pub fn offset(&self) -> u64 {
         self.header.offset()
     }
@@ -299,11 +935,32 @@ impl HintEntry {
         self.header.timestamp()
     }
 
+    pub fn seq(&self) -> u64 {
+        self.header.seq()
+    }
+
+    /// Expiry of the disk entry, 0 if it never expires.
+    pub fn expiry(&self) -> u32 {
+        self.expiry
+    }
+
+    /// Whether the disk entry is a touch, see `DiskEntry::touch`.
+    pub fn is_touch(&self) -> bool {
+        self.header.is_touch()
+    }
+
+    /// Whether the disk entry holds merge operands, see
+    /// `DiskEntry::merge`.
+    pub fn is_merge(&self) -> bool {
+        self.header.is_merge()
+    }
+
     pub fn hint_size(&self) -> u64 {
-        HINT_HEADER_SIZE as u64 + self.key.len() as u64
+        let expiry = if self.expiry == 0 { 0 } else { EXPIRY_SIZE };
+        (HINT_HEADER_SIZE + self.key.len() + expiry) as u64
     }
 
-    pub fn file_id(mut self, file_id: u64) -> Self {
+    pub(crate) fn file_id(mut self, file_id: u64) -> Self {
         self.file_id = Some(file_id);
         self
     }
@@ -334,13 +991,15 @@ impl From<&DiskEntry> for HintEntry {
         let header = HintHeader::new(
             v.offset.unwrap(),
             v.key.len() as u32,
-            v.value.len() as u32,
+            v.encode_value_sz() & !COMPRESSED_FLAG,
             v.timestamp(),
+            v.seq(),
         );
         Self {
             header,
             key: v.key.clone(),
-            file_id: v.file_id.clone(),
+            file_id: v.file_id,
+            expiry: v.expiry,
         }
     }
 }
@@ -353,22 +1012,7 @@ impl EntryIO for HintEntry {
         R: Read + Seek,
     {
         r.seek(SeekFrom::Start(offset))?;
-
-        let mut buf = [0u8; HINT_HEADER_SIZE];
-        if r.read(&mut buf)? == 0 {
-            return Ok(None);
-        }
-
-        let header = HintHeader::from(buf);
-
-        let mut key = vec![0u8; header.key_sz()];
-        r.read_exact(&mut key)?;
-
-        Ok(Some(Self::Entry {
-            header,
-            key,
-            file_id: None,
-        }))
+        Self::read_next(r)
     }
 
     fn write_to<W>(&self, w: &mut W) -> Result<u64>
@@ -379,12 +1023,294 @@ impl EntryIO for HintEntry {
 
         w.write_all(self.header.as_ref())?;
         w.write_all(self.key.as_ref())?;
+        if self.expiry != 0 {
+            w.write_all(&self.expiry.to_le_bytes())?;
+        }
 
         Ok(offset)
     }
 }
 
+/// Size of a metadata file header: magic, version and their crc32.
+const META_HEADER_SIZE: usize = 12;
+
+/// `len` of the record ending a metadata file, never a valid record size.
+const META_END_LEN: u32 = u32::MAX;
+
+/// Writes an internal metadata file, all at once and atomically.
+///
+/// A header of a 4 byte magic, a u32 version and their crc32, then
+/// records of a u32 length, a crc32 of length and payload and the
+/// payload, then an end marker of length `u32::MAX`, a crc32 and the
+/// u64 number of records, all little endian. The end marker tells a
+/// complete file from a truncated one.
+///
+/// Written to `<path>-tmp`, synced, renamed over `path`, then the dir is
+/// synced. Dropped unfinished, the tmp file is removed.
+pub(crate) struct MetaFileWriter {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    file: BufWriter<fs::File>,
+    records: u64,
+    finished: bool,
+}
+
+impl MetaFileWriter {
+    pub(crate) fn create(
+        path: &Path,
+        magic: &[u8; 4],
+        version: u32,
+        file_mode: Option<u32>,
+    ) -> Result<Self> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push("-tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let file = utils::open_with_mode(
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true),
+            &tmp_path,
+            file_mode,
+        )?;
+        let mut writer = Self {
+            path: path.to_path_buf(),
+            tmp_path,
+            file: BufWriter::new(file),
+            records: 0,
+            finished: false,
+        };
+
+        let mut header = [0; META_HEADER_SIZE];
+        header[..4].copy_from_slice(magic);
+        header[4..8].copy_from_slice(&version.to_le_bytes());
+        let crc = crc32fast::hash(&header[..8]);
+        header[8..].copy_from_slice(&crc.to_le_bytes());
+        writer.file.write_all(&header)?;
+
+        Ok(writer)
+    }
+
+    pub(crate) fn append(&mut self, record: &[u8]) -> Result<()> {
+        let len = u32::try_from(record.len())
+            .ok()
+            .filter(|len| *len != META_END_LEN)
+            .ok_or(LSMLibError::ValueIsTooLarge)?;
+        self.write_record(len, record)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    fn write_record(&mut self, len: u32, payload: &[u8]) -> Result<()> {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&len.to_le_bytes());
+        hasher.update(payload);
+
+        self.file.write_all(&len.to_le_bytes())?;
+        self.file.write_all(&hasher.finalize().to_le_bytes())?;
+        self.file.write_all(payload)?;
+        Ok(())
+    }
+
+    /// Write the end marker and move the file in place.
+    pub(crate) fn finish(self) -> Result<()> {
+        let path = self.path.clone();
+        self.finish_unsynced_dir()?;
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::File::open(dir)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    /// Like `finish`, leaving the dir for the caller to sync.
+    pub(crate) fn finish_unsynced_dir(mut self) -> Result<()> {
+        let records = self.records.to_le_bytes();
+        self.write_record(META_END_LEN, &records)?;
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+
+        fs::rename(&self.tmp_path, &self.path)?;
+        self.finished = true;
+
+        Ok(())
+    }
+}
+
+impl Drop for MetaFileWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+/// Reads a file written by `MetaFileWriter`.
+///
+/// The whole file is checked on open, so a damaged one fails with
+/// `CorruptMetaFile` before any record is handed out.
+#[derive(Debug)]
+pub(crate) struct MetaFileReader {
+    path: PathBuf,
+    version: u32,
+    records: std::vec::IntoIter<Vec<u8>>,
+
+    /// number of records handed out.
+    read: u64,
+}
+
+/// A metadata file as found on disk, see `MetaFileReader::open_or_legacy`.
+#[derive(Debug)]
+pub(crate) enum MetaFile {
+    Framed(MetaFileReader),
+
+    /// text of a file written before it was framed.
+    Legacy(String),
+}
+
+impl MetaFileReader {
+    /// Open the file at `path`, of `magic` and a version up to `version`.
+    pub(crate) fn open(path: &Path, magic: &[u8; 4], version: u32) -> Result<Self> {
+        let buf = fs::read(path)?;
+        Self::decode_at(path, &buf, magic, version)
+    }
+
+    /// Open the file at `path` like `open`, `None` if there is none.
+    ///
+    /// A text file not starting with `magic` predates the framing of
+    /// its kind of file and is handed out as is, the next write
+    /// replacing it with a framed one.
+    pub(crate) fn open_or_legacy(
+        path: &Path,
+        magic: &[u8; 4],
+        version: u32,
+    ) -> Result<Option<MetaFile>> {
+        let buf = match fs::read(path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if !buf.starts_with(magic) {
+            if let Ok(text) = String::from_utf8(buf) {
+                return Ok(Some(MetaFile::Legacy(text)));
+            }
+            return Err(LSMLibError::CorruptMetaFile {
+                path: path.to_path_buf(),
+                damage: MetaFileDamage::BadMagic,
+            });
+        }
+
+        Self::decode_at(path, &buf, magic, version).map(|reader| Some(MetaFile::Framed(reader)))
+    }
+
+    fn decode_at(path: &Path, buf: &[u8], magic: &[u8; 4], version: u32) -> Result<Self> {
+        let mut reader =
+            Self::decode(buf, magic, version).map_err(|damage| LSMLibError::CorruptMetaFile {
+                path: path.to_path_buf(),
+                damage,
+            })?;
+        reader.path = path.to_path_buf();
+        Ok(reader)
+    }
+
+    fn decode(
+        buf: &[u8],
+        magic: &[u8; 4],
+        max_version: u32,
+    ) -> std::result::Result<Self, MetaFileDamage> {
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+
+        if buf.len() < META_HEADER_SIZE {
+            return Err(MetaFileDamage::Truncated);
+        }
+        if &buf[..4] != magic {
+            return Err(MetaFileDamage::BadMagic);
+        }
+        if u32_at(8) != crc32fast::hash(&buf[..8]) {
+            return Err(MetaFileDamage::BadHeader);
+        }
+        let version = u32_at(4);
+        if version == 0 || version > max_version {
+            return Err(MetaFileDamage::UnsupportedVersion(version));
+        }
+
+        let mut records = Vec::new();
+        let mut pos = META_HEADER_SIZE;
+        loop {
+            if buf.len() - pos < 8 {
+                return Err(MetaFileDamage::Truncated);
+            }
+            let len = u32_at(pos);
+            let payload_len = match len {
+                META_END_LEN => 8,
+                len => len as usize,
+            };
+            let start = pos + 8;
+            if buf.len() - start < payload_len {
+                return Err(MetaFileDamage::Truncated);
+            }
+            let payload = &buf[start..start + payload_len];
+
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&buf[pos..pos + 4]);
+            hasher.update(payload);
+            if u32_at(pos + 4) != hasher.finalize() {
+                return Err(MetaFileDamage::ChecksumMismatch(records.len() as u64));
+            }
+            pos = start + payload_len;
+
+            if len != META_END_LEN {
+                records.push(payload.to_vec());
+                continue;
+            }
+            let expected = u64::from_le_bytes(payload.try_into().unwrap());
+            let found = records.len() as u64;
+            if expected != found {
+                return Err(MetaFileDamage::RecordCountMismatch { expected, found });
+            }
+            if pos != buf.len() {
+                return Err(MetaFileDamage::TrailingBytes);
+            }
+            break;
+        }
+
+        Ok(Self {
+            path: PathBuf::new(),
+            version,
+            records: records.into_iter(),
+            read: 0,
+        })
+    }
+
+    pub(crate) fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Next record, `None` past the last one.
+    pub(crate) fn next_record(&mut self) -> Option<Vec<u8>> {
+        let record = self.records.next()?;
+        self.read += 1;
+        Some(record)
+    }
+
+    /// Next record, failing with `invalid` past the last one.
+    pub(crate) fn record(&mut self) -> Result<Vec<u8>> {
+        self.next_record().ok_or_else(|| self.invalid())
+    }
+
+    /// `CorruptMetaFile` for content which makes no sense, blaming the
+    /// record handed out last.
+    pub(crate) fn invalid(&self) -> LSMLibError {
+        LSMLibError::CorruptMetaFile {
+            path: self.path.clone(),
+            damage: MetaFileDamage::InvalidRecord(self.read),
+        }
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -405,6 +1331,7 @@ mod tests {
 
         let e = entry1.unwrap();
         assert_eq!(e.key, b"hello".to_vec());
+        assert_eq!(e.seq(), 0);
     }
 
     #[test]
@@ -417,9 +1344,220 @@ mod tests {
         assert_eq!(entry.is_validate(), false);
     }
 
+    #[test]
+    fn test_expiry_io() {
+        let entry = DiskEntry::new(b"hello".to_vec(), b"world".to_vec())
+            .with_expiry(1_000)
+            .with_seq(3);
+        assert_eq!(entry.size(), (HEADER_SIZE + 5 + 5 + EXPIRY_SIZE) as u64);
+        assert!(entry.is_validate());
+        assert!(!entry.is_expired(999));
+        assert!(entry.is_expired(1_000));
+
+        let mut buf = Vec::new();
+        entry.write_to(&mut Cursor::new(&mut buf)).unwrap();
+        assert_eq!(buf.len() as u64, entry.size());
+
+        let e = DiskEntry::read_from(&mut Cursor::new(&buf), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (e.value(), e.expiry(), e.seq()),
+            (b"world".as_slice(), 1_000, 3)
+        );
+        assert!(e.is_validate());
+        assert_eq!(DiskEntry::decode(&buf).unwrap().expiry(), 1_000);
+
+        // the crc covers the expiry.
+        let last = buf.len() - 1;
+        buf[last] ^= 0xFF;
+        assert!(!DiskEntry::decode(&buf).unwrap().is_validate());
+
+        let hint = HintEntry::from(&e);
+        let mut buf = Vec::new();
+        hint.write_to(&mut Cursor::new(&mut buf)).unwrap();
+        assert_eq!(buf.len() as u64, hint.hint_size());
+
+        let h = HintEntry::read_next(&mut buf.as_slice()).unwrap().unwrap();
+        assert_eq!((h.size(), h.expiry()), (entry.size(), 1_000));
+    }
+
+    #[test]
+    fn test_touch_io() {
+        for expiry in [0, 1_000] {
+            let entry = DiskEntry::touch(b"hello".to_vec(), expiry, 500).with_seq(3);
+            assert!(entry.is_touch() && !entry.is_tombstone());
+
+            let mut buf = Vec::new();
+            let offset = entry.write_to(&mut Cursor::new(&mut buf)).unwrap();
+            assert_eq!(buf.len() as u64, entry.size());
+
+            let e = DiskEntry::decode(&buf).unwrap().offset(offset);
+            assert!(e.is_touch() && e.is_validate());
+            assert_eq!(
+                (e.value(), e.expiry(), e.seq(), e.timestamp()),
+                (b"".as_slice(), expiry, 3, 500)
+            );
+
+            let hint = HintEntry::from(&e);
+            let mut buf = Vec::new();
+            hint.write_to(&mut Cursor::new(&mut buf)).unwrap();
+            let h = HintEntry::read_next(&mut buf.as_slice()).unwrap().unwrap();
+            assert!(h.is_touch());
+            assert_eq!((h.size(), h.expiry()), (entry.size(), expiry));
+        }
+
+        // a tombstone is no touch.
+        assert!(!DiskEntry::new(b"hello".to_vec(), Vec::new()).is_touch());
+    }
+
+    #[test]
+    fn test_merge_io() {
+        let entry = DiskEntry::merge(b"hello".to_vec(), b"operands".to_vec(), 500)
+            .with_expiry(1_000)
+            .with_seq(3);
+        assert!(entry.is_merge() && !entry.is_tombstone());
+
+        let mut buf = Vec::new();
+        let offset = entry.write_to(&mut Cursor::new(&mut buf)).unwrap();
+        let e = DiskEntry::decode(&buf).unwrap().offset(offset);
+        assert!(e.is_merge() && e.is_validate());
+        assert_eq!((e.value(), e.expiry()), (b"operands".as_slice(), 1_000));
+
+        let hint = HintEntry::from(&e);
+        let mut buf = Vec::new();
+        hint.write_to(&mut Cursor::new(&mut buf)).unwrap();
+        let h = HintEntry::read_next(&mut buf.as_slice()).unwrap().unwrap();
+        assert!(h.is_merge() && !h.is_touch());
+        assert_eq!((h.size(), h.value_sz()), (entry.size(), 12));
+    }
+
+    #[test]
+    fn test_padding_skipped() {
+        let entry = DiskEntry::new(b"hello".to_vec(), b"world".to_vec());
+
+        let mut buf = Vec::new();
+        let mut cursor = Cursor::new(&mut buf);
+
+        let padding = DiskEntry::padding_size(0, 8);
+        assert_eq!(padding, 0);
+
+        let padding = DiskEntry::padding_size(3, 8);
+        assert_eq!(padding, 29);
+
+        DiskEntry::write_padding(&mut cursor, padding).unwrap();
+        entry.write_to(&mut cursor).unwrap();
+
+        let e = DiskEntry::read_from(&mut cursor, 0).unwrap().unwrap();
+        assert_eq!(e.key, b"hello".to_vec());
+        assert_eq!(e.offset, Some(padding));
+    }
+
+    #[test]
+    fn test_meta_file_damage() {
+        let dir = tempdir::TempDir::new("lsmlib").unwrap();
+        let path = dir.path().join("META");
+        let records: [&[u8]; 3] = [b"first", b"", &[7; 300]];
+
+        let mut writer = MetaFileWriter::create(&path, b"TEST", 2, None).unwrap();
+        writer.append(records[0]).unwrap();
+        drop(writer);
+        assert!(fs::read_dir(dir.path()).unwrap().next().is_none());
+
+        let mut writer = MetaFileWriter::create(&path, b"TEST", 2, None).unwrap();
+        for record in records {
+            writer.append(record).unwrap();
+        }
+        writer.finish().unwrap();
+        let buf = fs::read(&path).unwrap();
+
+        let mut reader = MetaFileReader::open(&path, b"TEST", 2).unwrap();
+        assert_eq!(reader.version(), 2);
+        for record in records {
+            assert_eq!(reader.next_record().unwrap(), record);
+        }
+        assert_eq!(reader.next_record(), None);
+
+        let damage = |buf: &[u8]| {
+            fs::write(&path, buf).unwrap();
+            match MetaFileReader::open(&path, b"TEST", 2) {
+                Err(LSMLibError::CorruptMetaFile { damage, .. }) => damage,
+                other => panic!("read damaged file: {:?}", other),
+            }
+        };
+        assert_eq!(damage(&buf[..buf.len() - 12]), MetaFileDamage::Truncated);
+        assert_eq!(
+            damage(&[&buf[..], b"x"].concat()),
+            MetaFileDamage::TrailingBytes
+        );
+        assert_eq!(
+            MetaFileReader::decode(&buf, b"ELSE", 2).unwrap_err(),
+            MetaFileDamage::BadMagic
+        );
+        assert_eq!(
+            MetaFileReader::decode(&buf, b"TEST", 1).unwrap_err(),
+            MetaFileDamage::UnsupportedVersion(2)
+        );
+
+        // every truncation and every bit flip is caught.
+        for len in 0..buf.len() {
+            damage(&buf[..len]);
+        }
+        for bit in 0..buf.len() * 8 {
+            let mut flipped = buf.clone();
+            flipped[bit / 8] ^= 1 << (bit % 8);
+            damage(&flipped);
+        }
+    }
+
+    #[test]
+    fn test_meta_file_legacy() {
+        let dir = tempdir::TempDir::new("lsmlib").unwrap();
+        let path = dir.path().join("META");
+        let open = || MetaFileReader::open_or_legacy(&path, b"TEST", 1);
+
+        assert!(open().unwrap().is_none());
+
+        fs::write(&path, "42\n").unwrap();
+        assert!(matches!(open().unwrap(), Some(MetaFile::Legacy(text)) if text == "42\n"));
+        fs::write(&path, [0xff, 0xfe]).unwrap();
+        assert!(matches!(
+            open(),
+            Err(LSMLibError::CorruptMetaFile {
+                damage: MetaFileDamage::BadMagic,
+                ..
+            })
+        ));
+
+        // a framed file is never taken for text, damaged or not.
+        let mut writer = MetaFileWriter::create(&path, b"TEST", 1, None).unwrap();
+        writer.append(b"42").unwrap();
+        writer.finish().unwrap();
+        let Some(MetaFile::Framed(mut reader)) = open().unwrap() else {
+            panic!("framed file read as legacy");
+        };
+        assert_eq!(reader.record().unwrap(), b"42");
+        assert!(matches!(
+            reader.record(),
+            Err(LSMLibError::CorruptMetaFile {
+                damage: MetaFileDamage::InvalidRecord(1),
+                ..
+            })
+        ));
+        let buf = fs::read(&path).unwrap();
+        fs::write(&path, &buf[..buf.len() - 1]).unwrap();
+        assert!(matches!(
+            open(),
+            Err(LSMLibError::CorruptMetaFile {
+                damage: MetaFileDamage::Truncated,
+                ..
+            })
+        ));
+    }
+
     #[test]
     fn test_hint_entry_io() {
-        let entry = HintEntry::new(b"hello".to_vec(), 0, 100, 0);
+        let entry = HintEntry::new(b"hello".to_vec(), 0, 100, 0, 7);
 
         assert_eq!(entry.header.key_sz(), 5);
         assert_eq!(entry.header.value_sz(), 100 - 5 - HEADER_SIZE);
@@ -438,6 +1576,7 @@ mod tests {
         let e = entry1.unwrap();
         assert_eq!(e.key, b"hello".to_vec());
         assert_eq!(e.size(), 100);
+        assert_eq!(e.seq(), 7);
         assert_eq!(entry.hint_size(), 5 + HINT_HEADER_SIZE as u64);
     }
 }
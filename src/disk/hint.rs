@@ -1,12 +1,18 @@
 //! Hint File Module.
 
 use crate::error::Result;
+use crate::stats::{FileClass, SyncMonitor};
 use std::fs::File;
+use std::io::BufReader;
 use std::path::Path;
+use std::sync::Arc;
 
 use super::format::{EntryIO, HintEntry};
 use super::logfile::LogFile;
 
+/// Read buffer of `HintEntryIter`, hints are read front to back.
+const HINT_READ_BUFFER_SIZE: usize = 1 << 20;
+
 pub struct HintFile {
     inner: LogFile,
 }
@@ -25,10 +31,22 @@ impl AsMut<LogFile> for HintFile {
 
 impl HintFile {
     pub fn new(path: impl AsRef<Path>, writeable: bool) -> Result<Self> {
-        let inner = LogFile::new(path, writeable)?;
+        let inner = LogFile::new(path, writeable, None)?;
+        Ok(Self { inner })
+    }
+
+    /// Open a writeable hint file, created with permissions `mode` if given.
+    pub(crate) fn create(path: impl AsRef<Path>, mode: Option<u32>) -> Result<Self> {
+        let inner = LogFile::new(path, true, mode)?;
         Ok(Self { inner })
     }
 
+    /// Report syncs of this file to `monitor`.
+    pub(crate) fn with_monitor(mut self, monitor: Arc<SyncMonitor>) -> Self {
+        self.inner.set_monitor(monitor, FileClass::Hint);
+        self
+    }
+
     pub fn path(&self) -> &Path {
         self.inner.path.as_path()
     }
@@ -47,12 +65,14 @@ impl HintFile {
         offset: u64,
         size: u64,
         timestamp: u32,
+        seq: u64,
     ) -> Result<u64> {
         self.write_entry(HintEntry::new(
             key.as_ref().to_vec(),
             offset,
             size,
             timestamp,
+            seq,
         ))
     }
 
@@ -65,17 +85,20 @@ impl HintFile {
     }
 
     pub fn iter(&mut self) -> HintEntryIter {
-        HintEntryIter {
-            reader: self.inner.reader().unwrap(),
-            offset: 0,
+        self.try_iter().unwrap()
+    }
+
+    /// `iter`, failing if the file cannot be opened.
+    pub(crate) fn try_iter(&mut self) -> Result<HintEntryIter> {
+        Ok(HintEntryIter {
+            reader: BufReader::with_capacity(HINT_READ_BUFFER_SIZE, self.inner.reader()?),
             file_id: self.inner.id,
-        }
+        })
     }
 }
 
 pub struct HintEntryIter {
-    reader: File,
-    offset: u64,
+    reader: BufReader<File>,
     file_id: u64,
 }
 
@@ -83,12 +106,8 @@ impl Iterator for HintEntryIter {
     type Item = HintEntry;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match HintEntry::read_from(&mut self.reader, self.offset).unwrap() {
-            None => None,
-            Some(entry) => {
-                self.offset += entry.hint_size();
-                Some(entry.file_id(self.file_id))
-            }
-        }
+        HintEntry::read_next(&mut self.reader)
+            .unwrap()
+            .map(|entry| entry.file_id(self.file_id))
     }
 }
@@ -0,0 +1,198 @@
+//! SSTable Lineage Module.
+//!
+//! Where each sstable comes from, kept in a `<id>.lineage` metadata file
+//! next to it, see `MetaFileWriter`: a first record of the origin as a
+//! byte and the creation time as u64, then a record of the id and the
+//! creation time as u64 per compacted input, all little endian. A file
+//! of the former text lines is still read.
+//!
+//! Lineage is advisory, for debugging: the store never reads it to open,
+//! a crash may leave it missing or stale, and sstables written before
+//! lineage was recorded have none.
+
+use std::fmt;
+use std::path::Path;
+
+use crate::disk::format::{MetaFile, MetaFileReader, MetaFileWriter};
+use crate::error::{LSMLibError, Result};
+use crate::utils;
+
+const LINEAGE_MAGIC: &[u8; 4] = b"LINE";
+const LINEAGE_VERSION: u32 = 1;
+
+/// Origins of the first record, see `Lineage::read`.
+const UNKNOWN: u8 = 0;
+const FLUSH: u8 = 1;
+const COMPACTION: u8 = 2;
+
+/// What wrote an sstable, see `Lineage`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum SSTableOrigin {
+    /// a memtable flush, ingested sstables included.
+    Flush,
+
+    /// a merge of older sstables.
+    Compaction,
+
+    /// no lineage recorded.
+    #[default]
+    Unknown,
+}
+
+impl SSTableOrigin {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SSTableOrigin::Flush => "flush",
+            SSTableOrigin::Compaction => "compaction",
+            SSTableOrigin::Unknown => "unknown",
+        }
+    }
+}
+
+/// Lineage of an sstable, see `Lsm::sstable_lineage`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lineage {
+    pub origin: SSTableOrigin,
+
+    /// seconds since the unix epoch, 0 when unknown.
+    pub created_at: u64,
+
+    /// id and creation time of the inputs of a compaction, oldest first.
+    /// The output takes the id of its newest input, listed too.
+    pub inputs: Vec<(u64, u64)>,
+}
+
+impl Lineage {
+    /// Lineage of an sstable flushed at `created_at`.
+    pub(crate) fn flushed(created_at: u32) -> Self {
+        Self {
+            origin: SSTableOrigin::Flush,
+            created_at: created_at.into(),
+            inputs: Vec::new(),
+        }
+    }
+
+    /// Lineage of an sstable compacted out of `inputs` at `created_at`.
+    pub(crate) fn compacted(inputs: Vec<(u64, u64)>, created_at: u32) -> Self {
+        Self {
+            origin: SSTableOrigin::Compaction,
+            created_at: created_at.into(),
+            inputs,
+        }
+    }
+
+    /// Lineage of sstable `id` of the store at `dir`, `Unknown` if none
+    /// was recorded.
+    pub(crate) fn read(dir: &Path, id: u64) -> Result<Self> {
+        let path = utils::format_lineage_path(dir, id);
+        let mut reader =
+            match MetaFileReader::open_or_legacy(&path, LINEAGE_MAGIC, LINEAGE_VERSION)? {
+                Some(MetaFile::Framed(reader)) => reader,
+                Some(MetaFile::Legacy(text)) => return Self::parse_legacy(&path, &text),
+                None => return Ok(Self::default()),
+            };
+
+        let record = reader.record()?;
+        let origin = match record.first() {
+            Some(&FLUSH) => SSTableOrigin::Flush,
+            Some(&COMPACTION) => SSTableOrigin::Compaction,
+            Some(&UNKNOWN) => SSTableOrigin::Unknown,
+            _ => return Err(reader.invalid()),
+        };
+        let created_at = u64::from_le_bytes(record[1..].try_into().map_err(|_| reader.invalid())?);
+
+        let mut inputs = Vec::new();
+        while let Some(record) = reader.next_record() {
+            if record.len() != 16 {
+                return Err(reader.invalid());
+            }
+            inputs.push((
+                u64::from_le_bytes(record[..8].try_into().unwrap()),
+                u64::from_le_bytes(record[8..].try_into().unwrap()),
+            ));
+        }
+
+        Ok(Self {
+            origin,
+            created_at,
+            inputs,
+        })
+    }
+
+    /// Lineage in the text format of before the framing, a first line
+    /// `<origin> <created_at>`, then one line `input <id> <created_at>`
+    /// per compacted input.
+    fn parse_legacy(path: &Path, text: &str) -> Result<Self> {
+        let invalid = || LSMLibError::Custom(format!("invalid lineage file {}", path.display()));
+
+        let mut lines = text.lines();
+        let (origin, created_at) = lines
+            .next()
+            .and_then(|line| line.split_once(' '))
+            .ok_or_else(invalid)?;
+        let origin = match origin {
+            "flush" => SSTableOrigin::Flush,
+            "compaction" => SSTableOrigin::Compaction,
+            _ => return Err(invalid()),
+        };
+
+        let mut inputs = Vec::new();
+        for line in lines {
+            let mut fields = line.split(' ');
+            let (Some("input"), Some(id), Some(created_at), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid());
+            };
+            inputs.push((id.parse()?, created_at.parse()?));
+        }
+
+        Ok(Self {
+            origin,
+            created_at: created_at.parse()?,
+            inputs,
+        })
+    }
+
+    /// Write the lineage of sstable `id` of the store at `dir`, replacing
+    /// any. The dir is not synced here, the caller syncs it.
+    pub(crate) fn write(&self, dir: &Path, id: u64, file_mode: Option<u32>) -> Result<()> {
+        let path = utils::format_lineage_path(dir, id);
+        let mut writer = MetaFileWriter::create(&path, LINEAGE_MAGIC, LINEAGE_VERSION, file_mode)?;
+
+        let origin = match self.origin {
+            SSTableOrigin::Flush => FLUSH,
+            SSTableOrigin::Compaction => COMPACTION,
+            SSTableOrigin::Unknown => UNKNOWN,
+        };
+        let mut record = vec![origin];
+        record.extend_from_slice(&self.created_at.to_le_bytes());
+        writer.append(&record)?;
+        for (id, created_at) in &self.inputs {
+            let mut record = id.to_le_bytes().to_vec();
+            record.extend_from_slice(&created_at.to_le_bytes());
+            writer.append(&record)?;
+        }
+
+        writer.finish_unsynced_dir()
+    }
+}
+
+impl fmt::Display for Lineage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.origin.as_str())?;
+        if self.origin == SSTableOrigin::Unknown {
+            return Ok(());
+        }
+        write!(f, " at {}", self.created_at)?;
+        if !self.inputs.is_empty() {
+            let inputs: Vec<String> = self
+                .inputs
+                .iter()
+                .map(|(id, created_at)| format!("{}@{}", id, created_at))
+                .collect();
+            write!(f, " from {}", inputs.join(", "))?;
+        }
+        Ok(())
+    }
+}
@@ -3,8 +3,10 @@
 use std::fs::{self, File};
 use std::io::{Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use crate::error::{LSMLibError, Result};
+use crate::stats::{FileClass, SyncMonitor};
 use crate::utils;
 
 #[derive(Debug)]
@@ -20,26 +22,30 @@ pub(crate) struct LogFile {
 
     /// Current file writer.
     writer: Option<File>,
+
+    /// Monitor of syncs, with the class of this file.
+    monitor: Option<(Arc<SyncMonitor>, FileClass)>,
+
+    /// file length at the last sync.
+    synced_len: u64,
 }
 
 impl LogFile {
-    pub(crate) fn new(path: impl AsRef<Path>, writeable: bool) -> Result<Self> {
+    /// Open the log file at `path`, a writeable one is created
+    /// with permissions `mode` if given.
+    pub(crate) fn new(path: impl AsRef<Path>, writeable: bool, mode: Option<u32>) -> Result<Self> {
         let path = path.as_ref();
 
         // Data name must starts with valid file id.
-        let file_id = utils::parse_file_id(path).expect(&format!(
-            "file id not found in file path: {}",
-            path.display()
-        ));
+        let file_id = utils::parse_file_id(path)
+            .unwrap_or_else(|| panic!("file id not found in file path: {}", path.display()));
 
         let writer = if writeable {
-            Some(
-                fs::OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .append(true)
-                    .open(path)?,
-            )
+            Some(utils::open_with_mode(
+                fs::OpenOptions::new().create(true).append(true),
+                path,
+                mode,
+            )?)
         } else {
             None
         };
@@ -49,16 +55,37 @@ impl LogFile {
             id: file_id,
             writeable,
             writer,
+            monitor: None,
+            synced_len: 0,
         })
     }
 
+    /// Report syncs of this file of `class` to `monitor`.
+    pub(crate) fn set_monitor(&mut self, monitor: Arc<SyncMonitor>, class: FileClass) {
+        self.monitor = Some((monitor, class));
+    }
+
+    /// Sync writer, through the monitor if any.
+    fn sync_writer(&mut self, bytes: u64) -> Result<()> {
+        let w = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| LSMLibError::FileNotWriteable(self.path.to_path_buf()))?;
+
+        match &self.monitor {
+            Some((monitor, class)) => monitor.sync(*class, &self.path, bytes, || w.sync_all()),
+            None => Ok(w.sync_all()?),
+        }
+    }
+
     /// Truncate file.
     pub(crate) fn truncate(&mut self, offset: u64) -> Result<()> {
         let w = self.writer()?;
 
         w.seek(SeekFrom::Start(offset))?;
         w.set_len(offset)?;
-        w.sync_all()?;
+        self.sync_writer(0)?;
+        self.synced_len = offset;
 
         Ok(())
     }
@@ -74,12 +101,19 @@ impl LogFile {
     }
 
     pub(crate) fn sync(&mut self) -> Result<()> {
-        self.writer()?.sync_all()?;
+        let len = self.size()?;
+        self.sync_writer(len.saturating_sub(self.synced_len))?;
+        self.synced_len = len;
         Ok(())
     }
 
     /// Datafile size current.
+    ///
+    /// Read from the open writer if any, which outlives the file path.
     pub(crate) fn size(&self) -> Result<u64> {
+        if let Some(writer) = &self.writer {
+            return Ok(writer.metadata()?.len());
+        }
         let reader = self.reader()?;
         Ok(reader.metadata()?.len())
     }
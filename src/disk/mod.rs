@@ -1,8 +1,10 @@
 //! disk objects.
 pub mod format;
 pub mod hint;
+pub mod lineage;
 pub mod sstable;
 pub mod wal;
 
+pub(crate) mod bloom;
 mod crc;
 mod logfile;
@@ -1,20 +1,73 @@
 //! SSTable Module.
 
-use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
-use std::fs::File;
-use std::iter::Peekable;
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
 
-use crate::error::Result;
+use crate::bloomfilter::{self, BloomFilter};
+use crate::config::Config;
+use crate::error::{LSMLibError, Result};
+use crate::lsm::merge::{self, Base, MergeOperatorFn, Operands};
+use crate::stats::{FileClass, SyncMonitor};
+use crate::utils;
 
-use super::format::{DiskEntry, EntryIO};
+use super::bloom::{self, BLOOM_SEED};
+use super::crc::EntryHasher;
+use super::format::{
+    DiskEntry, EntryIO, Header, HintEntry, EXPIRY_SIZE, HEADER_SIZE, PADDING_KEY_SZ,
+};
+use super::hint::HintFile;
 use super::logfile::LogFile;
+use super::wal::WalRecords;
 
 #[derive(Debug)]
 pub struct SSTable {
     inner: LogFile,
     reader: File,
+
+    /// entries are aligned to multiple of this, 0 means no alignment.
+    alignment: u64,
+
+    /// zstd level values written are compressed at, 0 means none.
+    compression_level: u8,
+
+    /// max sequence number of the entries known in this sstable.
+    max_seq: u64,
+
+    /// file identity when opened read only, to detect external changes.
+    fingerprint: Option<Fingerprint>,
+
+    /// filter of the keys of the sstable, if it has one.
+    bloom: Option<Arc<BloomFilter>>,
+}
+
+/// Size, modification time and inode of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Fingerprint {
+    len: u64,
+    modified: Option<SystemTime>,
+    ino: u64,
+}
+
+impl Fingerprint {
+    fn of(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+
+        #[cfg(unix)]
+        let ino = std::os::unix::fs::MetadataExt::ino(&metadata);
+        #[cfg(not(unix))]
+        let ino = 0;
+
+        Ok(Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            ino,
+        })
+    }
 }
 
 impl AsRef<LogFile> for SSTable {
@@ -31,10 +84,88 @@ impl AsMut<LogFile> for SSTable {
 
 impl SSTable {
     pub fn new(path: impl AsRef<Path>, writeable: bool) -> Result<Self> {
-        let inner = LogFile::new(path, writeable)?;
+        Self::open(path, writeable, None)
+    }
+
+    /// Open a writeable sstable, created with permissions `mode` if given.
+    pub(crate) fn create(path: impl AsRef<Path>, mode: Option<u32>) -> Result<Self> {
+        Self::open(path, true, mode)
+    }
+
+    fn open(path: impl AsRef<Path>, writeable: bool, mode: Option<u32>) -> Result<Self> {
+        let inner = LogFile::new(path, writeable, mode)?;
         let reader = inner.reader()?;
+        let fingerprint = match writeable {
+            true => None,
+            false => Some(Fingerprint::of(&inner.path)?),
+        };
+
+        Ok(SSTable {
+            inner,
+            reader,
+            alignment: 0,
+            compression_level: 0,
+            max_seq: 0,
+            fingerprint,
+            bloom: None,
+        })
+    }
+
+    /// Check the file is still the one opened, read only sstables
+    /// being never modified by the store.
+    ///
+    /// Fails with `ExternallyModified` if it was replaced, removed,
+    /// or changed in size or modification time.
+    pub(crate) fn verify_fingerprint(&self) -> Result<()> {
+        let expected = match &self.fingerprint {
+            Some(fingerprint) => fingerprint,
+            None => return Ok(()),
+        };
+
+        let actual = Fingerprint::of(&self.inner.path).ok();
+        if actual.as_ref() != Some(expected) {
+            return Err(LSMLibError::ExternallyModified {
+                path: self.inner.path.clone(),
+                modified: actual.and_then(|f| f.modified),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Report syncs of this file as `class` to `monitor`.
+    pub(crate) fn with_monitor(mut self, monitor: Arc<SyncMonitor>, class: FileClass) -> Self {
+        self.inner.set_monitor(monitor, class);
+        self
+    }
+
+    /// Align entries written to this sstable to multiple of `alignment` bytes.
+    pub fn with_alignment(mut self, alignment: u64) -> Self {
+        self.alignment = alignment;
+        self
+    }
+
+    /// Compress the values written to this sstable at zstd `level`, see
+    /// `DiskEntry::compress`. 0 writes them uncompressed.
+    pub fn with_compression(mut self, level: u8) -> Self {
+        self.compression_level = level;
+        self
+    }
+
+    /// Filter the keys of the sstable with `bloom`, see `may_contain`.
+    pub(crate) fn with_bloom(mut self, bloom: Option<BloomFilter>) -> Self {
+        self.bloom = bloom.map(Arc::new);
+        self
+    }
+
+    pub(crate) fn bloom(&self) -> Option<Arc<BloomFilter>> {
+        self.bloom.clone()
+    }
 
-        Ok(SSTable { inner, reader })
+    /// Whether the sstable may hold a version of `key`, `false` only
+    /// if its bloom filter rules it out.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.bloom.as_ref().is_none_or(|b| b.may_contain(key))
     }
 
     pub fn path(&self) -> &Path {
@@ -45,8 +176,18 @@ impl SSTable {
         self.inner.id
     }
 
+    /// Max sequence number of the entries written or indexed.
+    pub fn max_seq(&self) -> u64 {
+        self.max_seq
+    }
+
+    pub fn update_max_seq(&mut self, seq: u64) {
+        self.max_seq = self.max_seq.max(seq);
+    }
+
+    /// Size of the file opened with the sstable.
     pub fn size(&self) -> u64 {
-        self.inner.size().unwrap()
+        self.reader.metadata().unwrap().len()
     }
 
     pub fn truncate(&mut self, offset: u64) -> Result<()> {
@@ -64,9 +205,19 @@ impl SSTable {
 
     pub fn write_entry(&mut self, disk_entry: DiskEntry) -> Result<DiskEntry> {
         let path = self.inner.path.to_path_buf();
+        let disk_entry = match self.compression_level {
+            0 => disk_entry,
+            level => disk_entry.compress(level.into())?,
+        };
 
+        let alignment = self.alignment;
         let w = self.inner.writer()?;
 
+        let padding = DiskEntry::padding_size(w.stream_position()?, alignment);
+        if padding > 0 {
+            DiskEntry::write_padding(w, padding)?;
+        }
+
         log::trace!(
             "append {} to segement file {}",
             String::from_utf8_lossy(&disk_entry.key),
@@ -74,6 +225,7 @@ impl SSTable {
         );
 
         let offset = disk_entry.write_to(w)?;
+        self.max_seq = self.max_seq.max(disk_entry.seq());
 
         log::trace!(
             "successfully append {} to data file {}",
@@ -84,6 +236,16 @@ impl SSTable {
         Ok(disk_entry.offset(offset).file_id(self.inner.id))
     }
 
+    /// Append `entries` as one batch, see `DiskEntry::write_batch_header`.
+    pub(crate) fn write_batch(&mut self, entries: Vec<DiskEntry>) -> Result<Vec<DiskEntry>> {
+        // padding between the entries would break the batch length.
+        debug_assert_eq!(self.alignment, 0);
+
+        let len = entries.iter().map(DiskEntry::size).sum();
+        DiskEntry::write_batch_header(self.inner.writer()?, len)?;
+        entries.into_iter().map(|e| self.write_entry(e)).collect()
+    }
+
     /// Read key value in data file.
     pub fn read(&mut self, offset: u64) -> Result<Option<DiskEntry>> {
         log::trace!(
@@ -92,7 +254,7 @@ impl SSTable {
             self.inner.path.display()
         );
 
-        if self.inner.size()? < offset {
+        if self.reader.metadata()?.len() < offset {
             return Ok(None);
         }
 
@@ -110,94 +272,917 @@ impl SSTable {
         }
     }
 
+    /// Read the entry of `size` bytes at `offset`, as recorded in the keydir.
+    ///
+    /// The entry is read in a single positional read and checked against its crc.
+    pub fn read_sized(&self, offset: u64, size: u64) -> Result<DiskEntry> {
+        log::trace!(
+            "read {} bytes entry with offset {} in data file {}",
+            size,
+            offset,
+            self.inner.path.display()
+        );
+
+        let stale = || LSMLibError::StaleKeydirEntry {
+            file_id: self.inner.id,
+            offset,
+            expected: size,
+        };
+
+        let mut buf = vec![0u8; size as usize];
+        match read_exact_at(&self.reader, &mut buf, offset) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(stale()),
+            r => r?,
+        }
+
+        let entry = DiskEntry::decode(&buf).ok_or_else(stale)?;
+        if !entry.is_validate() {
+            return Err(LSMLibError::ChecksumMismatch {
+                file_id: self.inner.id,
+                offset,
+                expected: entry.crc_expected(),
+                actual: entry.crc_actual(),
+            });
+        }
+
+        Ok(entry.offset(offset).file_id(self.inner.id))
+    }
+
+    /// Reader of the value of the entry of `size` bytes at `offset`, as
+    /// `read_sized` but in chunks of the caller's choosing, so a large
+    /// value is never held whole.
+    ///
+    /// Reads go through a handle of their own, which on unix outlives
+    /// the sstable being compacted away meanwhile.
+    pub(crate) fn value_reader(&self, offset: u64, size: u64) -> Result<ValueReader> {
+        let stale = || LSMLibError::StaleKeydirEntry {
+            file_id: self.inner.id,
+            offset,
+            expected: size,
+        };
+        let read_at = |buf: &mut [u8], at: u64| match read_exact_at(&self.reader, buf, at) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(stale()),
+            r => Ok(r?),
+        };
+
+        let mut header = [0u8; HEADER_SIZE];
+        read_at(&mut header, offset)?;
+        let header = Header::from(header);
+
+        let (key_sz, value_sz) = (header.key_sz() as u64, header.value_sz() as u64);
+        let expiry_sz = if header.has_expiry() { EXPIRY_SIZE } else { 0 } as u64;
+        if header.key_sz() == PADDING_KEY_SZ
+            || HEADER_SIZE as u64 + key_sz + value_sz != size
+            || value_sz < expiry_sz
+        {
+            return Err(stale());
+        }
+
+        let mut key = vec![0u8; key_sz as usize];
+        read_at(&mut key, offset + HEADER_SIZE as u64)?;
+        let mut expiry = [0u8; EXPIRY_SIZE];
+        if header.has_expiry() {
+            read_at(&mut expiry, offset + size - expiry_sz)?;
+        }
+
+        let section = FileSection {
+            file: self.reader.try_clone()?,
+            offset: offset + HEADER_SIZE as u64 + key_sz,
+            end: offset + size - expiry_sz,
+        };
+        let inner: Box<dyn Read> = match header.is_compressed() {
+            true => Box::new(zstd::stream::read::Decoder::new(section)?),
+            false => Box::new(section),
+        };
+
+        Ok(ValueReader {
+            inner,
+            hasher: EntryHasher::new(&key),
+            crc: header.crc(),
+            expiry: u32::from_le_bytes(expiry),
+            file_id: self.inner.id,
+            offset,
+        })
+    }
+
+    /// Iterate the entries physically in the file, in file order.
+    ///
+    /// Not a view of the store: a key may be shadowed by a newer
+    /// sstable and tombstones are yielded as empty values. Iterate a
+    /// store with `Lsm::iter`, which yields each live key once.
+    ///
+    /// Reads through the file opened with the sstable, so it iterates
+    /// even once the file is removed, e.g. compacted away. An entry
+    /// which cannot be read ends the iteration with an error.
     pub fn iter(&mut self) -> DiskEntryIter {
         DiskEntryIter {
-            reader: self.inner.reader().unwrap(),
+            reader: self.reader.try_clone().unwrap(),
             offset: 0,
             file_id: self.inner.id,
+            failed: false,
+        }
+    }
+
+    /// Iterate the records of a WAL, batches whole.
+    pub(crate) fn records(&mut self) -> Result<WalRecords> {
+        Ok(WalRecords::new(self.iter(), self.inner.reader()?))
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(not(unix))]
+fn read_exact_at(mut file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::io::{Read, SeekFrom};
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+/// Bytes `[offset, end)` of a file, read positionally.
+struct FileSection {
+    file: File,
+    offset: u64,
+    end: u64,
+}
+
+impl Read for FileSection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (self.end - self.offset).min(buf.len() as u64) as usize;
+        read_exact_at(&self.file, &mut buf[..n], self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// Value of an sstable entry read in chunks, see `SSTable::value_reader`.
+///
+/// Compressed values are decompressed as read. The crc is checked by
+/// `finish`, once the whole value went through.
+pub(crate) struct ValueReader {
+    inner: Box<dyn Read>,
+    hasher: EntryHasher,
+    crc: u32,
+    expiry: u32,
+    file_id: u64,
+    offset: u64,
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl ValueReader {
+    /// Read what is left of the value and check its crc.
+    pub(crate) fn finish(mut self) -> Result<()> {
+        io::copy(&mut self, &mut io::sink())?;
+
+        let actual = self.hasher.finalize(self.expiry);
+        if actual != self.crc {
+            return Err(LSMLibError::ChecksumMismatch {
+                file_id: self.file_id,
+                offset: self.offset,
+                expected: self.crc,
+                actual,
+            });
         }
+        Ok(())
     }
 }
 
+/// Iterator over the entries of a file, see `SSTable::iter`.
+///
+/// An entry which cannot be read is yielded as an error ending the
+/// iteration: whether a torn tail may be dropped is up to the caller,
+/// see `WalRecords`. Crcs are left to the caller to check.
 pub struct DiskEntryIter {
     reader: File,
     offset: u64,
     file_id: u64,
+    failed: bool,
 }
 
 impl Iterator for DiskEntryIter {
-    type Item = DiskEntry;
+    type Item = Result<DiskEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match DiskEntry::read_from(&mut self.reader, self.offset).unwrap() {
-            None => None,
-            Some(entry) => {
-                let entry = entry.offset(self.offset).file_id(self.file_id);
-                self.offset += entry.size();
-                Some(entry)
+        if self.failed {
+            return None;
+        }
+
+        let entry = match DiskEntry::read_from(&mut self.reader, self.offset) {
+            Ok(None) => return None,
+            Ok(Some(entry)) => entry,
+            Err(e) => {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        };
+
+        // entry may start after a padding record.
+        let offset = entry.offset.unwrap_or(self.offset);
+        let entry = entry.file_id(self.file_id);
+        self.offset = offset + entry.size();
+        Some(Ok(entry))
+    }
+}
+
+/// Options of an `SSTableWriter`, the defaults are those of a store
+/// opened with the default `Config`.
+#[derive(Debug, Clone)]
+pub struct SSTableWriterOptions {
+    /// permissions of the created files, see `Config::file_mode`.
+    pub file_mode: Option<u32>,
+
+    /// entries are aligned to multiple of this, 0 means no alignment.
+    pub block_alignment: u64,
+
+    /// whether to write the hint file next to the sstable.
+    pub write_hint: bool,
+
+    /// largest key accepted by `add`.
+    pub max_key_size: u64,
+
+    /// largest value accepted by `add`.
+    pub max_value_size: u64,
+
+    /// bits per key of the bloom filter written next to the sstable,
+    /// `None` writes none.
+    pub bloom_bits_per_key: Option<u8>,
+
+    /// zstd level values are compressed at, 0 writes them uncompressed.
+    pub compression_level: u8,
+}
+
+impl SSTableWriterOptions {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            file_mode: config.file_mode,
+            block_alignment: config.sstable_block_alignment,
+            write_hint: config.hints_on_flush,
+            max_key_size: config.max_key_size,
+            max_value_size: config.max_value_size,
+            bloom_bits_per_key: config.bloom_bits_per_key,
+            compression_level: config.zstd_sstable_compression_level,
+        }
+    }
+}
+
+impl Default for SSTableWriterOptions {
+    fn default() -> Self {
+        Self::from_config(&Config::default())
+    }
+}
+
+/// Files written by an `SSTableWriter`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SSTableMeta {
+    pub path: PathBuf,
+
+    /// hint file, if written.
+    pub hint_path: Option<PathBuf>,
+
+    /// bloom filter file, if written.
+    pub bloom_path: Option<PathBuf>,
+
+    /// number of entries, tombstones included.
+    pub entries: u64,
+    pub tombstones: u64,
+
+    /// size of the sstable in bytes.
+    pub size: u64,
+
+    pub first_key: Option<Vec<u8>>,
+    pub last_key: Option<Vec<u8>>,
+
+    /// max sequence number of the entries.
+    pub max_seq: u64,
+}
+
+/// Builder of an sstable and its hint file, outside of a live store.
+///
+/// Entries are added in increasing key order, the files are those a
+/// store flush writes for the same entries. The files are removed if
+/// the writer is dropped before `finish`.
+///
+/// # Examples
+///
+/// ```
+/// use slmlib::lsm::sstable::{SSTableWriter, SSTableWriterOptions};
+///
+/// let dir = tempdir::TempDir::new("lsmlib").unwrap();
+/// let path = dir.path().join("000000000001.sst");
+///
+/// let mut writer = SSTableWriter::create(&path, SSTableWriterOptions::default()).unwrap();
+/// writer.add(b"a", b"1", 0).unwrap();
+/// writer.add_tombstone(b"b", 0).unwrap();
+/// let meta = writer.finish().unwrap();
+/// assert_eq!((meta.entries, meta.tombstones), (2, 1));
+/// ```
+pub struct SSTableWriter {
+    sstable: SSTable,
+    hint: Option<HintFile>,
+
+    /// bloom filter file and the hashes of the keys written.
+    bloom: Option<(PathBuf, Vec<u64>)>,
+    options: SSTableWriterOptions,
+    first_key: Option<Vec<u8>>,
+    last_key: Option<Vec<u8>>,
+    entries: u64,
+    tombstones: u64,
+    finished: bool,
+}
+
+impl SSTableWriter {
+    /// Create the sstable at `path`, named `<id>.sst` like those of a
+    /// store, and its hint and bloom filter files next to it.
+    ///
+    /// Fails with `InvalidFileName` if `path` has no file id, and
+    /// with an `AlreadyExists` io error if it names an existing file.
+    pub fn create(path: impl AsRef<Path>, options: SSTableWriterOptions) -> Result<Self> {
+        let path = path.as_ref();
+        let id = utils::parse_file_id(path)
+            .ok_or_else(|| LSMLibError::InvalidFileName(path.to_path_buf()))?;
+        if path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("sstable '{}' already exists", path.display()),
+            )
+            .into());
+        }
+
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let hint_path = utils::format_hint_path(dir, id);
+        Self::create_at(
+            path,
+            options.write_hint.then_some(&hint_path),
+            &utils::format_bloom_path(dir, id),
+            options,
+        )
+    }
+
+    /// Create the sstable at `path` and, if any, its hint file at
+    /// `hint_path` and bloom filter file at `bloom_path`, e.g. under the
+    /// temporary names of a compaction.
+    /// `SSTableWriterOptions::write_hint` is ignored.
+    pub(crate) fn create_at(
+        path: &Path,
+        hint_path: Option<&Path>,
+        bloom_path: &Path,
+        options: SSTableWriterOptions,
+    ) -> Result<Self> {
+        let sstable = SSTable::create(path, options.file_mode)?
+            .with_alignment(options.block_alignment)
+            .with_compression(options.compression_level);
+        let hint = hint_path
+            .map(|hint_path| HintFile::create(hint_path, options.file_mode))
+            .transpose()?;
+
+        let bloom = options
+            .bloom_bits_per_key
+            .map(|_| (bloom_path.to_path_buf(), Vec::new()));
+
+        Ok(Self {
+            sstable,
+            hint,
+            bloom,
+            options,
+            first_key: None,
+            last_key: None,
+            entries: 0,
+            tombstones: 0,
+            finished: false,
+        })
+    }
+
+    /// Report syncs of the files to `monitor`.
+    pub(crate) fn with_monitor(mut self, monitor: Arc<SyncMonitor>) -> Self {
+        self.sstable
+            .inner
+            .set_monitor(Arc::clone(&monitor), FileClass::SSTable);
+        if let Some(hint) = &mut self.hint {
+            hint.as_mut().set_monitor(monitor, FileClass::Hint);
+        }
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        self.sstable.path()
+    }
+
+    pub fn hint_path(&self) -> Option<&Path> {
+        self.hint.as_ref().map(HintFile::path)
+    }
+
+    pub fn bloom_path(&self) -> Option<&Path> {
+        self.bloom.as_ref().map(|(path, _)| path.as_path())
+    }
+
+    /// Add `key` and `value` written at `timestamp`, in seconds since the
+    /// unix epoch. An empty value is a tombstone.
+    ///
+    /// Fails with `OutOfOrderKey` unless `key` is greater than the keys
+    /// added before, with `EmptyKey`, `KeyIsTooLarge` or `ValueIsTooLarge`
+    /// for keys and values the options do not allow.
+    pub fn add(&mut self, key: &[u8], value: &[u8], timestamp: u32) -> Result<()> {
+        if key.is_empty() {
+            return Err(LSMLibError::EmptyKey);
+        }
+        if key.len() as u64 > self.options.max_key_size {
+            return Err(LSMLibError::KeyIsTooLarge);
+        }
+        if value.len() as u64 > self.options.max_value_size {
+            return Err(LSMLibError::ValueIsTooLarge);
+        }
+        if self.last_key.as_deref().is_some_and(|last| key <= last) {
+            return Err(LSMLibError::OutOfOrderKey(key.to_vec()));
+        }
+
+        self.write_entry(DiskEntry::stamped(key.to_vec(), value.to_vec(), timestamp))?;
+        Ok(())
+    }
+
+    /// Add a tombstone of `key`, see `add`.
+    pub fn add_tombstone(&mut self, key: &[u8], timestamp: u32) -> Result<()> {
+        self.add(key, &[], timestamp)
+    }
+
+    /// Append `entry` to the sstable and the hint file, unchecked.
+    pub(crate) fn write_entry(&mut self, entry: DiskEntry) -> Result<DiskEntry> {
+        let disk_entry = self.sstable.write_entry(entry)?;
+        if let Some(hint) = &mut self.hint {
+            hint.write_entry(HintEntry::from(&disk_entry))?;
+        }
+
+        // range tombstones have an empty key.
+        if !disk_entry.key.is_empty() {
+            if let Some((_, hashes)) = &mut self.bloom {
+                hashes.push(bloomfilter::hash(&disk_entry.key, BLOOM_SEED));
+            }
+            self.entries += 1;
+            if disk_entry.is_tombstone() {
+                self.tombstones += 1;
             }
+            if self.first_key.is_none() {
+                self.first_key = Some(disk_entry.key.clone());
+            }
+            self.last_key = Some(disk_entry.key.clone());
+        }
+
+        Ok(disk_entry)
+    }
+
+    /// Sync the files, which are then kept when the writer is dropped.
+    pub(crate) fn seal(&mut self) -> Result<SSTableMeta> {
+        self.sstable.sync()?;
+        if let Some(hint) = &mut self.hint {
+            hint.sync()?;
+        }
+        if let (Some((path, hashes)), Some(bits_per_key)) =
+            (&self.bloom, self.options.bloom_bits_per_key)
+        {
+            let mut filter = BloomFilter::new(hashes.len() as u64, bits_per_key, BLOOM_SEED);
+            for h in hashes {
+                filter.insert_hash(*h);
+            }
+            bloom::write(&filter, path, self.options.file_mode)?;
+        }
+        self.finished = true;
+
+        Ok(SSTableMeta {
+            path: self.sstable.path().to_path_buf(),
+            hint_path: self.hint_path().map(Path::to_path_buf),
+            bloom_path: self.bloom_path().map(Path::to_path_buf),
+            entries: self.entries,
+            tombstones: self.tombstones,
+            size: self.sstable.size(),
+            first_key: self.first_key.clone(),
+            last_key: self.last_key.clone(),
+            max_seq: self.sstable.max_seq(),
+        })
+    }
+
+    /// Sync the files and their directory.
+    pub fn finish(mut self) -> Result<SSTableMeta> {
+        let meta = self.seal()?;
+        if let Some(dir) = meta.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(meta)
+    }
+}
+
+impl Drop for SSTableWriter {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        // unfinished files are incomplete, ignore errors.
+        let _ = fs::remove_file(self.sstable.path());
+        if let Some(hint) = &self.hint {
+            let _ = fs::remove_file(hint.path());
+        }
+        if let Some(bloom) = self.bloom_path() {
+            let _ = fs::remove_file(bloom);
         }
     }
 }
 
+/// Physical contents of the sstable at `path`, the last entry of each
+/// key, tombstones as empty values. Not the live pairs of a store, whose
+/// other sstables may shadow them, see `SSTable::iter`.
 pub fn read_sstable(path: &Path) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
     let mut sst = SSTable::new(path, false)?;
 
     let mut items = BTreeMap::new();
 
     for entry in sst.iter() {
+        let entry = entry?;
         let _ = items.insert(entry.key, entry.value);
     }
 
     Ok(items)
 }
 
+/// Rolling hash of `keys`, in order.
+fn keys_hash<'a>(keys: impl Iterator<Item = &'a [u8]>) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for key in keys {
+        hasher.update(&(key.len() as u64).to_le_bytes());
+        hasher.update(key);
+    }
+    hasher.finalize()
+}
+
+/// Check the crc of every entry of the sstable at `path`, returning the
+/// length of its intact prefix, the file length when undamaged.
+///
+/// Only a torn tail, a damaged last entry or one followed by zeros
+/// alone, is cut off this way, other damage fails with
+/// `VerificationFailed`.
+pub(crate) fn intact_len(path: &Path) -> Result<u64> {
+    let mut sst = SSTable::new(path, false)?;
+    let len = sst.size();
+
+    let mut offset = 0;
+    loop {
+        let entry = match sst.read(offset) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return Ok(len),
+            // partially written entry.
+            Err(LSMLibError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(offset)
+            }
+            Err(e) => return Err(e),
+        };
+
+        let entry_offset = entry.offset.unwrap_or(offset);
+        let end = entry_offset + entry.size();
+        if !entry.is_validate() {
+            if end >= len || zeros_from(sst.inner.reader()?, end)? {
+                return Ok(offset);
+            }
+            return Err(LSMLibError::VerificationFailed {
+                path: path.to_path_buf(),
+                reason: format!(
+                    "crc mismatch at offset {} before intact data, expected {}, actual {}",
+                    entry_offset,
+                    entry.crc_expected(),
+                    entry.crc_actual()
+                ),
+            });
+        }
+
+        offset = end;
+    }
+}
+
+/// Whether `file` holds only zeros from `offset` on.
+fn zeros_from(mut file: File, offset: u64) -> io::Result<bool> {
+    file.seek(io::SeekFrom::Start(offset))?;
+    let mut buf = [0u8; 4096];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(true),
+            n if buf[..n].iter().any(|b| *b != 0) => return Ok(false),
+            _ => {}
+        }
+    }
+}
+
+/// Check the sstable at `path` holds exactly the keys of `items`,
+/// every entry with a valid crc.
+///
+/// Entries are read with `SSTable::read`, the same path as lookups.
+pub fn verify_sstable(path: &Path, items: &BTreeMap<Vec<u8>, DiskEntry>) -> Result<()> {
+    let failed = |reason: String| LSMLibError::VerificationFailed {
+        path: path.to_path_buf(),
+        reason,
+    };
+
+    let mut sst = SSTable::new(path, false)?;
+
+    let mut keys = Vec::new();
+    let mut offset = 0;
+    while let Some(entry) = sst.read(offset)? {
+        let entry_offset = entry.offset.unwrap_or(offset);
+        if !entry.is_validate() {
+            return Err(failed(format!(
+                "crc mismatch at offset {}, expected {}, actual {}",
+                entry_offset,
+                entry.crc_expected(),
+                entry.crc_actual()
+            )));
+        }
+
+        offset = entry_offset + entry.size();
+        if entry.range_tombstone().is_none() {
+            keys.push(entry.key);
+        }
+    }
+
+    if keys.len() != items.len() {
+        return Err(failed(format!(
+            "{} entries, expected {}",
+            keys.len(),
+            items.len()
+        )));
+    }
+
+    let (actual, expected) = (
+        keys_hash(keys.iter().map(|k| k.as_slice())),
+        keys_hash(items.keys().map(|k| k.as_slice())),
+    );
+    if actual != expected {
+        return Err(failed(format!(
+            "keys hash {:#x}, expected {:#x}",
+            actual, expected
+        )));
+    }
+
+    Ok(())
+}
+
+/// Merge of sstables into the newest version of each key, in key
+/// order, as compaction writes it.
 pub struct CompactMergeIter {
-    sstables: Vec<RefCell<Peekable<DiskEntryIter>>>,
+    sstables: Vec<DiskEntryIter>,
+
+    /// next entry of each sstable, `None` once it is exhausted.
+    heads: Vec<Option<DiskEntry>>,
+
+    /// operator collapsing merge operands into their value, if any.
+    merge_operator: Option<MergeOperatorFn>,
+
+    /// error an sstable ended with, yielded in place of the next entry.
+    error: Option<LSMLibError>,
 }
 
 impl CompactMergeIter {
     pub fn new(iters: Vec<DiskEntryIter>) -> Self {
-        let mut sstables = Vec::new();
-        for iter in iters {
-            sstables.push(RefCell::new(iter.peekable()));
+        let mut merge_iter = Self {
+            heads: iters.iter().map(|_| None).collect(),
+            sstables: iters,
+            merge_operator: None,
+            error: None,
+        };
+        for index in 0..merge_iter.sstables.len() {
+            merge_iter.advance(index);
+        }
+
+        merge_iter
+    }
+
+    /// Collapse merge operands into the value they make with `operator`,
+    /// a tombstone if they delete their key. Left as they are without.
+    pub(crate) fn with_merge_operator(mut self, operator: Option<MergeOperatorFn>) -> Self {
+        self.merge_operator = operator;
+        self
+    }
+
+    /// `entry`, collapsed into a put or a tombstone if merge operands,
+    /// see `with_merge_operator`.
+    fn collapse(&self, entry: DiskEntry) -> DiskEntry {
+        let Some(operator) = self.merge_operator.as_ref().filter(|_| entry.is_merge()) else {
+            return entry;
+        };
+        let value = match Operands::decode(&entry.value) {
+            // flushes settle operands on their base.
+            Ok(operands) if operands.base != Base::Below => {
+                operands.resolve(operator, &entry.key, None)
+            }
+            _ => {
+                log::warn!("merge operands of key {:?} left as they are", entry.key);
+                return entry;
+            }
+        };
+
+        merge::collapsed(&entry, value)
+    }
+
+    /// Replace the head of sstable `index` by its next entry,
+    /// returning the replaced one.
+    fn advance(&mut self, index: usize) -> Option<DiskEntry> {
+        let next = match self.sstables[index].next() {
+            Some(Ok(entry)) if !entry.is_validate() => {
+                self.error.get_or_insert(LSMLibError::ChecksumMismatch {
+                    file_id: entry.file_id.unwrap_or_default(),
+                    offset: entry.offset.unwrap_or_default(),
+                    expected: entry.crc_expected(),
+                    actual: entry.crc_actual(),
+                });
+                None
+            }
+            Some(Ok(entry)) => Some(entry),
+            Some(Err(e)) => {
+                self.error.get_or_insert(e);
+                None
+            }
+            None => None,
+        };
+        std::mem::replace(&mut self.heads[index], next)
+    }
+
+    /// Merge the heads `newer` and `older` of the same key, returning
+    /// the index of the one left.
+    ///
+    /// The newer version wins, but a touch over an older version: its
+    /// expiry is folded into a put, dropped over a tombstone.
+    fn fold(&mut self, newer: usize, older: usize) -> usize {
+        let is_touch = |head: &Option<DiskEntry>| head.as_ref().is_some_and(DiskEntry::is_touch);
+        if !is_touch(&self.heads[newer]) || is_touch(&self.heads[older]) {
+            self.advance(older);
+            return newer;
         }
 
-        Self { sstables }
+        let touch = self.advance(newer).unwrap();
+        if let Some(entry) = self.heads[older].take() {
+            self.heads[older] = Some(match entry.is_tombstone() {
+                true => entry,
+                false => entry.with_expiry(touch.expiry()),
+            });
+        }
+        older
     }
 }
 
+/// Yields an error, and nothing after it, once an sstable cannot be read
+/// through or holds an entry failing its crc: the entries merged so far
+/// may miss newer versions of it.
 impl Iterator for CompactMergeIter {
-    type Item = DiskEntry;
+    type Item = Result<DiskEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut top: Option<(usize, Vec<u8>, u32)> = None;
-        for (index, iter) in self.sstables.iter().enumerate() {
-            if let Some(entry) = iter.borrow_mut().peek() {
-                match &top {
-                    None => top = Some((index, entry.key.clone(), entry.timestamp())),
-                    Some((top_index, key, timestamp)) => {
-                        if *key > entry.key {
-                            top = Some((index, entry.key.clone(), entry.timestamp()));
-                        } else if *key == entry.key {
-                            if *timestamp < entry.timestamp() {
-                                // next last iter.
-                                self.sstables[*top_index].borrow_mut().next();
-                                // use newer data.
-                                top = Some((index, entry.key.clone(), entry.timestamp()));
-                            } else {
-                                // drop older data.
-                                iter.borrow_mut().next();
-                            }
-                        }
-                    }
+        if let Some(e) = self.error.take() {
+            self.heads.iter_mut().for_each(|head| *head = None);
+            return Some(Err(e));
+        }
+
+        // heads are compared in place, keys are never copied.
+        let mut top: Option<usize> = None;
+        for index in 0..self.heads.len() {
+            let (entry, top_entry) = match (&self.heads[index], top) {
+                (None, _) => continue,
+                (Some(_), None) => {
+                    top = Some(index);
+                    continue;
+                }
+                (Some(entry), Some(top_index)) => (entry, self.heads[top_index].as_ref().unwrap()),
+            };
+
+            match top_entry.key.cmp(&entry.key) {
+                Ordering::Less => {}
+                Ordering::Greater => top = Some(index),
+                Ordering::Equal => {
+                    let top_index = top.unwrap();
+                    top = Some(match top_entry.seq() < entry.seq() {
+                        true => self.fold(index, top_index),
+                        false => self.fold(top_index, index),
+                    });
                 }
             }
         }
 
-        match top {
-            None => None,
-            Some((index, _, _)) => self.sstables[index].borrow_mut().next(),
+        top.and_then(|index| self.advance(index))
+            .map(|entry| Ok(self.collapse(entry)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    use crate::utils;
+
+    #[test]
+    fn test_aligned_write_entry() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let path = utils::format_sstable_path(dir.path(), 1);
+
+        let mut sst = SSTable::new(&path, true).unwrap().with_alignment(512);
+
+        let mut offsets = Vec::new();
+        for i in 0..10u8 {
+            let entry = sst.write(&[i], &vec![i; i as usize * 100]).unwrap();
+            let offset = entry.offset.unwrap();
+            assert_eq!(offset % 512, 0);
+            offsets.push(offset);
+        }
+        sst.sync().unwrap();
+
+        for (i, offset) in offsets.iter().enumerate() {
+            let entry = sst.read(*offset).unwrap().unwrap();
+            assert_eq!(entry.key, vec![i as u8]);
+            assert_eq!(entry.value, vec![i as u8; i * 100]);
         }
+
+        let entries: Vec<DiskEntry> = sst.iter().map(Result::unwrap).collect();
+        assert_eq!(entries.len(), 10);
+        for (entry, offset) in entries.iter().zip(offsets) {
+            assert_eq!(entry.offset, Some(offset));
+            assert!(entry.is_validate());
+        }
+    }
+
+    #[test]
+    fn test_verify_sstable() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let path = utils::format_sstable_path(dir.path(), 1);
+
+        let mut items = BTreeMap::new();
+        let mut sst = SSTable::new(&path, true).unwrap();
+        for i in 0..10u8 {
+            let entry = DiskEntry::new(vec![i], vec![i; 10]);
+            sst.write_entry(entry.clone()).unwrap();
+            items.insert(vec![i], entry);
+        }
+        sst.sync().unwrap();
+
+        verify_sstable(&path, &items).unwrap();
+
+        // key set mismatch.
+        let mut other = items.clone();
+        other.remove(&vec![3]);
+        assert!(matches!(
+            verify_sstable(&path, &other),
+            Err(LSMLibError::VerificationFailed { .. })
+        ));
+        other.insert(vec![42], DiskEntry::new(vec![42], vec![42]));
+        assert!(matches!(
+            verify_sstable(&path, &other),
+            Err(LSMLibError::VerificationFailed { .. })
+        ));
+
+        // flip a byte of the last value.
+        let mut data = std::fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xFF;
+        std::fs::write(&path, data).unwrap();
+        assert!(matches!(
+            verify_sstable(&path, &items),
+            Err(LSMLibError::VerificationFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_read_sized() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let path = utils::format_sstable_path(dir.path(), 1);
+
+        let mut sst = SSTable::new(&path, true).unwrap();
+        let first = sst.write(b"k1", b"v1").unwrap();
+        let second = sst.write(b"k2", b"v2").unwrap();
+        sst.sync().unwrap();
+
+        let (offset, size) = (second.offset.unwrap(), second.size());
+        let entry = sst.read_sized(offset, size).unwrap();
+        assert_eq!(entry.key, b"k2");
+        assert_eq!(entry.value, b"v2");
+
+        for (offset, size) in [(offset, size - 1), (offset, size + 1), (offset + 1, size)] {
+            assert!(matches!(
+                sst.read_sized(offset, size),
+                Err(LSMLibError::StaleKeydirEntry { .. })
+            ));
+        }
+
+        // flip a byte of the first value.
+        let mut data = std::fs::read(&path).unwrap();
+        data[(first.offset.unwrap() + first.size() - 1) as usize] ^= 0xFF;
+        std::fs::write(&path, data).unwrap();
+        assert!(matches!(
+            sst.read_sized(first.offset.unwrap(), first.size()),
+            Err(LSMLibError::ChecksumMismatch { .. })
+        ));
     }
 }
@@ -1,5 +1,110 @@
 //! Write-Ahead Log Module.
+//!
+//! The WAL is an sstable of the mutations not flushed yet, in write
+//! order. The entries of a batch follow a batch header holding their
+//! length, see `DiskEntry::write_batch_header`: a batch torn by a crash
+//! is dropped whole at recovery.
 
-use super::sstable::SSTable;
+use std::fs::File;
+use std::io;
+use std::iter::Peekable;
 
+use super::format::{DiskEntry, BATCH_HEADER_SIZE};
+use super::sstable::{DiskEntryIter, SSTable};
+use crate::error::{LSMLibError, Result};
+
+#[allow(clippy::upper_case_acronyms)]
 pub type WAL = SSTable;
+
+/// Record of a WAL, see `WalRecords`.
+#[derive(Debug)]
+pub(crate) enum WalRecord {
+    Entry(DiskEntry),
+
+    /// entries of a batch, logged whole.
+    Batch(Vec<DiskEntry>),
+}
+
+/// Iterator over the records of a WAL with the bytes each one takes,
+/// ending at a torn batch or entry, cut short by the end of the file.
+/// Any other read error is yielded, crcs are checked by the caller.
+pub(crate) struct WalRecords {
+    entries: Peekable<DiskEntryIter>,
+
+    /// reader of the batch headers.
+    reader: File,
+
+    /// end offset of the last record.
+    end: u64,
+}
+
+impl WalRecords {
+    pub(crate) fn new(entries: DiskEntryIter, reader: File) -> Self {
+        Self {
+            entries: entries.peekable(),
+            reader,
+            end: 0,
+        }
+    }
+
+    /// End of the records at `e` if the WAL is torn there, else `e`.
+    fn torn<T>(&self, e: LSMLibError) -> Option<Result<T>> {
+        match e {
+            LSMLibError::Io(ref io) if io.kind() == io::ErrorKind::UnexpectedEof => {}
+            e => return Some(Err(e)),
+        }
+
+        log::warn!("stop reading WAL at offset {}: {}", self.end, e);
+        None
+    }
+}
+
+impl Iterator for WalRecords {
+    type Item = Result<(u64, WalRecord)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = match self.entries.next()? {
+            Ok(entry) => entry,
+            Err(e) => return self.torn(e),
+        };
+        let start = self.end;
+        let offset = entry.offset.unwrap_or(start);
+
+        // padding records were skipped before the entry.
+        let batch_len = if offset == start {
+            None
+        } else {
+            match DiskEntry::read_batch_header(&mut self.reader, start) {
+                Ok(len) => len,
+                Err(e) => return self.torn(e),
+            }
+        };
+
+        let Some(len) = batch_len else {
+            self.end = offset + entry.size();
+            return Some(Ok((self.end - start, WalRecord::Entry(entry))));
+        };
+
+        let batch_end = start + BATCH_HEADER_SIZE + len;
+        let mut end = offset + entry.size();
+        let mut entries = vec![entry];
+        while end < batch_end {
+            // the next entry must follow, unpadded.
+            let entry = match self
+                .entries
+                .next_if(|e| e.as_ref().map_or(true, |e| e.offset == Some(end)))?
+            {
+                Ok(entry) => entry,
+                Err(e) => return self.torn(e),
+            };
+            end += entry.size();
+            entries.push(entry);
+        }
+        if offset != start + BATCH_HEADER_SIZE || end != batch_end {
+            return None;
+        }
+
+        self.end = end;
+        Some(Ok((end - start, WalRecord::Batch(entries))))
+    }
+}
@@ -2,6 +2,8 @@
 
 use thiserror::Error;
 
+use crate::lsm::quota::QuotaLimit;
+
 pub type Result<T> = std::result::Result<T, LSMLibError>;
 
 #[derive(Debug, Error)]
@@ -21,6 +23,9 @@ pub enum LSMLibError {
     #[error("key '{}' not found", String::from_utf8_lossy(.0))]
     KeyNotFound(Vec<u8>),
 
+    #[error("key is empty")]
+    EmptyKey,
+
     #[error("key is too large")]
     KeyIsTooLarge,
 
@@ -33,6 +38,135 @@ pub enum LSMLibError {
     #[error("db is already locked")]
     AlreadyLocked,
 
+    #[error("db is opened read only")]
+    ReadOnly,
+
+    #[error("store format version {from} needs migration to {to}, run `lsm::migrate` first")]
+    NeedsMigration { from: u32, to: u32 },
+
+    #[error("keydir entry of {expected} bytes at offset {offset} of file {file_id} is stale")]
+    StaleKeydirEntry {
+        file_id: u64,
+        offset: u64,
+        expected: u64,
+    },
+
+    #[error("checksum mismatch at offset {offset} of file {file_id}, expected {expected}, actual {actual}")]
+    ChecksumMismatch {
+        file_id: u64,
+        offset: u64,
+        expected: u32,
+        actual: u32,
+    },
+
+    #[error("sstable '{}' failed verification: {}", .path.display(), .reason)]
+    VerificationFailed {
+        path: std::path::PathBuf,
+        reason: String,
+    },
+
+    #[error("file '{}' changed on disk{}, outside of the store", .path.display(), changed_at(.modified))]
+    ExternallyModified {
+        path: std::path::PathBuf,
+        modified: Option<std::time::SystemTime>,
+    },
+
+    #[error("{conflicts} keys of the range were written after the export")]
+    RangeConflict { conflicts: u64 },
+
+    #[error("io budget exhausted, retry after {retry_after:?}")]
+    Throttled { retry_after: std::time::Duration },
+
+    #[error("store directory '{}' is missing", .0.display())]
+    StoreDirectoryMissing(std::path::PathBuf),
+
+    #[error("read would scan {files} sstables, more than the {max} allowed")]
+    ReadAmplificationExceeded { files: u64, max: u32 },
+
+    #[error("invalid config: {}", .0)]
+    InvalidConfig(String),
+
+    #[error("sstable id {} is not a reserved id above every sstable id", .0)]
+    SSTableIdUnavailable(u64),
+
+    #[error("file name '{}' has no file id", .0.display())]
+    InvalidFileName(std::path::PathBuf),
+
+    #[error("key '{}' is not greater than the previous key", String::from_utf8_lossy(.0))]
+    OutOfOrderKey(Vec<u8>),
+
+    #[error("database is full, writing would exceed {limit} bytes")]
+    DatabaseFull { limit: u64 },
+
+    #[error("memtable is full, writing would exceed {limit} bytes")]
+    MemtableFull { limit: u64 },
+
+    #[error("quota of prefix '{}' exceeded, writing would go over its {which}", String::from_utf8_lossy(.prefix))]
+    QuotaExceeded { prefix: Vec<u8>, which: QuotaLimit },
+
+    #[error("store keys were written with key transform {stored:?}, opened with {requested:?}")]
+    KeyTransformMismatch {
+        stored: Option<String>,
+        requested: Option<String>,
+    },
+
+    #[error("store opened without a merge operator")]
+    NoMergeOperator,
+
+    #[error("key not in the {indexed} of {total} sstables indexed so far")]
+    IndexingInProgress { indexed: u64, total: u64 },
+
+    #[error("metadata file '{}' is damaged: {}", .path.display(), .damage)]
+    CorruptMetaFile {
+        path: std::path::PathBuf,
+        damage: MetaFileDamage,
+    },
+
+    #[error(
+        "store files kept changing while opening read only, gave up after {attempts} attempts"
+    )]
+    ConcurrentCompaction { attempts: u32 },
+
     #[error("{}", .0)]
     Custom(String),
 }
+
+/// How a metadata file failed to read, see `disk::format::MetaFileReader`.
+#[derive(Debug, Error, Copy, Clone, PartialEq, Eq)]
+pub enum MetaFileDamage {
+    #[error("bad magic")]
+    BadMagic,
+
+    #[error("header checksum mismatch")]
+    BadHeader,
+
+    #[error("unsupported version {}", .0)]
+    UnsupportedVersion(u32),
+
+    #[error("truncated")]
+    Truncated,
+
+    #[error("checksum mismatch in record {}", .0)]
+    ChecksumMismatch(u64),
+
+    #[error("end marker counts {expected} records, found {found}")]
+    RecordCountMismatch { expected: u64, found: u64 },
+
+    #[error("bytes after the end marker")]
+    TrailingBytes,
+
+    /// content the reader cannot make sense of, in the record counted
+    /// from 1 or past it, 0 if before any.
+    #[error("invalid record {}", .0)]
+    InvalidRecord(u64),
+}
+
+fn changed_at(modified: &Option<std::time::SystemTime>) -> String {
+    match modified {
+        Some(t) => format!(
+            " at {}",
+            chrono::DateTime::<chrono::Utc>::from(*t).to_rfc3339()
+        ),
+        None => String::new(),
+    }
+}
@@ -1,24 +1,89 @@
 //! KeyDir Module.
 
-use std::collections::HashMap;
+use std::collections::{btree_map, hash_map, BTreeMap, HashMap};
+use std::ops::{Bound, RangeBounds};
 
+use crate::config::Config;
 use crate::disk::format::{DiskEntry, HintEntry};
 use crate::error::{LSMLibError, Result};
+use crate::utils;
 
 /// keyDirEntry represents.
 #[derive(Debug, Copy, Clone)]
 pub struct KeydirEntry {
     /// file id the entry is associated.
-    pub file_id: u64,
+    pub(crate) file_id: u64,
 
     /// offset of the entry in the file.
-    pub offset: u64,
+    pub(crate) offset: u64,
 
     /// size of the entry in bytes.
-    pub size: u64,
+    pub(crate) size: u64,
 
     /// timestamp of the entry.
-    pub timestamp: u32,
+    pub(crate) timestamp: u32,
+
+    /// global sequence number of the entry.
+    pub(crate) seq: u64,
+
+    /// whether the entry deletes its key.
+    pub(crate) tombstone: bool,
+
+    /// expiry in seconds since the unix epoch, 0 if never.
+    pub(crate) expiry: u32,
+
+    /// whether the entry holds merge operands, its value read by
+    /// applying them.
+    pub(crate) merge: bool,
+}
+
+impl KeydirEntry {
+    /// Id of the sstable holding the entry.
+    ///
+    /// Stable.
+    pub fn file_id(&self) -> u64 {
+        self.file_id
+    }
+
+    /// Stable.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Size of the entry on disk, header included.
+    ///
+    /// Stable.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Stable.
+    pub fn timestamp(&self) -> u32 {
+        self.timestamp
+    }
+
+    /// Experimental, may change with the format version.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Whether the entry deletes its key.
+    ///
+    /// Stable.
+    pub fn is_tombstone(&self) -> bool {
+        self.tombstone
+    }
+
+    /// Experimental, may change with the format version.
+    pub fn expiry(&self) -> u32 {
+        self.expiry
+    }
+
+    /// Whether the entry is a put not expired at `now`, seconds since
+    /// the unix epoch.
+    pub fn is_live(&self, now: u32) -> bool {
+        !self.tombstone && (self.expiry == 0 || self.expiry > now)
+    }
 }
 
 impl TryFrom<&DiskEntry> for KeydirEntry {
@@ -42,6 +107,10 @@ impl TryFrom<&DiskEntry> for KeydirEntry {
             offset,
             size: value.size(),
             timestamp: value.timestamp(),
+            seq: value.seq(),
+            tombstone: value.is_tombstone(),
+            expiry: value.expiry(),
+            merge: value.is_merge(),
         })
     }
 }
@@ -61,12 +130,28 @@ impl TryFrom<&HintEntry> for KeydirEntry {
             offset: value.offset(),
             size: value.size(),
             timestamp: value.timestamp(),
+            seq: value.seq(),
+            tombstone: value.value_sz() == 0,
+            expiry: value.expiry(),
+            merge: value.is_merge(),
         })
     }
 }
 
 /// Keydir methods.
+///
+/// Tombstones are kept as entries until compaction drops them, so an older
+/// version can never come back by re-indexing its file. `get` returns
+/// them, while `keys`, `prefix`, `len` and `contains_key` only see live keys.
 pub trait Keydir: Default {
+    /// Empty keydir of a store opened with `config`.
+    fn new(_config: &Config) -> Self {
+        Self::default()
+    }
+
+    /// Whether `keys`, `entries`, `prefix` and `range` come in key order.
+    fn is_ordered(&self) -> bool;
+
     /// Returns a reference to corresponding entry.
     fn get(&self, key: &[u8]) -> Option<&KeydirEntry>;
 
@@ -76,9 +161,26 @@ pub trait Keydir: Default {
     /// Removes a key and entry from the keydir.
     fn remove(&mut self, key: &[u8]);
 
+    /// Keep only the entries for which `f` returns `true`.
+    fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&[u8], &KeydirEntry) -> bool;
+
     /// List all keys in the keydir.
     fn keys(&self) -> Vec<Vec<u8>>;
 
+    /// All entries, tombstones included, in no particular order.
+    fn entries(&self) -> Box<dyn Iterator<Item = (&[u8], &KeydirEntry)> + '_>;
+
+    /// Entries whose key starts with `prefix`, in no particular order.
+    fn prefix(&self, prefix: &[u8]) -> Vec<(&[u8], &KeydirEntry)>;
+
+    /// Entries, tombstones included, whose key lies within `range`,
+    /// in no particular order.
+    fn range<R>(&self, range: &R) -> Box<dyn Iterator<Item = (&[u8], &KeydirEntry)> + '_>
+    where
+        R: RangeBounds<Vec<u8>>;
+
     /// Iterate all keys in datastore and call function `f`
     /// for each entry.
     ///
@@ -91,7 +193,7 @@ pub trait Keydir: Default {
     where
         F: FnMut(&[u8], &mut KeydirEntry) -> Result<bool>;
 
-    /// length of the keys in the keydir
+    /// number of live keys in the keydir.
     fn len(&self) -> u64;
 
     /// Return `true` if datastore contains the given key.
@@ -99,36 +201,312 @@ pub trait Keydir: Default {
 
     /// Return on disk size.
     fn disk_size(&self) -> u64;
+
+    /// Keep counting the live keys starting with `prefix` and their size,
+    /// as they are put and removed.
+    fn count_prefix(&mut self, prefix: &[u8]);
+
+    /// Stop counting the keys starting with `prefix`.
+    fn uncount_prefix(&mut self, prefix: &[u8]);
+
+    /// Live keys starting with `prefix` and their size, `None` if the
+    /// prefix is not counted.
+    fn prefix_count(&self, prefix: &[u8]) -> Option<PrefixCount>;
+}
+
+/// Live keys under a prefix and their size on disk, header included.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct PrefixCount {
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+impl PrefixCount {
+    /// Count of the live `entries` of a prefix.
+    fn of(entries: &[(&[u8], &KeydirEntry)]) -> Self {
+        Self {
+            keys: entries.len() as u64,
+            bytes: entries.iter().map(|(_, e)| e.size).sum(),
+        }
+    }
+}
+
+/// Counts of the counted prefixes, kept by the keydirs.
+#[derive(Debug, Default)]
+struct PrefixCounters {
+    counts: BTreeMap<Vec<u8>, PrefixCount>,
+}
+
+impl PrefixCounters {
+    fn add(&mut self, key: &[u8], entry: &KeydirEntry) {
+        if entry.tombstone {
+            return;
+        }
+        for (prefix, count) in self.counts.iter_mut() {
+            if key.starts_with(prefix) {
+                count.keys += 1;
+                count.bytes += entry.size;
+            }
+        }
+    }
+
+    fn sub(&mut self, key: &[u8], entry: &KeydirEntry) {
+        if entry.tombstone {
+            return;
+        }
+        for (prefix, count) in self.counts.iter_mut() {
+            if key.starts_with(prefix) {
+                count.keys -= 1;
+                count.bytes -= entry.size;
+            }
+        }
+    }
 }
 
 /// Keydir represented as a hashmap.
 #[derive(Debug, Default)]
 pub struct HashmapKeydir {
     mapping: HashMap<Vec<u8>, KeydirEntry>,
+
+    /// number of tombstones in the mapping.
+    tombstones: u64,
+
+    /// live keys and size of the counted prefixes.
+    counters: PrefixCounters,
 }
 
 impl Keydir for HashmapKeydir {
+    fn is_ordered(&self) -> bool {
+        false
+    }
+
     fn get(&self, key: &[u8]) -> Option<&KeydirEntry> {
         self.mapping.get(key)
     }
 
     fn put(&mut self, key: Vec<u8>, entry: KeydirEntry) -> &KeydirEntry {
+        match self.mapping.entry(key) {
+            hash_map::Entry::Occupied(mut o) => {
+                let e = o.get();
+                if e.seq <= entry.seq {
+                    self.tombstones -= e.tombstone as u64;
+                    self.tombstones += entry.tombstone as u64;
+                    self.counters.sub(o.key(), e);
+                    self.counters.add(o.key(), &entry);
+                    *o.get_mut() = entry;
+                }
+                o.into_mut()
+            }
+            hash_map::Entry::Vacant(v) => {
+                self.tombstones += entry.tombstone as u64;
+                self.counters.add(v.key(), &entry);
+                v.insert(entry)
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(e) = self.mapping.remove(key) {
+            self.tombstones -= e.tombstone as u64;
+            self.counters.sub(key, &e);
+        }
+    }
+
+    fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&[u8], &KeydirEntry) -> bool,
+    {
+        let counters = &mut self.counters;
+        self.mapping.retain(|k, e| {
+            let keep = f(k, e);
+            if !keep {
+                counters.sub(k, e);
+            }
+            keep
+        });
+        self.tombstones = self.mapping.values().filter(|e| e.tombstone).count() as u64;
+    }
+
+    fn keys(&self) -> Vec<Vec<u8>> {
+        self.mapping
+            .iter()
+            .filter(|(_, e)| !e.tombstone)
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (&[u8], &KeydirEntry)> + '_> {
+        Box::new(self.mapping.iter().map(|(k, v)| (k.as_slice(), v)))
+    }
+
+    /// Hashmap is unordered, so this scans every key.
+    fn prefix(&self, prefix: &[u8]) -> Vec<(&[u8], &KeydirEntry)> {
         self.mapping
-            .entry(key)
-            .and_modify(|e| {
-                if e.timestamp <= entry.timestamp {
-                    *e = entry.clone();
+            .iter()
+            .filter(|(k, e)| !e.tombstone && k.starts_with(prefix))
+            .map(|(k, v)| (k.as_slice(), v))
+            .collect()
+    }
+
+    /// Hashmap is unordered, so this scans every key.
+    fn range<R>(&self, range: &R) -> Box<dyn Iterator<Item = (&[u8], &KeydirEntry)> + '_>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let entries: Vec<_> = self
+            .mapping
+            .iter()
+            .filter(|(k, _)| utils::range_contains(range, k))
+            .map(|(k, v)| (k.as_slice(), v))
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    fn for_each<F>(&mut self, f: &mut F) -> Result<()>
+    where
+        F: FnMut(&[u8], &mut KeydirEntry) -> Result<bool>,
+    {
+        for (k, v) in self.mapping.iter_mut() {
+            if f(k, v)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.mapping.len() as u64 - self.tombstones
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.mapping.get(key).is_some_and(|e| !e.tombstone)
+    }
+
+    fn disk_size(&self) -> u64 {
+        self.mapping.iter().map(|e| e.1.size).sum()
+    }
+
+    fn count_prefix(&mut self, prefix: &[u8]) {
+        let count = PrefixCount::of(&self.prefix(prefix));
+        self.counters.counts.insert(prefix.to_vec(), count);
+    }
+
+    fn uncount_prefix(&mut self, prefix: &[u8]) {
+        self.counters.counts.remove(prefix);
+    }
+
+    fn prefix_count(&self, prefix: &[u8]) -> Option<PrefixCount> {
+        self.counters.counts.get(prefix).copied()
+    }
+}
+
+/// Keydir represented as a btree, iterated in key order, see
+/// `Config::ordered_keydir`.
+#[derive(Debug, Default)]
+pub struct BTreeKeydir {
+    mapping: BTreeMap<Vec<u8>, KeydirEntry>,
+
+    /// number of tombstones in the mapping.
+    tombstones: u64,
+
+    /// live keys and size of the counted prefixes.
+    counters: PrefixCounters,
+}
+
+impl Keydir for BTreeKeydir {
+    fn is_ordered(&self) -> bool {
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&KeydirEntry> {
+        self.mapping.get(key)
+    }
+
+    fn put(&mut self, key: Vec<u8>, entry: KeydirEntry) -> &KeydirEntry {
+        match self.mapping.entry(key) {
+            btree_map::Entry::Occupied(mut o) => {
+                let e = o.get();
+                if e.seq <= entry.seq {
+                    self.tombstones -= e.tombstone as u64;
+                    self.tombstones += entry.tombstone as u64;
+                    self.counters.sub(o.key(), e);
+                    self.counters.add(o.key(), &entry);
+                    *o.get_mut() = entry;
                 }
-            })
-            .or_insert(entry)
+                o.into_mut()
+            }
+            btree_map::Entry::Vacant(v) => {
+                self.tombstones += entry.tombstone as u64;
+                self.counters.add(v.key(), &entry);
+                v.insert(entry)
+            }
+        }
     }
 
     fn remove(&mut self, key: &[u8]) {
-        self.mapping.remove(key);
+        if let Some(e) = self.mapping.remove(key) {
+            self.tombstones -= e.tombstone as u64;
+            self.counters.sub(key, &e);
+        }
+    }
+
+    fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&[u8], &KeydirEntry) -> bool,
+    {
+        let counters = &mut self.counters;
+        self.mapping.retain(|k, e| {
+            let keep = f(k, e);
+            if !keep {
+                counters.sub(k, e);
+            }
+            keep
+        });
+        self.tombstones = self.mapping.values().filter(|e| e.tombstone).count() as u64;
     }
 
     fn keys(&self) -> Vec<Vec<u8>> {
-        self.mapping.keys().cloned().collect()
+        self.mapping
+            .iter()
+            .filter(|(_, e)| !e.tombstone)
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (&[u8], &KeydirEntry)> + '_> {
+        Box::new(self.mapping.iter().map(|(k, v)| (k.as_slice(), v)))
+    }
+
+    fn prefix(&self, prefix: &[u8]) -> Vec<(&[u8], &KeydirEntry)> {
+        self.range(&utils::prefix_range(prefix))
+            .filter(|(_, e)| !e.tombstone)
+            .collect()
+    }
+
+    fn range<R>(&self, range: &R) -> Box<dyn Iterator<Item = (&[u8], &KeydirEntry)> + '_>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let bounds: (Bound<&[u8]>, Bound<&[u8]>) = (
+            range.start_bound().map(Vec::as_slice),
+            range.end_bound().map(Vec::as_slice),
+        );
+        // an empty range would make `BTreeMap::range` panic.
+        let empty = match bounds {
+            (Bound::Included(s), Bound::Included(e)) => s > e,
+            (Bound::Included(s) | Bound::Excluded(s), Bound::Excluded(e))
+            | (Bound::Excluded(s), Bound::Included(e)) => s >= e,
+            _ => false,
+        };
+        if empty {
+            return Box::new(std::iter::empty());
+        }
+        Box::new(
+            self.mapping
+                .range::<[u8], _>(bounds)
+                .map(|(k, v)| (k.as_slice(), v)),
+        )
     }
 
     fn for_each<F>(&mut self, f: &mut F) -> Result<()>
@@ -145,14 +523,132 @@ impl Keydir for HashmapKeydir {
     }
 
     fn len(&self) -> u64 {
-        self.mapping.len() as u64
+        self.mapping.len() as u64 - self.tombstones
     }
 
     fn contains_key(&self, key: &[u8]) -> bool {
-        self.mapping.contains_key(key)
+        self.mapping.get(key).is_some_and(|e| !e.tombstone)
     }
 
     fn disk_size(&self) -> u64 {
         self.mapping.iter().map(|e| e.1.size).sum()
     }
+
+    fn count_prefix(&mut self, prefix: &[u8]) {
+        let count = PrefixCount::of(&self.prefix(prefix));
+        self.counters.counts.insert(prefix.to_vec(), count);
+    }
+
+    fn uncount_prefix(&mut self, prefix: &[u8]) {
+        self.counters.counts.remove(prefix);
+    }
+
+    fn prefix_count(&self, prefix: &[u8]) -> Option<PrefixCount> {
+        self.counters.counts.get(prefix).copied()
+    }
+}
+
+/// Either keydir, chosen when the store opens.
+#[derive(Debug)]
+pub enum AnyKeydir {
+    Hashmap(HashmapKeydir),
+    BTree(BTreeKeydir),
+}
+
+impl Default for AnyKeydir {
+    fn default() -> Self {
+        Self::Hashmap(HashmapKeydir::default())
+    }
+}
+
+macro_rules! dispatch {
+    ($self:expr, $keydir:ident => $body:expr) => {
+        match $self {
+            AnyKeydir::Hashmap($keydir) => $body,
+            AnyKeydir::BTree($keydir) => $body,
+        }
+    };
+}
+
+impl Keydir for AnyKeydir {
+    fn new(config: &Config) -> Self {
+        if config.ordered_keydir {
+            Self::BTree(BTreeKeydir::default())
+        } else {
+            Self::Hashmap(HashmapKeydir::default())
+        }
+    }
+
+    fn is_ordered(&self) -> bool {
+        dispatch!(self, k => k.is_ordered())
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&KeydirEntry> {
+        dispatch!(self, k => k.get(key))
+    }
+
+    fn put(&mut self, key: Vec<u8>, entry: KeydirEntry) -> &KeydirEntry {
+        dispatch!(self, k => k.put(key, entry))
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        dispatch!(self, k => k.remove(key))
+    }
+
+    fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&[u8], &KeydirEntry) -> bool,
+    {
+        dispatch!(self, k => k.retain(f))
+    }
+
+    fn keys(&self) -> Vec<Vec<u8>> {
+        dispatch!(self, k => k.keys())
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (&[u8], &KeydirEntry)> + '_> {
+        dispatch!(self, k => k.entries())
+    }
+
+    fn prefix(&self, prefix: &[u8]) -> Vec<(&[u8], &KeydirEntry)> {
+        dispatch!(self, k => k.prefix(prefix))
+    }
+
+    fn range<R>(&self, range: &R) -> Box<dyn Iterator<Item = (&[u8], &KeydirEntry)> + '_>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        dispatch!(self, k => k.range(range))
+    }
+
+    fn for_each<F>(&mut self, f: &mut F) -> Result<()>
+    where
+        F: FnMut(&[u8], &mut KeydirEntry) -> Result<bool>,
+    {
+        dispatch!(self, k => k.for_each(f))
+    }
+
+    fn len(&self) -> u64 {
+        dispatch!(self, k => k.len())
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        dispatch!(self, k => k.contains_key(key))
+    }
+
+    fn disk_size(&self) -> u64 {
+        dispatch!(self, k => k.disk_size())
+    }
+
+    fn count_prefix(&mut self, prefix: &[u8]) {
+        dispatch!(self, k => k.count_prefix(prefix))
+    }
+
+    fn uncount_prefix(&mut self, prefix: &[u8]) {
+        dispatch!(self, k => k.uncount_prefix(prefix))
+    }
+
+    fn prefix_count(&self, prefix: &[u8]) -> Option<PrefixCount> {
+        dispatch!(self, k => k.prefix_count(prefix))
+    }
 }
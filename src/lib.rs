@@ -1,12 +1,17 @@
 // #![cfg_attr(debug_assertions, allow(dead_code, unused_imports, unused_variables))]
 #![cfg_attr(debug_assertions, allow(dead_code))]
 mod bloomfilter;
+mod budget;
+mod cache;
+mod clock;
 mod config;
 mod disk;
 mod error;
 mod keydir;
+mod migrate;
 
 mod request;
+mod snapshot;
 mod stats;
 mod storage;
 mod utils;
@@ -1,26 +1,134 @@
 //! LSM Module.
 
-use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
 use std::fs;
+use std::io::{Read, Write};
+use std::ops::{RangeBounds, RangeFull};
 
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use crate::config::Config;
-use crate::disk::format::DiskEntry;
-use crate::disk::sstable::SSTable;
-use crate::disk::wal::WAL;
-use crate::error::Result;
-use crate::storage::{Storage, Store};
+use crate::bloomfilter;
+use crate::budget::IoLimiter;
+use crate::cache::NegativeCache;
+use crate::clock::StoreClock;
+use crate::config::{self, Config};
+use crate::disk::format::{
+    DiskEntry, RangeTombstone, BATCH_HEADER_SIZE, COMPRESSION_FORMAT_VERSION,
+    EXPIRY_FORMAT_VERSION, EXPIRY_SIZE, FORMAT_VERSION, HEADER_SIZE, MERGE_FORMAT_VERSION,
+    RANGE_TOMBSTONE_FORMAT_VERSION, TOUCH_FORMAT_VERSION,
+};
+use crate::disk::sstable::{SSTable, SSTableWriter, SSTableWriterOptions};
+use crate::disk::wal::{WalRecord, WAL};
+use crate::keydir::Keydir;
+use crate::migrate;
+use crate::snapshot::SnapshotValue;
+use crate::stats::{CompactionCounters, FileClass, SyncMonitor, WorkerStats};
+use crate::storage::{self, Found, Store};
 use crate::utils;
+use crate::worker;
 use crate::worker::compact::{Compactor, CompactorMessage};
+use crate::worker::index::{IndexProgress, Indexor, IndexorMessage};
+use digest::DigestBuilder;
+use export::{ExportReader, ExportWriter};
+use quota::{UnflushedUsage, UsageDelta};
+use transform::KeyTransform;
+
+pub use crate::budget::{IoBudget, ThrottleMode};
+pub use crate::clock::ClockFn;
+pub use crate::config::{MemtableFullPolicy, PartialOpenReadPolicy, VerifyOnOpen};
+pub use crate::disk::lineage::{Lineage, SSTableOrigin};
+pub use crate::error::{LSMLibError, Result};
+pub use crate::migrate::{migrate, MigrateOptions, MigrateReport};
+pub use crate::snapshot::{Snapshot, SnapshotIter};
+pub use crate::stats::{
+    CompactionOutcome, CompactionStats, DiskUsage, FlushOutcome, FlushStats, IoStats,
+    NegativeCacheStats, PrefixStats, ReadSource, RewriteReport, Stats, SyncClassStats, SyncStats,
+};
+pub use crate::storage::{FlushHandle, Storage};
+pub use crate::worker::compact::{
+    CandidateKind, CandidateRun, CompactionGate, CompactionPolicy, DefaultCompactionPolicy,
+};
+pub use crate::worker::WorkerInfo;
+pub use batch::WriteBatch;
+pub use digest::{KeyDigest, KeyDigestHeader, KeyDigestKind};
+pub use export::ExportSummary;
+pub use identity::StoreIdentity;
+pub use merge::MergeOperatorFn;
+pub use observer::{WriteEvent, WriteObserverFn, WriteOp};
+pub use publish::SnapshotManifest;
+pub use quota::{NamespaceUsage, Quota, QuotaLimit};
+pub use replication::ApplyReport;
+pub use transform::KeyTransformFn;
+
+pub mod batch;
+pub mod digest;
+pub mod export;
+pub mod format;
+pub mod identity;
+pub mod keys;
+pub mod merge;
+pub mod observer;
+pub mod publish;
+pub mod quota;
+pub mod replication;
+pub mod sstable;
+pub(crate) mod transform;
+
+/// Mutations `Lsm::apply_changes` logs as one WAL batch.
+const APPLY_BATCH_SIZE: usize = 1024;
+
+type Memtable = BTreeMap<Vec<u8>, DiskEntry>;
+
+/// Old and new value of a key, see `Lsm::update_and_fetch`.
+type Update = (Option<Vec<u8>>, Option<Vec<u8>>);
+
+/// Bytes of the key and value of a memtable entry.
+fn entry_bytes(key: &[u8], entry: &DiskEntry) -> u64 {
+    (key.len() + entry.value.len()) as u64
+}
+
+fn memtable_bytes(memtable: &Memtable) -> u64 {
+    memtable.iter().map(|(k, e)| entry_bytes(k, e)).sum()
+}
+
+/// Number of entries in each of at most `partitions` runs of
+/// consecutive memtable entries of about the same bytes, at least one
+/// run even for an empty memtable.
+fn partition_lengths(memtable: &Memtable, partitions: u8) -> Vec<usize> {
+    let total = memtable_bytes(memtable);
+    let partitions = u64::from(partitions.max(1));
+
+    let mut lengths = vec![0];
+    let mut bytes = 0;
+    for (key, entry) in memtable {
+        // start the next run once this one holds its share.
+        let runs = lengths.len() as u64;
+        if runs < partitions && lengths[lengths.len() - 1] > 0 && bytes >= total * runs / partitions
+        {
+            lengths.push(0);
+        }
+        *lengths.last_mut().unwrap() += 1;
+        bytes += entry_bytes(key, entry);
+    }
+
+    lengths
+}
 
 /// KVStore API definitions.
 pub trait KVStore {
     /// Put a key/value pair into the store.
+    ///
+    /// Fails with `EmptyKey` for an empty key.
     fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
 
     /// Delete a key/value pair from the store.
+    ///
+    /// Fails with `EmptyKey` for an empty key.
     fn delete(&mut self, key: &[u8]) -> Result<()>;
 
     /// Get a key/value pair from the store.
@@ -29,7 +137,7 @@ pub trait KVStore {
     /// Contains a key/value pair in the store or not.
     fn contains(&self, key: &[u8]) -> bool;
 
-    /// List all keys in the store.
+    /// List all keys in the store, each once, in key order.
     fn list_keys(&self) -> Result<Vec<Vec<u8>>>;
 }
 
@@ -44,62 +152,461 @@ pub struct Lsm {
     /// OutBox for sync message with compactor.
     worker_outbox: mpsc::Sender<CompactorMessage>,
 
+    /// compactor thread, joined on drop so it releases the store.
+    worker_handle: Option<std::thread::JoinHandle<()>>,
+
+    /// OutBox for sync message with indexor, while a partial open
+    /// indexes older sstables.
+    indexor_outbox: Option<mpsc::Sender<IndexorMessage>>,
+
+    /// indexor thread, joined on drop like the compactor.
+    indexor_handle: Option<std::thread::JoinHandle<()>>,
+
+    /// sstables indexed out of those opened with.
+    index_progress: Arc<IndexProgress>,
+
     /// MemTable of the key/value pair.
     /// use for read first, update write, sorted.
     /// memtable: MemTable,
     memtable: BTreeMap<Vec<u8>, DiskEntry>,
 
-    /// wal for memtable crushed.
-    log: WAL,
+    /// bytes of the keys and values of the memtable.
+    memtable_bytes: u64,
+
+    /// memtable being flushed to sstable, still readable
+    /// until the keydir has been updated.
+    flushing: Option<Arc<BTreeMap<Vec<u8>, DiskEntry>>>,
+
+    /// range tombstones not flushed yet. Memtable entries they cover
+    /// are dropped when they are written.
+    range_tombstones: Vec<RangeTombstone>,
+
+    /// wal for memtable crushed, none when read only.
+    log: Option<WAL>,
 
     /// dirty_bytes.
     dirty_bytes: u64,
 
+    /// bytes of the sstables and hint files when last measured, after
+    /// open and flushes, not compactions.
+    disk_bytes: u64,
+
+    /// when the oldest WAL entry not yet synced was written.
+    unsynced_since: Option<Instant>,
+
+    /// clock of `unsynced_since`.
+    now: fn() -> Instant,
+
+    /// monitor of all syncs of the store.
+    sync_monitor: Arc<SyncMonitor>,
+
+    /// keys known to be absent, if enabled.
+    negative_cache: Option<Arc<NegativeCache>>,
+
+    /// foreground bytes read and written.
+    io_stats: WorkerStats,
+
+    /// foreground io budget, if any.
+    io_limiter: Option<IoLimiter>,
+
+    /// counters of the compactor.
+    compaction_stats: Arc<CompactionCounters>,
+
+    /// normalization of every key, if any.
+    key_transform: Option<KeyTransform>,
+
+    /// callback of every mutation, if any.
+    write_observer: Option<WriteObserverFn>,
+
+    /// operator of `merge`, if any.
+    merge_operator: Option<MergeOperatorFn>,
+
+    /// identity of the store as of this open.
+    identity: StoreIdentity,
+
+    /// quotas by key prefix as stored, see `set_prefix_quota`.
+    quotas: BTreeMap<Vec<u8>, Quota>,
+
+    /// what the memtable changes of the usage of the quota prefixes,
+    /// `None` until counted again, see `namespace_usage`.
+    unflushed_usage: Option<UnflushedUsage>,
+
+    /// whether the store is known stamped with `EXPIRY_FORMAT_VERSION`.
+    expiry_format: bool,
+
+    /// whether the store is known stamped with `TOUCH_FORMAT_VERSION`.
+    touch_format: bool,
+
+    /// whether the store is known stamped with `MERGE_FORMAT_VERSION`.
+    merge_format: bool,
+
+    /// source of entry timestamps.
+    clock: Arc<StoreClock>,
+
+    /// whether the store directory went missing, failing every operation.
+    failed: AtomicBool,
+
+    /// sequence number of the last write.
+    seq: u64,
+
     /// config of store.
     config: Config,
+
+    /// WAL recovery info of the last open.
+    recovery_info: RecoveryInfo,
+
+    /// called in flush between memtable taken and sstable written.
+    #[cfg(test)]
+    flush_hook: Option<fn(&Lsm)>,
     //// stats.
     //// stats: Stats,
 }
 
-pub struct OpenOptions(Config);
+/// What recovery did when the store was opened.
+///
+/// A truncation means the last shutdown was unclean and
+/// writes not yet fully logged were lost.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryInfo {
+    /// bytes of valid entries replayed from the WAL.
+    pub recovered_bytes: u64,
+
+    /// number of entries replayed from the WAL.
+    pub recovered_entries: u64,
+
+    /// bytes chopped off the WAL tail as a torn write.
+    pub truncated_bytes: u64,
+
+    /// whether a torn WAL tail was truncated.
+    pub truncated: bool,
+
+    /// replayed entries dropped, an sstable holding a newer version.
+    pub stale_entries: u64,
+
+    /// hint, lineage and bloom filter files removed without their sstable.
+    pub orphan_files_removed: u64,
+
+    /// sstables whose torn tail was cut off, see `Config::verify_on_open`.
+    pub sstables_truncated: u64,
+
+    /// whether the last open was ended by dropping the store, or
+    /// this open created it.
+    pub was_clean_shutdown: bool,
+}
+
+/// What `Lsm::repair_key` did.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RepairOutcome {
+    /// the key reads fine, nothing to repair.
+    Intact,
+
+    /// the newest intact older version, found in this sstable, was written back.
+    RestoredFromVersion(u64),
+
+    /// no intact version was left, the key was deleted.
+    Tombstoned,
+
+    /// no intact version was left, the key still fails to read.
+    Unrepairable,
+}
+
+/// Failed `Lsm::compare_and_swap`, with the value found instead of the
+/// expected one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CasError {
+    pub actual: Option<Vec<u8>>,
+}
+
+#[derive(Clone)]
+pub struct OpenOptions {
+    /// config of store.
+    config: Config,
+
+    /// gate consulted before every background compaction.
+    compaction_gate: Option<Arc<dyn CompactionGate>>,
+
+    /// policy choosing the runs background compactions merge.
+    compaction_policy: Option<Arc<dyn CompactionPolicy>>,
+
+    /// normalization of every key.
+    key_transform: Option<KeyTransform>,
+
+    /// callback of every mutation.
+    write_observer: Option<WriteObserverFn>,
+
+    /// operator of `Lsm::merge`.
+    merge_operator: Option<MergeOperatorFn>,
+
+    /// source of entry timestamps, the system clock if unset.
+    clock: Option<ClockFn>,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl OpenOptions {
     pub fn new() -> Self {
-        Self(Config::default())
+        Self {
+            config: Config::default(),
+            compaction_gate: None,
+            compaction_policy: None,
+            key_transform: None,
+            write_observer: None,
+            merge_operator: None,
+            clock: None,
+        }
     }
 
     pub fn max_space_amp(mut self, value: u8) -> Self {
-        self.0.max_space_amp = value;
+        self.config.max_space_amp = value;
         self
     }
 
     pub fn max_log_length(mut self, value: u64) -> Self {
-        self.0.max_log_length = value;
+        self.config.max_log_length = value;
         self
     }
 
     pub fn merge_ratio(mut self, value: u8) -> Self {
-        self.0.merge_ratio = value;
+        self.config.merge_ratio = value;
         self
     }
 
     pub fn merge_window(mut self, value: u8) -> Self {
-        self.0.merge_window = value;
+        self.config.merge_window = value;
+        self
+    }
+
+    pub fn small_file_size(mut self, value: u64) -> Self {
+        self.config.small_file_size = value;
+        self
+    }
+
+    pub fn small_file_merge_threshold(mut self, value: u32) -> Self {
+        self.config.small_file_merge_threshold = value;
+        self
+    }
+
+    pub fn small_file_merge_max_bytes(mut self, value: u64) -> Self {
+        self.config.small_file_merge_max_bytes = value;
         self
     }
 
     pub fn log_bufwriter_size(mut self, value: u32) -> Self {
-        self.0.log_bufwriter_size = value;
+        self.config.log_bufwriter_size = value;
         self
     }
 
     pub fn zstd_sstable_compression_level(mut self, value: u8) -> Self {
-        self.0.zstd_sstable_compression_level = value;
+        self.config.zstd_sstable_compression_level = value;
+        self
+    }
+
+    pub fn slow_sync_warn_threshold(mut self, value: std::time::Duration) -> Self {
+        self.config.slow_sync_warn_threshold = value;
+        self
+    }
+
+    pub fn read_only(mut self, value: bool) -> Self {
+        self.config.read_only = value;
+        self
+    }
+
+    pub fn tombstone_grace(mut self, value: std::time::Duration) -> Self {
+        self.config.tombstone_grace = value;
+        self
+    }
+
+    pub fn file_mode(mut self, value: u32) -> Self {
+        self.config.file_mode = Some(value);
+        self
+    }
+
+    pub fn dir_mode(mut self, value: u32) -> Self {
+        self.config.dir_mode = Some(value);
+        self
+    }
+
+    pub fn negative_cache_entries(mut self, value: u32) -> Self {
+        self.config.negative_cache_entries = value;
+        self
+    }
+
+    pub fn repair_writes_tombstone(mut self, value: bool) -> Self {
+        self.config.repair_writes_tombstone = value;
+        self
+    }
+
+    pub fn max_files_per_read(mut self, value: u32) -> Self {
+        self.config.max_files_per_read = Some(value);
+        self
+    }
+
+    pub fn clamp_files_per_read(mut self, value: bool) -> Self {
+        self.config.clamp_files_per_read = value;
+        self
+    }
+
+    pub fn paranoid_flush_checks(mut self, value: bool) -> Self {
+        self.config.paranoid_flush_checks = value;
+        self
+    }
+
+    pub fn max_unsynced_age(mut self, value: std::time::Duration) -> Self {
+        self.config.max_unsynced_age = Some(value);
+        self
+    }
+
+    pub fn verify_on_open(mut self, value: VerifyOnOpen) -> Self {
+        self.config.verify_on_open = value;
+        self
+    }
+
+    pub fn compactor_nice(mut self, value: i32) -> Self {
+        self.config.compactor_nice = Some(value);
+        self
+    }
+
+    pub fn max_database_bytes(mut self, value: u64) -> Self {
+        self.config.max_database_bytes = Some(value);
+        self
+    }
+
+    pub fn memtable_hard_limit_bytes(mut self, value: u64) -> Self {
+        self.config.memtable_hard_limit_bytes = Some(value);
+        self
+    }
+
+    pub fn memtable_full_policy(mut self, value: MemtableFullPolicy) -> Self {
+        self.config.memtable_full_policy = value;
+        self
+    }
+
+    pub fn database_soft_limit_percent(mut self, value: u8) -> Self {
+        self.config.database_soft_limit_percent = value;
+        self
+    }
+
+    pub fn hints_on_flush(mut self, value: bool) -> Self {
+        self.config.hints_on_flush = value;
+        self
+    }
+
+    pub fn replay_writes_on_open(mut self, value: bool) -> Self {
+        self.config.replay_writes_on_open = value;
+        self
+    }
+
+    pub fn sstable_block_alignment(mut self, value: u64) -> Self {
+        self.config.sstable_block_alignment = value;
+        self
+    }
+
+    pub fn sstable_id_start(mut self, value: u64) -> Self {
+        self.config.sstable_id_start = value;
+        self
+    }
+
+    pub fn reserved_id_ranges(mut self, value: Vec<std::ops::Range<u64>>) -> Self {
+        self.config.reserved_id_ranges = value;
+        self
+    }
+
+    pub fn value_prefix_index_bytes(mut self, value: Option<u8>) -> Self {
+        self.config.value_prefix_index_bytes = value;
+        self
+    }
+
+    pub fn flush_partitions(mut self, value: u8) -> Self {
+        self.config.flush_partitions = value;
+        self
+    }
+
+    pub fn partial_open_sstables(mut self, value: Option<u32>) -> Self {
+        self.config.partial_open_sstables = value;
+        self
+    }
+
+    pub fn partial_open_read_policy(mut self, value: PartialOpenReadPolicy) -> Self {
+        self.config.partial_open_read_policy = value;
+        self
+    }
+
+    pub fn bloom_bits_per_key(mut self, value: Option<u8>) -> Self {
+        self.config.bloom_bits_per_key = value;
+        self
+    }
+
+    pub fn export_chunk_size(mut self, value: u32) -> Self {
+        self.config.export_chunk_size = value;
+        self
+    }
+
+    pub fn ordered_keydir(mut self, value: bool) -> Self {
+        self.config.ordered_keydir = value;
+        self
+    }
+
+    /// Normalize keys with `transform` before they reach the store, on
+    /// writes as on lookups, range bounds and prefixes, e.g. lowercase
+    /// them for case-insensitive lookups. Only the transformed keys are
+    /// stored and listed.
+    ///
+    /// `transform` must be idempotent, and map a prefix of a key to a
+    /// prefix of the transformed key for prefix queries to hold. The
+    /// store records `id`, and refuses to open with another transform
+    /// once it holds keys.
+    pub fn key_transform(mut self, id: impl Into<String>, transform: KeyTransformFn) -> Self {
+        self.key_transform = Some(KeyTransform::new(id.into(), transform));
+        self
+    }
+
+    /// Source of the entry timestamps, in seconds since the unix epoch,
+    /// instead of the system clock. Also the clock of
+    /// `Config::tombstone_grace`.
+    ///
+    /// Readings far behind the newest timestamp of the store, e.g. of
+    /// an RTC starting at the epoch, are replaced by that timestamp
+    /// plus one until the clock catches up, and logged once.
+    pub fn clock(mut self, clock: ClockFn) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Call `observer` with every mutation once in the WAL, in commit
+    /// order, see `observer` for the delivery guarantees.
+    ///
+    /// `observer` runs on the writing thread, delaying the write.
+    pub fn write_observer(mut self, observer: WriteObserverFn) -> Self {
+        self.write_observer = Some(observer);
+        self
+    }
+
+    /// Operator combining the value of a key with the operands of
+    /// `Lsm::merge`, see `merge`.
+    pub fn merge_operator(mut self, operator: MergeOperatorFn) -> Self {
+        self.merge_operator = Some(operator);
+        self
+    }
+
+    /// Register a gate which can veto background compactions.
+    pub fn compaction_gate(mut self, gate: Arc<dyn CompactionGate>) -> Self {
+        self.compaction_gate = Some(gate);
+        self
+    }
+
+    /// Choose which eligible run each background compaction merges,
+    /// see `CompactionPolicy`.
+    pub fn compaction_policy(mut self, policy: Arc<dyn CompactionPolicy>) -> Self {
+        self.compaction_policy = Some(policy);
         self
     }
 
     pub fn open(&self, path: impl AsRef<Path>) -> Result<Lsm> {
-        Lsm::open_with_options(path, self.0.clone())
+        Lsm::open_with(path, self.clone())
     }
 }
 
@@ -109,15 +616,121 @@ impl Lsm {
     }
 
     pub fn open_with_options(path: impl AsRef<Path>, config: Config) -> Result<Self> {
+        Self::open_with(
+            path,
+            OpenOptions {
+                config,
+                ..OpenOptions::new()
+            },
+        )
+    }
+
+    fn open_with(path: impl AsRef<Path>, options: OpenOptions) -> Result<Self> {
         let path = path.as_ref();
+        if !options.config.read_only {
+            return Self::open_once(path, options);
+        }
+
+        // a live writer may flush or compact meanwhile: the WAL read may
+        // miss a flushed sstable, or an sstable vanish, so retry unless the
+        // sstable and hint files are the same before and after.
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let before = storage::list_sstable_files(path)?;
+            let result = Self::open_once(path, options.clone());
+            if storage::list_sstable_files(path)? == before {
+                return result;
+            }
+            if attempts == config::READ_ONLY_OPEN_ATTEMPTS {
+                return Err(LSMLibError::ConcurrentCompaction { attempts });
+            }
+            log::debug!(
+                "sstables of {} changed while opening, retrying",
+                path.display()
+            );
+        }
+    }
 
-        let store = Store::open_with_options(path, config)?;
+    fn open_once(path: &Path, options: OpenOptions) -> Result<Self> {
+        let config = options.config;
+
+        let mut store = Store::open_indexing(path, config.clone(), config.partial_open_sstables)?;
+        store.set_merge_operator(options.merge_operator.clone());
+        let path = &store.path().to_path_buf();
         let sstables = store.list_sstables();
+        let disk_bytes = store.disk_bytes()?;
+        let store_seq = store.max_seq();
+        let sync_monitor = store.sync_monitor();
+
+        let high_water = store
+            .keydir()
+            .entries()
+            .map(|(_, e)| e.timestamp())
+            .max()
+            .unwrap_or(0);
 
         let store = Arc::new(RwLock::new(store));
 
         // build memtable from WAL.
-        let (log, memtable, dirty_bytes) = Self::build_memtable(path)?;
+        let (log, mut memtable, range_tombstones, mut recovery_info) = Self::build_memtable(
+            path,
+            Arc::clone(&sync_monitor),
+            &config,
+            options.write_observer.as_ref(),
+            store.read().unwrap().keydir(),
+        )?;
+        let stale_bytes = Self::drop_stale_entries(
+            &mut memtable,
+            store.read().unwrap().keydir(),
+            &mut recovery_info,
+        );
+        // recovered touches move the expiry of the flushed versions.
+        {
+            let mut store = store.write().unwrap();
+            for (key, entry) in memtable.iter().filter(|(_, e)| e.is_touch()) {
+                store.apply_touch(key, entry.seq(), entry.expiry());
+            }
+        }
+        let seq = memtable
+            .values()
+            .map(|e| e.seq())
+            .chain(range_tombstones.iter().map(|t| t.seq))
+            .fold(store_seq, u64::max);
+        let high_water = memtable
+            .values()
+            .map(|e| e.timestamp())
+            .chain(range_tombstones.iter().map(|t| t.timestamp))
+            .fold(high_water, u32::max);
+        let clock = Arc::new(StoreClock::new(
+            options.clock.unwrap_or_else(|| Arc::new(utils::now_secs)),
+            high_water,
+        ));
+        store.write().unwrap().set_clock({
+            let clock = Arc::clone(&clock);
+            Arc::new(move || clock.now())
+        });
+
+        transform::check(
+            path,
+            options.key_transform.as_ref(),
+            sstables.is_empty() && memtable.is_empty() && range_tombstones.is_empty(),
+            config.file_mode,
+            config.read_only,
+        )?;
+        let identity = identity::open(path, config.file_mode, config.read_only, clock.now())?;
+
+        let repairs = store.read().unwrap().open_repairs();
+        recovery_info.orphan_files_removed = repairs.orphan_files_removed;
+        recovery_info.sstables_truncated = repairs.sstables_truncated;
+        recovery_info.was_clean_shutdown =
+            Self::take_clean_shutdown(path, &sync_monitor, config.read_only)?
+                || identity.generation == 1;
+
+        let negative_cache = (config.negative_cache_entries > 0)
+            .then(|| Arc::new(NegativeCache::new(config.negative_cache_entries as usize)));
+
+        let compaction_stats = Arc::new(CompactionCounters::default());
 
         // create worker message channel.
         let (tx, rx) = mpsc::channel();
@@ -127,61 +740,245 @@ impl Lsm {
             sstables,
             store: Arc::clone(&store),
             inbox: rx,
+            gate: options.compaction_gate,
+            policy: options.compaction_policy,
+            negative_cache: negative_cache.clone(),
             config: config.clone(),
+            stats: Arc::clone(&compaction_stats),
+            now: {
+                let clock = Arc::clone(&clock);
+                Arc::new(move || clock.now())
+            },
+            #[cfg(test)]
+            merge_hook: None,
         };
 
-        std::thread::spawn(move || worker.run());
+        let worker_handle = std::thread::Builder::new()
+            .name(worker::thread_name("compactor", path))
+            .spawn(move || worker.run())?;
 
         let (hb_tx, hb_rx) = mpsc::channel();
         tx.send(CompactorMessage::HeartBeat(hb_tx)).unwrap();
 
         for _ in hb_rx {}
 
+        let index_progress = store.read().unwrap().index_progress();
+        let (indexor_outbox, indexor_handle) = if store.read().unwrap().indexing() {
+            let (tx, rx) = mpsc::channel();
+            let indexor = Indexor {
+                store: Arc::clone(&store),
+                inbox: rx,
+                progress: Arc::clone(&index_progress),
+            };
+            let handle = std::thread::Builder::new()
+                .name(worker::thread_name("indexor", path))
+                .spawn(move || indexor.run())?;
+            (Some(tx), Some(handle))
+        } else {
+            (None, None)
+        };
+
+        let quotas = quota::read(path)?;
+        for prefix in quotas.keys() {
+            store.write().unwrap().count_prefix(prefix);
+        }
+
         log::info!("config: {:?}", config);
 
         Ok(Self {
             path: path.to_path_buf(),
             store: store.clone(),
+            memtable_bytes: memtable_bytes(&memtable),
             memtable,
+            flushing: None,
+            range_tombstones,
             log,
-            dirty_bytes,
+            dirty_bytes: recovery_info.recovered_bytes - stale_bytes,
+            disk_bytes,
+            // the recovered entries may not have reached the disk.
+            unsynced_since: (!config.read_only && recovery_info.recovered_bytes > 0)
+                .then(Instant::now),
+            now: Instant::now,
+            sync_monitor,
+            negative_cache,
+            io_stats: WorkerStats::new(),
+            io_limiter: None,
+            compaction_stats,
+            key_transform: options.key_transform,
+            write_observer: options.write_observer,
+            merge_operator: options.merge_operator,
+            identity,
+            quotas,
+            unflushed_usage: None,
+            expiry_format: false,
+            touch_format: false,
+            merge_format: false,
+            clock,
+            failed: AtomicBool::new(false),
+            seq,
+            recovery_info,
+            #[cfg(test)]
+            flush_hook: None,
             config,
             worker_outbox: tx,
+            worker_handle: Some(worker_handle),
+            indexor_outbox,
+            indexor_handle,
+            index_progress,
             // stats: Stats::default(),
         })
     }
 
+    /// Whether the last open left a clean shutdown marker, removing it
+    /// unless read only so a crash of this open is told apart.
+    fn take_clean_shutdown(
+        dir: &Path,
+        sync_monitor: &SyncMonitor,
+        read_only: bool,
+    ) -> Result<bool> {
+        let path = dir.join(config::CLEAN_SHUTDOWN_FILE);
+        if !path.exists() {
+            return Ok(false);
+        }
+        if !read_only {
+            fs::remove_file(&path)?;
+            sync_monitor.sync_dir(dir)?;
+        }
+        Ok(true)
+    }
+
+    /// Sync the WAL and leave the marker `take_clean_shutdown` looks for.
+    fn mark_clean_shutdown(&mut self) -> Result<()> {
+        if self.config.read_only || self.failed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.sync_log()?;
+
+        let path = self.path.join(config::CLEAN_SHUTDOWN_FILE);
+        let file = utils::open_with_mode(
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true),
+            &path,
+            self.config.file_mode,
+        )?;
+        file.sync_all()?;
+        self.sync_monitor.sync_dir(&self.path)
+    }
+
     /// Create or Recover memtable
-    fn build_memtable(path: &Path) -> Result<(SSTable, BTreeMap<Vec<u8>, DiskEntry>, u64)> {
-        let path = utils::format_wal_path(path, 0);
+    ///
+    /// A read only store replays the WAL if any, leaving it untouched.
+    /// Recovered entries are delivered to `observer` with
+    /// `Config::replay_writes_on_open`.
+    fn build_memtable(
+        dir: &Path,
+        sync_monitor: Arc<SyncMonitor>,
+        config: &Config,
+        observer: Option<&WriteObserverFn>,
+        keydir: &impl Keydir,
+    ) -> Result<(Option<WAL>, Memtable, Vec<RangeTombstone>, RecoveryInfo)> {
+        let path = utils::format_wal_path(dir, 0);
 
         log::info!("recover memtable from log {}", path.display());
 
-        let mut log = WAL::new(path, true)?;
+        if config.read_only && !path.exists() {
+            return Ok((None, BTreeMap::new(), Vec::new(), RecoveryInfo::default()));
+        }
+
+        // an empty WAL never renamed over the log, see `reset_log`.
+        let tmp_path = utils::format_wal_tmp_path(dir, 0);
+        if !config.read_only && tmp_path.exists() {
+            log::warn!("removing unfinished WAL {}", tmp_path.display());
+            fs::remove_file(&tmp_path)?;
+        }
+
+        let mut log = if config.read_only {
+            WAL::new(path, false)?
+        } else {
+            WAL::create(path, config.file_mode)?.with_monitor(sync_monitor, FileClass::Wal)
+        };
 
-        let mut memtable = BTreeMap::new();
+        let mut memtable = Memtable::new();
+        let mut range_tombstones = Vec::new();
         let mut recoverd = 0u64;
+        let mut entries = 0u64;
 
-        for entry in log.iter() {
-            let (crc_expected, crc_actual) = (entry.crc_expected(), entry.crc_actual());
-            if crc_actual != crc_expected {
-                log::warn!(
-                    "crc mismatch for kv pairs {:?}-{:?} expected {} actual {}, torn log detected",
-                    entry.key,
-                    entry.value,
-                    crc_expected,
-                    crc_actual,
-                );
-                break;
+        'records: for record in log.records()? {
+            let (bytes, record) = record?;
+            let record = match record {
+                WalRecord::Entry(entry) => vec![entry],
+                WalRecord::Batch(entries) => entries,
+            };
+
+            // a batch is replayed whole or not at all.
+            for entry in &record {
+                let (crc_expected, crc_actual) = (entry.crc_expected(), entry.crc_actual());
+                if crc_actual != crc_expected {
+                    log::warn!(
+                        "crc mismatch for kv pairs {:?}-{:?} expected {} actual {}, torn log detected",
+                        entry.key,
+                        entry.value,
+                        crc_expected,
+                        crc_actual,
+                    );
+                    break 'records;
+                }
             }
 
-            recoverd += entry.size();
+            recoverd += bytes;
+            entries += record.len() as u64;
+
+            for entry in record {
+                if let Some(observer) = observer.filter(|_| config.replay_writes_on_open) {
+                    observer::notify(observer, &entry, true);
+                }
+
+                if let Some(tombstone) = entry.range_tombstone() {
+                    memtable.retain(|k, e| !tombstone.covers(k, e.seq()));
+                    range_tombstones.push(tombstone);
+                    continue;
+                }
+
+                // operands fold into the unflushed version they apply
+                // to, those a flush settled before a crash are dropped.
+                if entry.is_merge() {
+                    if keydir.get(&entry.key).is_some_and(|e| e.seq >= entry.seq()) {
+                        continue;
+                    }
+                    let entry = merge::fold(memtable.get(&entry.key), entry)?;
+                    memtable.insert(entry.key.clone(), entry);
+                    continue;
+                }
+
+                // a touch of an unflushed put folds into it.
+                if let Some(put) = memtable
+                    .get(&entry.key)
+                    .filter(|e| entry.is_touch() && !e.is_touch())
+                {
+                    if !put.is_tombstone() {
+                        let put = put
+                            .clone()
+                            .with_expiry(entry.expiry())
+                            .with_seq(entry.seq());
+                        memtable.insert(entry.key, put);
+                    }
+                    continue;
+                }
 
-            memtable.insert(entry.key.clone(), entry);
+                memtable.insert(entry.key.clone(), entry);
+            }
         }
 
         // truncate log file.
-        if log.size() > recoverd {
+        let log_size = log.size();
+        if log_size > recoverd && !config.read_only {
+            log::warn!(
+                "torn log detected, truncating {} bytes from {}",
+                log_size - recoverd,
+                log.path().display()
+            );
             log.truncate(recoverd)?;
         }
 
@@ -189,141 +986,5589 @@ impl Lsm {
         log::debug!("recoverd {} kv pairs", memtable.len());
         log::debug!("rewinding log down to length {}", recoverd);
 
-        Ok((log, memtable, recoverd))
-    }
-
-    fn log_mutation(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-        // first: record log.
-        let disk_entry = self.log.write(&key, &value)?;
-        self.dirty_bytes += disk_entry.size();
+        let info = RecoveryInfo {
+            recovered_bytes: recoverd,
+            recovered_entries: entries,
+            truncated_bytes: log_size.saturating_sub(recoverd),
+            truncated: log_size > recoverd,
+            ..Default::default()
+        };
 
-        // then: insert memory.
-        self.memtable.insert(key, disk_entry);
+        let log = (!config.read_only).then_some(log);
 
-        Ok(())
+        Ok((log, memtable, range_tombstones, info))
     }
 
-    fn flush(&mut self) -> Result<()> {
-        log::info!("flush start...");
+    /// Drop the recovered entries older than the version of their key
+    /// in `keydir`, returning their size.
+    ///
+    /// Reads check the memtable first, a WAL replayed after its entries
+    /// were flushed and overwritten would otherwise serve stale values.
+    fn drop_stale_entries(
+        memtable: &mut Memtable,
+        keydir: &impl Keydir,
+        info: &mut RecoveryInfo,
+    ) -> u64 {
+        let mut stale_bytes = 0;
+        memtable.retain(|key, entry| {
+            let stale = keydir.get(key).is_some_and(|e| e.seq > entry.seq());
+            if stale {
+                info.stale_entries += 1;
+                stale_bytes += entry.size();
+            }
+            !stale
+        });
 
-        // WAL sync and flush.
-        self.log.sync()?;
+        if info.stale_entries > 0 {
+            log::warn!(
+                "dropped {} recovered entries older than their flushed version",
+                info.stale_entries
+            );
+        }
 
-        if self.dirty_bytes > self.config.max_log_length {
-            log::debug!("compacting log to new sstable...");
-            let memtable = std::mem::take(&mut self.memtable);
+        stale_bytes
+    }
 
-            let sstable = self.store.write().unwrap().set(&memtable);
+    /// Identity of the store, its generation counting this open.
+    pub fn identity(&self) -> StoreIdentity {
+        self.identity
+    }
 
-            if let Err(e) = sstable {
-                // put memtable back together before returning
-                self.memtable = memtable;
+    /// Return what recovery did when the store was opened.
+    pub fn recovery_info(&self) -> RecoveryInfo {
+        self.recovery_info
+    }
 
-                log::error!("failed to flush memtable to sstable, error: {}", e);
-                return Err(e.into());
-            }
+    /// Take a point-in-time snapshot of the store.
+    ///
+    /// After a partial open, blocks until every sstable is indexed.
+    pub fn snapshot(&self) -> Snapshot {
+        if let Err(e) = self.wait_indexed() {
+            log::error!("snapshot of a partially indexed store: {}", e);
+        }
+        // merge operands are applied by the snapshot, as of it.
+        let memtable = self
+            .unflushed_range::<RangeFull>(..)
+            .into_iter()
+            .map(|(k, v)| (k.to_vec(), v.clone()))
+            .collect();
 
-            let (next_sstable_id, size) = sstable.unwrap();
+        Snapshot::new(
+            self.seq,
+            memtable,
+            self.range_tombstones.clone(),
+            self.clock.now(),
+            Arc::clone(&self.store),
+            self.key_transform.clone(),
+        )
+    }
 
-            // Send message to worker, it may trigger compacting.
-            if let Err(e) = self.worker_outbox.send(CompactorMessage::NewSSTable {
-                id: next_sstable_id,
-                size: size,
-            }) {
-                log::error!("failed to send message to worker: {:?}", e);
-                log::logger().flush();
-                panic!("failed to send message to worker: {:?}", e);
-            }
+    /// Cap foreground read and write bytes per second, replacing
+    /// any previous budget.
+    pub fn io_budget(&mut self, budget: IoBudget) {
+        self.io_limiter = Some(IoLimiter::new(budget));
+    }
+
+    /// Statistics of the memtable flushes since open.
+    pub fn flush_stats(&self) -> FlushStats {
+        self.store.read().unwrap().flush_stats()
+    }
+
+    /// Statistics of the background compactions.
+    pub fn compaction_stats(&self) -> CompactionStats {
+        self.compaction_stats.stats()
+    }
+
+    /// What the last compaction, background or manual, did.
+    pub fn last_compaction(&self) -> Option<CompactionOutcome> {
+        self.compaction_stats.last()
+    }
 
-            // truncate log file.
-            self.log.truncate(0)?;
-            fs::File::open(&self.path)?.sync_all()?;
+    /// Bytes read and written by foreground `get` and `put`.
+    pub fn io_stats(&self) -> IoStats {
+        self.io_stats.io_stats()
+    }
 
-            self.dirty_bytes = 0;
+    /// Size and amplification of the store at a glance.
+    pub fn stats(&self) -> Stats {
+        let (sstable_bytes, keydir_bytes) = {
+            let store = self.store.read().unwrap();
+            let sstable_bytes: u64 = store.list_sstables().values().sum();
+            (sstable_bytes, store.keydir().disk_size())
+        };
+        let wal_bytes = match &self.log {
+            Some(log) => log.size(),
+            None => self.dirty_bytes,
+        };
+        let on_disk_bytes = sstable_bytes + wal_bytes;
+        let resident_bytes =
+            keydir_bytes + self.memtable.values().map(DiskEntry::size).sum::<u64>();
+        let io = self.io_stats.io_stats();
+        let sync = self.sync_stats();
 
-            log::info!("created sstable: {} size: {}", next_sstable_id, size);
+        Stats {
+            resident_bytes,
+            on_disk_bytes,
+            logged_bytes: self.dirty_bytes,
+            read_bytes: io.read_bytes,
+            written_bytes: io.written_bytes,
+            space_amp: match resident_bytes {
+                0 => 0.0,
+                resident => on_disk_bytes as f64 / resident as f64,
+            },
+            write_amp: sync.write_amplification(),
+            slow_syncs: sync.slow_syncs(),
         }
+    }
 
-        Ok(())
+    /// Statistics of the negative lookup cache, `None` when disabled.
+    pub fn negative_cache_stats(&self) -> Option<NegativeCacheStats> {
+        self.negative_cache.as_ref().map(|c| c.stats())
     }
-}
 
-impl Drop for Lsm {
-    fn drop(&mut self) {
-        let (tx, rx) = mpsc::channel();
+    /// `(indexed, total)` sstables of the keydir, short of `total` while
+    /// a partial open indexes the older ones, see
+    /// `OpenOptions::partial_open_sstables`.
+    pub fn index_progress(&self) -> (u64, u64) {
+        self.index_progress.get()
+    }
 
-        if self.worker_outbox.send(CompactorMessage::Stop(tx)).is_err() {
-            log::error!("failed to shutdown compaction worker on Lsm drop");
-            return;
+    /// Background threads of the store, to tell apart the threads of
+    /// the stores a process opens in dumps and profilers.
+    pub fn worker_info(&self) -> Vec<WorkerInfo> {
+        let compactor = self.worker_handle.iter().map(|h| ("compactor", h));
+        let indexor = self.indexor_handle.iter().map(|h| ("indexor", h));
+        compactor
+            .chain(indexor)
+            .map(|(kind, handle)| WorkerInfo {
+                kind,
+                thread_name: handle.thread().name().unwrap_or_default().to_string(),
+            })
+            .collect()
+    }
+
+    /// Bytes the store takes on disk, and how close it is to
+    /// `Config::max_database_bytes`.
+    pub fn disk_usage(&self) -> Result<DiskUsage> {
+        let wal_bytes = match &self.log {
+            Some(log) => log.size(),
+            None => self.dirty_bytes,
+        };
+        let bytes = self.store.read().unwrap().disk_bytes()? + wal_bytes;
+        Ok(DiskUsage {
+            bytes,
+            max_bytes: self.config.max_database_bytes,
+            nearly_full: self
+                .config
+                .database_soft_limit()
+                .is_some_and(|soft| bytes > soft),
+        })
+    }
+
+    /// Latency statistics of the syncs issued by the store.
+    pub fn sync_stats(&self) -> SyncStats {
+        SyncStats {
+            unsynced_age: self.unsynced_age(),
+            user_bytes: self.io_stats.io_stats().written_bytes,
+            ..self.sync_monitor.stats()
         }
+    }
 
-        // #[cfg(test)]
-        // assert!(!self.worker.tick());
+    /// Age of the oldest WAL entry not synced to disk yet, `None` when
+    /// the WAL is synced. Entries recovered at open count from open.
+    pub fn unsynced_age(&self) -> Option<Duration> {
+        self.unsynced_since
+            .map(|since| (self.now)().saturating_duration_since(since))
+    }
 
-        for _ in rx {}
+    /// Lineage of sstable `id`: what wrote it and when, and for a
+    /// compaction output the sstables merged into it.
+    pub fn sstable_lineage(&self, id: u64) -> Result<Lineage> {
+        // the compactor removes merged sstables with the store locked.
+        let store = self.store.read().unwrap();
+        if !store.list_sstables().contains_key(&id) {
+            return Err(LSMLibError::Custom(format!("no sstable {}", id)));
+        }
+        Lineage::read(store.path(), id)
     }
-}
 
-impl KVStore for Lsm {
-    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-        self.log_mutation(key, value)?;
+    /// Statistics of the live keys starting with `prefix`.
+    ///
+    /// Unless `Config::ordered_keydir`, this scans all of the keydir.
+    pub fn prefix_stats(&self, prefix: &[u8]) -> Result<PrefixStats> {
+        self.prefix_stats_stored(&self.key(prefix))
+    }
 
-        // log::info!("dirty_bytes: {:?}", self.dirty_bytes);
+    /// `prefix_stats` of a prefix as stored, already transformed.
+    fn prefix_stats_stored(&self, prefix: &[u8]) -> Result<PrefixStats> {
+        self.wait_indexed()?;
+        let mut stats = PrefixStats::default();
 
-        // rotate log and flush memtable to disk.
-        if self.dirty_bytes > self.config.max_log_length {
-            self.flush()?;
+        let memtable = self.memtable_range(utils::prefix_range(prefix))?;
+        for entry in memtable.values() {
+            stats.pending += 1;
+            if !entry.value.is_empty() {
+                stats.keys += 1;
+                stats.live_bytes += entry.size();
+            }
         }
 
+        let store = self.store.read().unwrap();
+        for (key, entry) in store.keydir().prefix(prefix) {
+            // memtable holds the latest version.
+            if !memtable.contains_key(key) && !self.range_deleted(key) {
+                stats.keys += 1;
+                stats.live_bytes += entry.size;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Limit the live keys starting with `prefix`, replacing its quota
+    /// if any, `Quota::default()` removing it.
+    ///
+    /// Every write is checked against the `namespace_usage` of the
+    /// prefixes of its keys with a quota. A put, batch, merge or ingest
+    /// adding keys past `max_keys`, or growing the entries past
+    /// `max_bytes`, fails with `QuotaExceeded` and changes nothing.
+    /// Deletes, and writes leaving a prefix over a lowered quota no
+    /// fuller, are not held back.
+    pub fn set_prefix_quota(&mut self, prefix: &[u8], quota: Quota) -> Result<()> {
+        self.check_failed()?;
+        if self.log.is_none() {
+            return Err(LSMLibError::ReadOnly);
+        }
+
+        let prefix = self.key(prefix).into_owned();
+        let mut quotas = self.quotas.clone();
+        if quota.is_unlimited() {
+            quotas.remove(&prefix);
+        } else {
+            quotas.insert(prefix.clone(), quota);
+        }
+        quota::write(&self.path, &quotas, self.config.file_mode)?;
+        self.sync_monitor.sync_dir(&self.path)?;
+
+        if !quotas.contains_key(&prefix) {
+            self.store.write().unwrap().uncount_prefix(&prefix);
+        } else if !self.quotas.contains_key(&prefix) {
+            self.store.write().unwrap().count_prefix(&prefix);
+        }
+        self.quotas = quotas;
+        self.unflushed_usage = None;
+
         Ok(())
     }
 
-    fn delete(&mut self, key: &[u8]) -> Result<()> {
-        if !self.contains(key) {
-            log::trace!(
-                "remove key: `{}`, but it not found in database",
-                String::from_utf8_lossy(key)
+    /// Quota of the live keys starting with `prefix`, if any.
+    pub fn prefix_quota(&self, prefix: &[u8]) -> Option<Quota> {
+        self.quotas.get(&*self.key(prefix)).copied()
+    }
+
+    /// Live keys starting with `prefix` and their bytes, as checked
+    /// against its quota, `None` unless it has one.
+    ///
+    /// Counted as writes, flushes, compactions and replays go, this
+    /// matches `prefix_stats` without scanning the keydir. Expired keys
+    /// count until compaction drops them.
+    pub fn namespace_usage(&self, prefix: &[u8]) -> Result<Option<NamespaceUsage>> {
+        let prefix = self.key(prefix);
+        if !self.quotas.contains_key(&*prefix) {
+            return Ok(None);
+        }
+        self.wait_indexed()?;
+        self.quota_usage(&prefix).map(Some)
+    }
+
+    /// `namespace_usage` of a quota prefix as stored.
+    fn quota_usage(&self, prefix: &[u8]) -> Result<NamespaceUsage> {
+        if let Some(usage) = self
+            .unflushed_usage
+            .as_ref()
+            .and_then(|unflushed| self.usage_with(prefix, unflushed))
+        {
+            return Ok(usage);
+        }
+        // compaction may rewrite the keydir again while counting.
+        loop {
+            let unflushed = self.count_unflushed_usage()?;
+            if let Some(usage) = self.usage_with(prefix, &unflushed) {
+                return Ok(usage);
+            }
+        }
+    }
+
+    /// Usage of `prefix` from the keydir count and `unflushed`, `None`
+    /// if the keydir was rewritten since `unflushed` was counted.
+    fn usage_with(&self, prefix: &[u8], unflushed: &UnflushedUsage) -> Option<NamespaceUsage> {
+        let store = self.store.read().unwrap();
+        if store.rewrites() != unflushed.rewrites {
+            return None;
+        }
+        let count = store.keydir().prefix_count(prefix).unwrap_or_default();
+        let delta = unflushed.deltas.get(prefix).copied().unwrap_or_default();
+        Some(NamespaceUsage::of(count, delta))
+    }
+
+    /// Count what the memtable and the unflushed range tombstones change
+    /// of the usage of every quota prefix.
+    ///
+    /// Scans the memtable keys of the quota prefixes, and the keydir if
+    /// a range delete is unflushed.
+    fn count_unflushed_usage(&self) -> Result<UnflushedUsage> {
+        let rewrites = self.store.read().unwrap().rewrites();
+        let mut keys = std::collections::BTreeSet::new();
+        for prefix in self.quotas.keys() {
+            keys.extend(
+                self.memtable
+                    .range(utils::prefix_range(prefix))
+                    .map(|(k, _)| k.clone()),
             );
+            if !self.range_tombstones.is_empty() {
+                let store = self.store.read().unwrap();
+                keys.extend(
+                    store
+                        .keydir()
+                        .prefix(prefix)
+                        .into_iter()
+                        .filter(|(k, _)| self.range_deleted(k))
+                        .map(|(k, _)| k.to_vec()),
+                );
+            }
+        }
+
+        let mut unflushed = UnflushedUsage {
+            rewrites,
+            deltas: self
+                .quotas
+                .keys()
+                .map(|p| (p.clone(), UsageDelta::default()))
+                .collect(),
+        };
+        for key in keys {
+            unflushed.add(&key, self.unflushed_delta(&key)?);
+        }
+
+        Ok(unflushed)
+    }
+
+    /// What the memtable and the unflushed range tombstones change of
+    /// the live entry of `key` in the keydir.
+    fn unflushed_delta(&self, key: &[u8]) -> Result<UsageDelta> {
+        let latest = match self.memtable_entry(key)? {
+            Some(entry) => (!entry.value.is_empty()).then(|| entry.size()),
+            None if self.range_deleted(key) => None,
+            None => return Ok(UsageDelta::default()),
+        };
+        let store = self.store.read().unwrap();
+        let stored = store
+            .keydir()
+            .get(key)
+            .filter(|e| !e.tombstone)
+            .map(|e| e.size);
+        Ok(UsageDelta::between(stored, latest))
+    }
+
+    /// Count the unflushed usage again unless it still holds.
+    fn refresh_unflushed_usage(&mut self) -> Result<()> {
+        let rewrites = self.store.read().unwrap().rewrites();
+        if self
+            .unflushed_usage
+            .as_ref()
+            .is_some_and(|u| u.rewrites == rewrites)
+        {
             return Ok(());
         }
+        self.unflushed_usage = Some(self.count_unflushed_usage()?);
+        Ok(())
+    }
 
-        self.put(key.to_vec(), Vec::new())
+    /// Whether a prefix of `key`, as stored, has a quota.
+    fn has_quota(&self, key: &[u8]) -> bool {
+        self.quotas.keys().any(|p| key.starts_with(p))
     }
 
-    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        if let Some(entry) = self.memtable.get(key) {
-            if entry.value.is_empty() {
-                return Ok(None);
+    /// Check the `writes` of entries of the given sizes at their keys,
+    /// as stored, `None` for deletes, keep every prefix they add keys or
+    /// bytes to within its quota.
+    fn check_quotas(&mut self, writes: &[(&[u8], Option<u64>)]) -> Result<()> {
+        if !writes.iter().any(|(key, _)| self.has_quota(key)) {
+            return Ok(());
+        }
+        self.wait_indexed()?;
+        self.refresh_unflushed_usage()?;
+
+        // later writes of a key replace the earlier ones.
+        let mut latest: HashMap<&[u8], Option<u64>> = HashMap::new();
+        let mut deltas: BTreeMap<&[u8], UsageDelta> = BTreeMap::new();
+        for &(key, size) in writes.iter().filter(|(key, _)| self.has_quota(key)) {
+            let old = match latest.insert(key, size) {
+                Some(old) => old,
+                None => self.live_entry_size(key)?,
+            };
+            for prefix in self.quotas.keys().filter(|p| key.starts_with(p)) {
+                deltas
+                    .entry(prefix)
+                    .or_default()
+                    .add(UsageDelta::between(old, size));
             }
-            return Ok(Some(entry.value.clone()));
-        } else {
-            self.store.write().unwrap().get(key)
         }
+
+        for (prefix, delta) in deltas {
+            let usage = self.quota_usage(prefix)?;
+            let quota = self.quotas[prefix];
+            let over = |used: u64, delta: i64, max: Option<u64>| {
+                delta > 0 && max.is_some_and(|max| used + delta as u64 > max)
+            };
+            let which = if over(usage.keys, delta.keys, quota.max_keys) {
+                QuotaLimit::Keys
+            } else if over(usage.bytes, delta.bytes, quota.max_bytes) {
+                QuotaLimit::Bytes
+            } else {
+                continue;
+            };
+            return Err(LSMLibError::QuotaExceeded {
+                prefix: prefix.to_vec(),
+                which,
+            });
+        }
+
+        Ok(())
     }
 
-    fn contains(&self, key: &[u8]) -> bool {
-        // first: check memtable.
-        if self.memtable.contains_key(key) {
-            return true;
+    /// Size of the latest entry of `key`, as counted by `prefix_stats`,
+    /// `None` unless live.
+    fn live_entry_size(&self, key: &[u8]) -> Result<Option<u64>> {
+        if let Some(entry) = self.memtable_entry(key)? {
+            return Ok((!entry.value.is_empty()).then(|| entry.size()));
         }
-        // then: check keydir.
-        self.store.read().unwrap().contains_key(key)
+        if self.range_deleted(key) {
+            return Ok(None);
+        }
+        let store = self.store.read().unwrap();
+        Ok(store
+            .keydir()
+            .get(key)
+            .filter(|entry| !entry.tombstone)
+            .map(|entry| entry.size))
     }
 
-    fn list_keys(&self) -> Result<Vec<Vec<u8>>> {
-        let mut keys = self.store.read().unwrap().keys()?;
-        keys.sort();
+    /// Visit every live key once with the size of its value.
+    fn for_each_live_key<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], u64),
+    {
+        self.wait_indexed()?;
+        let memtable = self.memtable_range::<RangeFull>(..)?;
+        for (key, entry) in memtable.iter().filter(|(_, e)| !e.is_tombstone()) {
+            f(key, entry.value.len() as u64);
+        }
 
-        self.memtable.iter().for_each(|v| {
-            if !v.1.value.is_empty() {
-                keys.push(v.0.clone());
-            } else {
-                if let Ok(index) = keys.binary_search(v.0) {
-                    keys.remove(index);
+        let store = self.store.read().unwrap();
+        for (key, entry) in store.keydir().entries() {
+            // memtable holds the latest version.
+            if !entry.is_tombstone() && !memtable.contains_key(key) && !self.range_deleted(key) {
+                f(
+                    key,
+                    entry.size.saturating_sub((HEADER_SIZE + key.len()) as u64),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Uniform sample of up to `n` live keys with the size of their value,
+    /// for diagnostics.
+    ///
+    /// Sizes are as stored, compressed values count their frame.
+    ///
+    /// Single pass keeping the `n` keys of lowest hash under `seed`, so
+    /// the sample only depends on the live keys and the seed.
+    pub fn sample_keys(&self, n: usize, seed: u64) -> Result<Vec<(Vec<u8>, u64)>> {
+        self.check_failed()?;
+        let mut sample = BinaryHeap::with_capacity(n + 1);
+        self.for_each_live_key(|key, size| {
+            let rank = bloomfilter::hash(key, seed);
+            if sample.len() < n {
+                sample.push((rank, key.to_vec(), size));
+            } else if sample.peek().is_some_and(|(max, _, _)| rank < *max) {
+                sample.pop();
+                sample.push((rank, key.to_vec(), size));
+            }
+        })?;
+
+        Ok(sample
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(_, key, size)| (key, size))
+            .collect())
+    }
+
+    /// The `n` live keys with the largest values, largest first,
+    /// with the size of their value as stored, see `sample_keys`.
+    pub fn largest_values(&self, n: usize) -> Result<Vec<(Vec<u8>, u64)>> {
+        self.check_failed()?;
+        let mut largest = BinaryHeap::with_capacity(n + 1);
+        self.for_each_live_key(|key, size| {
+            if largest.len() < n {
+                largest.push(Reverse((size, key.to_vec())));
+            } else if largest.peek().is_some_and(|Reverse((min, _))| size > *min) {
+                largest.pop();
+                largest.push(Reverse((size, key.to_vec())));
+            }
+        })?;
+
+        Ok(largest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((size, key))| (key, size))
+            .collect())
+    }
+
+    /// Live keys whose value starts with `prefix`, in key order, with
+    /// their value.
+    ///
+    /// Scans every key. With `Config::value_prefix_index_bytes`, flushed
+    /// values whose indexed prefix cannot match are skipped unread, see
+    /// `IoStats::value_scan_reads_avoided`. Hint files carry no values:
+    /// values flushed before the open are read by the first scan.
+    pub fn scan_by_value_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.check_failed()?;
+        self.wait_indexed()?;
+        let now = self.clock.now();
+        let memtable = self.memtable_range::<RangeFull>(..)?;
+
+        // memtable holds the latest version.
+        let scan = self
+            .store
+            .read()
+            .unwrap()
+            .scan_by_value_prefix(prefix, |key, entry| {
+                !entry.is_live(now) || memtable.contains_key(key) || self.range_deleted(key)
+            })?;
+        self.io_stats
+            .value_scan_reads
+            .fetch_add(scan.reads, Ordering::Relaxed);
+        self.io_stats
+            .value_scan_reads_avoided
+            .fetch_add(scan.reads_avoided, Ordering::Relaxed);
+
+        let mut found: BTreeMap<Vec<u8>, Vec<u8>> = scan.found.into_iter().collect();
+        for (key, entry) in memtable {
+            if !entry.is_tombstone() && !entry.is_expired(now) && entry.value.starts_with(prefix) {
+                found.insert(key.to_vec(), entry.value.clone());
+            }
+        }
+
+        Ok(found.into_iter().collect())
+    }
+
+    /// Stream a digest of the live keys to `w`, see `digest`.
+    ///
+    /// Keys are not collected: a bloom filter is built in place,
+    /// sorted hashes take 8 bytes per key.
+    pub fn export_key_digest(
+        &self,
+        mut w: impl Write,
+        kind: KeyDigestKind,
+    ) -> Result<KeyDigestHeader> {
+        self.wait_indexed()?;
+        let memtable = self.memtable_range::<RangeFull>(..)?;
+        let store = self.store.read().unwrap();
+
+        // memtable holds the latest version.
+        let keydir_keys = || {
+            store
+                .keydir()
+                .entries()
+                .filter(|(k, e)| {
+                    !e.is_tombstone() && !memtable.contains_key(k) && !self.range_deleted(k)
+                })
+                .map(|(k, _)| k)
+        };
+        let memtable_keys = || {
+            memtable
+                .iter()
+                .filter(|(_, e)| !e.is_tombstone())
+                .map(|(k, _)| *k)
+        };
+
+        let key_count = (keydir_keys().count() + memtable_keys().count()) as u64;
+        let mut builder = DigestBuilder::new(kind, key_count);
+        for key in keydir_keys().chain(memtable_keys()) {
+            builder.insert(key);
+        }
+
+        let header = KeyDigestHeader {
+            kind,
+            seed: digest::DEFAULT_SEED,
+            key_count,
+            seq: self.seq,
+            generation: store.list_sstables().keys().max().copied().unwrap_or(0),
+        };
+        builder.finish(&mut w, &header)?;
+
+        Ok(header)
+    }
+
+    /// Get the value of `key` along with what answered the read,
+    /// metered and counted in `io_stats` like `get`.
+    pub fn get_traced(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, ReadSource)> {
+        self.check_failed()?;
+        if let Some(limiter) = &self.io_limiter {
+            limiter.acquire_read()?;
+        }
+
+        let key = self.key(key);
+        let key = &*key;
+        let (value, source) = self.read_traced(key)?;
+
+        let bytes = (key.len() + value.as_ref().map_or(0, Vec::len)) as u64;
+        if let Some(limiter) = &self.io_limiter {
+            limiter.charge_read(bytes);
+        }
+        self.io_stats.read_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.io_stats.record_read(source);
+
+        Ok((value, source))
+    }
+
+    /// Get the values of `keys` in their order, as `get` each would.
+    ///
+    /// Keys the memtable does not answer are looked up under a single
+    /// store lock, their values read sstable by sstable in offset order.
+    pub fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.check_failed()?;
+        if let Some(limiter) = &self.io_limiter {
+            limiter.acquire_read()?;
+        }
+
+        let keys: Vec<_> = keys.iter().map(|key| self.key(key)).collect();
+        let mut reads = keys
+            .iter()
+            .map(|key| self.read_unflushed(key))
+            .collect::<Result<Vec<_>>>()?;
+
+        let pending: Vec<&[u8]> = keys
+            .iter()
+            .zip(&reads)
+            .filter(|(_, read)| read.is_none())
+            .map(|(key, _)| &**key)
+            .collect();
+        if !pending.is_empty() {
+            let (mut found, indexing, rejected) = {
+                let store = self.store.read().unwrap();
+                let indexing = store.indexing();
+                let rejected: Vec<bool> = pending
+                    .iter()
+                    .map(|key| indexing && !store.unindexed_may_contain(key))
+                    .collect();
+                (
+                    store.lookup_many(&pending, self.clock.now())?,
+                    indexing,
+                    rejected,
+                )
+            };
+            // older sstables may hold the missed keys unless their bloom
+            // filters rule them out, look again once indexed.
+            let unsettled = found
+                .iter()
+                .zip(&rejected)
+                .any(|(found, rejected)| found.is_none() && !rejected);
+            if indexing && unsettled {
+                self.indexing_miss()?;
+                found = self
+                    .store
+                    .read()
+                    .unwrap()
+                    .lookup_many(&pending, self.clock.now())?;
+            }
+            let mut found = found.into_iter().zip(rejected);
+            for (key, read) in keys.iter().zip(&mut reads) {
+                if read.is_none() {
+                    *read = found
+                        .next()
+                        .map(|(found, rejected)| self.found_read(key, found, rejected));
+                }
+            }
+        }
+
+        let mut bytes = 0;
+        let values = keys
+            .iter()
+            .zip(reads)
+            .map(|(key, read)| {
+                let (value, source) = read.unwrap();
+                bytes += (key.len() + value.as_ref().map_or(0, Vec::len)) as u64;
+                self.io_stats.record_read(source);
+                value
+            })
+            .collect();
+
+        if let Some(limiter) = &self.io_limiter {
+            limiter.charge_read(bytes);
+        }
+        self.io_stats.read_bytes.fetch_add(bytes, Ordering::Relaxed);
+
+        Ok(values)
+    }
+
+    fn read_traced(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, ReadSource)> {
+        if let Some(read) = self.read_unflushed(key)? {
+            return Ok(read);
+        }
+        self.read_stored(key)
+    }
+
+    /// Read of `key` from the store, whatever the memtable holds.
+    fn read_stored(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, ReadSource)> {
+        let (mut found, indexing, rejected) = {
+            let store = self.store.read().unwrap();
+            let indexing = store.indexing();
+            let rejected = indexing && !store.unindexed_may_contain(key);
+            (store.lookup(key, self.clock.now())?, indexing, rejected)
+        };
+        // older sstables may hold the key unless their bloom filters rule
+        // it out, look again once indexed.
+        if found.is_none() && indexing && !rejected {
+            self.indexing_miss()?;
+            found = self.store.read().unwrap().lookup(key, self.clock.now())?;
+        }
+        Ok(self.found_read(key, found, rejected))
+    }
+
+    /// Read of `key` answered without the store: by the memtable, an
+    /// unflushed range tombstone or the negative cache.
+    fn read_unflushed(&self, key: &[u8]) -> Result<Option<(Option<Vec<u8>>, ReadSource)>> {
+        if let Some(entry) = self.memtable_entry(key)? {
+            return Ok(Some(
+                match entry.is_tombstone() || entry.is_expired(self.clock.now()) {
+                    true => (None, ReadSource::MemtableTombstone),
+                    false => (Some(entry.into_owned().value), ReadSource::Memtable),
+                },
+            ));
+        }
+
+        if self.range_deleted(key) {
+            return Ok(Some((None, ReadSource::MemtableTombstone)));
+        }
+
+        if let Some(cache) = &self.negative_cache {
+            if cache.is_absent(key) {
+                return Ok(Some((None, ReadSource::ReadCache)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Read of `key` from what the store `lookup` found, caching misses.
+    /// `rejected` if the bloom filters of the sstables left to index
+    /// rule the key out.
+    fn found_read(
+        &self,
+        key: &[u8],
+        found: Option<Found>,
+        rejected: bool,
+    ) -> (Option<Vec<u8>>, ReadSource) {
+        let (value, source) = match found {
+            Some((file_id, value)) => (
+                value,
+                ReadSource::SSTable {
+                    file_id,
+                    bloom_checked: false,
+                },
+            ),
+            None => (
+                None,
+                ReadSource::NotFound {
+                    bloom_rejected: rejected,
+                },
+            ),
+        };
+        if let (None, Some(cache)) = (&value, &self.negative_cache) {
+            cache.insert(key);
+        }
+        (value, source)
+    }
+
+    /// Stream the key/value pairs within `range` to `w`, see `export`.
+    ///
+    /// The export is a point-in-time view taken through a snapshot,
+    /// writes made meanwhile are not included. Values are copied off the
+    /// sstables in chunks of `Config::export_chunk_size`, so large values
+    /// are never held whole. A value failing its checksum fails the
+    /// export once copied, before the trailer, so no import accepts it.
+    pub fn export_range<R>(&self, range: R, w: impl Write) -> Result<ExportSummary>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let snapshot = self.snapshot();
+        let mut writer = ExportWriter::new(
+            w,
+            snapshot.seq(),
+            self.identity,
+            self.config.export_chunk_size,
+        )?;
+        for key in snapshot.range_keys(range) {
+            match snapshot.open_stored(&key)? {
+                Some(SnapshotValue::Loaded(value)) => writer.write(&key, &mut value.as_ref())?,
+                Some(SnapshotValue::Stored(mut value)) => {
+                    writer.write(&key, &mut value)?;
+                    value.finish()?;
+                }
+                None => {}
+            }
+        }
+
+        writer.finish()
+    }
+
+    /// Put every key/value pair of an export written by `export_range`.
+    ///
+    /// Pairs are put as they are read, a truncated or corrupt export
+    /// fails after putting the pairs before the damage. Importing the
+    /// same export again is harmless. Each value is reassembled whole
+    /// before it is put.
+    pub fn import_from(&mut self, r: impl Read) -> Result<ExportSummary> {
+        let mut reader = ExportReader::new(r)?;
+        for pair in reader.by_ref() {
+            let (key, value) = pair?;
+            self.put(key, value)?;
+        }
+
+        Ok(reader.summary())
+    }
+
+    /// Iterate the live key/value pairs within `range` in key order,
+    /// memtable and sstables merged, each key once.
+    ///
+    /// The keys are collected up front, the values read when yielded.
+    /// The iterator borrows the store, so no write lands meanwhile, but a
+    /// compaction may: values are looked up by key, not by file.
+    ///
+    /// The keydir being unordered, a range visits every flushed key and
+    /// sorts the `m` in range: `O(n + m log m)` for `n` keys in the store,
+    /// whatever the width of the range. With `Config::ordered_keydir` it
+    /// visits only those in range: `O(log n + m)`.
+    pub fn range<R>(&self, range: R) -> Result<RangeIter<'_>>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let range = transform::apply_bounds(self.key_transform.as_ref(), &range);
+        self.range_stored(range)
+    }
+
+    /// Iterate every live key/value pair in key order, from either end,
+    /// see `range`.
+    pub fn iter(&self) -> Result<RangeIter<'_>> {
+        self.range(..)
+    }
+
+    /// Iterate the live key/value pairs whose key starts with `prefix`
+    /// in key order, see `range`.
+    ///
+    /// Only the matching keys are collected, but unless
+    /// `Config::ordered_keydir` this scans all of the keydir.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<RangeIter<'_>> {
+        let prefix = self.key(prefix);
+        self.range_stored(utils::prefix_range(&prefix))
+    }
+
+    /// `range` of keys as stored, already transformed.
+    ///
+    /// The one merge of memtable and keydir logical iterations go
+    /// through, so each live key is yielded once with its newest value,
+    /// never read off the sstable files where older versions linger
+    /// until compacted.
+    fn range_stored<R>(&self, range: R) -> Result<RangeIter<'_>>
+    where
+        R: RangeBounds<Vec<u8>> + Clone,
+    {
+        self.check_failed()?;
+        self.wait_indexed()?;
+        let now = self.clock.now();
+        let memtable = self.memtable_range(range.clone())?;
+        let mut keys: Vec<Vec<u8>> = memtable
+            .iter()
+            .filter(|(_, e)| !e.is_tombstone() && !e.is_expired(now))
+            .map(|(k, _)| k.to_vec())
+            .collect();
+
+        // memtable holds the latest version.
+        let store = self.store.read().unwrap();
+        let flushed = store
+            .keydir()
+            .range(&range)
+            // a failed read is left to the iteration to report.
+            .filter(|(k, e)| {
+                !memtable.contains_key(*k)
+                    && !self.range_deleted(k)
+                    && store.is_live(k, e, now).unwrap_or(true)
+            })
+            .map(|(k, _)| k.to_vec());
+        if store.keydir().is_ordered() {
+            keys = utils::merge_sorted(keys, flushed.collect());
+        } else {
+            keys.extend(flushed);
+            keys.sort_unstable();
+        }
+
+        Ok(RangeIter {
+            lsm: self,
+            keys: keys.into_iter(),
+        })
+    }
+
+    /// Delete the keys within `range` once exported at sequence number
+    /// `export_seq`, returning the number of keys deleted.
+    ///
+    /// Fails with `RangeConflict` if keys of the range were written or
+    /// deleted after the export, as their latest version would be lost,
+    /// unless `force` is set.
+    pub fn delete_exported_range<R>(
+        &mut self,
+        range: R,
+        export_seq: u64,
+        force: bool,
+    ) -> Result<u64>
+    where
+        R: RangeBounds<Vec<u8>> + Clone,
+    {
+        self.wait_indexed()?;
+        let range = transform::apply_bounds(self.key_transform.as_ref(), &range);
+        let mut conflicts = 0;
+        let mut live = Vec::new();
+        {
+            let memtable = self.memtable_range(range.clone())?;
+            for (key, entry) in &memtable {
+                conflicts += (entry.seq() > export_seq) as u64;
+                if !entry.is_tombstone() {
+                    live.push(key.to_vec());
+                }
+            }
+
+            // memtable holds the latest version.
+            let store = self.store.read().unwrap();
+            for (key, entry) in store.keydir().range(&range) {
+                if memtable.contains_key(key) || self.range_deleted(key) {
+                    continue;
                 }
+                conflicts += (entry.seq() > export_seq) as u64;
+                if !entry.is_tombstone() {
+                    live.push(key.to_vec());
+                }
+            }
+        }
+
+        if conflicts > 0 && !force {
+            return Err(LSMLibError::RangeConflict { conflicts });
+        }
+
+        let deleted = live.len() as u64;
+        for key in live {
+            self.put(key, Vec::new())?;
+        }
+
+        Ok(deleted)
+    }
+
+    /// Delete every key in `[start, end)` with a single range tombstone,
+    /// whatever the number of keys in the range.
+    ///
+    /// The tombstone is logged and flushed like a put. Covered versions
+    /// stay on disk until compaction merges them with the tombstone, and
+    /// the tombstone itself goes once merged into the oldest sstable and
+    /// older than `Config::tombstone_grace`. The first range delete
+    /// stamps the store with `RANGE_TOMBSTONE_FORMAT_VERSION`, which
+    /// older builds refuse to open.
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) -> Result<()> {
+        let (start, end) = (self.key(start), self.key(end));
+        let (start, end) = (&*start, &*end);
+        if start >= end {
+            return Ok(());
+        }
+        if self.log.is_none() {
+            return Err(LSMLibError::ReadOnly);
+        }
+        self.check_failed()?;
+
+        self.require_format_version(RANGE_TOMBSTONE_FORMAT_VERSION)?;
+
+        let log = self.log.as_mut().ok_or(LSMLibError::ReadOnly)?;
+        self.seq += 1;
+        let tombstone =
+            RangeTombstone::new(start.to_vec(), end.to_vec(), self.seq, self.clock.now());
+        let entry = log.write_entry(tombstone.to_entry())?;
+        self.dirty_bytes += entry.size();
+        self.unsynced_since.get_or_insert_with(self.now);
+
+        let memtable_bytes = &mut self.memtable_bytes;
+        self.memtable.retain(|k, e| {
+            let covered = tombstone.covers(k, e.seq());
+            if covered {
+                *memtable_bytes -= entry_bytes(k, e);
             }
+            !covered
         });
+        self.range_tombstones.push(tombstone);
+        self.unflushed_usage = None;
+
+        if let Some(observer) = &self.write_observer {
+            observer::notify(observer, &entry, false);
+        }
 
-        Ok(keys)
+        if self.dirty_bytes > self.config.max_log_length {
+            self.flush_if_full()?;
+        }
+
+        self.assert_invariants("delete_range");
+
+        Ok(())
+    }
+
+    /// Apply the puts and deletes of `batch` as one unit.
+    ///
+    /// The batch is logged as a single WAL record: after a crash either
+    /// all of its mutations are recovered or none. It counts as one
+    /// write towards `Config::max_log_length`, the memtable is flushed
+    /// after the batch, never within it. Fails with `EmptyKey` if any
+    /// key is empty, or `QuotaExceeded` if the batch as a whole takes a
+    /// prefix over its quota, before logging anything.
+    pub fn apply_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.check_failed()?;
+        if self.log.is_none() {
+            return Err(LSMLibError::ReadOnly);
+        }
+
+        let mut ops = Vec::with_capacity(batch.len());
+        for (key, value) in batch.ops {
+            let key = transform::apply_owned(self.key_transform.as_ref(), key);
+            if key.is_empty() {
+                return Err(LSMLibError::EmptyKey);
+            }
+            ops.push((key, value));
+        }
+
+        let writes: Vec<_> = ops
+            .iter()
+            .map(|(k, v)| {
+                let size = (HEADER_SIZE + k.len() + v.len()) as u64;
+                (k.as_slice(), (!v.is_empty()).then_some(size))
+            })
+            .collect();
+        self.check_quotas(&writes)?;
+
+        let bytes: u64 = ops.iter().map(|(k, v)| (k.len() + v.len()) as u64).sum();
+        let put_bytes: u64 = ops
+            .iter()
+            .filter(|(_, v)| !v.is_empty())
+            .map(|(k, v)| (k.len() + v.len()) as u64)
+            .sum();
+        // deletes go through, they free space once compacted.
+        if put_bytes > 0 {
+            self.check_disk_space(put_bytes)?;
+        }
+
+        let mut seen = std::collections::HashSet::new();
+        let replaced = ops
+            .iter()
+            .filter(|(k, _)| seen.insert(k.as_slice()))
+            .filter_map(|(k, _)| self.memtable.get(k).map(|e| entry_bytes(k, e)))
+            .sum();
+        self.reserve_memtable(bytes, replaced)?;
+
+        if let Some(limiter) = &self.io_limiter {
+            limiter.acquire_write(bytes)?;
+        }
+        self.io_stats
+            .written_bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+
+        if let Some(cache) = &self.negative_cache {
+            for (key, _) in &ops {
+                cache.invalidate(key);
+            }
+        }
+
+        // first: record log.
+        let timestamp = self.clock.now();
+        let entries = ops
+            .into_iter()
+            .map(|(key, value)| {
+                self.seq += 1;
+                DiskEntry::stamped(key, value, timestamp).with_seq(self.seq)
+            })
+            .collect();
+        let log = self.log.as_mut().ok_or(LSMLibError::ReadOnly)?;
+        let entries = log.write_batch(entries)?;
+        self.dirty_bytes += BATCH_HEADER_SIZE + entries.iter().map(DiskEntry::size).sum::<u64>();
+        self.unsynced_since.get_or_insert_with(self.now);
+
+        // then: insert memory.
+        for entry in entries {
+            if let Some(observer) = &self.write_observer {
+                observer::notify(observer, &entry, false);
+            }
+
+            self.insert_memtable(entry.key.clone(), entry);
+        }
+
+        if self.dirty_bytes > self.config.max_log_length {
+            self.flush_if_full()?;
+        } else if self
+            .config
+            .max_unsynced_age
+            .is_some_and(|max| self.unsynced_age().is_some_and(|age| age > max))
+        {
+            self.sync_log()?;
+        }
+
+        self.assert_invariants("apply_batch");
+
+        Ok(())
+    }
+
+    /// Replace the value of `key` with what the merge operator makes of
+    /// it and `operand`, see `merge`.
+    ///
+    /// Nothing is read: `operand` is logged as is, and kept in the
+    /// memtable with the other unflushed operands of the key. Reads apply
+    /// them to the value below, a flush settles them on it, and
+    /// compaction collapses them into the value they make, deleting the
+    /// key when the operator returns `None`. Until then, operands deleting
+    /// their key still count in `prefix_stats`, key digests and samples.
+    /// Under a quota, the merge is checked with the value it makes,
+    /// which may read the version below.
+    ///
+    /// Fails with `NoMergeOperator` unless the store was opened with
+    /// `OpenOptions::merge_operator`.
+    pub fn merge(&mut self, key: Vec<u8>, operand: &[u8]) -> Result<()> {
+        if self.merge_operator.is_none() {
+            return Err(LSMLibError::NoMergeOperator);
+        }
+        if self.log.is_none() {
+            return Err(LSMLibError::ReadOnly);
+        }
+        self.check_failed()?;
+        let key = transform::apply_owned(self.key_transform.as_ref(), key);
+        if key.is_empty() {
+            return Err(LSMLibError::EmptyKey);
+        }
+
+        if !self.merge_format {
+            self.require_format_version(MERGE_FORMAT_VERSION)?;
+            self.merge_format = true;
+        }
+
+        let bytes = (key.len() + operand.len()) as u64;
+        if let Some(limiter) = &self.io_limiter {
+            limiter.acquire_write(bytes)?;
+        }
+        self.io_stats
+            .written_bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+
+        self.check_disk_space(bytes)?;
+        let replaced = self.memtable.get(&key).map_or(0, |e| entry_bytes(&key, e));
+        self.reserve_memtable(bytes, replaced)?;
+
+        if let Some(cache) = &self.negative_cache {
+            cache.invalidate(&key);
+        }
+
+        // the operand applies to the version below, unless none is live
+        // or a touch of it, which the memtable would lose, is unflushed.
+        let now = self.clock.now();
+        let stored;
+        let base = match self.memtable.get(&key) {
+            Some(e) if e.is_touch() => {
+                stored = self.read_stored(&key)?.0;
+                stored
+                    .as_deref()
+                    .map_or(merge::Base::Absent, merge::Base::Value)
+            }
+            Some(e) if e.is_tombstone() || e.is_expired(now) => merge::Base::Absent,
+            None if self.range_deleted(&key) => merge::Base::Absent,
+            _ => merge::Base::Below,
+        };
+        let operands = merge::Operands {
+            base,
+            operands: vec![operand],
+        };
+        if self.has_quota(&key) {
+            let entry =
+                DiskEntry::merge(key.clone(), operands.encode(), now).with_seq(self.seq + 1);
+            let entry = merge::fold(self.memtable.get(&key), entry)?;
+            let entry = self.resolve_unflushed(&key, &entry)?;
+            let size = (!entry.value.is_empty()).then(|| entry.size());
+            self.check_quotas(&[(&key, size)])?;
+        }
+
+        // first: record log.
+        let log = self.log.as_mut().ok_or(LSMLibError::ReadOnly)?;
+        self.seq += 1;
+        let disk_entry = log.write_entry(
+            DiskEntry::merge(key.clone(), operands.encode(), now).with_seq(self.seq),
+        )?;
+        self.dirty_bytes += disk_entry.size();
+        self.unsynced_since.get_or_insert_with(self.now);
+
+        if let Some(observer) = &self.write_observer {
+            observer::notify(observer, &disk_entry, false);
+        }
+
+        // then: fold into the unflushed operands of the key.
+        let entry = merge::fold(self.memtable.get(&key), disk_entry)?;
+        self.insert_memtable(key, entry);
+
+        if self.dirty_bytes > self.config.max_log_length {
+            self.flush_if_full()?;
+        } else if self
+            .config
+            .max_unsynced_age
+            .is_some_and(|max| self.unsynced_age().is_some_and(|age| age > max))
+        {
+            self.sync_log()?;
+        }
+
+        self.assert_invariants("merge");
+
+        Ok(())
+    }
+
+    /// Replace the value of `key` with what `f` makes of it, `None`
+    /// standing for an absent key, returning the new value.
+    ///
+    /// The current value is read as `get` would, the result logged as a
+    /// put, or as a delete when `f` returns `None` or an empty value.
+    pub fn update_and_fetch<F>(&mut self, key: &[u8], f: F) -> Result<Option<Vec<u8>>>
+    where
+        F: FnMut(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        let key = self.key(key).into_owned();
+        Ok(self.update_stored(key, f)?.1)
+    }
+
+    /// `update_and_fetch` returning the value replaced instead.
+    pub fn fetch_and_update<F>(&mut self, key: &[u8], f: F) -> Result<Option<Vec<u8>>>
+    where
+        F: FnMut(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        let key = self.key(key).into_owned();
+        Ok(self.update_stored(key, f)?.0)
+    }
+
+    /// Update of a key already transformed, returning the old and the
+    /// new value, see `update_and_fetch`.
+    fn update_stored<F>(&mut self, key: Vec<u8>, mut f: F) -> Result<Update>
+    where
+        F: FnMut(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        self.check_failed()?;
+        if key.is_empty() {
+            return Err(LSMLibError::EmptyKey);
+        }
+
+        let (old, _) = self.read_traced(&key)?;
+        let new = f(old.as_deref()).filter(|value| !value.is_empty());
+        match &new {
+            Some(value) => self.put_stored(key, value.clone())?,
+            // nothing to delete.
+            None if old.is_none() => {}
+            None => self.put_stored(key, Vec::new())?,
+        }
+
+        Ok((old, new))
+    }
+
+    /// Set `key` to `new` if its value is `expected`, `None` standing for
+    /// an absent key, returning the actual value otherwise.
+    ///
+    /// A `None` or empty `new` deletes the key. The write is logged like
+    /// a put.
+    pub fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<std::result::Result<(), CasError>> {
+        self.check_failed()?;
+
+        let key = self.key(key).into_owned();
+        if key.is_empty() {
+            return Err(LSMLibError::EmptyKey);
+        }
+
+        let (actual, _) = self.read_traced(&key)?;
+        if actual.as_deref() != expected {
+            return Ok(Err(CasError { actual }));
+        }
+
+        match new {
+            Some(value) => self.put_stored(key, value.to_vec())?,
+            // nothing to delete.
+            None if actual.is_none() => {}
+            None => self.put_stored(key, Vec::new())?,
+        }
+        Ok(Ok(()))
+    }
+
+    /// Apply the mutations `(seq, key, value)` of another store's
+    /// changefeed in order, `None` values deleting, see `replication`.
+    ///
+    /// Mutations at or below the last sequence number recorded for
+    /// `slot` are skipped, so a stream can be replayed from any point
+    /// before it. Mutations are logged in batches, each as one WAL
+    /// record, see `apply_batch`, followed by a WAL sync and then the
+    /// slot update: after a crash either all of a batch is recovered or
+    /// none, the slot may lag behind the store, never run ahead of it,
+    /// and replaying the lag is harmless.
+    pub fn apply_changes(
+        &mut self,
+        slot: &str,
+        changes: impl Iterator<Item = (u64, Vec<u8>, Option<Vec<u8>>)>,
+    ) -> Result<ApplyReport> {
+        let mut report = ApplyReport {
+            last_seq: replication::read_slot(&self.path, slot)?.unwrap_or(0),
+            ..ApplyReport::default()
+        };
+
+        let mut batch = WriteBatch::new();
+        for (seq, key, value) in changes {
+            if seq <= report.last_seq {
+                report.skipped += 1;
+                continue;
+            }
+
+            batch.put(key, value.unwrap_or_default());
+            report.applied += 1;
+            report.last_seq = seq;
+
+            if batch.len() == APPLY_BATCH_SIZE {
+                self.apply_batch(std::mem::take(&mut batch))?;
+                self.commit_slot(slot, report.last_seq)?;
+            }
+        }
+
+        if !batch.is_empty() {
+            self.apply_batch(batch)?;
+            self.commit_slot(slot, report.last_seq)?;
+        }
+
+        Ok(report)
+    }
+
+    /// Last sequence number applied through replication `slot`.
+    pub fn replication_slot(&self, slot: &str) -> Result<Option<u64>> {
+        replication::read_slot(&self.path, slot)
+    }
+
+    /// Sync the WAL, then record `seq` in `slot`.
+    fn commit_slot(&mut self, slot: &str, seq: u64) -> Result<()> {
+        // syncs the WAL, flushing the memtable if it grew too large.
+        self.flush_if_full()?;
+
+        replication::write_slot(&self.path, slot, seq, self.config.file_mode)?;
+        self.sync_monitor.sync_dir(&self.path)
+    }
+
+    /// Latest in memory entries within the range, touches left out:
+    /// the keydir holds the expiry they set, see `touch`. Merge operands
+    /// are applied, see `resolve_unflushed`.
+    fn memtable_range<R>(&self, range: R) -> Result<BTreeMap<&[u8], Cow<'_, DiskEntry>>>
+    where
+        R: RangeBounds<Vec<u8>> + Clone,
+    {
+        self.unflushed_range(range)
+            .into_iter()
+            .map(|(key, entry)| Ok((key, self.resolve_unflushed(key, entry)?)))
+            .collect()
+    }
+
+    /// `memtable_range`, merge operands left as they are.
+    fn unflushed_range<R>(&self, range: R) -> BTreeMap<&[u8], &DiskEntry>
+    where
+        R: RangeBounds<Vec<u8>> + Clone,
+    {
+        let mut entries = BTreeMap::new();
+        if let Some(flushing) = &self.flushing {
+            entries.extend(
+                flushing
+                    .range(range.clone())
+                    .filter(|(_, v)| !v.is_touch())
+                    .map(|(k, v)| (k.as_slice(), v)),
+            );
+        }
+        entries.extend(
+            self.memtable
+                .range(range)
+                .filter(|(_, v)| !v.is_touch())
+                .map(|(k, v)| (k.as_slice(), v)),
+        );
+
+        entries
+    }
+
+    /// Fail with `StoreDirectoryMissing` once the store directory went
+    /// missing, before touching any file.
+    fn check_failed(&self) -> Result<()> {
+        if self.failed.load(Ordering::Relaxed) {
+            return Err(LSMLibError::StoreDirectoryMissing(self.path.clone()));
+        }
+        Ok(())
+    }
+
+    /// Block until the sstables a partial open left out are indexed,
+    /// for the operations that need the whole keydir.
+    fn wait_indexed(&self) -> Result<()> {
+        self.index_progress.wait()
+    }
+
+    /// Follow `Config::partial_open_read_policy` on a keydir miss made
+    /// while indexing: wait for the keydir to be complete, or fail.
+    fn indexing_miss(&self) -> Result<()> {
+        match self.config.partial_open_read_policy {
+            PartialOpenReadPolicy::Wait => self.wait_indexed(),
+            PartialOpenReadPolicy::Fail => {
+                let (indexed, total) = self.index_progress.get();
+                Err(LSMLibError::IndexingInProgress { indexed, total })
+            }
+        }
+    }
+
+    /// Replace the error of a failed operation by `StoreDirectoryMissing`
+    /// if the store directory is gone, e.g. with its volume unmounted,
+    /// failing every later operation.
+    fn fail_if_dir_missing<T>(&self, result: Result<T>) -> Result<T> {
+        match result {
+            Err(e) if !self.path.is_dir() => {
+                if !self.failed.swap(true, Ordering::Relaxed) {
+                    log::error!(
+                        "store directory {} is missing, failing the store: {}",
+                        self.path.display(),
+                        e
+                    );
+                }
+                Err(LSMLibError::StoreDirectoryMissing(self.path.clone()))
+            }
+            result => result,
+        }
+    }
+
+    /// `key` as stored, see `OpenOptions::key_transform`.
+    fn key<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> {
+        transform::apply(self.key_transform.as_ref(), key)
+    }
+
+    /// Whether an unflushed range tombstone deletes the flushed versions
+    /// of `key`, all older than it.
+    fn range_deleted(&self, key: &[u8]) -> bool {
+        self.range_tombstones.iter().any(|t| t.contains(key))
+    }
+
+    /// Latest in memory entry of the key, from memtable or flushing one,
+    /// touches left out, see `memtable_range`.
+    fn memtable_entry(&self, key: &[u8]) -> Result<Option<Cow<'_, DiskEntry>>> {
+        self.memtable
+            .get(key)
+            .or_else(|| self.flushing.as_ref().and_then(|m| m.get(key)))
+            .filter(|e| !e.is_touch())
+            .map(|entry| self.resolve_unflushed(key, entry))
+            .transpose()
+    }
+
+    /// `entry` of `key` in memory, or the put of the value its merge
+    /// operands make over the version below, a tombstone if they delete
+    /// the key, see `merge`.
+    fn resolve_unflushed<'a>(
+        &self,
+        key: &[u8],
+        entry: &'a DiskEntry,
+    ) -> Result<Cow<'a, DiskEntry>> {
+        if !entry.is_merge() {
+            return Ok(Cow::Borrowed(entry));
+        }
+
+        let operator = self
+            .merge_operator
+            .as_ref()
+            .ok_or(LSMLibError::NoMergeOperator)?;
+        let operands = merge::Operands::decode(&entry.value)?;
+        let below = match operands.base {
+            merge::Base::Below => self.read_stored(key)?.0,
+            _ => None,
+        };
+        let value = operands.resolve(operator, key, below);
+
+        Ok(Cow::Owned(merge::collapsed(entry, value)))
+    }
+
+    /// Check the invariants tying the memtable, the WAL and the store
+    /// together, describing the first violation found.
+    ///
+    /// Scans the whole keydir and memtable, meant for debugging and tests.
+    pub(crate) fn check_invariants(&self) -> std::result::Result<(), String> {
+        let store = self.store.read().unwrap();
+        store.check_invariants()?;
+
+        if self.flushing.is_some() {
+            return Err("flushing memtable left behind".to_string());
+        }
+
+        if self.memtable_bytes != memtable_bytes(&self.memtable) {
+            return Err(format!(
+                "memtable_bytes {} does not match the memtable, {} bytes",
+                self.memtable_bytes,
+                memtable_bytes(&self.memtable)
+            ));
+        }
+
+        // the memtable holds newer writes than the sstables.
+        for (key, entry) in &self.memtable {
+            if entry.seq() > self.seq {
+                return Err(format!(
+                    "memtable entry of key {:?} has seq {} above the store seq {}",
+                    key,
+                    entry.seq(),
+                    self.seq
+                ));
+            }
+            // equal seqs are the same write, flushed before a crash.
+            if let Some(flushed) = store.keydir().get(key) {
+                if flushed.seq > entry.seq() {
+                    return Err(format!(
+                        "memtable entry of key {:?} with seq {} shadows newer sstable {} entry with seq {}",
+                        key,
+                        entry.seq(),
+                        flushed.file_id,
+                        flushed.seq
+                    ));
+                }
+            }
+        }
+        if store.max_seq() > self.seq {
+            return Err(format!(
+                "sstables max seq {} above the store seq {}",
+                store.max_seq(),
+                self.seq
+            ));
+        }
+        let indexing = store.indexing();
+        drop(store);
+
+        // the counted usage is the one scanned.
+        for prefix in self.quotas.keys().filter(|_| !indexing) {
+            let usage = self.quota_usage(prefix).map_err(|e| e.to_string())?;
+            let stats = self
+                .prefix_stats_stored(prefix)
+                .map_err(|e| e.to_string())?;
+            if (usage.keys, usage.bytes) != (stats.keys, stats.live_bytes) {
+                return Err(format!(
+                    "usage of prefix {:?} counted as {:?}, scanned as {:?}",
+                    prefix, usage, stats
+                ));
+            }
+        }
+
+        self.check_wal_invariants()
+    }
+
+    /// Check the unflushed bytes are those of the WAL.
+    fn check_wal_invariants(&self) -> std::result::Result<(), String> {
+        if let Some(log) = &self.log {
+            if log.size() != self.dirty_bytes {
+                return Err(format!(
+                    "dirty bytes {} differ from the WAL length {}",
+                    self.dirty_bytes,
+                    log.size()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the invariants after `op`: every one with the
+    /// `strict-invariants` feature, panicking on violation, only the
+    /// cheap ones in debug builds otherwise.
+    fn assert_invariants(&self, op: &str) {
+        if cfg!(feature = "strict-invariants") {
+            if let Err(e) = self.check_invariants() {
+                panic!("invariant violated after {}: {}", op, e);
+            }
+        } else if cfg!(debug_assertions) {
+            let checked = self.check_wal_invariants();
+            debug_assert!(
+                checked.is_ok(),
+                "invariant violated after {}: {:?}",
+                op,
+                checked
+            );
+        }
+    }
+
+    /// Fail with `DatabaseFull` if writing `bytes` more would take the
+    /// store past `Config::max_database_bytes` once flushed.
+    ///
+    /// The WAL is counted as the sstable it is flushed to. The disk
+    /// usage is measured again before failing, compactions may have
+    /// freed space since the last flush.
+    fn check_disk_space(&mut self, bytes: u64) -> Result<()> {
+        let Some(limit) = self.config.max_database_bytes else {
+            return Ok(());
+        };
+
+        if self.disk_bytes + self.dirty_bytes + bytes <= limit {
+            return Ok(());
+        }
+
+        self.disk_bytes = self.store.read().unwrap().disk_bytes()?;
+        if self.disk_bytes + self.dirty_bytes + bytes <= limit {
+            return Ok(());
+        }
+
+        Err(LSMLibError::DatabaseFull { limit })
+    }
+
+    /// Put `key` with `value` expiring `ttl` from now, rounded up to the
+    /// second: `get`, `contains` and iterations no longer see it once
+    /// expired, and compaction turns it into a tombstone.
+    ///
+    /// An empty value deletes the key, as with `put`. The first expiring
+    /// put stamps the store with `EXPIRY_FORMAT_VERSION`, which older
+    /// builds refuse to open.
+    pub fn put_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        if self.log.is_none() {
+            return Err(LSMLibError::ReadOnly);
+        }
+        self.check_failed()?;
+
+        let key = transform::apply_owned(self.key_transform.as_ref(), key);
+        if value.is_empty() {
+            return self.put_stored(key, value);
+        }
+
+        if !self.expiry_format {
+            self.require_format_version(EXPIRY_FORMAT_VERSION)?;
+            self.expiry_format = true;
+        }
+
+        let expiry = self.expiry_after(ttl);
+        self.put_expiring(key, value, expiry)
+    }
+
+    /// Expiry `ttl` from now, rounded up to the second.
+    fn expiry_after(&self, ttl: Duration) -> u32 {
+        let secs = ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0);
+        let expiry = u64::from(self.clock.now()) + secs;
+        // 0 stands for never.
+        u32::try_from(expiry).unwrap_or(u32::MAX).max(1)
+    }
+
+    /// Move the expiry of `key` to `ttl` from now, or drop it with
+    /// `None`, without rewriting its value. Returns whether the key was
+    /// live, nothing is written otherwise.
+    ///
+    /// A touch, an entry of the key and the new expiry only, is logged:
+    /// reads see the new expiry at once, flushes write the touch to the
+    /// sstables, and compaction folds it into the version it applies
+    /// to. The first touch stamps the store with `TOUCH_FORMAT_VERSION`,
+    /// which older builds refuse to open.
+    pub fn touch(&mut self, key: &[u8], ttl: Option<Duration>) -> Result<bool> {
+        if self.log.is_none() {
+            return Err(LSMLibError::ReadOnly);
+        }
+        self.check_failed()?;
+
+        // the keydir must hold the version the touch applies to.
+        self.wait_indexed()?;
+        if !self.contains(key) {
+            return Ok(false);
+        }
+        let key = self.key(key).into_owned();
+
+        if !self.touch_format {
+            self.require_format_version(TOUCH_FORMAT_VERSION)?;
+            self.touch_format = true;
+        }
+        let expiry = ttl.map_or(0, |ttl| self.expiry_after(ttl));
+
+        let bytes = key.len() as u64;
+        if let Some(limiter) = &self.io_limiter {
+            limiter.acquire_write(bytes)?;
+        }
+        self.io_stats
+            .written_bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+
+        let replaced = self.memtable.get(&key).map_or(0, |e| entry_bytes(&key, e));
+        self.reserve_memtable(bytes, replaced)?;
+
+        // first: record log.
+        let log = self.log.as_mut().ok_or(LSMLibError::ReadOnly)?;
+        self.seq += 1;
+        let touch = log.write_entry(
+            DiskEntry::touch(key.clone(), expiry, self.clock.now()).with_seq(self.seq),
+        )?;
+        self.dirty_bytes += touch.size();
+        self.unsynced_since.get_or_insert_with(self.now);
+
+        if let Some(observer) = &self.write_observer {
+            observer::notify(observer, &touch, false);
+        }
+
+        // then: fold into an unflushed put, or move the flushed expiry.
+        match self.memtable.get(&key).filter(|e| !e.is_touch()) {
+            Some(put) => {
+                let put = put.clone().with_expiry(expiry).with_seq(self.seq);
+                self.insert_memtable(key, put);
+            }
+            None => {
+                self.store
+                    .write()
+                    .unwrap()
+                    .apply_touch(&key, self.seq, expiry);
+                self.insert_memtable(key, touch);
+            }
+        }
+
+        if self.dirty_bytes > self.config.max_log_length {
+            self.flush_if_full()?;
+        } else if self
+            .config
+            .max_unsynced_age
+            .is_some_and(|max| self.unsynced_age().is_some_and(|age| age > max))
+        {
+            self.sync_log()?;
+        }
+
+        self.assert_invariants("touch");
+
+        Ok(true)
+    }
+
+    /// Stamp the store with format `version`, unless stamped with it or
+    /// a later one.
+    fn require_format_version(&self, version: u32) -> Result<()> {
+        migrate::require_format_version(&self.path, version, self.config.file_mode)
+    }
+
+    /// `put` of a key already transformed.
+    fn put_stored(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.put_expiring(key, value, 0)
+    }
+
+    /// `put_stored` of a value expiring at `expiry`, 0 for never.
+    fn put_expiring(&mut self, key: Vec<u8>, value: Vec<u8>, expiry: u32) -> Result<()> {
+        if key.is_empty() {
+            return Err(LSMLibError::EmptyKey);
+        }
+        if !value.is_empty() {
+            let expiry_size = if expiry == 0 { 0 } else { EXPIRY_SIZE };
+            let size = (HEADER_SIZE + key.len() + value.len() + expiry_size) as u64;
+            self.check_quotas(&[(&key, Some(size))])?;
+        }
+
+        let bytes = (key.len() + value.len()) as u64;
+        if let Some(limiter) = &self.io_limiter {
+            limiter.acquire_write(bytes)?;
+        }
+        self.io_stats
+            .written_bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+
+        self.log_mutation(key, value, expiry)?;
+
+        // log::info!("dirty_bytes: {:?}", self.dirty_bytes);
+
+        // rotate log and flush memtable to disk.
+        if self.dirty_bytes > self.config.max_log_length {
+            self.flush_if_full()?;
+        } else if self
+            .config
+            .max_unsynced_age
+            .is_some_and(|max| self.unsynced_age().is_some_and(|age| age > max))
+        {
+            self.sync_log()?;
+        }
+
+        self.assert_invariants("put");
+
+        Ok(())
+    }
+
+    fn log_mutation(&mut self, key: Vec<u8>, value: Vec<u8>, expiry: u32) -> Result<()> {
+        self.check_failed()?;
+
+        // deletes go through, they free space once compacted.
+        if !value.is_empty() {
+            self.check_disk_space((key.len() + value.len()) as u64)?;
+        }
+
+        let replaced = self.memtable.get(&key).map_or(0, |e| entry_bytes(&key, e));
+        self.reserve_memtable((key.len() + value.len()) as u64, replaced)?;
+
+        if let Some(cache) = &self.negative_cache {
+            cache.invalidate(&key);
+        }
+
+        let log = self.log.as_mut().ok_or(LSMLibError::ReadOnly)?;
+
+        // first: record log.
+        self.seq += 1;
+        let disk_entry = log.write_entry(
+            DiskEntry::stamped(key.clone(), value, self.clock.now())
+                .with_expiry(expiry)
+                .with_seq(self.seq),
+        )?;
+        self.dirty_bytes += disk_entry.size();
+        self.unsynced_since.get_or_insert_with(self.now);
+
+        if let Some(observer) = &self.write_observer {
+            observer::notify(observer, &disk_entry, false);
+        }
+
+        // then: insert memory.
+        self.insert_memtable(key, disk_entry);
+
+        Ok(())
+    }
+
+    /// Insert `entry` of `key` into the memtable, keeping its bytes and
+    /// the unflushed usage of the quota prefixes of `key` counted.
+    fn insert_memtable(&mut self, key: Vec<u8>, entry: DiskEntry) {
+        let counted = (self.unflushed_usage.is_some() && self.has_quota(&key))
+            .then(|| (key.clone(), self.unflushed_delta(&key)));
+
+        self.memtable_bytes += entry_bytes(&key, &entry);
+        if let Some(old) = self.memtable.insert(key, entry) {
+            self.memtable_bytes -= entry_bytes(&old.key, &old);
+        }
+
+        let Some((key, before)) = counted else {
+            return;
+        };
+        match (before, self.unflushed_delta(&key)) {
+            (Ok(before), Ok(after)) => {
+                let delta = UsageDelta {
+                    keys: after.keys - before.keys,
+                    bytes: after.bytes - before.bytes,
+                };
+                if let Some(unflushed) = self.unflushed_usage.as_mut() {
+                    unflushed.add(&key, delta);
+                }
+            }
+            // counted again when next needed.
+            _ => self.unflushed_usage = None,
+        }
+    }
+
+    /// Make room in the memtable for `bytes` of entries replacing
+    /// `replaced` bytes, see `Config::memtable_hard_limit_bytes`.
+    fn reserve_memtable(&mut self, bytes: u64, replaced: u64) -> Result<()> {
+        let Some(limit) = self.config.memtable_hard_limit_bytes else {
+            return Ok(());
+        };
+
+        if self.memtable_bytes - replaced + bytes <= limit {
+            return Ok(());
+        }
+        if bytes > limit || self.config.memtable_full_policy == MemtableFullPolicy::Reject {
+            return Err(LSMLibError::MemtableFull { limit });
+        }
+
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Replace the WAL by an empty one once its entries are flushed.
+    ///
+    /// The empty WAL is synced then renamed over the old one, rather than
+    /// the old one truncated in place, so a power failure leaves either
+    /// WAL whole, never a torn one replaying stale entries.
+    fn reset_log(&mut self) -> Result<()> {
+        if self.log.is_none() {
+            return Err(LSMLibError::ReadOnly);
+        }
+
+        let path = utils::format_wal_path(&self.path, 0);
+        let tmp_path = utils::format_wal_tmp_path(&self.path, 0);
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path)?;
+        }
+        WAL::create(&tmp_path, self.config.file_mode)?
+            .with_monitor(Arc::clone(&self.sync_monitor), FileClass::Wal)
+            .sync()?;
+        self.sync_monitor.sync_dir(&self.path)?;
+
+        fs::rename(&tmp_path, &path)?;
+        self.sync_monitor.sync_dir(&self.path)?;
+
+        self.log = Some(
+            WAL::create(&path, self.config.file_mode)?
+                .with_monitor(Arc::clone(&self.sync_monitor), FileClass::Wal),
+        );
+
+        Ok(())
+    }
+
+    /// Sync the WAL to disk.
+    fn sync_log(&mut self) -> Result<()> {
+        self.log.as_mut().ok_or(LSMLibError::ReadOnly)?.sync()?;
+        self.unsynced_since = None;
+        Ok(())
+    }
+
+    /// Whether the sstables and hint files are past the soft limit.
+    fn nearly_full(&self) -> bool {
+        self.config
+            .database_soft_limit()
+            .is_some_and(|soft| self.disk_bytes > soft)
+    }
+
+    /// Sync the WAL, then flush the memtable if the WAL grew too large.
+    fn flush_if_full(&mut self) -> Result<()> {
+        log::info!("flush start...");
+
+        // WAL sync and flush.
+        self.sync_log()?;
+
+        if self.dirty_bytes > self.config.max_log_length {
+            self.flush_memtable()?;
+        }
+
+        Ok(())
+    }
+
+    /// Sync the WAL and write the memtable to a new sstable, whatever
+    /// its size. An empty memtable writes nothing.
+    pub fn flush(&mut self) -> Result<FlushOutcome> {
+        if self.log.is_none() {
+            return Err(LSMLibError::ReadOnly);
+        }
+        self.check_failed()?;
+
+        self.sync_log()?;
+        if self.memtable.is_empty() && self.range_tombstones.is_empty() {
+            return Ok(FlushOutcome::default());
+        }
+
+        self.flush_memtable()
+    }
+
+    /// Write the hint file of every sstable lacking one, so the next
+    /// open does not scan their data, returning how many were written.
+    /// See `Config::hints_on_flush`.
+    pub fn write_missing_hints(&mut self) -> Result<u64> {
+        if self.log.is_none() {
+            return Err(LSMLibError::ReadOnly);
+        }
+        self.check_failed()?;
+
+        let result = self.store.write().unwrap().write_missing_hints();
+        self.fail_if_dir_missing(result)
+    }
+
+    /// Merge every sstable into one, waiting for the compactor to finish.
+    ///
+    /// Runs whatever the compaction gate says, and is recorded like a
+    /// background compaction, see `last_compaction`. Nothing is merged,
+    /// and the outcome is empty, with fewer than two sstables.
+    pub fn compact(&self) -> Result<CompactionOutcome> {
+        if self.log.is_none() {
+            return Err(LSMLibError::ReadOnly);
+        }
+        self.check_failed()?;
+        self.wait_indexed()?;
+
+        let (tx, rx) = mpsc::channel();
+        self.worker_outbox
+            .send(CompactorMessage::Compact(tx))
+            .map_err(|e| LSMLibError::Custom(format!("compaction worker gone: {}", e)))?;
+        let result = rx
+            .recv()
+            .map_err(|e| LSMLibError::Custom(format!("compaction worker gone: {}", e)))?;
+        self.fail_if_dir_missing(result)
+    }
+
+    /// `flush` then `compact`, leaving every write in one sstable.
+    pub fn compact_now(&mut self) -> Result<CompactionOutcome> {
+        self.flush()?;
+        self.compact()
+    }
+
+    /// Write the memtable to a new sstable and truncate the log.
+    fn flush_memtable(&mut self) -> Result<FlushOutcome> {
+        self.flush_memtable_as(None)
+    }
+
+    /// `flush_memtable` to sstable `id` if given, a reserved one.
+    fn flush_memtable_as(&mut self, id: Option<u64>) -> Result<FlushOutcome> {
+        self.check_failed()?;
+        let result = self.write_memtable(id);
+        self.fail_if_dir_missing(result)
+    }
+
+    /// `flush_memtable_as`, whatever the state of the store directory.
+    fn write_memtable(&mut self, id: Option<u64>) -> Result<FlushOutcome> {
+        log::debug!("compacting log to new sstable...");
+        let started = Instant::now();
+        self.settle_merges()?;
+        let skipped_tombstones = self.store.read().unwrap().flush_stats().skipped_tombstones;
+        // keep the memtable readable until the keydir knows the new sstable.
+        let memtable = Arc::new(std::mem::take(&mut self.memtable));
+        self.memtable_bytes = 0;
+        self.unflushed_usage = None;
+        self.flushing = Some(Arc::clone(&memtable));
+
+        #[cfg(test)]
+        if let Some(hook) = self.flush_hook {
+            hook(self);
+        }
+
+        let lengths = match id {
+            // a reserved id names a single sstable.
+            Some(_) => vec![memtable.len()],
+            None => partition_lengths(&memtable, self.config.flush_partitions),
+        };
+
+        let mut store = self.store.write().unwrap();
+        let mut entries = memtable.iter();
+        let mut written = Vec::with_capacity(lengths.len());
+        let mut sstable = Ok(());
+        for (i, len) in lengths.into_iter().enumerate() {
+            let partition: Vec<_> = entries.by_ref().take(len).collect();
+            let flush = match id {
+                Some(id) => store.begin_flush_as(id),
+                None => store.begin_flush(),
+            };
+            sstable = flush
+                .and_then(|mut flush| {
+                    // range tombstones go to the first partition.
+                    for tombstone in self.range_tombstones.iter().filter(|_| i == 0) {
+                        flush.write_range_tombstone(tombstone)?;
+                    }
+                    for (key, entry) in &partition {
+                        flush.write(key, entry)?;
+                    }
+                    flush.finish()
+                })
+                .and_then(|(id, size)| {
+                    written.push((id, size));
+                    if self.config.paranoid_flush_checks {
+                        let path = utils::format_sstable_path(&self.path, id);
+                        let items = partition
+                            .iter()
+                            .map(|(k, e)| ((*k).clone(), (*e).clone()))
+                            .collect();
+                        sstable::verify_sstable(&path, &items)?;
+                    }
+                    Ok(())
+                });
+            if sstable.is_err() {
+                break;
+            }
+        }
+        drop(store);
+        self.flushing = None;
+
+        // Send message to worker, it may trigger compacting.
+        if !written.is_empty() {
+            if let Err(e) = self
+                .worker_outbox
+                .send(CompactorMessage::NewSSTables(written.clone()))
+            {
+                log::error!("failed to send message to worker: {:?}", e);
+                log::logger().flush();
+                panic!("failed to send message to worker: {:?}", e);
+            }
+        }
+
+        if let Err(e) = sstable {
+            // put memtable back together before returning,
+            // newer writes win over the flushing ones.
+            let mut memtable = Arc::try_unwrap(memtable).unwrap_or_else(|m| (*m).clone());
+            memtable.append(&mut self.memtable);
+            self.memtable = memtable;
+            self.memtable_bytes = memtable_bytes(&self.memtable);
+
+            log::error!("failed to flush memtable to sstable, error: {}", e);
+            return Err(e);
+        }
+
+        let next_sstable_id = written.last().map(|(id, _)| *id);
+        let size = written.iter().map(|(_, size)| size).sum();
+        let skipped_tombstones =
+            self.store.read().unwrap().flush_stats().skipped_tombstones - skipped_tombstones;
+        let tombstones = memtable.values().filter(|e| e.is_tombstone()).count() as u64;
+        let outcome = FlushOutcome {
+            flushed: true,
+            sstable_id: next_sstable_id,
+            sstables: written.len() as u64,
+            size,
+            entries: memtable.len() as u64 - skipped_tombstones,
+            tombstones: tombstones - skipped_tombstones,
+            range_tombstones: self.range_tombstones.len() as u64,
+            duration: started.elapsed(),
+        };
+        self.range_tombstones.clear();
+
+        if let Some(cache) = &self.negative_cache {
+            cache.clear();
+        }
+
+        self.reset_log()?;
+
+        self.dirty_bytes = 0;
+        self.unsynced_since = None;
+
+        let was_nearly_full = self.nearly_full();
+        self.disk_bytes = self.store.read().unwrap().disk_bytes()?;
+        if !was_nearly_full && self.nearly_full() {
+            log::warn!(
+                "store {} is nearly full: {} of {:?} bytes",
+                self.path.display(),
+                self.disk_bytes,
+                self.config.max_database_bytes
+            );
+        }
+
+        log::info!("created sstables: {:?}", written);
+
+        self.assert_invariants("flush");
+
+        Ok(outcome)
+    }
+
+    /// Settle the unflushed merge operands applying to the version below
+    /// them on its value, as the keydir only knows the newest version of
+    /// a key, see `merge`.
+    fn settle_merges(&mut self) -> Result<()> {
+        let unsettled: Vec<Vec<u8>> = self
+            .memtable
+            .iter()
+            .filter(|(_, e)| {
+                e.is_merge()
+                    && merge::Operands::decode(&e.value).is_ok_and(|o| o.base == merge::Base::Below)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in unsettled {
+            let below = self.read_stored(&key)?.0;
+            let entry = &self.memtable[&key];
+            let mut operands = merge::Operands::decode(&entry.value)?;
+            operands.base = below
+                .as_deref()
+                .map_or(merge::Base::Absent, merge::Base::Value);
+            let settled = DiskEntry::merge(key.clone(), operands.encode(), entry.timestamp())
+                .with_expiry(entry.expiry())
+                .with_seq(entry.seq());
+            self.memtable.insert(key, settled);
+        }
+
+        Ok(())
+    }
+
+    /// Ingest the sstable at `path`, e.g. built with `SSTableWriter`,
+    /// its entries newer than every write before, tombstones included.
+    ///
+    /// The memtable is flushed first, then the entries, checked for crc
+    /// and key order, are given new sequence numbers and written to a
+    /// new sstable, held in memory meanwhile. They are not delivered to
+    /// the write observer. The file at `path` is left as is.
+    ///
+    /// Fails with `VerificationFailed` for a damaged or unsorted sstable.
+    pub fn ingest_sstable(&mut self, path: impl AsRef<Path>) -> Result<FlushOutcome> {
+        self.ingest(path.as_ref(), None)
+    }
+
+    /// `ingest_sstable`, keeping the id the file at `path` is named
+    /// after, one of `Config::reserved_id_ranges` above every sstable
+    /// id given so far.
+    ///
+    /// Fails with `SSTableIdUnavailable` for any other id.
+    pub fn ingest_sstable_keep_id(&mut self, path: impl AsRef<Path>) -> Result<FlushOutcome> {
+        let path = path.as_ref();
+        let id = utils::parse_file_id(path)
+            .ok_or_else(|| LSMLibError::InvalidFileName(path.to_path_buf()))?;
+        self.ingest(path, Some(id))
+    }
+
+    fn ingest(&mut self, path: &Path, id: Option<u64>) -> Result<FlushOutcome> {
+        if self.log.is_none() {
+            return Err(LSMLibError::ReadOnly);
+        }
+        self.check_failed()?;
+
+        let failed = |reason: String| LSMLibError::VerificationFailed {
+            path: path.to_path_buf(),
+            reason,
+        };
+
+        let mut sst = SSTable::new(path, false)?;
+        if sstable::intact_len(path)? != sst.size() {
+            return Err(failed("torn tail".to_string()));
+        }
+        self.check_disk_space(sst.size())?;
+
+        self.sync_log()?;
+        if !self.memtable.is_empty() || !self.range_tombstones.is_empty() {
+            self.flush_memtable()?;
+        }
+
+        let mut ingested = BTreeMap::new();
+        let mut last_key: Option<Vec<u8>> = None;
+        for entry in sst.iter() {
+            let entry = entry?;
+            if entry.key.is_empty() {
+                return Err(failed("range tombstones cannot be ingested".to_string()));
+            }
+            if entry.is_touch() {
+                return Err(failed("touches cannot be ingested".to_string()));
+            }
+            if entry.is_merge() {
+                return Err(failed("merge operands cannot be ingested".to_string()));
+            }
+            if last_key.as_ref().is_some_and(|last| entry.key <= *last) {
+                return Err(failed(format!(
+                    "key '{}' out of order",
+                    String::from_utf8_lossy(&entry.key)
+                )));
+            }
+            last_key = Some(entry.key.clone());
+
+            let (timestamp, expiry) = (entry.timestamp(), entry.expiry());
+            let key = transform::apply_owned(self.key_transform.as_ref(), entry.key);
+            self.seq += 1;
+            let entry = DiskEntry::stamped(key.clone(), entry.value, timestamp)
+                .with_expiry(expiry)
+                .with_seq(self.seq);
+            ingested.insert(key, entry);
+        }
+        if ingested.is_empty() {
+            return Ok(FlushOutcome::default());
+        }
+        let writes: Vec<_> = ingested
+            .iter()
+            .map(|(k, e)| (k.as_slice(), (!e.value.is_empty()).then(|| e.size())))
+            .collect();
+        self.check_quotas(&writes)?;
+        if ingested.values().any(|e| e.expiry() != 0) {
+            self.require_format_version(EXPIRY_FORMAT_VERSION)?;
+        }
+
+        // the memtable is empty, and the ingested entries are not in the WAL.
+        self.memtable_bytes = memtable_bytes(&ingested);
+        self.memtable = ingested;
+        self.unflushed_usage = None;
+        let outcome = self.flush_memtable_as(id);
+        if outcome.is_err() {
+            self.memtable.clear();
+            self.memtable_bytes = 0;
+        }
+        outcome
+    }
+
+    /// Publish the live data into the empty or missing directory
+    /// `target` as a frozen store, see `publish`.
+    ///
+    /// Unlike `clone_to`, the output is compacted into one sstable
+    /// holding only live keys. The memtable is flushed first, then the
+    /// data is read through a snapshot, so writes go on meanwhile
+    /// without being published. Every entry carries the snapshot
+    /// sequence number.
+    pub fn publish_snapshot(&mut self, target: impl AsRef<Path>) -> Result<SnapshotManifest> {
+        self.check_failed()?;
+        if self.log.is_none() {
+            return Err(LSMLibError::ReadOnly);
+        }
+
+        let target = target.as_ref();
+        if target.exists() && fs::read_dir(target)?.next().is_some() {
+            return Err(LSMLibError::Custom(format!(
+                "publish target '{}' is not empty",
+                target.display()
+            )));
+        }
+
+        self.sync_log()?;
+        if !self.memtable.is_empty() || !self.range_tombstones.is_empty() {
+            self.flush_memtable()?;
+        }
+
+        let snapshot = self.snapshot();
+        let timestamp = self.clock.now();
+        let (key_count, size) = self.write_snapshot_into(&snapshot, target, timestamp)?;
+
+        let manifest = SnapshotManifest {
+            key_count,
+            size,
+            created_at: timestamp as u64,
+            source_seq: snapshot.seq(),
+            source_uuid: self.identity.uuid,
+            source_generation: self.identity.generation,
+        };
+        manifest.write_to(target, self.config.file_mode)?;
+        self.sync_monitor.sync_dir(target)?;
+
+        Ok(manifest)
+    }
+
+    /// Write the live data of `snapshot` into sstable 1 of the store at
+    /// `target`, with its hint, bloom filter and lineage, every entry
+    /// stamped with the snapshot sequence number and `timestamp` and
+    /// keeping its expiry. Returns the key count and the sstable size.
+    fn write_snapshot_into(
+        &self,
+        snapshot: &Snapshot,
+        target: &Path,
+        timestamp: u32,
+    ) -> Result<(u64, u64)> {
+        utils::create_dir_all(target, self.config.dir_mode)?;
+        migrate::write_format_version(target, FORMAT_VERSION, self.config.file_mode)?;
+        if self.config.zstd_sstable_compression_level != 0 {
+            migrate::require_format_version(
+                target,
+                COMPRESSION_FORMAT_VERSION,
+                self.config.file_mode,
+            )?;
+        }
+
+        let mut writer = SSTableWriter::create(
+            utils::format_sstable_path(target, 1),
+            SSTableWriterOptions::from_config(&self.config),
+        )?
+        .with_monitor(Arc::clone(&self.sync_monitor));
+        let mut key_count = 0;
+        let mut expiring = false;
+        for entry in snapshot.iter_expiring() {
+            let (key, value, expiry) = entry?;
+            expiring |= expiry != 0;
+            writer.write_entry(
+                DiskEntry::stamped(key, value, timestamp)
+                    .with_seq(snapshot.seq())
+                    .with_expiry(expiry),
+            )?;
+            key_count += 1;
+        }
+        let size = writer.finish()?.size;
+        if expiring {
+            migrate::require_format_version(target, EXPIRY_FORMAT_VERSION, self.config.file_mode)?;
+        }
+        Lineage::flushed(timestamp).write(target, 1, self.config.file_mode)?;
+
+        Ok((key_count, size))
+    }
+
+    /// Repair `key` when its entry fails its checksum or no longer
+    /// matches the keydir.
+    ///
+    /// Every sstable is scanned for an older intact version, which is
+    /// written back through `put`. Once flushed, the corrupt entry is no
+    /// longer referenced and the next merge of its sstable drops it.
+    /// Without any intact version the key is deleted if
+    /// `Config::repair_writes_tombstone` is set, and left alone otherwise.
+    pub fn repair_key(&mut self, key: &[u8]) -> Result<RepairOutcome> {
+        let key = self.key(key);
+        let key = &*key;
+        if self.memtable_entry(key)?.is_some() || self.range_deleted(key) {
+            return Ok(RepairOutcome::Intact);
+        }
+
+        let mut store = self.store.write().unwrap();
+        match store.get(key) {
+            Ok(_) => return Ok(RepairOutcome::Intact),
+            Err(LSMLibError::ChecksumMismatch { .. })
+            | Err(LSMLibError::StaleKeydirEntry { .. }) => {
+                log::warn!("repairing corrupt key `{}`", String::from_utf8_lossy(key));
+            }
+            Err(e) => return Err(e),
+        }
+        // older versions are only trusted from unchanged files.
+        let sstable_ids: Vec<u64> = store.list_sstables().into_keys().collect();
+        let sstable_ids = self.limit_files_per_read(&sstable_ids)?;
+        store.verify_fingerprints(sstable_ids)?;
+        let older = store.find_older_version(key, sstable_ids);
+        drop(store);
+
+        match older {
+            Some((file_id, entry)) => {
+                // merge operands are put as the value they make.
+                let value = self.store.read().unwrap().resolve(key, entry)?;
+                self.put(key.to_vec(), value.unwrap_or_default())?;
+                Ok(RepairOutcome::RestoredFromVersion(file_id))
+            }
+            None if self.config.repair_writes_tombstone => {
+                self.put(key.to_vec(), Vec::new())?;
+                Ok(RepairOutcome::Tombstoned)
+            }
+            None => Ok(RepairOutcome::Unrepairable),
+        }
+    }
+
+    /// The sstables among `sstable_ids`, oldest first, a read scanning
+    /// several files may read, see `Config::max_files_per_read`.
+    fn limit_files_per_read<'a>(&self, sstable_ids: &'a [u64]) -> Result<&'a [u64]> {
+        let files = sstable_ids.len();
+        let max = match self.config.max_files_per_read {
+            Some(max) if files > max as usize => max,
+            _ => return Ok(sstable_ids),
+        };
+
+        self.io_stats
+            .reads_over_file_limit
+            .fetch_add(1, Ordering::Relaxed);
+        if !self.config.clamp_files_per_read {
+            return Err(LSMLibError::ReadAmplificationExceeded {
+                files: files as u64,
+                max,
+            });
+        }
+
+        log::warn!(
+            "read would scan {} sstables, scanning the newest {} only, compaction is behind",
+            files,
+            max
+        );
+        Ok(&sstable_ids[files - max as usize..])
+    }
+
+    /// Clone the store into the empty or missing directory `target` and
+    /// open the clone, an independent store with its own WAL and lock.
+    ///
+    /// The memtable is flushed first, then every sstable and hint and the
+    /// format version file are hard linked into `target`, or copied where
+    /// linking fails, so the clone costs no disk space until either store
+    /// compacts. This is safe as
+    /// sstables and hints are never modified once written: compaction
+    /// writes new files, renames them in place and unlinks the merged ones,
+    /// which leaves the other directory entries of the same file alone.
+    /// The clone rebuilds its keydir from the hints.
+    pub fn clone_to(&mut self, target: impl AsRef<Path>) -> Result<Lsm> {
+        let target = target.as_ref();
+        if target.exists() && fs::read_dir(target)?.next().is_some() {
+            return Err(LSMLibError::Custom(format!(
+                "clone target '{}' is not empty",
+                target.display()
+            )));
+        }
+
+        self.sync_log()?;
+        if !self.memtable.is_empty() || !self.range_tombstones.is_empty() {
+            self.flush_memtable()?;
+        }
+
+        utils::create_dir_all(target, self.config.dir_mode)?;
+        {
+            // the compactor unlinks merged sstables with the store locked.
+            let store = self.store.write().unwrap();
+            utils::link_or_copy(
+                &self.path.join(config::VERSION_FILE),
+                &target.join(config::VERSION_FILE),
+            )?;
+            if self.key_transform.is_some() {
+                utils::link_or_copy(
+                    &self.path.join(config::KEY_TRANSFORM_FILE),
+                    &target.join(config::KEY_TRANSFORM_FILE),
+                )?;
+            }
+            for id in store.list_sstables().keys() {
+                utils::link_or_copy(
+                    &utils::format_sstable_path(&self.path, *id),
+                    &utils::format_sstable_path(target, *id),
+                )?;
+
+                let hint_path = utils::format_hint_path(&self.path, *id);
+                if hint_path.exists() {
+                    utils::link_or_copy(&hint_path, &utils::format_hint_path(target, *id))?;
+                }
+                let lineage_path = utils::format_lineage_path(&self.path, *id);
+                if lineage_path.exists() {
+                    utils::link_or_copy(&lineage_path, &utils::format_lineage_path(target, *id))?;
+                }
+                let bloom_path = utils::format_bloom_path(&self.path, *id);
+                if bloom_path.exists() {
+                    utils::link_or_copy(&bloom_path, &utils::format_bloom_path(target, *id))?;
+                }
+            }
+        }
+        self.sync_monitor.sync_dir(target)?;
+
+        Self::open_with(
+            target,
+            OpenOptions {
+                config: self.config.clone(),
+                compaction_gate: None,
+                compaction_policy: None,
+                key_transform: self.key_transform.clone(),
+                write_observer: None,
+                merge_operator: self.merge_operator.clone(),
+                clock: Some(self.clock.source()),
+            },
+        )
+    }
+
+    /// Rewrite the live data into the empty or missing directory
+    /// `target`, e.g. on another filesystem, and open the rewritten store.
+    ///
+    /// The memtable is flushed, then the live data of a snapshot is
+    /// streamed into a single sstable, every entry carrying the snapshot
+    /// sequence number the new store goes on from. Writes go through
+    /// `&mut self`, so none lands during the rewrite and there is no
+    /// delta to replay: writes wait for the whole call, reported as
+    /// `pause`. This store is left intact, for the caller to drop and
+    /// delete.
+    pub fn rewrite_into(&mut self, target: impl AsRef<Path>) -> Result<(Lsm, RewriteReport)> {
+        let start = Instant::now();
+        let target = target.as_ref();
+        if target.exists() && fs::read_dir(target)?.next().is_some() {
+            return Err(LSMLibError::Custom(format!(
+                "rewrite target '{}' is not empty",
+                target.display()
+            )));
+        }
+
+        let mut report = RewriteReport {
+            flush: self.flush()?,
+            ..RewriteReport::default()
+        };
+
+        let copy_start = Instant::now();
+        let snapshot = self.snapshot();
+        (report.keys, report.bytes) =
+            self.write_snapshot_into(&snapshot, target, self.clock.now())?;
+        if self.key_transform.is_some() {
+            utils::link_or_copy(
+                &self.path.join(config::KEY_TRANSFORM_FILE),
+                &target.join(config::KEY_TRANSFORM_FILE),
+            )?;
+        }
+        drop(snapshot);
+        report.copy_duration = copy_start.elapsed();
+
+        let open_start = Instant::now();
+        let lsm = Self::open_with(
+            target,
+            OpenOptions {
+                config: self.config.clone(),
+                compaction_gate: None,
+                compaction_policy: None,
+                key_transform: self.key_transform.clone(),
+                write_observer: None,
+                merge_operator: self.merge_operator.clone(),
+                clock: Some(self.clock.source()),
+            },
+        )?;
+        report.open_duration = open_start.elapsed();
+        report.pause = start.elapsed();
+
+        Ok((lsm, report))
+    }
+}
+
+impl Drop for Lsm {
+    fn drop(&mut self) {
+        // the indexor may be done and gone already.
+        if let Some(outbox) = self.indexor_outbox.take() {
+            let (tx, rx) = mpsc::channel();
+            if outbox.send(IndexorMessage::Stop(tx)).is_ok() {
+                for _ in rx {}
+            }
+        }
+        if let Some(handle) = self.indexor_handle.take() {
+            if handle.join().is_err() {
+                log::error!("index worker panicked");
+            }
+        }
+
+        let (tx, rx) = mpsc::channel();
+
+        if self.worker_outbox.send(CompactorMessage::Stop(tx)).is_err() {
+            log::error!("failed to shutdown compaction worker on Lsm drop");
+            return;
+        }
+
+        // #[cfg(test)]
+        // assert!(!self.worker.tick());
+
+        for _ in rx {}
+
+        // worker holds the store (and its lock) until it exits.
+        if let Some(handle) = self.worker_handle.take() {
+            if handle.join().is_err() {
+                log::error!("compaction worker panicked");
+            }
+        }
+
+        if let Err(e) = self.mark_clean_shutdown() {
+            log::error!("failed to mark clean shutdown on Lsm drop: {:?}", e);
+        }
+    }
+}
+
+impl KVStore for Lsm {
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let key = transform::apply_owned(self.key_transform.as_ref(), key);
+        self.put_stored(key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        if key.is_empty() {
+            return Err(LSMLibError::EmptyKey);
+        }
+
+        // while indexing, write the tombstone rather than wait to know.
+        let (indexed, total) = self.index_progress.get();
+        if indexed == total && !self.contains(key) {
+            log::trace!(
+                "remove key: `{}`, but it not found in database",
+                String::from_utf8_lossy(key)
+            );
+            return Ok(());
+        }
+
+        self.put(key.to_vec(), Vec::new())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_traced(key).map(|(value, _)| value)
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        let key = self.key(key);
+        let key = &*key;
+        let now = self.clock.now();
+
+        // first: check memtable, a tombstone deletes the key.
+        match self.memtable_entry(key) {
+            Ok(Some(entry)) => return !entry.is_tombstone() && !entry.is_expired(now),
+            Ok(None) => {}
+            Err(e) => {
+                log::error!("failed to check {:?}: {}", key, e);
+                return false;
+            }
+        }
+
+        if self.range_deleted(key) {
+            return false;
+        }
+
+        if let Some(cache) = &self.negative_cache {
+            if cache.is_absent(key) {
+                return false;
+            }
+        }
+
+        // then: check keydir, complete once indexed.
+        let lookup = || {
+            let store = self.store.read().unwrap();
+            let contains = store.keydir().get(key).is_some_and(|e| {
+                store.is_live(key, e, now).unwrap_or_else(|e| {
+                    log::error!("failed to check {:?}: {}", key, e);
+                    false
+                })
+            });
+            (
+                contains,
+                store.indexing() && store.unindexed_may_contain(key),
+            )
+        };
+        let contains = match lookup() {
+            (false, true) => match self.wait_indexed() {
+                Ok(()) => lookup().0,
+                Err(e) => {
+                    log::error!("failed to check {:?}: {}", key, e);
+                    return false;
+                }
+            },
+            (contains, _) => contains,
+        };
+        if let (false, Some(cache)) = (contains, &self.negative_cache) {
+            cache.insert(key);
+        }
+        contains
+    }
+
+    fn list_keys(&self) -> Result<Vec<Vec<u8>>> {
+        Ok(self.range_stored(..)?.keys.collect())
+    }
+}
+
+/// Iterator over the key/value pairs of a range, see `Lsm::range`.
+pub struct RangeIter<'a> {
+    lsm: &'a Lsm,
+    keys: std::vec::IntoIter<Vec<u8>>,
+}
+
+impl RangeIter<'_> {
+    /// Value of `key`, `None` for a key deleted since collected.
+    fn resolve(&self, key: Vec<u8>) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        match self.lsm.read_traced(&key) {
+            Ok((Some(value), _)) => Some(Ok((key, value))),
+            Ok((None, _)) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl Iterator for RangeIter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(key) = self.keys.next() {
+            if let Some(item) = self.resolve(key) {
+                return Some(item);
+            }
+        }
+        None
+    }
+}
+
+impl DoubleEndedIterator for RangeIter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while let Some(key) = self.keys.next_back() {
+            if let Some(item) = self.resolve(key) {
+                return Some(item);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+    use tempdir::TempDir;
+
+    use crate::disk::format::{EntryIO, COMPRESSION_FORMAT_VERSION};
+
+    /// Wait until the compactor handled every message sent before.
+    fn wait_worker(lsm: &Lsm) {
+        let (tx, rx) = mpsc::channel();
+        lsm.worker_outbox
+            .send(CompactorMessage::HeartBeat(tx))
+            .unwrap();
+        for _ in rx {}
+    }
+
+    fn sstable_count(lsm: &Lsm) -> usize {
+        lsm.store.read().unwrap().list_sstables().len()
+    }
+
+    #[test]
+    fn test_recovery_info_torn_log() {
+        let dir = TempDir::new("lsmlib").unwrap();
+
+        let lsm = Lsm::open(dir.path()).unwrap();
+        assert_eq!(
+            lsm.recovery_info(),
+            RecoveryInfo {
+                was_clean_shutdown: true,
+                ..Default::default()
+            }
+        );
+        drop(lsm);
+
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+        lsm.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        lsm.put(b"k2".to_vec(), b"v2".to_vec()).unwrap();
+        drop(lsm);
+
+        // append a partial entry, as a crash in the middle of a write would.
+        let entry = DiskEntry::new(b"k3".to_vec(), b"v3".to_vec());
+        let mut buf = std::io::Cursor::new(Vec::new());
+        entry.write_to(&mut buf).unwrap();
+        let torn = &buf.get_ref()[..entry.size() as usize - 3];
+
+        let wal_path = utils::format_wal_path(dir.path(), 0);
+        let mut wal = fs::OpenOptions::new().append(true).open(&wal_path).unwrap();
+        wal.write_all(torn).unwrap();
+        drop(wal);
+
+        let lsm = Lsm::open(dir.path()).unwrap();
+        let info = lsm.recovery_info();
+        assert!(info.truncated);
+        assert_eq!(info.truncated_bytes, torn.len() as u64);
+        assert_eq!(info.recovered_entries, 2);
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), info.recovered_bytes);
+
+        assert_eq!(lsm.get(b"k2").unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(lsm.get(b"k3").unwrap(), None);
+    }
+
+    #[test]
+    fn test_recovery_info_clean_shutdown() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let marker = dir.path().join(config::CLEAN_SHUTDOWN_FILE);
+
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+        assert!(lsm.recovery_info().was_clean_shutdown);
+        lsm.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        drop(lsm);
+        assert!(marker.exists());
+
+        // a read only open leaves the marker to the next one.
+        let lsm = OpenOptions::new().read_only(true).open(dir.path()).unwrap();
+        assert!(lsm.recovery_info().was_clean_shutdown);
+        drop(lsm);
+
+        let lsm = Lsm::open(dir.path()).unwrap();
+        assert!(lsm.recovery_info().was_clean_shutdown);
+        assert!(!marker.exists());
+        drop(lsm);
+
+        // no marker, as a crash before the drop would leave it.
+        fs::remove_file(&marker).unwrap();
+        let lsm = Lsm::open(dir.path()).unwrap();
+        let info = lsm.recovery_info();
+        assert!(!info.was_clean_shutdown);
+        assert_eq!(info.recovered_entries, 1);
+        assert_eq!(info.orphan_files_removed, 0);
+        assert_eq!(info.sstables_truncated, 0);
+    }
+
+    #[test]
+    fn test_apply_batch() {
+        let dir = TempDir::new("lsmlib").unwrap();
+
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+        lsm.put(b"k1".to_vec(), b"old".to_vec()).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"k1".to_vec(), b"v1".to_vec())
+            .put(b"k2".to_vec(), b"v2".to_vec())
+            .delete(b"k1".to_vec())
+            .put(b"k3".to_vec(), b"v3".to_vec());
+        lsm.apply_batch(batch).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"k4".to_vec(), b"v4".to_vec())
+            .put(Vec::new(), b"v".to_vec());
+        assert!(matches!(lsm.apply_batch(batch), Err(LSMLibError::EmptyKey)));
+        assert_eq!(lsm.get(b"k4").unwrap(), None);
+        drop(lsm);
+
+        let lsm = Lsm::open(dir.path()).unwrap();
+        assert_eq!(lsm.recovery_info().recovered_entries, 5);
+        assert_eq!(lsm.get(b"k1").unwrap(), None);
+        assert_eq!(lsm.get(b"k2").unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(lsm.get(b"k3").unwrap(), Some(b"v3".to_vec()));
+    }
+
+    #[test]
+    fn test_apply_batch_torn() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let wal_path = utils::format_wal_path(dir.path(), 0);
+
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+        lsm.put(b"k0".to_vec(), b"v0".to_vec()).unwrap();
+        lsm.sync_log().unwrap();
+        let before = fs::metadata(&wal_path).unwrap().len();
+
+        let mut batch = WriteBatch::new();
+        for i in 1..4 {
+            batch.put(format!("k{}", i).into_bytes(), b"v".to_vec());
+        }
+        lsm.apply_batch(batch).unwrap();
+        drop(lsm);
+
+        // a crash while writing the last entry of the batch.
+        let len = fs::metadata(&wal_path).unwrap().len();
+        let wal = fs::OpenOptions::new().write(true).open(&wal_path).unwrap();
+        wal.set_len(len - 3).unwrap();
+        drop(wal);
+
+        let lsm = Lsm::open(dir.path()).unwrap();
+        let info = lsm.recovery_info();
+        assert!(info.truncated);
+        assert_eq!(info.recovered_entries, 1);
+        assert_eq!(info.truncated_bytes, len - 3 - before);
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), before);
+
+        assert_eq!(lsm.get(b"k0").unwrap(), Some(b"v0".to_vec()));
+        for key in [b"k1", b"k2", b"k3"] {
+            assert_eq!(lsm.get(key).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_apply_batch_crc_mismatch() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let wal_path = utils::format_wal_path(dir.path(), 0);
+
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+        lsm.put(b"k0".to_vec(), b"v0".to_vec()).unwrap();
+        lsm.sync_log().unwrap();
+        let before = fs::metadata(&wal_path).unwrap().len();
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"k1".to_vec(), b"v1".to_vec())
+            .put(b"k2".to_vec(), b"v2".to_vec());
+        lsm.apply_batch(batch).unwrap();
+        drop(lsm);
+
+        // flip the last byte of the first entry of the batch, its value.
+        let mut wal = fs::read(&wal_path).unwrap();
+        let offset = before + BATCH_HEADER_SIZE + DiskEntry::entry_size(b"k1", b"v1") - 1;
+        wal[offset as usize] ^= 0xFF;
+        fs::write(&wal_path, &wal).unwrap();
+
+        let lsm = Lsm::open(dir.path()).unwrap();
+        assert_eq!(lsm.recovery_info().recovered_entries, 1);
+        assert_eq!(lsm.get(b"k0").unwrap(), Some(b"v0".to_vec()));
+        assert_eq!(lsm.get(b"k1").unwrap(), None);
+        assert_eq!(lsm.get(b"k2").unwrap(), None);
+    }
+
+    #[test]
+    fn test_stale_wal_entries() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let wal_path = utils::format_wal_path(dir.path(), 0);
+
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+        lsm.put(b"k1".to_vec(), b"old".to_vec()).unwrap();
+        lsm.put(b"k2".to_vec(), b"v2".to_vec()).unwrap();
+        lsm.sync_log().unwrap();
+        let old_wal = fs::read(&wal_path).unwrap();
+
+        lsm.put(b"k1".to_vec(), b"new".to_vec()).unwrap();
+        lsm.flush().unwrap();
+        drop(lsm);
+
+        // the WAL is left over by a crash after the flush, behind the
+        // newer version of k1.
+        fs::write(&wal_path, &old_wal).unwrap();
+
+        let lsm = Lsm::open(dir.path()).unwrap();
+        let info = lsm.recovery_info();
+        assert_eq!((info.recovered_entries, info.stale_entries), (2, 1));
+        assert_eq!(
+            lsm.dirty_bytes,
+            DiskEntry::entry_size(b"k2", b"v2"),
+            "stale entries are not dirty"
+        );
+        assert_eq!(lsm.get(b"k1").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(lsm.get(b"k2").unwrap(), Some(b"v2".to_vec()));
+    }
+
+    #[test]
+    fn test_wal_reset_crash() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let wal_path = utils::format_wal_path(dir.path(), 0);
+        let tmp_path = utils::format_wal_tmp_path(dir.path(), 0);
+
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+        lsm.put(b"k1".to_vec(), b"v1".to_vec()).unwrap();
+        lsm.put(b"k2".to_vec(), b"v2".to_vec()).unwrap();
+        lsm.sync_log().unwrap();
+        let old_wal = fs::read(&wal_path).unwrap();
+
+        lsm.flush().unwrap();
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+        assert!(!tmp_path.exists());
+        drop(lsm);
+
+        // power fails after the empty WAL is written, before its rename:
+        // the old WAL replays entries already flushed.
+        fs::write(&wal_path, &old_wal).unwrap();
+        fs::write(&tmp_path, b"").unwrap();
+
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+        assert!(!tmp_path.exists());
+        assert_eq!(lsm.recovery_info().recovered_entries, 2);
+        assert!(!lsm.recovery_info().truncated);
+        assert_eq!(lsm.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+
+        lsm.delete(b"k1").unwrap();
+        lsm.flush().unwrap();
+        drop(lsm);
+
+        let lsm = Lsm::open(dir.path()).unwrap();
+        assert_eq!(lsm.get(b"k1").unwrap(), None);
+        assert_eq!(lsm.get(b"k2").unwrap(), Some(b"v2".to_vec()));
+    }
+
+    #[test]
+    fn test_get_while_flushing() {
+        let dir = TempDir::new("lsmlib").unwrap();
+
+        // the 9th put makes dirty_bytes exceed max_log_length.
+        let mut lsm = OpenOptions::new()
+            .max_log_length(1024)
+            .open(dir.path())
+            .unwrap();
+        lsm.flush_hook = Some(|lsm| {
+            assert!(lsm.memtable.is_empty());
+            for i in 0..9u8 {
+                assert_eq!(lsm.get(&[i]).unwrap(), Some(vec![i; 100]));
+                assert!(lsm.contains(&[i]));
+            }
+            assert_eq!(lsm.list_keys().unwrap().len(), 9);
+        });
+
+        for i in 0..9u8 {
+            lsm.put(vec![i], vec![i; 100]).unwrap();
+        }
+        assert!(lsm.flushing.is_none());
+        assert!(lsm.memtable.is_empty());
+
+        for i in 0..9u8 {
+            assert_eq!(lsm.get(&[i]).unwrap(), Some(vec![i; 100]));
+        }
+    }
+
+    #[test]
+    fn test_snapshot_survives_overwrite_and_compaction() {
+        let dir = TempDir::new("lsmlib").unwrap();
+
+        let mut lsm = OpenOptions::new()
+            .max_log_length(1)
+            .merge_window(2)
+            .open(dir.path())
+            .unwrap();
+
+        for i in 0..4u8 {
+            lsm.put(vec![i], vec![i; 10]).unwrap();
+        }
+        wait_worker(&lsm);
+
+        let snapshot = lsm.snapshot();
+        assert_eq!(snapshot.seq(), 4);
+
+        for i in 0..4u8 {
+            lsm.put(vec![i], vec![i + 100; 10]).unwrap();
+        }
+        lsm.delete(&[0]).unwrap();
+        lsm.put(vec![9], vec![9; 10]).unwrap();
+
+        // let the compactor merge everything it can.
+        for _ in 0..10 {
+            wait_worker(&lsm);
+        }
+        assert!(sstable_count(&lsm) < 10);
+
+        assert_eq!(lsm.get(&[0]).unwrap(), None);
+        assert_eq!(lsm.get(&[1]).unwrap(), Some(vec![101; 10]));
+        for i in 0..4u8 {
+            assert_eq!(snapshot.get(&[i]).unwrap(), Some(vec![i; 10]));
+        }
+        assert_eq!(snapshot.get(&[9]).unwrap(), None);
+        assert_eq!(
+            snapshot.list_keys().unwrap(),
+            (0..4u8).map(|i| vec![i]).collect::<Vec<_>>()
+        );
+
+        // the compacted sstables the snapshot pins go with it.
+        let retired = || {
+            let pattern = format!("{}/*{}", dir.path().display(), config::RETIRED_FILE_SUFFIX);
+            glob::glob(&pattern).unwrap().count()
+        };
+        assert!(retired() > 0);
+        drop(snapshot);
+        assert_eq!(retired(), 0);
+
+        // sequence numbers continue after reopen.
+        let seq = lsm.seq;
+        drop(lsm);
+        let lsm = Lsm::open(dir.path()).unwrap();
+        assert_eq!(lsm.seq, seq);
+    }
+
+    #[test]
+    fn test_snapshot_iter_across_flush_and_compaction() {
+        let dir = TempDir::new("lsmlib").unwrap();
+
+        let mut lsm = OpenOptions::new()
+            .max_log_length(1)
+            .merge_window(2)
+            .open(dir.path())
+            .unwrap();
+
+        for i in 0..20u8 {
+            lsm.put(vec![i], vec![i; 10]).unwrap();
+        }
+        lsm.delete(&[3]).unwrap();
+        wait_worker(&lsm);
+
+        let snapshot = lsm.snapshot();
+        let mut iter = snapshot.iter();
+        let mut seen: Vec<_> = iter.by_ref().take(5).map(|kv| kv.unwrap()).collect();
+
+        // overwrite, delete and add keys, flush them and let the
+        // compactor merge, drop tombstones and remove old sstables.
+        for i in 0..20u8 {
+            lsm.put(vec![i], vec![i + 100; 10]).unwrap();
+        }
+        for i in 10..15u8 {
+            lsm.delete(&[i]).unwrap();
+        }
+        lsm.put(vec![50], vec![50; 10]).unwrap();
+        for _ in 0..20 {
+            wait_worker(&lsm);
+        }
+        assert!(sstable_count(&lsm) < 10);
+
+        seen.extend(iter.map(|kv| kv.unwrap()));
+        let expected: Vec<_> = (0..20u8)
+            .filter(|i| *i != 3)
+            .map(|i| (vec![i], vec![i; 10]))
+            .collect();
+        assert_eq!(seen, expected);
+
+        // a fresh iterator of the same snapshot sees the same.
+        let again: Vec<_> = snapshot.iter().map(|kv| kv.unwrap()).collect();
+        assert_eq!(again, expected);
+    }
+
+    #[test]
+    fn test_prefix_stats() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .max_log_length(200)
+            .open(dir.path())
+            .unwrap();
+
+        // entries are 34 bytes, the 6th put flushes to sstable.
+        for key in [&b"a:1"[..], b"a:2", b"a:3", b"b:1", b"a:4", b"a:5"] {
+            lsm.put(key.to_vec(), b"0123456".to_vec()).unwrap();
+        }
+        assert!(lsm.memtable.is_empty());
+
+        lsm.put(b"a:6".to_vec(), b"0123456".to_vec()).unwrap();
+        lsm.put(b"b:2".to_vec(), b"0123456".to_vec()).unwrap();
+        lsm.delete(b"a:1").unwrap();
+
+        let stats = lsm.prefix_stats(b"a:").unwrap();
+        assert_eq!(stats.keys, 5);
+        assert_eq!(stats.live_bytes, 5 * 34);
+        assert_eq!(stats.pending, 2);
+
+        assert_eq!(lsm.prefix_stats(b"b:").unwrap().keys, 2);
+        assert_eq!(lsm.prefix_stats(b"").unwrap().keys, 7);
+        assert_eq!(lsm.prefix_stats(b"c").unwrap(), PrefixStats::default());
+    }
+
+    #[derive(Default)]
+    struct SwitchGate {
+        allow: AtomicBool,
+        denied: AtomicUsize,
+    }
+
+    impl CompactionGate for SwitchGate {
+        fn allow(&self, _candidate_ids: &[u64]) -> bool {
+            let allow = self.allow.load(Ordering::SeqCst);
+            if !allow {
+                self.denied.fetch_add(1, Ordering::SeqCst);
+            }
+            allow
+        }
+    }
+
+    #[test]
+    fn test_repair_key() {
+        let dir = TempDir::new("lsmlib").unwrap();
+
+        let mut lsm = OpenOptions::new()
+            .max_log_length(1)
+            .merge_window(2)
+            .compaction_gate(Arc::new(SwitchGate::default()))
+            .open(dir.path())
+            .unwrap();
+
+        lsm.put(b"a".to_vec(), vec![1; 10]).unwrap();
+        lsm.put(b"a".to_vec(), vec![2; 10]).unwrap();
+        lsm.put(b"b".to_vec(), vec![3; 10]).unwrap();
+        wait_worker(&lsm);
+
+        // bit rot, leaving the modification time alone.
+        let corrupt = |lsm: &Lsm, key: &[u8]| {
+            let entry = *lsm.store.read().unwrap().keydir().get(key).unwrap();
+            let path = utils::format_sstable_path(&lsm.path, entry.file_id);
+            let modified = fs::metadata(&path).unwrap().modified().unwrap();
+            let mut buf = fs::read(&path).unwrap();
+            buf[(entry.offset + entry.size - 1) as usize] ^= 0xFF;
+            fs::write(&path, buf).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        };
+        corrupt(&lsm, b"a");
+        corrupt(&lsm, b"b");
+
+        assert!(matches!(
+            lsm.get(b"a"),
+            Err(LSMLibError::ChecksumMismatch { .. })
+        ));
+        assert_eq!(
+            lsm.repair_key(b"a").unwrap(),
+            RepairOutcome::RestoredFromVersion(1)
+        );
+        assert_eq!(lsm.get(b"a").unwrap(), Some(vec![1; 10]));
+        assert_eq!(lsm.repair_key(b"a").unwrap(), RepairOutcome::Intact);
+
+        assert_eq!(lsm.repair_key(b"b").unwrap(), RepairOutcome::Unrepairable);
+        assert!(lsm.get(b"b").is_err());
+
+        lsm.config.repair_writes_tombstone = true;
+        assert_eq!(lsm.repair_key(b"b").unwrap(), RepairOutcome::Tombstoned);
+        assert_eq!(lsm.get(b"b").unwrap(), None);
+    }
+
+    #[test]
+    fn test_ingest_sstable() {
+        use super::sstable::{SSTableWriter, SSTableWriterOptions};
+
+        let dir = TempDir::new("lsmlib").unwrap();
+        let (db, build) = (dir.path().join("db"), dir.path().join("build"));
+        fs::create_dir(&build).unwrap();
+
+        let mut lsm = Lsm::open(&db).unwrap();
+        lsm.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        lsm.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        lsm.flush().unwrap();
+        lsm.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+
+        let path = utils::format_sstable_path(&build, 1);
+        let mut writer = SSTableWriter::create(&path, SSTableWriterOptions::default()).unwrap();
+        writer.add(b"a", b"10", 0).unwrap();
+        writer.add_tombstone(b"b", 0).unwrap();
+        writer.add_tombstone(b"c", 0).unwrap();
+        writer.add(b"d", b"4", 0).unwrap();
+        writer.finish().unwrap();
+
+        let outcome = lsm.ingest_sstable(&path).unwrap();
+        assert_eq!((outcome.entries, outcome.tombstones), (4, 2));
+        assert!(path.exists());
+
+        let expected = [
+            (b"a", Some(b"10".to_vec())),
+            (b"b", None),
+            (b"c", None),
+            (b"d", Some(b"4".to_vec())),
+        ];
+        for (key, value) in &expected {
+            assert_eq!(lsm.get(*key).unwrap(), *value);
+        }
+
+        // ingested entries are newer than the writes before.
+        lsm.put(b"d".to_vec(), b"5".to_vec()).unwrap();
+        assert_eq!(lsm.get(b"d").unwrap(), Some(b"5".to_vec()));
+        drop(lsm);
+
+        let lsm = Lsm::open(&db).unwrap();
+        for (key, value) in &expected[..3] {
+            assert_eq!(lsm.get(*key).unwrap(), *value);
+        }
+        assert_eq!(lsm.get(b"d").unwrap(), Some(b"5".to_vec()));
+        drop(lsm);
+
+        // unsorted entries, written bypassing the writer.
+        let path = utils::format_sstable_path(&build, 2);
+        let mut sst = SSTable::new(&path, true).unwrap();
+        sst.write(b"y", b"1").unwrap();
+        sst.write(b"x", b"1").unwrap();
+        sst.sync().unwrap();
+        drop(sst);
+
+        let mut lsm = Lsm::open(&db).unwrap();
+        assert!(matches!(
+            lsm.ingest_sstable(&path),
+            Err(LSMLibError::VerificationFailed { .. })
+        ));
+        assert_eq!(lsm.get(b"y").unwrap(), None);
+        assert_eq!(lsm.get(b"a").unwrap(), Some(b"10".to_vec()));
+    }
+
+    #[test]
+    fn test_ingest_sstable_keep_id() {
+        use super::sstable::{SSTableWriter, SSTableWriterOptions};
+
+        let dir = TempDir::new("lsmlib").unwrap();
+        let (db, build) = (dir.path().join("db"), dir.path().join("build"));
+        fs::create_dir(&build).unwrap();
+
+        let build_sstable = |id: u64, value: &[u8]| {
+            let path = utils::format_sstable_path(&build, id);
+            let mut writer = SSTableWriter::create(&path, SSTableWriterOptions::default()).unwrap();
+            writer.add(b"k", value, 0).unwrap();
+            writer.finish().unwrap();
+            path
+        };
+
+        let mut lsm = OpenOptions::new()
+            .reserved_id_ranges(vec![100..200, 300..400])
+            .open(&db)
+            .unwrap();
+        lsm.put(b"k".to_vec(), b"1".to_vec()).unwrap();
+
+        let outcome = lsm
+            .ingest_sstable_keep_id(build_sstable(150, b"2"))
+            .unwrap();
+        assert_eq!(outcome.sstable_id, Some(150));
+        assert_eq!(lsm.get(b"k").unwrap(), Some(b"2".to_vec()));
+
+        lsm.put(b"k".to_vec(), b"3".to_vec()).unwrap();
+        assert_eq!(lsm.flush().unwrap().sstable_id, Some(200));
+
+        for id in [120, 201] {
+            assert!(matches!(
+                lsm.ingest_sstable_keep_id(build_sstable(id, b"4")),
+                Err(LSMLibError::SSTableIdUnavailable(_))
+            ));
+        }
+        assert_eq!(lsm.get(b"k").unwrap(), Some(b"3".to_vec()));
+
+        // reassigned otherwise.
+        let outcome = lsm.ingest_sstable(build_sstable(130, b"5")).unwrap();
+        assert_eq!(outcome.sstable_id, Some(201));
+        assert_eq!(lsm.get(b"k").unwrap(), Some(b"5".to_vec()));
+    }
+
+    #[test]
+    fn test_get_traced() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .negative_cache_entries(16)
+            .open(dir.path())
+            .unwrap();
+
+        lsm.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        lsm.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        lsm.delete(b"b").unwrap();
+        lsm.delete_range(b"c", b"d").unwrap();
+        assert_eq!(
+            lsm.get_traced(b"a").unwrap(),
+            (Some(b"1".to_vec()), ReadSource::Memtable)
+        );
+        for key in [b"b", b"c"] {
+            assert_eq!(
+                lsm.get_traced(key).unwrap(),
+                (None, ReadSource::MemtableTombstone)
+            );
+        }
+
+        let file_id = lsm.flush().unwrap().sstable_id.unwrap();
+        let sstable = ReadSource::SSTable {
+            file_id,
+            bloom_checked: false,
+        };
+        assert_eq!(
+            lsm.get_traced(b"a").unwrap(),
+            (Some(b"1".to_vec()), sstable)
+        );
+        // the flush dropped the tombstone of b, deleting nothing.
+        let not_found = ReadSource::NotFound {
+            bloom_rejected: false,
+        };
+        for key in [b"b", b"z"] {
+            assert_eq!(lsm.get_traced(key).unwrap(), (None, not_found));
+        }
+        assert_eq!(lsm.get(b"z").unwrap(), None);
+        assert_eq!(lsm.get_traced(b"z").unwrap(), (None, ReadSource::ReadCache));
+
+        let stats = lsm.io_stats();
+        assert_eq!(
+            (
+                stats.reads_from_memtable,
+                stats.reads_from_memtable_tombstone,
+                stats.reads_from_sstable,
+                stats.reads_not_found,
+                stats.reads_from_cache
+            ),
+            (1, 2, 1, 2, 2)
+        );
+        assert_eq!(stats.memtable_hit_rate(), 3.0 / 8.0);
+    }
+
+    #[test]
+    fn test_scan_by_value_prefix() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let open = || {
+            OpenOptions::new()
+                .value_prefix_index_bytes(Some(2))
+                .compaction_gate(Arc::new(SwitchGate::default()))
+                .open(dir.path())
+                .unwrap()
+        };
+        let scan_stats = |lsm: &Lsm| {
+            let stats = lsm.io_stats();
+            (stats.value_scan_reads, stats.value_scan_reads_avoided)
+        };
+
+        let mut lsm = open();
+        for i in 0..6u8 {
+            let value = if i % 2 == 0 { "red" } else { "blue" };
+            lsm.put(vec![b'k', b'0' + i], value.as_bytes().to_vec())
+                .unwrap();
+        }
+        lsm.put(b"s".to_vec(), b"r".to_vec()).unwrap();
+        lsm.flush().unwrap();
+        lsm.put(b"k1".to_vec(), b"reddish".to_vec()).unwrap();
+        lsm.delete(b"k2").unwrap();
+
+        // only the values starting with "re" are read, "r" is all of s.
+        let keys = |found: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<Vec<u8>> {
+            found.into_iter().map(|(k, _)| k).collect()
+        };
+        let found = lsm.scan_by_value_prefix(b"red").unwrap();
+        assert_eq!(keys(found), [b"k0", b"k1", b"k4"]);
+        assert_eq!(scan_stats(&lsm), (2, 3));
+        lsm.flush().unwrap();
+        drop(lsm);
+
+        // hints carry no values, the first scan indexes them.
+        let lsm = open();
+        let found = lsm.scan_by_value_prefix(b"bl").unwrap();
+        assert_eq!(
+            found,
+            [
+                (b"k3".to_vec(), b"blue".to_vec()),
+                (b"k5".to_vec(), b"blue".to_vec())
+            ]
+        );
+        assert_eq!(scan_stats(&lsm), (6, 0));
+        lsm.scan_by_value_prefix(b"bl").unwrap();
+        assert_eq!(scan_stats(&lsm), (8, 4));
+
+        lsm.compact().unwrap();
+        assert_eq!(sstable_count(&lsm), 1);
+        assert_eq!(
+            keys(lsm.scan_by_value_prefix(b"r").unwrap()),
+            [&b"k0"[..], b"k1", b"k4", b"s"]
+        );
+        assert_eq!(scan_stats(&lsm), (12, 6));
+    }
+
+    #[test]
+    fn test_range() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .compaction_gate(Arc::new(SwitchGate::default()))
+            .open(dir.path())
+            .unwrap();
+
+        for key in [b"a", b"b", b"c", b"d", b"e"] {
+            lsm.put(key.to_vec(), b"old".to_vec()).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.put(b"b".to_vec(), b"new".to_vec()).unwrap();
+        lsm.put(b"bb".to_vec(), b"new".to_vec()).unwrap();
+        lsm.delete(b"c").unwrap();
+        lsm.delete_range(b"e", b"f").unwrap();
+
+        let all: Vec<_> = lsm.range(..).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(
+            all,
+            [
+                (b"a".to_vec(), b"old".to_vec()),
+                (b"b".to_vec(), b"new".to_vec()),
+                (b"bb".to_vec(), b"new".to_vec()),
+                (b"d".to_vec(), b"old".to_vec()),
+            ]
+        );
+
+        let keys = |iter: RangeIter<'_>| -> Vec<Vec<u8>> { iter.map(|r| r.unwrap().0).collect() };
+        let (b, d) = (b"b".to_vec(), b"d".to_vec());
+        assert_eq!(
+            keys(lsm.range(b.clone()..=d.clone()).unwrap()),
+            [&b"b"[..], b"bb", b"d"]
+        );
+        assert_eq!(keys(lsm.range(b.clone()..d).unwrap()), [&b"b"[..], b"bb"]);
+        assert_eq!(keys(lsm.range(..b).unwrap()), [b"a"]);
+
+        lsm.flush().unwrap();
+        let flushed: Vec<_> = lsm.range(..).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(flushed, all);
+    }
+
+    #[test]
+    fn test_ordered_keydir() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let open = || {
+            OpenOptions::new()
+                .ordered_keydir(true)
+                .compaction_gate(Arc::new(SwitchGate::default()))
+                .open(dir.path())
+                .unwrap()
+        };
+
+        let mut lsm = open();
+        assert!(lsm.store.read().unwrap().keydir().is_ordered());
+        for key in [b"d", b"b", b"e", b"a", b"c"] {
+            lsm.put(key.to_vec(), b"old".to_vec()).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.put(b"bb".to_vec(), b"new".to_vec()).unwrap();
+        lsm.put(b"ba".to_vec(), b"new".to_vec()).unwrap();
+        lsm.delete(b"c").unwrap();
+
+        let keys = |iter: RangeIter<'_>| -> Vec<Vec<u8>> { iter.map(|r| r.unwrap().0).collect() };
+        let expected = [&b"a"[..], b"b", b"ba", b"bb", b"d", b"e"];
+        assert_eq!(keys(lsm.iter().unwrap()), expected);
+        assert_eq!(
+            keys(lsm.scan_prefix(b"b").unwrap()),
+            [&b"b"[..], b"ba", b"bb"]
+        );
+        let (b, d) = (b"b".to_vec(), b"d".to_vec());
+        assert_eq!(
+            keys(lsm.range(b.clone()..d.clone()).unwrap()),
+            [&b"b"[..], b"ba", b"bb"]
+        );
+        assert_eq!(lsm.prefix_stats(b"b").unwrap().keys, 3);
+        drop(lsm);
+
+        // the keydir is rebuilt in order from the sstables.
+        let lsm = open();
+        assert_eq!(keys(lsm.iter().unwrap()), expected);
+        let (last, _) = lsm.iter().unwrap().next_back().unwrap().unwrap();
+        assert_eq!(last, b"e");
+    }
+
+    #[test]
+    fn test_concurrent_reads() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .compaction_gate(Arc::new(SwitchGate::default()))
+            .open(dir.path())
+            .unwrap();
+
+        for i in 0..256u32 {
+            lsm.put(i.to_be_bytes().to_vec(), i.to_le_bytes().repeat(4))
+                .unwrap();
+            if i % 64 == 63 {
+                lsm.flush().unwrap();
+            }
+        }
+        lsm.put(b"pending".to_vec(), b"memtable".to_vec()).unwrap();
+
+        // readers share the store lock, a compaction merges meanwhile.
+        let lsm = &lsm;
+        std::thread::scope(|s| {
+            for t in 0..8u32 {
+                s.spawn(move || {
+                    for round in 0..4 {
+                        for i in (0..256u32).map(|i| (i + t * 32 + round) % 256) {
+                            let value = lsm.get(&i.to_be_bytes()).unwrap();
+                            assert_eq!(value, Some(i.to_le_bytes().repeat(4)));
+                        }
+                        assert_eq!(lsm.get(b"pending").unwrap(), Some(b"memtable".to_vec()));
+                    }
+                });
+            }
+            lsm.compact().unwrap();
+        });
+        assert_eq!(sstable_count(lsm), 1);
+    }
+
+    #[test]
+    fn test_read_only_open_while_writing() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+        for i in 0..64u32 {
+            lsm.put(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec())
+                .unwrap();
+        }
+        lsm.flush().unwrap();
+
+        let check = |reader: &Lsm| {
+            for i in 0..64u32 {
+                let value = reader.get(&i.to_be_bytes()).unwrap();
+                assert_eq!(value, Some(i.to_le_bytes().to_vec()));
+            }
+        };
+        let done = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                // rewrite the same values, flushing and compacting.
+                for round in 0..40u32 {
+                    for i in (0..64u32).filter(|i| i % 4 == round % 4) {
+                        lsm.put(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec())
+                            .unwrap();
+                    }
+                    lsm.flush().unwrap();
+                    if round % 8 == 7 {
+                        lsm.compact().unwrap();
+                    }
+                }
+                done.store(true, Ordering::SeqCst);
+            });
+
+            while !done.load(Ordering::SeqCst) {
+                // giving up is fine, a wrong value is not.
+                match OpenOptions::new().read_only(true).open(dir.path()) {
+                    Ok(reader) => check(&reader),
+                    Err(LSMLibError::ConcurrentCompaction { attempts }) => {
+                        assert_eq!(attempts, config::READ_ONLY_OPEN_ATTEMPTS);
+                    }
+                    Err(e) => panic!("read only open failed: {}", e),
+                }
+            }
+        });
+        check(&OpenOptions::new().read_only(true).open(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn test_delete_then_contains() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+
+        lsm.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        lsm.flush().unwrap();
+        lsm.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        lsm.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        lsm.delete(b"a").unwrap();
+        lsm.delete(b"b").unwrap();
+
+        // deleted in the memtable, before any flush.
+        for key in [b"a", b"b"] {
+            assert!(!lsm.contains(key));
+            assert_eq!(lsm.get(key).unwrap(), None);
+        }
+        assert_eq!(lsm.list_keys().unwrap(), [b"c".to_vec()]);
+
+        // deleting again writes nothing.
+        let seq = lsm.seq;
+        lsm.delete(b"a").unwrap();
+        assert_eq!(lsm.seq, seq);
+
+        lsm.flush().unwrap();
+        drop(lsm);
+        let lsm = Lsm::open(dir.path()).unwrap();
+        assert!(!lsm.contains(b"a") && !lsm.contains(b"b"));
+        assert_eq!(lsm.list_keys().unwrap(), [b"c".to_vec()]);
+    }
+
+    #[test]
+    fn test_iter_across_compaction() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .compaction_gate(Arc::new(SwitchGate::default()))
+            .open(dir.path())
+            .unwrap();
+
+        for i in 0..4u8 {
+            lsm.put(vec![i], vec![i; 16]).unwrap();
+            lsm.put(vec![i, 0], vec![i; 16]).unwrap();
+            lsm.flush().unwrap();
+        }
+        lsm.delete(&[2]).unwrap();
+
+        let mut iter = lsm.iter().unwrap();
+        assert_eq!(iter.next().unwrap().unwrap(), (vec![0], vec![0; 16]));
+
+        // the sstables the keys were collected from are merged away.
+        lsm.compact().unwrap();
+        assert_eq!(sstable_count(&lsm), 1);
+
+        let rest: Vec<Vec<u8>> = iter.map(|r| r.unwrap().0).collect();
+        assert_eq!(
+            rest,
+            [
+                vec![0, 0],
+                vec![1],
+                vec![1, 0],
+                vec![2, 0],
+                vec![3],
+                vec![3, 0]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sstable_lineage() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .compaction_gate(Arc::new(SwitchGate::default()))
+            .open(dir.path())
+            .unwrap();
+
+        for i in 0..3u8 {
+            lsm.put(vec![i], vec![i]).unwrap();
+            lsm.flush().unwrap();
+        }
+        let flushed = lsm.sstable_lineage(2).unwrap();
+        assert_eq!(flushed.origin, SSTableOrigin::Flush);
+        assert!(flushed.created_at > 0 && flushed.inputs.is_empty());
+
+        lsm.compact().unwrap();
+        let compacted = lsm.sstable_lineage(3).unwrap();
+        assert_eq!(compacted.origin, SSTableOrigin::Compaction);
+        let inputs: Vec<u64> = compacted.inputs.iter().map(|(id, _)| *id).collect();
+        assert_eq!(inputs, [1, 2, 3]);
+        assert_eq!(compacted.inputs[1].1, flushed.created_at);
+        assert!(lsm.sstable_lineage(2).is_err());
+        assert!(!utils::format_lineage_path(&lsm.path, 2).exists());
+        drop(lsm);
+
+        let lsm = Lsm::open(dir.path()).unwrap();
+        assert_eq!(lsm.sstable_lineage(3).unwrap(), compacted);
+        drop(lsm);
+
+        // unknown for sstables written without lineage.
+        fs::remove_file(utils::format_lineage_path(dir.path(), 3)).unwrap();
+        let mut lsm = OpenOptions::new()
+            .compaction_gate(Arc::new(SwitchGate::default()))
+            .open(dir.path())
+            .unwrap();
+        assert_eq!(
+            lsm.sstable_lineage(3).unwrap().origin,
+            SSTableOrigin::Unknown
+        );
+
+        // nor does a corrupt one hold compaction back.
+        lsm.put(vec![3], vec![3]).unwrap();
+        lsm.flush().unwrap();
+        fs::write(utils::format_lineage_path(dir.path(), 4), "garbage").unwrap();
+        lsm.compact().unwrap();
+        assert_eq!(lsm.sstable_lineage(4).unwrap().inputs, [(3, 0), (4, 0)]);
+        assert_eq!(lsm.get(&[3]).unwrap(), Some(vec![3]));
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .compaction_gate(Arc::new(SwitchGate::default()))
+            .open(dir.path())
+            .unwrap();
+
+        for key in [
+            "user:1:name",
+            "user:1:mail",
+            "user:2:name",
+            "users",
+            "user:3:name",
+            "admin:1",
+        ] {
+            lsm.put(key.as_bytes().to_vec(), key.as_bytes().to_vec())
+                .unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.put(b"user:4:name".to_vec(), b"4".to_vec()).unwrap();
+        lsm.put(b"user:1:name".to_vec(), b"1".to_vec()).unwrap();
+        lsm.delete(b"user:3:name").unwrap();
+
+        let keys = |prefix: &str| -> Vec<String> {
+            lsm.scan_prefix(prefix.as_bytes())
+                .unwrap()
+                .map(|r| String::from_utf8(r.unwrap().0).unwrap())
+                .collect()
+        };
+        assert_eq!(keys("user:4:"), ["user:4:name"]);
+        assert_eq!(keys("user:2:"), ["user:2:name"]);
+        assert!(keys("user:3:").is_empty());
+        assert_eq!(
+            keys("user:"),
+            ["user:1:mail", "user:1:name", "user:2:name", "user:4:name"]
+        );
+        let values: Vec<_> = lsm
+            .scan_prefix(b"user:1:n")
+            .unwrap()
+            .map(|r| r.unwrap().1)
+            .collect();
+        assert_eq!(values, [b"1".to_vec()]);
+
+        // no upper bound past an all 0xFF prefix, none for the empty one.
+        lsm.put(vec![0xFF, 0xFF], b"ff".to_vec()).unwrap();
+        lsm.put(vec![0xFF, 0xFF, 0], b"ff".to_vec()).unwrap();
+        let count = |prefix: &[u8]| lsm.scan_prefix(prefix).unwrap().count();
+        assert_eq!(count(&[0xFF, 0xFF]), 2);
+        assert_eq!(count(&[0xFF]), 2);
+        assert_eq!(count(b""), 8);
+        assert_eq!(count(b"admin:"), 1);
+    }
+
+    #[test]
+    fn test_get_many() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .compaction_gate(Arc::new(SwitchGate::default()))
+            .open(dir.path())
+            .unwrap();
+
+        for i in 0..4 {
+            lsm.put(format!("a{}", i).into_bytes(), b"old".to_vec())
+                .unwrap();
+            lsm.flush().unwrap();
+        }
+        lsm.put(b"a1".to_vec(), b"new".to_vec()).unwrap();
+        lsm.delete(b"a2").unwrap();
+
+        let values = lsm
+            .get_many(&[b"a3", b"a2", b"a1", b"b", b"a0", b"a3"])
+            .unwrap();
+        let old = Some(b"old".to_vec());
+        assert_eq!(
+            values,
+            [
+                old.clone(),
+                None,
+                Some(b"new".to_vec()),
+                None,
+                old.clone(),
+                old
+            ]
+        );
+        assert!(lsm.get_many(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        // a counter, deleted once back to zero.
+        let counter: MergeOperatorFn = Arc::new(|_, existing, operand| {
+            let value = |bytes: &[u8]| i64::from_le_bytes(bytes.try_into().unwrap());
+            let sum = existing.map_or(0, value) + value(operand);
+            (sum != 0).then(|| sum.to_le_bytes().to_vec())
+        });
+        let open = || {
+            OpenOptions::new()
+                .merge_operator(Arc::clone(&counter))
+                .compaction_gate(Arc::new(SwitchGate::default()))
+                .open(dir.path())
+                .unwrap()
+        };
+        let count = |lsm: &Lsm| {
+            lsm.get(b"n")
+                .unwrap()
+                .map(|v| i64::from_le_bytes(v.try_into().unwrap()))
+        };
+
+        let mut lsm = open();
+        lsm.merge(b"n".to_vec(), &2i64.to_le_bytes()).unwrap();
+        lsm.flush().unwrap();
+        lsm.merge(b"n".to_vec(), &3i64.to_le_bytes()).unwrap();
+        lsm.merge(b"n".to_vec(), &4i64.to_le_bytes()).unwrap();
+        assert_eq!(count(&lsm), Some(9));
+        // operands are kept unresolved until flushed.
+        assert!(lsm.memtable[b"n".as_slice()].is_merge());
+        drop(lsm);
+
+        // and recovered as such from the WAL.
+        let mut lsm = open();
+        assert!(lsm.memtable[b"n".as_slice()].is_merge());
+        assert_eq!(count(&lsm), Some(9));
+
+        // operands a flush settled before a crash are not applied twice.
+        lsm.merge(b"n".to_vec(), &1i64.to_le_bytes()).unwrap();
+        lsm.sync_log().unwrap();
+        let wal = utils::format_wal_path(dir.path(), 0);
+        let unflushed = fs::read(&wal).unwrap();
+        lsm.flush().unwrap();
+        drop(lsm);
+        fs::write(&wal, unflushed).unwrap();
+        let lsm = open();
+        assert_eq!(lsm.recovery_info().recovered_entries, 3);
+        assert!(lsm.memtable.is_empty());
+        assert_eq!(count(&lsm), Some(10));
+
+        // compaction collapses the flushed operands into their value.
+        let merged = lsm.compact().unwrap().output.unwrap().0;
+        let mut sst = SSTable::new(utils::format_sstable_path(dir.path(), merged), false).unwrap();
+        let entries: Vec<DiskEntry> = sst.iter().map(Result::unwrap).collect();
+        assert_eq!(entries.len(), 1);
+        assert!(!entries[0].is_merge());
+        assert_eq!(count(&lsm), Some(10));
+        drop(lsm);
+
+        // the operator deleting the key deletes it.
+        let mut lsm = open();
+        lsm.merge(b"n".to_vec(), &(-10i64).to_le_bytes()).unwrap();
+        assert_eq!(count(&lsm), None);
+        assert!(!lsm.contains(b"n"));
+        lsm.flush().unwrap();
+        assert!(!lsm.contains(b"n"));
+        assert!(lsm.list_keys().unwrap().is_empty());
+        assert_eq!(lsm.snapshot().get(b"n").unwrap(), None);
+        drop(lsm);
+
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+        assert!(matches!(
+            lsm.merge(b"n".to_vec(), &1i64.to_le_bytes()),
+            Err(LSMLibError::NoMergeOperator)
+        ));
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+
+        // absent, then only in an sstable.
+        assert_eq!(
+            lsm.compare_and_swap(b"k", None, Some(b"v1")).unwrap(),
+            Ok(())
+        );
+        lsm.flush().unwrap();
+        assert_eq!(
+            lsm.compare_and_swap(b"k", None, Some(b"v2")).unwrap(),
+            Err(CasError {
+                actual: Some(b"v1".to_vec())
+            })
+        );
+        assert_eq!(
+            lsm.compare_and_swap(b"k", Some(b"v1"), Some(b"v2"))
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(lsm.get(b"k").unwrap(), Some(b"v2".to_vec()));
+
+        // a conditional delete, then the memtable tombstone is absent.
+        assert_eq!(
+            lsm.compare_and_swap(b"k", Some(b"v2"), None).unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            lsm.compare_and_swap(b"k", Some(b"v2"), Some(b"v3"))
+                .unwrap(),
+            Err(CasError { actual: None })
+        );
+        assert_eq!(
+            lsm.compare_and_swap(b"k", None, Some(b"v3")).unwrap(),
+            Ok(())
+        );
+        drop(lsm);
+
+        let lsm = Lsm::open(dir.path()).unwrap();
+        assert_eq!(lsm.get(b"k").unwrap(), Some(b"v3".to_vec()));
+    }
+
+    #[test]
+    fn test_flush_partitions() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let open = || {
+            OpenOptions::new()
+                .flush_partitions(4)
+                .compaction_gate(Arc::new(SwitchGate::default()))
+                .open(dir.path())
+                .unwrap()
+        };
+        let mut lsm = open();
+
+        for i in 0..100u64 {
+            lsm.put(i.to_be_bytes().to_vec(), vec![0; 8]).unwrap();
+        }
+        // written to the first sstable only.
+        lsm.delete_range(&200u64.to_be_bytes(), &300u64.to_be_bytes())
+            .unwrap();
+        let outcome = lsm.flush().unwrap();
+        assert_eq!((outcome.sstables, outcome.entries), (4, 100));
+        assert_eq!(outcome.range_tombstones, 1);
+
+        // runs of 25 consecutive keys of equal bytes, in id order.
+        let ids: Vec<u64> = lsm
+            .store
+            .read()
+            .unwrap()
+            .list_sstables()
+            .into_keys()
+            .collect();
+        assert_eq!(outcome.sstable_id, ids.last().copied());
+        let mut next = 0u64;
+        for id in &ids {
+            let items =
+                sstable::read_sstable(&utils::format_sstable_path(dir.path(), *id)).unwrap();
+            let keys: Vec<_> = items.keys().filter(|k| !k.is_empty()).collect();
+            assert_eq!(keys.len(), 25);
+            for key in keys {
+                assert_eq!(key, &next.to_be_bytes().to_vec());
+                next += 1;
+            }
+        }
+        assert_eq!(next, 100);
+        let range_tombstones = lsm.store.read().unwrap().range_tombstones().clone();
+        assert_eq!(range_tombstones.keys().collect::<Vec<_>>(), [&ids[0]]);
+        drop(lsm);
+
+        let lsm = open();
+        assert_eq!(lsm.list_keys().unwrap().len(), 100);
+        assert_eq!(lsm.get(&99u64.to_be_bytes()).unwrap(), Some(vec![0; 8]));
+
+        // one large entry takes a run of its own.
+        let memtable: Memtable = [(b"a", 1), (b"b", 100), (b"c", 1), (b"d", 1)]
+            .into_iter()
+            .map(|(k, len)| (k.to_vec(), DiskEntry::new(k.to_vec(), vec![0; len])))
+            .collect();
+        assert_eq!(partition_lengths(&memtable, 2), [2, 2]);
+        // a run takes entries until it reaches its share.
+        assert_eq!(partition_lengths(&memtable, 8), [2, 1, 1]);
+        assert_eq!(partition_lengths(&Memtable::new(), 4), [0]);
+    }
+
+    #[test]
+    fn test_put_with_ttl() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let now = Arc::new(AtomicU32::new(1_000_000));
+        let open = || {
+            let now = Arc::clone(&now);
+            OpenOptions::new()
+                .clock(Arc::new(move || now.load(Ordering::Relaxed)))
+                .tombstone_grace(Duration::ZERO)
+                .compaction_gate(Arc::new(SwitchGate::default()))
+                .open(dir.path())
+                .unwrap()
+        };
+
+        let mut lsm = open();
+        lsm.put_with_ttl(b"a".to_vec(), b"1".to_vec(), Duration::from_secs(10))
+            .unwrap();
+        lsm.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        lsm.put_with_ttl(b"c".to_vec(), b"3".to_vec(), Duration::from_millis(19_500))
+            .unwrap();
+        assert_eq!(
+            migrate::detect_format_version(dir.path()).unwrap(),
+            Some(EXPIRY_FORMAT_VERSION)
+        );
+
+        // expiries survive the WAL replay.
+        drop(lsm);
+        let mut lsm = open();
+        assert_eq!(lsm.memtable[b"a".as_slice()].expiry(), 1_000_010);
+        assert_eq!(lsm.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        now.store(1_000_010, Ordering::Relaxed);
+        assert_eq!(lsm.get(b"a").unwrap(), None);
+        assert!(!lsm.contains(b"a"));
+        assert_eq!(lsm.list_keys().unwrap(), vec![b"b".to_vec(), b"c".to_vec()]);
+
+        // expiries survive the flush and the keydir rebuilt from hints.
+        lsm.flush().unwrap();
+        drop(lsm);
+        let mut lsm = open();
+        assert_eq!(
+            lsm.get_many(&[b"a", b"c"]).unwrap(),
+            [None, Some(b"3".to_vec())]
+        );
+        assert!(!lsm.contains(b"a"));
+
+        // ttl rounded up to 20s.
+        now.store(1_000_019, Ordering::Relaxed);
+        assert!(lsm.contains(b"c"));
+        now.store(1_000_020, Ordering::Relaxed);
+        assert!(!lsm.contains(b"c"));
+
+        lsm.put(b"d".to_vec(), b"4".to_vec()).unwrap();
+        lsm.flush().unwrap();
+        let outcome = lsm.compact().unwrap();
+        assert_eq!((outcome.expired, outcome.tombstones_dropped), (2, 2));
+        assert_eq!(lsm.list_keys().unwrap(), vec![b"b".to_vec(), b"d".to_vec()]);
+
+        // a plain put makes the key live for good.
+        lsm.put(b"a".to_vec(), b"5".to_vec()).unwrap();
+        now.store(u32::MAX, Ordering::Relaxed);
+        assert_eq!(lsm.get(b"a").unwrap(), Some(b"5".to_vec()));
+        lsm.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn test_touch() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let now = Arc::new(AtomicU32::new(1_000_000));
+        let open = || {
+            let now = Arc::clone(&now);
+            OpenOptions::new()
+                .clock(Arc::new(move || now.load(Ordering::Relaxed)))
+                .tombstone_grace(Duration::ZERO)
+                .compaction_gate(Arc::new(SwitchGate::default()))
+                .open(dir.path())
+                .unwrap()
+        };
+        let ttl = Duration::from_secs;
+
+        let mut lsm = open();
+        lsm.put_with_ttl(b"a".to_vec(), b"1".to_vec(), ttl(10))
+            .unwrap();
+        lsm.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        lsm.flush().unwrap();
+        lsm.put_with_ttl(b"c".to_vec(), b"3".to_vec(), ttl(10))
+            .unwrap();
+
+        // a flushed key gets a touch, an unflushed put the new expiry.
+        assert!(lsm.touch(b"a", Some(ttl(100))).unwrap());
+        assert!(lsm.touch(b"c", Some(ttl(100))).unwrap());
+        assert!(!lsm.touch(b"x", None).unwrap());
+        assert!(lsm.memtable[b"a".as_slice()].is_touch());
+        assert_eq!(lsm.memtable[b"c".as_slice()].expiry(), 1_000_100);
+        assert_eq!(lsm.memtable_bytes, memtable_bytes(&lsm.memtable));
+        assert_eq!(
+            migrate::detect_format_version(dir.path()).unwrap(),
+            Some(TOUCH_FORMAT_VERSION)
+        );
+
+        // compaction folds flushed touches, and takes unflushed ones
+        // from the keydir.
+        lsm.put(b"d".to_vec(), b"4".to_vec()).unwrap();
+        lsm.flush().unwrap();
+        assert!(lsm.touch(b"d", Some(ttl(30))).unwrap());
+        now.store(1_000_020, Ordering::Relaxed);
+        let merged = lsm.compact().unwrap().output.unwrap().0;
+        let mut sst = SSTable::new(utils::format_sstable_path(dir.path(), merged), false).unwrap();
+        let expiries: Vec<(Vec<u8>, u32)> = sst
+            .iter()
+            .map(|e| e.unwrap())
+            .map(|e| (e.key.clone(), e.expiry()))
+            .collect();
+        assert_eq!(
+            expiries,
+            [
+                (b"a".to_vec(), 1_000_100),
+                (b"b".to_vec(), 0),
+                (b"c".to_vec(), 1_000_100),
+                (b"d".to_vec(), 1_000_030)
+            ]
+        );
+        assert_eq!(lsm.get(b"a").unwrap(), Some(b"1".to_vec()));
+        now.store(1_000_050, Ordering::Relaxed);
+        assert_eq!(lsm.get(b"d").unwrap(), None);
+
+        // a snapshot keeps the expiry it saw.
+        let snapshot = lsm.snapshot();
+        assert!(lsm.touch(b"b", Some(ttl(10))).unwrap());
+        now.store(1_000_060, Ordering::Relaxed);
+        assert_eq!(lsm.get(b"b").unwrap(), None);
+        assert_eq!(snapshot.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert!(!lsm.touch(b"b", None).unwrap());
+        drop(snapshot);
+
+        // touches survive the WAL replay.
+        drop(lsm);
+        let mut lsm = open();
+        assert_eq!(lsm.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(lsm.get(b"b").unwrap(), None);
+        assert!(lsm.touch(b"a", None).unwrap());
+
+        // and the flush, the keydir rebuilt from hints and the sstables.
+        let flushed = lsm.flush().unwrap().sstable_id.unwrap();
+        drop(lsm);
+        fs::remove_file(utils::format_hint_path(dir.path(), flushed)).unwrap();
+        let lsm = open();
+        now.store(1_000_200, Ordering::Relaxed);
+        assert_eq!(lsm.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(lsm.list_keys().unwrap(), vec![b"a".to_vec()]);
+        lsm.check_invariants().unwrap();
+
+        // compaction folds the touches into the values.
+        let outcome = lsm.compact().unwrap();
+        assert_eq!(outcome.expired, 3);
+        let store = lsm.store.read().unwrap();
+        for id in store.list_sstables().keys() {
+            let mut sst = SSTable::new(utils::format_sstable_path(dir.path(), *id), false).unwrap();
+            assert!(sst.iter().all(|e| !e.unwrap().is_touch()));
+        }
+        drop(store);
+        assert_eq!(lsm.list_keys().unwrap(), vec![b"a".to_vec()]);
+        lsm.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn test_update_and_fetch() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+        let incr = |old: Option<&[u8]>| {
+            let n = old.map_or(0, |v| u64::from_le_bytes(v.try_into().unwrap()));
+            Some((n + 1).to_le_bytes().to_vec())
+        };
+
+        assert_eq!(
+            lsm.update_and_fetch(b"n", incr).unwrap(),
+            Some(1u64.to_le_bytes().to_vec())
+        );
+        lsm.flush().unwrap();
+        assert_eq!(
+            lsm.fetch_and_update(b"n", incr).unwrap(),
+            Some(1u64.to_le_bytes().to_vec())
+        );
+        assert_eq!(lsm.get(b"n").unwrap(), Some(2u64.to_le_bytes().to_vec()));
+
+        // `None` deletes, and deleting an absent key writes nothing.
+        assert_eq!(
+            lsm.fetch_and_update(b"n", |_| None).unwrap(),
+            Some(2u64.to_le_bytes().to_vec())
+        );
+        assert_eq!(lsm.get(b"n").unwrap(), None);
+        let seq = lsm.seq;
+        assert_eq!(lsm.update_and_fetch(b"m", |_| None).unwrap(), None);
+        assert_eq!(lsm.seq, seq);
+
+        assert!(matches!(
+            lsm.update_and_fetch(b"", incr),
+            Err(LSMLibError::EmptyKey)
+        ));
+    }
+
+    #[test]
+    fn test_partial_open() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .compaction_gate(Arc::new(SwitchGate::default()))
+            .bloom_bits_per_key(Some(10))
+            .open(dir.path())
+            .unwrap();
+        for i in 0..4u8 {
+            lsm.put(vec![b'k', i], vec![i]).unwrap();
+            lsm.put(b"o".to_vec(), vec![i]).unwrap();
+            lsm.flush().unwrap();
+        }
+        lsm.delete_range(&[b'k', 1], &[b'k', 3]).unwrap();
+        lsm.flush().unwrap();
+        lsm.put(b"new".to_vec(), b"v".to_vec()).unwrap();
+        lsm.flush().unwrap();
+        assert_eq!(sstable_count(&lsm), 6);
+        drop(lsm);
+
+        let open = |policy| {
+            OpenOptions::new()
+                .compaction_gate(Arc::new(SwitchGate::default()))
+                .partial_open_sstables(Some(1))
+                .partial_open_read_policy(policy)
+                .open(dir.path())
+                .unwrap()
+        };
+        let expected = vec![
+            (vec![b'k', 0], vec![0]),
+            (vec![b'k', 3], vec![3]),
+            (b"new".to_vec(), b"v".to_vec()),
+            (b"o".to_vec(), vec![3]),
+        ];
+
+        // misses wait for the older sstables.
+        let lsm = open(PartialOpenReadPolicy::Wait);
+        assert_eq!(lsm.get(&[b'k', 0]).unwrap(), Some(vec![0]));
+        assert_eq!(lsm.get(&[b'k', 1]).unwrap(), None);
+        assert_eq!(lsm.index_progress(), (6, 6));
+        assert_eq!(
+            lsm.iter().unwrap().collect::<Result<Vec<_>>>().unwrap(),
+            expected
+        );
+        drop(lsm);
+
+        // or fail until indexed.
+        let mut lsm = open(PartialOpenReadPolicy::Fail);
+        assert_eq!(lsm.get(b"new").unwrap(), Some(b"v".to_vec()));
+        // unless the bloom filters settle the miss.
+        assert_eq!(lsm.get(b"absent").unwrap(), None);
+        match lsm.get(b"o") {
+            Ok(value) => assert_eq!(value, Some(vec![3])),
+            Err(LSMLibError::IndexingInProgress { indexed, total }) => {
+                assert!(indexed < total)
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+        assert!(lsm.contains(&[b'k', 3]));
+        assert_eq!(lsm.index_progress(), (6, 6));
+        assert_eq!(lsm.get(b"o").unwrap(), Some(vec![3]));
+        assert_eq!(lsm.list_keys().unwrap().len(), 4);
+        assert!(lsm.worker_info().iter().any(|w| w.kind == "indexor"));
+
+        // writes made while indexing stay newest.
+        lsm.put(b"o".to_vec(), b"x".to_vec()).unwrap();
+        lsm.compact().unwrap();
+        drop(lsm);
+        let lsm = open(PartialOpenReadPolicy::Wait);
+        assert_eq!(lsm.get(b"o").unwrap(), Some(b"x".to_vec()));
+        assert_eq!(lsm.get(&[b'k', 2]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_sstable_compression() {
+        let open = |dir: &Path, level: u8| {
+            OpenOptions::new()
+                .max_log_length(u64::MAX)
+                .compaction_gate(Arc::new(SwitchGate::default()))
+                .zstd_sstable_compression_level(level)
+                .open(dir)
+                .unwrap()
+        };
+        let data_size = |dir: &Path| {
+            fs::read_dir(dir)
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .filter(|p| p.to_string_lossy().ends_with(config::DATA_FILE_SUFFIX))
+                .map(|p| fs::metadata(p).unwrap().len())
+                .sum::<u64>()
+        };
+
+        let plain = TempDir::new("lsmlib").unwrap();
+        let packed = TempDir::new("lsmlib").unwrap();
+        for (dir, level) in [(plain.path(), 0), (packed.path(), 3)] {
+            let mut lsm = open(dir, level);
+            for i in 0..20u8 {
+                lsm.put(vec![i], vec![b'a' + i; 4000]).unwrap();
+            }
+            // too small to gain anything, kept as is.
+            lsm.put(b"small".to_vec(), b"v".to_vec()).unwrap();
+            lsm.flush().unwrap();
+        }
+        assert!(data_size(packed.path()) * 10 < data_size(plain.path()));
+        assert_eq!(
+            migrate::detect_format_version(packed.path()).unwrap(),
+            Some(COMPRESSION_FORMAT_VERSION)
+        );
+        assert_ne!(
+            migrate::detect_format_version(plain.path()).unwrap(),
+            Some(COMPRESSION_FORMAT_VERSION)
+        );
+
+        let check = |lsm: &Lsm| {
+            for i in 0..20u8 {
+                assert_eq!(lsm.get(&[i]).unwrap(), Some(vec![b'a' + i; 4000]));
+            }
+            assert_eq!(lsm.get(b"small").unwrap(), Some(b"v".to_vec()));
+        };
+
+        // read back from the keydir rebuilt from hints, then from data.
+        let mut lsm = open(packed.path(), 3);
+        check(&lsm);
+        drop(lsm);
+        for entry in fs::read_dir(packed.path()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.to_string_lossy().ends_with(config::HINT_FILE_SUFFIX) {
+                fs::remove_file(path).unwrap();
+            }
+        }
+        lsm = open(packed.path(), 0);
+        check(&lsm);
+
+        // compaction keeps compressed values as they are.
+        lsm.put(vec![0], vec![b'z'; 4000]).unwrap();
+        lsm.flush().unwrap();
+        lsm.compact().unwrap();
+        assert_eq!(lsm.get(&[0]).unwrap(), Some(vec![b'z'; 4000]));
+        assert!(data_size(packed.path()) * 10 < data_size(plain.path()));
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .max_log_length(u64::MAX)
+            .compaction_gate(Arc::new(SwitchGate::default()))
+            .zstd_sstable_compression_level(0)
+            .open(dir.path())
+            .unwrap();
+        assert_eq!(lsm.stats().space_amp, 0.0);
+
+        // every key written twice, the older copies still on disk.
+        for _ in 0..2 {
+            for i in 0..10u8 {
+                lsm.put(vec![i], vec![i; 100]).unwrap();
+            }
+            lsm.flush().unwrap();
+        }
+        lsm.put(vec![20], vec![20; 100]).unwrap();
+        lsm.get(&[1]).unwrap();
+
+        let stats = lsm.stats();
+        let entry_size = (HEADER_SIZE + 101) as u64;
+        assert_eq!(stats.resident_bytes, 11 * entry_size);
+        assert_eq!(stats.on_disk_bytes, 21 * entry_size);
+        assert_eq!(stats.logged_bytes, entry_size);
+        assert_eq!(stats.written_bytes, 21 * 101);
+        assert_eq!(stats.read_bytes, 101);
+        assert!(stats.write_amp > 1.0);
+
+        // the merge rewrites data, and drops the older copies.
+        lsm.compact().unwrap();
+        let compacted = lsm.stats();
+        assert_eq!(compacted.space_amp, 1.0);
+        assert!(compacted.write_amp > stats.write_amp);
+    }
+
+    #[test]
+    fn test_memtable_full_policy() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .memtable_hard_limit_bytes(10)
+            .memtable_full_policy(MemtableFullPolicy::Reject)
+            .open(dir.path())
+            .unwrap();
+
+        lsm.put(b"a".to_vec(), vec![0; 5]).unwrap();
+        // an overwrite only needs room for the difference.
+        lsm.put(b"a".to_vec(), vec![0; 9]).unwrap();
+        assert!(matches!(
+            lsm.put(b"b".to_vec(), vec![0; 1]),
+            Err(LSMLibError::MemtableFull { limit: 10 })
+        ));
+        assert_eq!(lsm.get(b"b").unwrap(), None);
+        assert_eq!(lsm.memtable_bytes, 10);
+
+        lsm.flush().unwrap();
+        lsm.put(b"b".to_vec(), vec![0; 1]).unwrap();
+        assert!(matches!(
+            lsm.put(b"c".to_vec(), vec![0; 10]),
+            Err(LSMLibError::MemtableFull { limit: 10 })
+        ));
+        lsm.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn test_iter_rev() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .compaction_gate(Arc::new(SwitchGate::default()))
+            .open(dir.path())
+            .unwrap();
+
+        for i in (0..10u8).step_by(2) {
+            lsm.put(vec![i], vec![i]).unwrap();
+        }
+        lsm.flush().unwrap();
+        for i in (1..10u8).step_by(2) {
+            lsm.put(vec![i], vec![i]).unwrap();
+        }
+        lsm.delete(&[8]).unwrap();
+        lsm.delete(&[9]).unwrap();
+
+        let keys: Vec<u8> = lsm.iter().unwrap().rev().map(|r| r.unwrap().0[0]).collect();
+        assert_eq!(keys, [7, 6, 5, 4, 3, 2, 1, 0]);
+
+        // both ends meet in the middle.
+        let mut iter = lsm.iter().unwrap();
+        assert_eq!(iter.next_back().unwrap().unwrap(), (vec![7], vec![7]));
+        assert_eq!(iter.next().unwrap().unwrap(), (vec![0], vec![0]));
+        assert_eq!(iter.count(), 6);
+    }
+
+    #[test]
+    fn test_max_files_per_read() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .max_log_length(1)
+            .max_files_per_read(3)
+            .compaction_gate(Arc::new(SwitchGate::default()))
+            .open(dir.path())
+            .unwrap();
+
+        // a backlog of sstables between the two versions of "a".
+        lsm.put(b"a".to_vec(), vec![1; 10]).unwrap();
+        for i in 0..5u8 {
+            lsm.put(vec![b'k', i], vec![i; 10]).unwrap();
+        }
+        lsm.put(b"a".to_vec(), vec![2; 10]).unwrap();
+        wait_worker(&lsm);
+        assert_eq!(sstable_count(&lsm), 7);
+
+        // bit rot, leaving the modification time alone.
+        let entry = *lsm.store.read().unwrap().keydir().get(b"a").unwrap();
+        let path = utils::format_sstable_path(&lsm.path, entry.file_id);
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        let mut buf = fs::read(&path).unwrap();
+        buf[(entry.offset + entry.size - 1) as usize] ^= 0xFF;
+        fs::write(&path, buf).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        // reads through the keydir are unaffected.
+        assert_eq!(lsm.get(&[b'k', 0]).unwrap(), Some(vec![0; 10]));
+        assert!(matches!(
+            lsm.repair_key(b"a"),
+            Err(LSMLibError::ReadAmplificationExceeded { files: 7, max: 3 })
+        ));
+        assert_eq!(lsm.io_stats().reads_over_file_limit, 1);
+
+        // the newest 3 sstables miss the older version.
+        lsm.config.clamp_files_per_read = true;
+        assert_eq!(lsm.repair_key(b"a").unwrap(), RepairOutcome::Unrepairable);
+        assert_eq!(lsm.io_stats().reads_over_file_limit, 2);
+
+        lsm.config.max_files_per_read = None;
+        assert_eq!(
+            lsm.repair_key(b"a").unwrap(),
+            RepairOutcome::RestoredFromVersion(1)
+        );
+        assert_eq!(lsm.io_stats().reads_over_file_limit, 2);
+    }
+
+    #[test]
+    fn test_externally_modified() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let gate = Arc::new(SwitchGate::default());
+
+        let mut lsm = OpenOptions::new()
+            .max_log_length(1)
+            .merge_window(2)
+            .compaction_gate(gate.clone())
+            .open(dir.path())
+            .unwrap();
+
+        for i in 0..4u8 {
+            lsm.put(vec![i], vec![i; 10]).unwrap();
+        }
+        wait_worker(&lsm);
+
+        // a script rewrites sstable 1 under the live store.
+        let path = utils::format_sstable_path(dir.path(), 1);
+        let mut buf = fs::read(&path).unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 0xFF;
+        fs::write(&path, buf).unwrap();
+
+        let err = lsm.get(&[0]).unwrap_err();
+        assert!(
+            matches!(&err, LSMLibError::ExternallyModified { path: p, modified: Some(_) } if *p == path)
+        );
+        assert!(err.to_string().contains("changed on disk at"));
+        assert!(matches!(
+            lsm.repair_key(&[0]),
+            Err(LSMLibError::ExternallyModified { .. })
+        ));
+
+        // compaction refuses to delete the modified input.
+        gate.allow.store(true, Ordering::SeqCst);
+        wait_worker(&lsm);
+        wait_worker(&lsm);
+        assert_eq!(sstable_count(&lsm), 4);
+        assert!(path.exists());
+        assert_eq!(lsm.get(&[3]).unwrap(), Some(vec![3; 10]));
+    }
+
+    #[test]
+    fn test_compaction_gate() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let gate = Arc::new(SwitchGate::default());
+
+        let mut lsm = OpenOptions::new()
+            .max_log_length(1)
+            .merge_window(2)
+            .compaction_gate(gate.clone())
+            .open(dir.path())
+            .unwrap();
+
+        for i in 0..4u8 {
+            lsm.put(vec![i], vec![i; 10]).unwrap();
+        }
+        wait_worker(&lsm);
+
+        assert_eq!(sstable_count(&lsm), 4);
+        assert!(gate.denied.load(Ordering::SeqCst) > 0);
+
+        gate.allow.store(true, Ordering::SeqCst);
+        // first tick compacts, second one waits for it.
+        wait_worker(&lsm);
+        wait_worker(&lsm);
+
+        assert!(sstable_count(&lsm) < 4);
+        for i in 0..4u8 {
+            assert_eq!(lsm.get(&[i]).unwrap(), Some(vec![i; 10]));
+        }
+    }
+
+    #[test]
+    fn test_flush_and_compact_outcome() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .compaction_gate(Arc::new(SwitchGate::default()))
+            .zstd_sstable_compression_level(0)
+            .open(dir.path())
+            .unwrap();
+
+        assert_eq!(lsm.flush().unwrap(), FlushOutcome::default());
+        assert_eq!(lsm.compact().unwrap(), CompactionOutcome::default());
+
+        for i in 0..4u8 {
+            lsm.put(vec![i], vec![i; 100]).unwrap();
+        }
+        let outcome = lsm.flush().unwrap();
+        assert!(outcome.flushed);
+        assert_eq!(outcome.sstable_id, Some(1));
+        assert_eq!((outcome.entries, outcome.tombstones), (4, 0));
+        assert!(outcome.size > 400);
+
+        // the tombstone of a key no sstable holds is skipped.
+        lsm.put(vec![9], vec![9]).unwrap();
+        lsm.delete(&[9]).unwrap();
+        for i in 0..2u8 {
+            lsm.put(vec![i], vec![i]).unwrap();
+        }
+        lsm.delete(&[3]).unwrap();
+        let outcome = lsm.flush().unwrap();
+        assert_eq!(outcome.sstable_id, Some(2));
+        assert_eq!((outcome.entries, outcome.tombstones), (3, 1));
+
+        // the gate denies background merges, not manual ones.
+        let outcome = lsm.compact().unwrap();
+        assert_eq!(
+            outcome.inputs.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        let (output, size) = outcome.output.unwrap();
+        assert_eq!(output, 2);
+        assert_eq!(
+            (outcome.entries_written, outcome.tombstones_dropped),
+            (3, 1)
+        );
+        let input_bytes: u64 = outcome.inputs.iter().map(|(_, size)| size).sum();
+        assert_eq!(outcome.bytes_reclaimed, input_bytes - size);
+        assert!(outcome.bytes_reclaimed > 300);
+        assert_eq!(lsm.last_compaction(), Some(outcome));
+        assert_eq!(lsm.compaction_stats().runs, 1);
+        assert_eq!(sstable_count(&lsm), 1);
+        assert_eq!(lsm.get(&[2]).unwrap(), Some(vec![2; 100]));
+
+        // the memtable is flushed first, then merged in.
+        lsm.put(vec![5], vec![5]).unwrap();
+        let outcome = lsm.compact_now().unwrap();
+        assert_eq!(outcome.inputs.len(), 2);
+        assert_eq!(sstable_count(&lsm), 1);
+        assert_eq!(lsm.compact_now().unwrap(), CompactionOutcome::default());
+        assert_eq!(lsm.get(&[5]).unwrap(), Some(vec![5]));
+    }
+
+    #[test]
+    fn test_database_full() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .max_log_length(1)
+            .max_database_bytes(4096)
+            .compaction_gate(Arc::new(SwitchGate::default()))
+            .open(dir.path())
+            .unwrap();
+        assert!(!lsm.disk_usage().unwrap().nearly_full);
+
+        let mut written = 0u8;
+        let err = loop {
+            match lsm.put(vec![written], vec![written; 200]) {
+                Ok(()) => written += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(err, LSMLibError::DatabaseFull { limit: 4096 }));
+        assert!(written > 5);
+        let usage = lsm.disk_usage().unwrap();
+        assert!(usage.nearly_full);
+        assert_eq!(usage.max_bytes, Some(4096));
+
+        // reads and deletes keep working.
+        assert_eq!(lsm.get(&[0]).unwrap(), Some(vec![0; 200]));
+        for i in 0..written {
+            lsm.delete(&[i]).unwrap();
+        }
+        assert!(lsm.put(vec![0], vec![0; 200]).is_err());
+
+        assert!(lsm.compact().unwrap().bytes_reclaimed > 0);
+        assert!(!lsm.disk_usage().unwrap().nearly_full);
+        lsm.put(vec![0], vec![0; 200]).unwrap();
+        assert_eq!(lsm.get(&[0]).unwrap(), Some(vec![0; 200]));
+    }
+
+    #[test]
+    fn test_store_directory_missing() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .max_log_length(1024)
+            .open(dir.path())
+            .unwrap();
+        lsm.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+
+        std::fs::remove_dir_all(dir.path()).unwrap();
+        let err = (0..1000u32)
+            .find_map(|i| lsm.put(i.to_be_bytes().to_vec(), vec![0; 64]).err())
+            .unwrap();
+        assert!(matches!(err, LSMLibError::StoreDirectoryMissing(ref p) if p == dir.path()));
+
+        // the store stays failed, even for reads.
+        assert!(matches!(
+            lsm.put(b"b".to_vec(), b"2".to_vec()),
+            Err(LSMLibError::StoreDirectoryMissing(_))
+        ));
+        assert!(matches!(
+            lsm.get(b"a"),
+            Err(LSMLibError::StoreDirectoryMissing(_))
+        ));
+        assert!(matches!(
+            lsm.flush(),
+            Err(LSMLibError::StoreDirectoryMissing(_))
+        ));
+
+        // closing a failed store does not panic.
+        drop(lsm);
+    }
+
+    #[test]
+    fn test_sample_keys() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .max_log_length(4096)
+            .zstd_sstable_compression_level(0)
+            .open(dir.path())
+            .unwrap();
+
+        // most keys flushed, the last ones and deletes in the memtable.
+        for i in 0..500u32 {
+            lsm.put(i.to_be_bytes().to_vec(), vec![1; 8]).unwrap();
+        }
+        lsm.put(7u32.to_be_bytes().to_vec(), vec![1; 5000]).unwrap();
+        lsm.put(9u32.to_be_bytes().to_vec(), vec![1; 3000]).unwrap();
+        for i in 100..400u32 {
+            lsm.delete(&i.to_be_bytes()).unwrap();
+        }
+
+        let sample = lsm.sample_keys(50, 1).unwrap();
+        assert_eq!(sample.len(), 50);
+        assert_eq!(lsm.sample_keys(50, 1).unwrap(), sample);
+        assert_ne!(lsm.sample_keys(50, 2).unwrap(), sample);
+        for (key, size) in &sample {
+            let i = u32::from_be_bytes(key.as_slice().try_into().unwrap());
+            assert!(!(100..400).contains(&i));
+            assert_eq!(lsm.get(key).unwrap().unwrap().len() as u64, *size);
+        }
+        assert_eq!(lsm.sample_keys(1000, 1).unwrap().len(), 200);
+
+        let largest = lsm.largest_values(2).unwrap();
+        assert_eq!(
+            largest,
+            vec![
+                (7u32.to_be_bytes().to_vec(), 5000),
+                (9u32.to_be_bytes().to_vec(), 3000)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_key_transform() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let lowercase: KeyTransformFn = Arc::new(|k: &[u8]| k.to_ascii_lowercase());
+        let open = |id: &str| {
+            OpenOptions::new()
+                .max_log_length(64)
+                .key_transform(id, lowercase.clone())
+                .open(dir.path())
+        };
+
+        let mut lsm = open("ascii-lowercase").unwrap();
+        lsm.put(b"Alice".to_vec(), b"1".to_vec()).unwrap();
+        lsm.put(b"ALFRED".to_vec(), b"2".to_vec()).unwrap();
+        lsm.put(b"bob".to_vec(), b"3".to_vec()).unwrap();
+        lsm.put(b"ALICE".to_vec(), b"4".to_vec()).unwrap();
+
+        assert_eq!(lsm.get(b"alice").unwrap(), Some(b"4".to_vec()));
+        assert!(lsm.contains(b"Alfred"));
+        lsm.flush().unwrap();
+        let mut keys = lsm.list_keys().unwrap();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![b"alfred".to_vec(), b"alice".to_vec(), b"bob".to_vec()]
+        );
+        assert_eq!(lsm.prefix_stats(b"AL").unwrap().keys, 2);
+
+        let snapshot = lsm.snapshot();
+        let scanned: Vec<_> = snapshot
+            .range(b"A".to_vec()..b"B".to_vec())
+            .map(|pair| pair.unwrap().0)
+            .collect();
+        assert_eq!(scanned, vec![b"alfred".to_vec(), b"alice".to_vec()]);
+        assert_eq!(snapshot.get(b"BOB").unwrap(), Some(b"3".to_vec()));
+        drop(snapshot);
+
+        lsm.delete(b"Bob").unwrap();
+        assert_eq!(lsm.get(b"bob").unwrap(), None);
+        drop(lsm);
+
+        // the keys are only readable through the same transform.
+        assert!(matches!(
+            Lsm::open(dir.path()),
+            Err(LSMLibError::KeyTransformMismatch {
+                stored: Some(_),
+                requested: None
+            })
+        ));
+        assert!(matches!(
+            open("unicode-casefold"),
+            Err(LSMLibError::KeyTransformMismatch { .. })
+        ));
+        let lsm = open("ascii-lowercase").unwrap();
+        assert_eq!(lsm.get(b"ALFRED").unwrap(), Some(b"2".to_vec()));
+        drop(lsm);
+
+        // a store holding keys cannot take a transform.
+        let plain_dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = Lsm::open(plain_dir.path()).unwrap();
+        lsm.put(b"Key".to_vec(), b"v".to_vec()).unwrap();
+        drop(lsm);
+        assert!(matches!(
+            OpenOptions::new()
+                .key_transform("ascii-lowercase", lowercase.clone())
+                .open(plain_dir.path()),
+            Err(LSMLibError::KeyTransformMismatch { stored: None, .. })
+        ));
+    }
+
+    #[test]
+    fn test_clock() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let open = |clock: ClockFn| OpenOptions::new().clock(clock).open(dir.path()).unwrap();
+
+        let mut lsm = open(Arc::new(|| 1_000_000));
+        lsm.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        assert_eq!(lsm.memtable[b"a".as_slice()].timestamp(), 1_000_000);
+        lsm.flush().unwrap();
+        // the metadata is stamped by the same clock.
+        assert_eq!(lsm.identity().created_at, 1_000_000);
+        assert_eq!(lsm.sstable_lineage(1).unwrap().created_at, 1_000_000);
+        drop(lsm);
+
+        // an RTC back at the epoch after a reboot.
+        let mut lsm = open(Arc::new(|| 0));
+        lsm.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        assert_eq!(lsm.memtable[b"b".as_slice()].timestamp(), 1_000_001);
+        drop(lsm);
+
+        // the high-water mark is recovered from the WAL as well.
+        let mut lsm = open(Arc::new(|| 10));
+        lsm.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        assert_eq!(lsm.memtable[b"c".as_slice()].timestamp(), 1_000_002);
+    }
+
+    #[test]
+    fn test_hints_on_flush() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let open = || {
+            OpenOptions::new()
+                .max_log_length(1)
+                .hints_on_flush(false)
+                .compaction_gate(Arc::new(SwitchGate::default()))
+                .open(dir.path())
+                .unwrap()
+        };
+        let hints = || {
+            (1..=3)
+                .filter(|id| utils::format_hint_path(dir.path(), *id).exists())
+                .count()
+        };
+
+        let mut lsm = open();
+        for i in 0..3u8 {
+            lsm.put(vec![i], vec![i; 10]).unwrap();
+        }
+        assert_eq!(sstable_count(&lsm), 3);
+        assert_eq!(hints(), 0);
+        drop(lsm);
+
+        // the keydir is rebuilt from the sstables.
+        let mut lsm = open();
+        assert_eq!(lsm.get(&[1]).unwrap(), Some(vec![1; 10]));
+
+        assert_eq!(lsm.write_missing_hints().unwrap(), 3);
+        assert_eq!(lsm.write_missing_hints().unwrap(), 0);
+        assert_eq!(hints(), 3);
+        drop(lsm);
+
+        let mut lsm = open();
+        for i in 0..3u8 {
+            assert_eq!(lsm.get(&[i]).unwrap(), Some(vec![i; 10]));
+        }
+
+        // merged sstables get a hint whatever the option.
+        lsm.put(vec![3], vec![3; 10]).unwrap();
+        let (id, _) = lsm.compact().unwrap().output.unwrap();
+        assert!(utils::format_hint_path(dir.path(), id).exists());
+    }
+
+    #[test]
+    fn test_sync_stats() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .max_log_length(1)
+            .slow_sync_warn_threshold(std::time::Duration::ZERO)
+            .open(dir.path())
+            .unwrap();
+
+        lsm.put(b"k".to_vec(), b"v".to_vec()).unwrap();
+
+        let stats = lsm.sync_stats();
+        for class in [&stats.wal, &stats.sstable, &stats.hint, &stats.dir] {
+            assert!(class.count >= 1);
+            assert_eq!(class.slow, class.count);
+            assert!(class.last_sync.is_some());
+            assert_eq!(class.latency_histogram.iter().sum::<u64>(), class.count);
+        }
+        assert_eq!(
+            stats.slow_syncs(),
+            stats.wal.count + stats.sstable.count + stats.hint.count + stats.dir.count
+        );
+    }
+
+    #[test]
+    fn test_sync_stats_bytes() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .max_log_length(u64::MAX)
+            .zstd_sstable_compression_level(0)
+            .open(dir.path())
+            .unwrap();
+        let before = lsm.sync_stats();
+        assert_eq!(before.write_amplification(), 0.0);
+
+        // puts sync nothing without `max_unsynced_age`.
+        for i in 0..100u8 {
+            lsm.put(vec![i], vec![i; 99]).unwrap();
+        }
+        let stats = lsm.sync_stats();
+        assert_eq!(stats.user_bytes, 100 * 100);
+        assert_eq!(stats.wal, before.wal);
+        assert_eq!(stats.sstable, before.sstable);
+
+        // the flush syncs the WAL, then again once truncated.
+        let outcome = lsm.flush().unwrap();
+        let stats = lsm.sync_stats();
+        let entry_size = (HEADER_SIZE + 100) as u64;
+        assert_eq!(stats.wal.count - before.wal.count, 2);
+        assert_eq!(
+            stats.wal.bytes_synced - before.wal.bytes_synced,
+            100 * entry_size
+        );
+        assert_eq!(stats.sstable.count - before.sstable.count, 1);
+        assert_eq!(
+            stats.sstable.bytes_synced - before.sstable.bytes_synced,
+            outcome.size
+        );
+        assert!(stats.hint.bytes_synced > before.hint.bytes_synced);
+        assert!(stats.dir.count > before.dir.count);
+        assert_eq!(stats.dir.bytes_synced, 0);
+
+        // the WAL and the sstable both hold every entry.
+        assert!(stats.write_amplification() > 2.0);
+        assert!(stats.write_amplification() < 3.0);
+    }
+
+    #[test]
+    fn test_paranoid_flush_checks() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .max_log_length(1)
+            .paranoid_flush_checks(true)
+            .open(dir.path())
+            .unwrap();
+
+        for i in 0..4u8 {
+            lsm.put(vec![i], vec![i; 10]).unwrap();
+        }
+        lsm.delete(&[0]).unwrap();
+
+        assert_eq!(lsm.get(&[0]).unwrap(), None);
+        for i in 1..4u8 {
+            assert_eq!(lsm.get(&[i]).unwrap(), Some(vec![i; 10]));
+        }
+    }
+
+    #[test]
+    fn test_small_file_merge() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .max_log_length(1)
+            .merge_window(255)
+            .small_file_size(64 * 1024)
+            .small_file_merge_threshold(16)
+            .open(dir.path())
+            .unwrap();
+
+        for i in 0..200u8 {
+            lsm.put(vec![i], vec![i; 10]).unwrap();
+        }
+        lsm.delete(&[7]).unwrap();
+
+        for _ in 0..10 {
+            if sstable_count(&lsm) <= 16 {
+                break;
+            }
+            wait_worker(&lsm);
+        }
+        assert!(sstable_count(&lsm) <= 16);
+
+        for i in 0..200u8 {
+            let expected = if i == 7 { None } else { Some(vec![i; 10]) };
+            assert_eq!(lsm.get(&[i]).unwrap(), expected);
+        }
+
+        drop(lsm);
+        let lsm = Lsm::open(dir.path()).unwrap();
+        assert_eq!(lsm.get(&[7]).unwrap(), None);
+        assert_eq!(lsm.get(&[8]).unwrap(), Some(vec![8; 10]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("lsmlib").unwrap();
+        let path = dir.path().join("db");
+
+        // group write bits are masked by the usual umask.
+        let mut lsm = OpenOptions::new()
+            .max_log_length(1)
+            .merge_window(2)
+            .file_mode(0o660)
+            .dir_mode(0o770)
+            .open(&path)
+            .unwrap();
+
+        for i in 0..4u8 {
+            lsm.put(vec![i], vec![i; 10]).unwrap();
+        }
+        wait_worker(&lsm);
+        wait_worker(&lsm);
+
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), 0o770);
+
+        let mut files = 0;
+        for entry in fs::read_dir(&path).unwrap() {
+            let entry = entry.unwrap().path();
+            assert_eq!(mode(&entry), 0o660, "{}", entry.display());
+            files += 1;
+        }
+        // LOCK, VERSION, WAL and at least one compacted sstable and hint.
+        assert!(files >= 5);
+        assert!(sstable_count(&lsm) < 4);
+    }
+
+    #[test]
+    fn test_empty_key() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+
+        assert!(matches!(
+            lsm.put(vec![], b"v".to_vec()),
+            Err(LSMLibError::EmptyKey)
+        ));
+        assert!(matches!(lsm.delete(&[]), Err(LSMLibError::EmptyKey)));
+        assert_eq!(lsm.get(&[]).unwrap(), None);
+        assert!(!lsm.contains(&[]));
+
+        // nothing was logged.
+        assert_eq!(lsm.seq, 0);
+        assert_eq!(lsm.dirty_bytes, 0);
+
+        // an empty prefix means every key.
+        lsm.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        lsm.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        assert_eq!(lsm.prefix_stats(&[]).unwrap().keys, 2);
+    }
+
+    #[test]
+    fn test_clone_to() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let clone_dir = TempDir::new("lsmlib-clone").unwrap();
+
+        let mut lsm = OpenOptions::new()
+            .max_log_length(1)
+            .merge_window(2)
+            .open(dir.path())
+            .unwrap();
+        for i in 0..8u8 {
+            lsm.put(vec![i], vec![i; 10]).unwrap();
+        }
+        // left in the memtable.
+        lsm.config.max_log_length = 1 << 20;
+        lsm.put(vec![8], vec![8; 10]).unwrap();
+        lsm.config.max_log_length = 1;
+        wait_worker(&lsm);
+
+        let mut clone = lsm.clone_to(clone_dir.path()).unwrap();
+        let cloned: Vec<u64> = clone
+            .store
+            .read()
+            .unwrap()
+            .list_sstables()
+            .into_keys()
+            .collect();
+
+        // the original rewrites and compacts away the linked sstables.
+        for i in 0..9u8 {
+            lsm.put(vec![i], vec![i + 100; 10]).unwrap();
+        }
+        for _ in 0..20 {
+            wait_worker(&lsm);
+        }
+        let original = lsm.store.read().unwrap().list_sstables();
+        assert!(cloned.iter().any(|id| !original.contains_key(id)));
+
+        clone.delete(&[0]).unwrap();
+        assert_eq!(clone.get(&[0]).unwrap(), None);
+        for i in 1..9u8 {
+            assert_eq!(clone.get(&[i]).unwrap(), Some(vec![i; 10]));
+        }
+        for i in 0..9u8 {
+            assert_eq!(lsm.get(&[i]).unwrap(), Some(vec![i + 100; 10]));
+        }
+
+        // the clone reopens on its own files.
+        drop(clone);
+        let clone = Lsm::open(clone_dir.path()).unwrap();
+        assert_eq!(clone.get(&[0]).unwrap(), None);
+        assert_eq!(clone.get(&[8]).unwrap(), Some(vec![8; 10]));
+
+        assert!(lsm.clone_to(clone_dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_rewrite_into() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let target = TempDir::new("lsmlib-rewrite").unwrap();
+
+        let mut lsm = OpenOptions::new()
+            .compaction_gate(Arc::new(SwitchGate::default()))
+            .open(dir.path())
+            .unwrap();
+        for i in 0..100u8 {
+            lsm.put(vec![i], vec![i; 100]).unwrap();
+        }
+        lsm.flush().unwrap();
+        for i in 0..90u8 {
+            lsm.delete(&[i]).unwrap();
+        }
+        lsm.put(vec![0], b"new".to_vec()).unwrap();
+        let live: Vec<_> = lsm.range(..).unwrap().map(|r| r.unwrap()).collect();
+
+        let (mut rewritten, report) = lsm.rewrite_into(target.path()).unwrap();
+        assert_eq!(report.keys, 11);
+        assert!(report.flush.flushed);
+        assert_eq!(sstable_count(&rewritten), 1);
+        assert!(report.pause >= report.copy_duration + report.open_duration);
+        let copied: Vec<_> = rewritten.range(..).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(copied, live);
+
+        // the new store goes on from the old sequence number.
+        rewritten.put(vec![1], b"1".to_vec()).unwrap();
+        assert_eq!(rewritten.seq, lsm.seq + 1);
+        drop(rewritten);
+        let rewritten = Lsm::open(target.path()).unwrap();
+        assert_eq!(rewritten.get(&[1]).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(rewritten.get(&[0]).unwrap(), Some(b"new".to_vec()));
+
+        // the old store is left intact.
+        assert_eq!(lsm.range(..).unwrap().count(), 11);
+        assert!(lsm.rewrite_into(target.path()).is_err());
+    }
+
+    #[test]
+    fn test_rewrite_into_keeps_expiry() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let target = TempDir::new("lsmlib-rewrite").unwrap();
+        let now = Arc::new(AtomicU32::new(1_000_000));
+        let clock = Arc::clone(&now);
+        let mut lsm = OpenOptions::new()
+            .clock(Arc::new(move || clock.load(Ordering::Relaxed)))
+            .zstd_sstable_compression_level(0)
+            .open(dir.path())
+            .unwrap();
+        lsm.put_with_ttl(b"a".to_vec(), b"1".to_vec(), Duration::from_secs(10))
+            .unwrap();
+        lsm.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+
+        let (rewritten, _) = lsm.rewrite_into(target.path()).unwrap();
+        assert_eq!(
+            migrate::detect_format_version(target.path()).unwrap(),
+            Some(EXPIRY_FORMAT_VERSION)
+        );
+        assert_eq!(rewritten.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        now.store(1_000_010, Ordering::Relaxed);
+        assert_eq!(rewritten.get(b"a").unwrap(), None);
+        assert_eq!(rewritten.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_io_budget() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+
+        let rate = 64 * 1024;
+        let budget = IoBudget {
+            read_bytes_per_sec: rate,
+            write_bytes_per_sec: rate,
+            ..IoBudget::default()
+        };
+        lsm.io_budget(budget);
+
+        let assert_converges = |bytes: u64, elapsed: std::time::Duration| {
+            let throughput = bytes as f64 / elapsed.as_secs_f64();
+            assert!(
+                (throughput - rate as f64).abs() < rate as f64 * 0.1,
+                "throughput {} for a budget of {}",
+                throughput,
+                rate
+            );
+        };
+
+        let start = std::time::Instant::now();
+        for i in 0..64u32 {
+            lsm.put(i.to_be_bytes().to_vec(), vec![0; 2044]).unwrap();
+        }
+        assert_converges(64 * 2048, start.elapsed());
+
+        // a new budget starts empty, no burst saved up during the writes.
+        lsm.io_budget(budget);
+        let start = std::time::Instant::now();
+        for i in 0..64u32 {
+            lsm.get(&i.to_be_bytes()).unwrap();
+        }
+        assert_converges(64 * 2048, start.elapsed());
+
+        let stats = lsm.io_stats();
+        assert_eq!(
+            (stats.read_bytes, stats.written_bytes),
+            (64 * 2048, 64 * 2048)
+        );
+
+        // a failing budget rejects while in debt.
+        lsm.io_budget(IoBudget {
+            write_bytes_per_sec: 1024,
+            mode: ThrottleMode::Error,
+            ..IoBudget::default()
+        });
+        lsm.put(b"a".to_vec(), vec![0; 4096]).unwrap();
+        assert!(matches!(
+            lsm.put(b"b".to_vec(), vec![0; 8]),
+            Err(LSMLibError::Throttled { .. })
+        ));
+        assert_eq!(lsm.get(b"b").unwrap(), None);
+    }
+
+    #[test]
+    fn test_negative_cache() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .max_log_length(1)
+            .negative_cache_entries(64)
+            .open(dir.path())
+            .unwrap();
+
+        // every put flushes.
+        lsm.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+
+        // the second miss is answered by the cache.
+        assert_eq!(lsm.get(b"k").unwrap(), None);
+        assert!(!lsm.contains(b"k"));
+        let stats = lsm.negative_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.lookups), (1, 2));
+        assert_eq!(stats.hit_rate(), 0.5);
+
+        // a put is never hidden by the cache.
+        lsm.put(b"k".to_vec(), b"v".to_vec()).unwrap();
+        assert_eq!(lsm.get(b"k").unwrap(), Some(b"v".to_vec()));
+        assert!(lsm.contains(b"k"));
+
+        // a flush forgets all absent keys.
+        assert_eq!(lsm.get(b"x").unwrap(), None);
+        lsm.put(b"y".to_vec(), b"2".to_vec()).unwrap();
+        assert_eq!(lsm.get(b"x").unwrap(), None);
+        assert_eq!(lsm.negative_cache_stats().unwrap().hits, 1);
+
+        let dir = TempDir::new("lsmlib").unwrap();
+        let lsm = Lsm::open(dir.path()).unwrap();
+        assert_eq!(lsm.get(b"k").unwrap(), None);
+        assert!(lsm.negative_cache_stats().is_none());
+    }
+
+    static MOCK_CLOCK_MILLIS: AtomicU64 = AtomicU64::new(0);
+
+    fn mock_now() -> Instant {
+        static BASE: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+        *BASE.get_or_init(Instant::now)
+            + Duration::from_millis(MOCK_CLOCK_MILLIS.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn test_max_unsynced_age() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .max_unsynced_age(Duration::from_secs(30))
+            .open(dir.path())
+            .unwrap();
+        lsm.now = mock_now;
+        let advance = |secs: u64| MOCK_CLOCK_MILLIS.fetch_add(secs * 1000, Ordering::SeqCst);
+        let wal_syncs = |lsm: &Lsm| lsm.sync_stats().wal.count;
+
+        assert_eq!(lsm.unsynced_age(), None);
+        lsm.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        assert_eq!(lsm.unsynced_age(), Some(Duration::ZERO));
+
+        advance(20);
+        lsm.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        assert_eq!(lsm.unsynced_age(), Some(Duration::from_secs(20)));
+        assert_eq!(lsm.sync_stats().unsynced_age, lsm.unsynced_age());
+        assert_eq!(wal_syncs(&lsm), 0);
+
+        // the oldest entry is 31s old, the next put syncs.
+        advance(11);
+        lsm.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        assert_eq!(lsm.unsynced_age(), None);
+        assert_eq!(wal_syncs(&lsm), 1);
+
+        advance(5);
+        lsm.put(b"d".to_vec(), b"4".to_vec()).unwrap();
+        assert_eq!(lsm.unsynced_age(), Some(Duration::ZERO));
+        drop(lsm);
+
+        // recovered entries count as unsynced.
+        let lsm = Lsm::open(dir.path()).unwrap();
+        assert!(lsm.unsynced_age().is_some());
+    }
+
+    #[test]
+    fn test_worker_info() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let other_dir = TempDir::new("lsmlib").unwrap();
+
+        let lsm = OpenOptions::new()
+            .compactor_nice(10)
+            .open(dir.path())
+            .unwrap();
+        let info = lsm.worker_info();
+        assert_eq!(info.len(), 1);
+        assert_eq!(info[0].kind, "compactor");
+        assert!(info[0].thread_name.starts_with("lsmlib-compactor-"));
+
+        let other = Lsm::open(other_dir.path()).unwrap();
+        assert_ne!(other.worker_info(), info);
+        drop(lsm);
+
+        // stable across opens of the same store.
+        let lsm = Lsm::open(dir.path()).unwrap();
+        assert_eq!(lsm.worker_info(), info);
+
+        #[cfg(target_os = "linux")]
+        std::thread::spawn(|| {
+            worker::set_thread_nice(10);
+            assert_eq!(unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) }, 10);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let gate = Arc::new(SwitchGate::default());
+        let open = || {
+            OpenOptions::new()
+                .max_log_length(256)
+                .merge_window(2)
+                .compaction_gate(gate.clone())
+                .zstd_sstable_compression_level(0)
+                .open(dir.path())
+                .unwrap()
+        };
+        let key = |i: u8| format!("k{:02}", i).into_bytes();
+        let live = |lsm: &Lsm| lsm.list_keys().unwrap().len();
+
+        let mut lsm = open();
+        for i in 0..80u8 {
+            lsm.put(key(i), vec![i; 8]).unwrap();
+        }
+        assert!(sstable_count(&lsm) > 1);
+        let snapshot = lsm.snapshot();
+
+        lsm.delete_range(&key(10), &key(50)).unwrap();
+        lsm.delete_range(b"z", b"a").unwrap();
+        assert_eq!(
+            migrate::detect_format_version(dir.path()).unwrap(),
+            Some(RANGE_TOMBSTONE_FORMAT_VERSION)
+        );
+        assert_eq!(lsm.get(&key(10)).unwrap(), None);
+        assert!(!lsm.contains(&key(49)));
+        assert_eq!(lsm.get(&key(50)).unwrap(), Some(vec![50; 8]));
+        assert_eq!(live(&lsm), 40);
+        assert_eq!(snapshot.get(&key(30)).unwrap(), Some(vec![30; 8]));
+
+        // a put after the tombstone is visible.
+        lsm.put(key(20), b"new".to_vec()).unwrap();
+        assert_eq!(lsm.get(&key(20)).unwrap(), Some(b"new".to_vec()));
+
+        lsm.flush_memtable().unwrap();
+        assert_eq!(lsm.get(&key(30)).unwrap(), None);
+        assert_eq!(live(&lsm), 41);
+        assert_eq!(snapshot.get(&key(30)).unwrap(), Some(vec![30; 8]));
+        drop(snapshot);
+
+        // one tombstone replayed from the WAL, one loaded from sstables.
+        lsm.delete_range(&key(70), &key(75)).unwrap();
+        drop(lsm);
+        let mut lsm = open();
+        assert_eq!(lsm.get(&key(30)).unwrap(), None);
+        assert_eq!(lsm.get(&key(72)).unwrap(), None);
+        assert_eq!(lsm.get(&key(20)).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(live(&lsm), 36);
+
+        // compaction keeps the keys deleted.
+        lsm.flush_memtable().unwrap();
+        gate.allow.store(true, Ordering::SeqCst);
+        wait_worker(&lsm);
+        wait_worker(&lsm);
+        assert_eq!(lsm.get(&key(30)).unwrap(), None);
+        assert_eq!(live(&lsm), 36);
+        drop(lsm);
+
+        let lsm = open();
+        assert_eq!(lsm.get(&key(72)).unwrap(), None);
+        assert_eq!(live(&lsm), 36);
+    }
+
+    #[derive(Debug, Clone)]
+    enum ModelOp {
+        Put(u8, Vec<u8>),
+        Delete(u8),
+        Flush,
+        WaitCompaction,
+        Reopen,
+    }
+
+    fn model_op() -> impl proptest::strategy::Strategy<Value = ModelOp> {
+        use proptest::prelude::*;
+
+        prop_oneof![
+            6 => (0..16u8, prop::collection::vec(any::<u8>(), 1..48))
+                .prop_map(|(k, v)| ModelOp::Put(k, v)),
+            3 => (0..16u8).prop_map(ModelOp::Delete),
+            1 => Just(ModelOp::Flush),
+            1 => Just(ModelOp::WaitCompaction),
+            1 => Just(ModelOp::Reopen),
+        ]
+    }
+
+    proptest::proptest! {
+        #![proptest_config(proptest::prelude::ProptestConfig::with_cases(32))]
+
+        /// Compare the store against a `BTreeMap`, checking the invariants
+        /// after every operation so a breakage shows where it came from.
+        #[test]
+        fn test_model(ops in proptest::collection::vec(model_op(), 1..64)) {
+            let dir = TempDir::new("lsmlib").unwrap();
+            let open = || {
+                OpenOptions::new()
+                    .max_log_length(256)
+                    .merge_window(2)
+                    .open(dir.path())
+                    .unwrap()
+            };
+            let mut lsm = open();
+            let mut model = BTreeMap::new();
+
+            for (i, op) in ops.iter().enumerate() {
+                match op {
+                    ModelOp::Put(k, v) => {
+                        lsm.put(vec![*k], v.clone()).unwrap();
+                        model.insert(vec![*k], v.clone());
+                    }
+                    ModelOp::Delete(k) => {
+                        lsm.delete(&[*k]).unwrap();
+                        model.remove(&vec![*k]);
+                    }
+                    ModelOp::Flush => {
+                        lsm.flush().unwrap();
+                    }
+                    ModelOp::WaitCompaction => {
+                        wait_worker(&lsm);
+                        wait_worker(&lsm);
+                    }
+                    ModelOp::Reopen => {
+                        drop(lsm);
+                        lsm = open();
+                    }
+                }
+
+                if let Err(e) = lsm.check_invariants() {
+                    panic!("invariant violated after op {} {:?}: {}", i, op, e);
+                }
+                for k in 0..16u8 {
+                    proptest::prop_assert_eq!(lsm.get(&[k]).unwrap(), model.get(&vec![k]).cloned());
+                }
+            }
+        }
+
+        #[test]
+        fn test_memtable_hard_limit(ops in proptest::collection::vec(model_op(), 1..64)) {
+            let dir = TempDir::new("lsmlib").unwrap();
+            let mut lsm = OpenOptions::new()
+                .memtable_hard_limit_bytes(100)
+                .open(dir.path())
+                .unwrap();
+
+            let mut peak = 0;
+            for op in &ops {
+                match op {
+                    ModelOp::Put(k, v) => lsm.put(vec![*k], v.clone()).unwrap(),
+                    ModelOp::Delete(k) => lsm.delete(&[*k]).unwrap(),
+                    _ => {}
+                }
+                lsm.check_invariants().unwrap();
+                peak = peak.max(lsm.memtable_bytes);
+            }
+            proptest::prop_assert!(peak <= 100);
+        }
+
+        /// Keys written across flushes left uncompacted, so the sstables
+        /// hold several versions of them, are iterated once each.
+        #[test]
+        fn test_iteration_once(ops in proptest::collection::vec(model_op(), 1..64)) {
+            let dir = TempDir::new("lsmlib").unwrap();
+            let open = || {
+                OpenOptions::new()
+                    .compaction_gate(Arc::new(SwitchGate::default()))
+                    .open(dir.path())
+                    .unwrap()
+            };
+            let mut lsm = open();
+            let mut model = BTreeMap::new();
+
+            for op in &ops {
+                match op {
+                    ModelOp::Put(k, v) => {
+                        lsm.put(vec![*k], v.clone()).unwrap();
+                        model.insert(vec![*k], v.clone());
+                    }
+                    ModelOp::Delete(k) => {
+                        lsm.delete(&[*k]).unwrap();
+                        model.remove(&vec![*k]);
+                    }
+                    ModelOp::Flush | ModelOp::WaitCompaction => {
+                        lsm.flush().unwrap();
+                    }
+                    ModelOp::Reopen => {
+                        drop(lsm);
+                        lsm = open();
+                    }
+                }
+            }
+            let pairs: Vec<_> = model.into_iter().collect();
+
+            let iterated: Vec<_> = lsm.iter().unwrap().map(|r| r.unwrap()).collect();
+            proptest::prop_assert_eq!(&iterated, &pairs);
+
+            let keys: Vec<_> = pairs.iter().map(|(k, _)| k.clone()).collect();
+            proptest::prop_assert_eq!(lsm.list_keys().unwrap(), keys);
+
+            let mut export = Vec::new();
+            lsm.export_range(.., &mut export).unwrap();
+            let exported: Vec<_> = ExportReader::new(&export[..])
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect();
+            proptest::prop_assert_eq!(&exported, &pairs);
+        }
     }
 }
@@ -0,0 +1,38 @@
+//! Write Batch Module.
+//!
+//! Mutations applied together by `Lsm::apply_batch`: logged as one WAL
+//! record, so a crash keeps all of them or none.
+
+/// Puts and deletes applied as one unit, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    /// key and value of each mutation, an empty value deleting.
+    pub(crate) ops: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Put `key` to `value`. An empty value deletes the key.
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.ops.push((key, value));
+        self
+    }
+
+    /// Delete `key`.
+    pub fn delete(&mut self, key: Vec<u8>) -> &mut Self {
+        self.ops.push((key, Vec::new()));
+        self
+    }
+
+    /// Number of mutations.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
@@ -0,0 +1,267 @@
+//! Key Digest Module.
+//!
+//! Compact summaries of the live key set, exported with
+//! `Lsm::export_key_digest` so clients can skip lookups of
+//! keys which are definitely absent.
+//!
+//! # Layout
+//!
+//! All integers are little-endian.
+//!
+//! - magic: `b"LKD1"`
+//! - kind: u8, 0 bloom filter, 1 sorted key hashes
+//! - hashes: u8, probed bits per key of a bloom filter
+//! - bits_per_key: u8, of a bloom filter
+//! - reserved: u8
+//! - seed: u64, key hash seed
+//! - key_count: u64
+//! - seq: u64, store sequence number at export
+//! - generation: u64, newest sstable id at export
+//! - len: u64, number of u64 words which follow
+
+use std::io::{Read, Write};
+
+use crate::bloomfilter::{self, BloomFilter};
+use crate::error::{LSMLibError, Result};
+
+const MAGIC: &[u8; 4] = b"LKD1";
+
+/// Seed of the key hashes of exported digests.
+pub(crate) const DEFAULT_SEED: u64 = 0x6c73_6d6c_6962_0001;
+
+/// Kind of key digest to export.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyDigestKind {
+    /// Bloom filter with this many bits per key.
+    Bloom { bits_per_key: u8 },
+
+    /// Sorted 64 bits key hashes, exact but for hash collisions.
+    SortedHashes,
+}
+
+/// Header of an exported key digest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KeyDigestHeader {
+    pub kind: KeyDigestKind,
+
+    /// seed of the key hash.
+    pub seed: u64,
+
+    /// number of live keys at export.
+    pub key_count: u64,
+
+    /// store sequence number at export, newer writes are not covered.
+    pub seq: u64,
+
+    /// newest sstable id at export.
+    pub generation: u64,
+}
+
+impl KeyDigestHeader {
+    fn write_to<W: Write>(&self, w: &mut W, hashes: u8, len: u64) -> Result<()> {
+        let (kind, bits_per_key) = match self.kind {
+            KeyDigestKind::Bloom { bits_per_key } => (0u8, bits_per_key),
+            KeyDigestKind::SortedHashes => (1u8, 0),
+        };
+
+        w.write_all(MAGIC)?;
+        w.write_all(&[kind, hashes, bits_per_key, 0])?;
+        for v in [self.seed, self.key_count, self.seq, self.generation, len] {
+            w.write_all(&v.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds a digest from the keys, one at a time.
+pub(crate) enum DigestBuilder {
+    Bloom(BloomFilter),
+    SortedHashes(Vec<u64>),
+}
+
+impl DigestBuilder {
+    pub(crate) fn new(kind: KeyDigestKind, key_count: u64) -> Self {
+        match kind {
+            KeyDigestKind::Bloom { bits_per_key } => {
+                Self::Bloom(BloomFilter::new(key_count, bits_per_key, DEFAULT_SEED))
+            }
+            KeyDigestKind::SortedHashes => {
+                Self::SortedHashes(Vec::with_capacity(key_count as usize))
+            }
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: &[u8]) {
+        match self {
+            Self::Bloom(bloom) => bloom.insert(key),
+            Self::SortedHashes(hashes) => hashes.push(bloomfilter::hash(key, DEFAULT_SEED)),
+        }
+    }
+
+    /// Write header and digest to `w`.
+    pub(crate) fn finish<W: Write>(self, w: &mut W, header: &KeyDigestHeader) -> Result<()> {
+        match self {
+            Self::Bloom(bloom) => {
+                header.write_to(w, bloom.hashes() as u8, bloom.words().len() as u64)?;
+                write_words(w, bloom.words())
+            }
+            Self::SortedHashes(mut hashes) => {
+                hashes.sort_unstable();
+                hashes.dedup();
+                header.write_to(w, 0, hashes.len() as u64)?;
+                write_words(w, &hashes)
+            }
+        }
+    }
+}
+
+fn write_words<W: Write>(w: &mut W, words: &[u64]) -> Result<()> {
+    for chunk in words.chunks(1024) {
+        let buf: Vec<u8> = chunk.iter().flat_map(|v| v.to_le_bytes()).collect();
+        w.write_all(&buf)?;
+    }
+    Ok(())
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Client side reader of an exported key digest.
+pub struct KeyDigest {
+    header: KeyDigestHeader,
+    inner: DigestBuilder,
+}
+
+impl KeyDigest {
+    /// Read a digest written by `Lsm::export_key_digest`.
+    pub fn read_from<R: Read>(mut r: R) -> Result<Self> {
+        let invalid = |reason: &str| LSMLibError::Custom(format!("invalid key digest: {}", reason));
+
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+
+        let mut flags = [0u8; 4];
+        r.read_exact(&mut flags)?;
+        let (kind, hashes, bits_per_key) = (flags[0], flags[1], flags[2]);
+
+        let seed = read_u64(&mut r)?;
+        let key_count = read_u64(&mut r)?;
+        let seq = read_u64(&mut r)?;
+        let generation = read_u64(&mut r)?;
+        let len = read_u64(&mut r)?;
+
+        let mut words = Vec::new();
+        for _ in 0..len {
+            words.push(read_u64(&mut r)?);
+        }
+
+        let (kind, inner) = match kind {
+            0 => {
+                if words.is_empty() || hashes == 0 {
+                    return Err(invalid("empty bloom filter"));
+                }
+                (
+                    KeyDigestKind::Bloom { bits_per_key },
+                    DigestBuilder::Bloom(BloomFilter::from_parts(words, hashes as u32, seed)),
+                )
+            }
+            1 => {
+                if words.windows(2).any(|w| w[0] >= w[1]) {
+                    return Err(invalid("unsorted hashes"));
+                }
+                (
+                    KeyDigestKind::SortedHashes,
+                    DigestBuilder::SortedHashes(words),
+                )
+            }
+            _ => return Err(invalid("unknown kind")),
+        };
+
+        Ok(Self {
+            header: KeyDigestHeader {
+                kind,
+                seed,
+                key_count,
+                seq,
+                generation,
+            },
+            inner,
+        })
+    }
+
+    pub fn header(&self) -> &KeyDigestHeader {
+        &self.header
+    }
+
+    /// `false` if `key` was definitely absent at export.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        match &self.inner {
+            DigestBuilder::Bloom(bloom) => bloom.may_contain(key),
+            DigestBuilder::SortedHashes(hashes) => hashes
+                .binary_search(&bloomfilter::hash(key, self.header.seed))
+                .is_ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    use crate::lsm::{KVStore, OpenOptions};
+
+    #[test]
+    fn test_export_key_digest() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .max_log_length(4096)
+            .open(dir.path())
+            .unwrap();
+
+        // most keys flushed, the last ones and a delete in the memtable.
+        for i in 0..1000u32 {
+            lsm.put(i.to_be_bytes().to_vec(), vec![1; 8]).unwrap();
+        }
+        lsm.delete(&7u32.to_be_bytes()).unwrap();
+
+        for kind in [
+            KeyDigestKind::Bloom { bits_per_key: 10 },
+            KeyDigestKind::SortedHashes,
+        ] {
+            let mut buf = Vec::new();
+            let header = lsm.export_key_digest(&mut buf, kind).unwrap();
+            assert_eq!(header.key_count, 999);
+            assert!(header.generation > 0);
+
+            let digest = KeyDigest::read_from(buf.as_slice()).unwrap();
+            assert_eq!(digest.header(), &header);
+
+            for i in (0..1000u32).filter(|i| *i != 7) {
+                assert!(digest.may_contain(&i.to_be_bytes()));
+            }
+
+            let false_positives = (1000..11000u32)
+                .filter(|i| digest.may_contain(&i.to_be_bytes()))
+                .count();
+            match kind {
+                KeyDigestKind::SortedHashes => {
+                    assert!(!digest.may_contain(&7u32.to_be_bytes()));
+                    assert_eq!(false_positives, 0);
+                }
+                // about 1% expected with 10 bits per key.
+                KeyDigestKind::Bloom { .. } => assert!(false_positives < 300),
+            }
+        }
+
+        assert!(KeyDigest::read_from(&b"nope"[..]).is_err());
+    }
+}
@@ -0,0 +1,468 @@
+//! Key Range Export Module.
+//!
+//! Stream of key/value pairs written by `Lsm::export_range` and
+//! loaded by `Lsm::import_from`, e.g. to move a key range between
+//! shards.
+//!
+//! # Layout
+//!
+//! All integers are little-endian.
+//!
+//! - magic: `b"LKE3"`
+//! - seq: u64, store sequence number at export
+//! - source_uuid: u128, `StoreIdentity::uuid` of the store exported
+//! - source_generation: u64, its `StoreIdentity::generation`
+//! - records, each:
+//!   - key_len: u32
+//!   - key
+//!   - value chunks, each:
+//!     - chunk_len: u32, top bit `LAST_CHUNK` set on the last chunk
+//!     - chunk, at most `Config::export_chunk_size` bytes
+//! - end marker: u32 `0xFFFF_FFFF` in place of a key_len
+//! - count: u64, number of records
+//! - crc: u32, crc32 of all record bytes
+//!
+//! `b"LKE2"` exports, whose records are `key_len: u32`, `value_len: u32`,
+//! key and value, and `b"LKE1"` exports, without the source fields
+//! either, are still imported.
+
+use std::io::{Read, Write};
+
+use crate::error::{LSMLibError, Result};
+use crate::lsm::StoreIdentity;
+
+const MAGIC: &[u8; 4] = b"LKE3";
+const MAGIC_V2: &[u8; 4] = b"LKE2";
+const MAGIC_V1: &[u8; 4] = b"LKE1";
+const END_MARKER: u32 = u32::MAX;
+
+/// Bit of `chunk_len` flagging the last chunk of a value.
+const LAST_CHUNK: u32 = 1 << 31;
+
+/// What an export held.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ExportSummary {
+    /// store sequence number at export, newer writes are not covered.
+    pub seq: u64,
+
+    /// number of key/value pairs.
+    pub keys: u64,
+
+    /// key and value bytes.
+    pub bytes: u64,
+
+    /// uuid and generation of the store exported, 0 for an `LKE1`
+    /// export, see `Lsm::identity`.
+    pub source_uuid: u128,
+    pub source_generation: u64,
+}
+
+/// Streams key/value pairs in the export layout.
+pub(crate) struct ExportWriter<W: Write> {
+    w: W,
+    hasher: crc32fast::Hasher,
+    summary: ExportSummary,
+
+    /// buffer of one value chunk.
+    chunk: Vec<u8>,
+}
+
+impl<W: Write> ExportWriter<W> {
+    pub(crate) fn new(mut w: W, seq: u64, source: StoreIdentity, chunk_size: u32) -> Result<Self> {
+        w.write_all(MAGIC)?;
+        w.write_all(&seq.to_le_bytes())?;
+        w.write_all(&source.uuid.to_le_bytes())?;
+        w.write_all(&source.generation.to_le_bytes())?;
+
+        Ok(Self {
+            w,
+            hasher: crc32fast::Hasher::new(),
+            summary: ExportSummary {
+                seq,
+                source_uuid: source.uuid,
+                source_generation: source.generation,
+                ..ExportSummary::default()
+            },
+            chunk: vec![0; chunk_size as usize],
+        })
+    }
+
+    fn put(&mut self, buf: &[u8]) -> Result<()> {
+        self.hasher.update(buf);
+        self.w.write_all(buf)?;
+        Ok(())
+    }
+
+    /// Write the pair of `key` and the value `value` reads to its end,
+    /// one chunk at a time.
+    pub(crate) fn write(&mut self, key: &[u8], value: &mut impl Read) -> Result<()> {
+        self.put(&(key.len() as u32).to_le_bytes())?;
+        self.put(key)?;
+
+        let mut chunk = std::mem::take(&mut self.chunk);
+        let result = self.write_chunks(value, &mut chunk);
+        self.chunk = chunk;
+        let value_len = result?;
+
+        self.summary.keys += 1;
+        self.summary.bytes += key.len() as u64 + value_len;
+        Ok(())
+    }
+
+    /// Copy `value` as chunks, a short read meaning the end of it.
+    fn write_chunks(&mut self, value: &mut impl Read, chunk: &mut [u8]) -> Result<u64> {
+        let mut value_len = 0;
+        loop {
+            let n = read_full(value, chunk)?;
+            value_len += n as u64;
+
+            let last = n < chunk.len();
+            let chunk_len = if last {
+                n as u32 | LAST_CHUNK
+            } else {
+                n as u32
+            };
+            self.put(&chunk_len.to_le_bytes())?;
+            self.put(&chunk[..n])?;
+            if last {
+                return Ok(value_len);
+            }
+        }
+    }
+
+    /// Write the trailer and flush.
+    pub(crate) fn finish(mut self) -> Result<ExportSummary> {
+        self.w.write_all(&END_MARKER.to_le_bytes())?;
+        self.w.write_all(&self.summary.keys.to_le_bytes())?;
+        self.w.write_all(&self.hasher.finalize().to_le_bytes())?;
+        self.w.flush()?;
+
+        Ok(self.summary)
+    }
+}
+
+/// Reads key/value pairs in the export layout.
+///
+/// The trailer is checked once the last pair has been read,
+/// a truncated or corrupt export fails the last `next`.
+pub(crate) struct ExportReader<R: Read> {
+    r: R,
+    hasher: crc32fast::Hasher,
+    summary: ExportSummary,
+    done: bool,
+
+    /// whether values come in chunks, as in `LKE3` exports.
+    chunked: bool,
+}
+
+fn invalid(reason: &str) -> LSMLibError {
+    LSMLibError::Custom(format!("invalid export: {}", reason))
+}
+
+/// Read into `buf` until it is full or `r` ends, returning the bytes read.
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match r.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(read)
+}
+
+fn read_array<R: Read, const N: usize>(r: &mut R) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+impl<R: Read> ExportReader<R> {
+    pub(crate) fn new(mut r: R) -> Result<Self> {
+        let magic = read_array::<_, 4>(&mut r)?;
+        if ![MAGIC, MAGIC_V2, MAGIC_V1].contains(&&magic) {
+            return Err(invalid("bad magic"));
+        }
+        let seq = u64::from_le_bytes(read_array(&mut r)?);
+        let (source_uuid, source_generation) = match &magic != MAGIC_V1 {
+            true => (
+                u128::from_le_bytes(read_array(&mut r)?),
+                u64::from_le_bytes(read_array(&mut r)?),
+            ),
+            false => (0, 0),
+        };
+
+        Ok(Self {
+            r,
+            hasher: crc32fast::Hasher::new(),
+            summary: ExportSummary {
+                seq,
+                source_uuid,
+                source_generation,
+                ..ExportSummary::default()
+            },
+            done: false,
+            chunked: &magic == MAGIC,
+        })
+    }
+
+    /// Summary of the pairs read so far.
+    pub(crate) fn summary(&self) -> ExportSummary {
+        self.summary
+    }
+
+    fn read_pair(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let key_len = read_array::<_, 4>(&mut self.r)?;
+        if u32::from_le_bytes(key_len) == END_MARKER {
+            let count = u64::from_le_bytes(read_array(&mut self.r)?);
+            let crc = u32::from_le_bytes(read_array(&mut self.r)?);
+            if count != self.summary.keys {
+                return Err(invalid("record count mismatch"));
+            }
+            if crc != self.hasher.clone().finalize() {
+                return Err(invalid("crc mismatch"));
+            }
+            return Ok(None);
+        }
+        self.hasher.update(&key_len);
+
+        let (key, value) = match self.chunked {
+            true => {
+                let key = self.read_hashed(u32::from_le_bytes(key_len))?;
+                (key, self.read_chunks()?)
+            }
+            false => {
+                let value_len = read_array::<_, 4>(&mut self.r)?;
+                self.hasher.update(&value_len);
+                let key = self.read_hashed(u32::from_le_bytes(key_len))?;
+                (key, self.read_hashed(u32::from_le_bytes(value_len))?)
+            }
+        };
+        self.summary.keys += 1;
+        self.summary.bytes += (key.len() + value.len()) as u64;
+
+        Ok(Some((key, value)))
+    }
+
+    /// Read `len` bytes, growing the buffer as they come rather than
+    /// trusting `len` up front.
+    fn read_hashed(&mut self, len: u32) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        (&mut self.r).take(len.into()).read_to_end(&mut buf)?;
+        if buf.len() != len as usize {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        self.hasher.update(&buf);
+        Ok(buf)
+    }
+
+    /// Reassemble a chunked value.
+    fn read_chunks(&mut self) -> Result<Vec<u8>> {
+        let mut value = Vec::new();
+        loop {
+            let chunk_len = match read_array::<_, 4>(&mut self.r) {
+                Ok(buf) => u32::from_le_bytes(buf),
+                Err(LSMLibError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Err(invalid("missing final chunk"));
+                }
+                Err(e) => return Err(e),
+            };
+            if chunk_len == END_MARKER {
+                return Err(invalid("missing final chunk"));
+            }
+            self.hasher.update(&chunk_len.to_le_bytes());
+
+            value.extend(self.read_hashed(chunk_len & !LAST_CHUNK)?);
+            if chunk_len & LAST_CHUNK != 0 {
+                return Ok(value);
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for ExportReader<R> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let pair = self.read_pair();
+        if !matches!(pair, Ok(Some(_))) {
+            self.done = true;
+        }
+        pair.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use crate::lsm::{KVStore, LSMLibError, OpenOptions};
+
+    #[test]
+    fn test_export_range() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut source = OpenOptions::new()
+            .max_log_length(256)
+            .open(dir.path())
+            .unwrap();
+
+        // flushed and in the memtable.
+        for c in b'a'..=b'z' {
+            source.put(vec![c], vec![c; 16]).unwrap();
+        }
+        source.delete(b"d").unwrap();
+
+        let range = b"c".to_vec()..b"g".to_vec();
+        let mut buf = Vec::new();
+        let summary = source.export_range(range.clone(), &mut buf).unwrap();
+        assert_eq!(summary.keys, 3);
+        assert_eq!(summary.bytes, 3 * 17);
+        assert_eq!(summary.source_uuid, source.identity().uuid);
+        assert_eq!(summary.source_generation, 1);
+
+        let target_dir = TempDir::new("lsmlib").unwrap();
+        let mut target = OpenOptions::new().open(target_dir.path()).unwrap();
+        assert_eq!(target.import_from(buf.as_slice()).unwrap(), summary);
+        assert_eq!(
+            target.list_keys().unwrap(),
+            vec![b"c".to_vec(), b"e".to_vec(), b"f".to_vec()]
+        );
+        assert_eq!(target.get(b"e").unwrap(), Some(vec![b'e'; 16]));
+
+        // a truncated export fails.
+        let mut truncated = target.import_from(&buf[..buf.len() - 1]);
+        assert!(truncated.is_err());
+        // a byte of the first record.
+        buf[44] ^= 0xFF;
+        truncated = target.import_from(buf.as_slice());
+        assert!(truncated.is_err());
+
+        // a write after the export blocks the delete.
+        source.put(b"e".to_vec(), b"new".to_vec()).unwrap();
+        assert!(matches!(
+            source.delete_exported_range(range.clone(), summary.seq, false),
+            Err(LSMLibError::RangeConflict { conflicts: 1 })
+        ));
+        assert_eq!(
+            source
+                .delete_exported_range(range.clone(), summary.seq, true)
+                .unwrap(),
+            3
+        );
+
+        let keys = source.list_keys().unwrap();
+        assert_eq!(keys.len(), 22);
+        assert!(keys.iter().all(|k| !range.contains(k)));
+        assert_eq!(source.get(b"g").unwrap(), Some(vec![b'g'; 16]));
+    }
+
+    #[test]
+    fn test_export_chunked() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut source = OpenOptions::new()
+            .export_chunk_size(1024)
+            .open(dir.path())
+            .unwrap();
+
+        // incompressible, compressed in an sstable, in the memtable.
+        let mut x = 1u32;
+        let noise: Vec<u8> = (0..5000)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (x >> 16) as u8
+            })
+            .collect();
+        source.put(b"a".to_vec(), noise.clone()).unwrap();
+        source.put(b"b".to_vec(), vec![b'b'; 4096]).unwrap();
+        source.flush().unwrap();
+        source.put(b"c".to_vec(), vec![b'c'; 3000]).unwrap();
+        source.put(b"d".to_vec(), b"d".to_vec()).unwrap();
+
+        let mut buf = Vec::new();
+        let summary = source.export_range(.., &mut buf).unwrap();
+        assert_eq!(summary.keys, 4);
+        assert_eq!(summary.bytes, 4 + 5000 + 4096 + 3000 + 1);
+
+        // chunk lengths of each record.
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        let mut chunks = Vec::new();
+        let mut pos = 36;
+        while u32_at(pos) != super::END_MARKER {
+            pos += 4 + u32_at(pos) as usize;
+            let mut lens = Vec::new();
+            loop {
+                let len = u32_at(pos);
+                lens.push(len & !super::LAST_CHUNK);
+                pos += 4 + (len & !super::LAST_CHUNK) as usize;
+                if len & super::LAST_CHUNK != 0 {
+                    break;
+                }
+            }
+            chunks.push(lens);
+        }
+        assert_eq!(
+            chunks,
+            vec![
+                vec![1024, 1024, 1024, 1024, 904],
+                vec![1024, 1024, 1024, 1024, 0],
+                vec![1024, 1024, 952],
+                vec![1],
+            ]
+        );
+
+        let target_dir = TempDir::new("lsmlib").unwrap();
+        let mut target = OpenOptions::new().open(target_dir.path()).unwrap();
+        assert_eq!(target.import_from(buf.as_slice()).unwrap(), summary);
+        assert_eq!(target.get(b"a").unwrap(), Some(noise));
+        assert_eq!(target.get(b"b").unwrap(), Some(vec![b'b'; 4096]));
+        assert_eq!(target.get(b"c").unwrap(), Some(vec![b'c'; 3000]));
+
+        // a record cut after its first chunk, then the trailer.
+        let mut cut = buf[..36 + 4 + 1 + 4 + 1024].to_vec();
+        cut.extend_from_slice(&buf[pos..]);
+        let err = target.import_from(cut.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("missing final chunk"), "{}", err);
+        let err = target
+            .import_from(&buf[..36 + 4 + 1 + 4 + 1024])
+            .unwrap_err();
+        assert!(err.to_string().contains("missing final chunk"), "{}", err);
+    }
+
+    #[test]
+    fn test_import_v1() {
+        let mut buf = b"LKE1".to_vec();
+        buf.extend_from_slice(&7u64.to_le_bytes());
+        let record = [&1u32.to_le_bytes()[..], &1u32.to_le_bytes(), b"k", b"v"].concat();
+        buf.extend_from_slice(&record);
+        buf.extend_from_slice(&u32::MAX.to_le_bytes());
+        buf.extend_from_slice(&1u64.to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&record).to_le_bytes());
+
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut target = OpenOptions::new().open(dir.path()).unwrap();
+        let summary = target.import_from(buf.as_slice()).unwrap();
+        assert_eq!((summary.seq, summary.keys, summary.source_uuid), (7, 1, 0));
+        assert_eq!(target.get(b"k").unwrap(), Some(b"v".to_vec()));
+
+        // with the source fields, values still whole.
+        let mut buf = b"LKE2".to_vec();
+        buf.extend_from_slice(&8u64.to_le_bytes());
+        buf.extend_from_slice(&9u128.to_le_bytes());
+        buf.extend_from_slice(&2u64.to_le_bytes());
+        let record = [&1u32.to_le_bytes()[..], &2u32.to_le_bytes(), b"k", b"v2"].concat();
+        buf.extend_from_slice(&record);
+        buf.extend_from_slice(&u32::MAX.to_le_bytes());
+        buf.extend_from_slice(&1u64.to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&record).to_le_bytes());
+
+        let summary = target.import_from(buf.as_slice()).unwrap();
+        assert_eq!((summary.seq, summary.source_uuid, summary.bytes), (8, 9, 3));
+        assert_eq!(target.get(b"k").unwrap(), Some(b"v2".to_vec()));
+    }
+}
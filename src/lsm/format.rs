@@ -0,0 +1,107 @@
+//! On-disk Format Module.
+//!
+//! Entry types appearing in the `Storage` trait, so stores can be
+//! implemented outside of this crate. Fields are reachable through
+//! methods only, each marked stable or experimental; experimental
+//! ones may change with the format version.
+//!
+//! # Examples
+//!
+//! A store keeping entries in memory:
+//!
+//! ```
+//! use std::collections::BTreeMap;
+//!
+//! use slmlib::lsm::format::DiskEntry;
+//! use slmlib::lsm::{FlushHandle, Result, Storage};
+//!
+//! #[derive(Default)]
+//! struct MemStorage {
+//!     items: BTreeMap<Vec<u8>, Vec<u8>>,
+//!     flushes: u64,
+//! }
+//!
+//! struct MemFlush<'a> {
+//!     store: &'a mut MemStorage,
+//!     written: Vec<DiskEntry>,
+//! }
+//!
+//! impl FlushHandle for MemFlush<'_> {
+//!     fn write(&mut self, _key: &[u8], entry: &DiskEntry) -> Result<()> {
+//!         self.written.push(entry.clone());
+//!         Ok(())
+//!     }
+//!
+//!     fn finish(self) -> Result<(u64, u64)> {
+//!         let size = self.written.iter().map(DiskEntry::size).sum();
+//!         for entry in self.written {
+//!             if entry.is_tombstone() {
+//!                 self.store.items.remove(entry.key());
+//!             } else {
+//!                 self.store.items.insert(entry.key().to_vec(), entry.value().to_vec());
+//!             }
+//!         }
+//!         self.store.flushes += 1;
+//!         Ok((self.store.flushes, size))
+//!     }
+//! }
+//!
+//! impl Storage for MemStorage {
+//!     type Flush<'a> = MemFlush<'a>;
+//!
+//!     fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+//!         Ok(self.items.get(key).cloned())
+//!     }
+//!
+//!     fn begin_flush(&mut self) -> Result<MemFlush<'_>> {
+//!         Ok(MemFlush {
+//!             store: self,
+//!             written: Vec::new(),
+//!         })
+//!     }
+//!
+//!     fn keys(&self) -> Result<Vec<Vec<u8>>> {
+//!         Ok(self.items.keys().cloned().collect())
+//!     }
+//!
+//!     fn len(&self) -> u64 {
+//!         self.items.len() as u64
+//!     }
+//!
+//!     fn is_empty(&self) -> bool {
+//!         self.items.is_empty()
+//!     }
+//!
+//!     fn contains_key(&self, key: &[u8]) -> bool {
+//!         self.items.contains_key(key)
+//!     }
+//!
+//!     fn for_each<F>(&self, f: &mut F) -> Result<()>
+//!     where
+//!         F: FnMut(&[u8], &[u8]) -> Result<bool>,
+//!     {
+//!         for (k, v) in &self.items {
+//!             if !f(k, v)? {
+//!                 break;
+//!             }
+//!         }
+//!         Ok(())
+//!     }
+//!
+//!     fn flush(&mut self) -> Result<()> {
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let mut store = MemStorage::default();
+//! let mut items = BTreeMap::new();
+//! items.insert(b"a".to_vec(), DiskEntry::new(b"a".to_vec(), b"1".to_vec()));
+//! items.insert(b"b".to_vec(), DiskEntry::new(b"b".to_vec(), vec![]));
+//!
+//! assert_eq!(store.set(&items).unwrap(), (1, items.values().map(DiskEntry::size).sum()));
+//! assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+//! assert!(!store.contains_key(b"b"));
+//! ```
+
+pub use crate::disk::format::{DiskEntry, HintEntry, RangeTombstone};
+pub use crate::keydir::KeydirEntry;
@@ -0,0 +1,216 @@
+//! Store Identity Module.
+//!
+//! Who a store is, for callers caching by store: a UUID drawn when the
+//! store is created, unchanged across reopens but not carried over to
+//! clones or rewrites, and a generation bumped by every read-write open.
+//!
+//! The identity lives in the `IDENTITY` metadata file of the store dir,
+//! see `MetaFileWriter`: a single record of the UUID as u128, then the
+//! creation time and the generation as u64, little endian. A file of
+//! the former single line `<uuid> <created_at> <generation>` is still
+//! read, and replaced at the next read-write open. Stores created
+//! before identities were recorded get one at their next read-write
+//! open.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+
+use crate::config;
+use crate::disk::format::{MetaFile, MetaFileReader, MetaFileWriter};
+use crate::error::{LSMLibError, Result};
+
+/// Identity of a store, see `Lsm::identity`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct StoreIdentity {
+    /// random version 4 UUID, 0 for a store opened read only before
+    /// it was given one.
+    pub uuid: u128,
+
+    /// number of read-write opens, this one included.
+    pub generation: u64,
+
+    /// seconds since the unix epoch.
+    pub created_at: u64,
+}
+
+impl StoreIdentity {
+    fn new(created_at: u32) -> Self {
+        Self {
+            uuid: random_uuid(),
+            generation: 0,
+            created_at: created_at.into(),
+        }
+    }
+
+    /// The UUID in its hyphenated form.
+    pub fn uuid_string(&self) -> String {
+        format_uuid(self.uuid)
+    }
+}
+
+pub(crate) fn format_uuid(uuid: u128) -> String {
+    let hex = format!("{:032x}", uuid);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+impl fmt::Display for StoreIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} generation {}", self.uuid_string(), self.generation)
+    }
+}
+
+/// Version 4 UUID from the per-process random keys of the std hasher.
+fn random_uuid() -> u128 {
+    let half = |salt: u64| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(salt);
+        hasher.write_u128(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos()),
+        );
+        hasher.write_u32(std::process::id());
+        hasher.finish() as u128
+    };
+    let uuid = (half(0) << 64) | half(1);
+
+    // version 4, variant 1.
+    (uuid & !(0xF << 76) & !(0x3 << 62)) | (0x4 << 76) | (0x2 << 62)
+}
+
+const IDENTITY_MAGIC: &[u8; 4] = b"IDNT";
+const IDENTITY_VERSION: u32 = 1;
+
+fn read(dir: &Path) -> Result<Option<StoreIdentity>> {
+    let path = dir.join(config::IDENTITY_FILE);
+    let mut reader = match MetaFileReader::open_or_legacy(&path, IDENTITY_MAGIC, IDENTITY_VERSION)?
+    {
+        Some(MetaFile::Framed(reader)) => reader,
+        Some(MetaFile::Legacy(text)) => return parse_legacy(&path, &text).map(Some),
+        None => return Ok(None),
+    };
+
+    let record = reader.record()?;
+    if record.len() != 32 {
+        return Err(reader.invalid());
+    }
+    Ok(Some(StoreIdentity {
+        uuid: u128::from_le_bytes(record[..16].try_into().unwrap()),
+        created_at: u64::from_le_bytes(record[16..24].try_into().unwrap()),
+        generation: u64::from_le_bytes(record[24..].try_into().unwrap()),
+    }))
+}
+
+/// Identity in the text format of before the framing, a single line
+/// `<uuid> <created_at> <generation>`.
+fn parse_legacy(path: &Path, text: &str) -> Result<StoreIdentity> {
+    let invalid = || LSMLibError::Custom(format!("invalid identity file {}", path.display()));
+
+    let mut fields = text.trim_end_matches('\n').split(' ');
+    let (Some(uuid), Some(created_at), Some(generation), None) =
+        (fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        return Err(invalid());
+    };
+
+    Ok(StoreIdentity {
+        uuid: u128::from_str_radix(&uuid.replace('-', ""), 16).map_err(|_| invalid())?,
+        generation: generation.parse()?,
+        created_at: created_at.parse()?,
+    })
+}
+
+fn write(dir: &Path, identity: &StoreIdentity, file_mode: Option<u32>) -> Result<()> {
+    let path = dir.join(config::IDENTITY_FILE);
+    let mut writer = MetaFileWriter::create(&path, IDENTITY_MAGIC, IDENTITY_VERSION, file_mode)?;
+
+    let mut record = identity.uuid.to_le_bytes().to_vec();
+    record.extend_from_slice(&identity.created_at.to_le_bytes());
+    record.extend_from_slice(&identity.generation.to_le_bytes());
+    writer.append(&record)?;
+
+    writer.finish()
+}
+
+/// Identity of the store at `dir`, created at `now` if missing and
+/// its generation bumped unless `read_only`.
+pub(crate) fn open(
+    dir: &Path,
+    file_mode: Option<u32>,
+    read_only: bool,
+    now: u32,
+) -> Result<StoreIdentity> {
+    let stored = read(dir)?;
+    if read_only {
+        return Ok(stored.unwrap_or_default());
+    }
+
+    let mut identity = stored.unwrap_or_else(|| StoreIdentity::new(now));
+    identity.generation += 1;
+    write(dir, &identity, file_mode)?;
+
+    Ok(identity)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_identity() {
+        let dir = TempDir::new("lsmlib").unwrap();
+
+        assert_eq!(
+            open(dir.path(), None, true, 100).unwrap(),
+            StoreIdentity::default()
+        );
+        let first = open(dir.path(), None, false, 100).unwrap();
+        assert_eq!((first.generation, first.created_at), (1, 100));
+        assert_eq!(first.uuid >> 76 & 0xF, 4);
+
+        let second = open(dir.path(), None, false, 200).unwrap();
+        assert_eq!(
+            (second.uuid, second.created_at),
+            (first.uuid, first.created_at)
+        );
+        assert_eq!(second.generation, 2);
+        assert_eq!(open(dir.path(), None, true, 300).unwrap(), second);
+
+        // wiped and recreated.
+        fs::remove_file(dir.path().join(config::IDENTITY_FILE)).unwrap();
+        let recreated = open(dir.path(), None, false, 400).unwrap();
+        assert_ne!(recreated.uuid, first.uuid);
+        assert_eq!((recreated.generation, recreated.created_at), (1, 400));
+    }
+
+    #[test]
+    fn test_identity_legacy_file() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let path = dir.path().join(config::IDENTITY_FILE);
+        fs::write(&path, "0a1b2c3d-0000-4000-8000-00000000000f 100 3\n").unwrap();
+
+        let identity = open(dir.path(), None, false, 200).unwrap();
+        assert_eq!(
+            identity.uuid_string(),
+            "0a1b2c3d-0000-4000-8000-00000000000f"
+        );
+        assert_eq!((identity.created_at, identity.generation), (100, 4));
+
+        // rewritten framed.
+        assert!(fs::read(&path).unwrap().starts_with(IDENTITY_MAGIC));
+        assert_eq!(read(dir.path()).unwrap(), Some(identity));
+    }
+}
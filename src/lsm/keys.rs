@@ -0,0 +1,143 @@
+//! Key Encoding Module.
+//!
+//! Order-preserving key encodings: the lexicographic byte order of
+//! encoded keys matches the natural order of the encoded values,
+//! which is what range and prefix scans rely on.
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ESCAPE: u8 = 0xFF;
+const TERMINATOR: u8 = 0x01;
+
+/// Encode `v` big-endian so byte order matches numeric order.
+pub fn encode_u64(v: u64) -> [u8; 8] {
+    v.to_be_bytes()
+}
+
+/// Decode a key produced by `encode_u64`.
+pub fn decode_u64(buf: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(buf.try_into().ok()?))
+}
+
+/// Encode `v` with the sign bit flipped so negative numbers sort first.
+pub fn encode_i64(v: i64) -> [u8; 8] {
+    ((v as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+/// Decode a key produced by `encode_i64`.
+pub fn decode_i64(buf: &[u8]) -> Option<i64> {
+    decode_u64(buf).map(|v| (v ^ (1 << 63)) as i64)
+}
+
+/// Key made of several components, ordered component by component.
+///
+/// Every component is escaped (`0x00` becomes `0x00 0xFF`) and terminated
+/// by `0x00 0x01`, so a component sorts before any longer component it is
+/// a prefix of, and no component can bleed into the next one.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CompositeKey {
+    buf: Vec<u8>,
+}
+
+impl CompositeKey {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a raw bytes component.
+    pub fn push(mut self, component: impl AsRef<[u8]>) -> Self {
+        for b in component.as_ref() {
+            self.buf.push(*b);
+            if *b == ESCAPE {
+                self.buf.push(ESCAPED_ESCAPE);
+            }
+        }
+        self.buf.extend_from_slice(&[ESCAPE, TERMINATOR]);
+        self
+    }
+
+    /// Append an unsigned integer component.
+    pub fn push_u64(self, v: u64) -> Self {
+        self.push(encode_u64(v))
+    }
+
+    /// Append a signed integer component.
+    pub fn push_i64(self, v: i64) -> Self {
+        self.push(encode_i64(v))
+    }
+
+    /// Return the encoded key.
+    pub fn build(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// Split an encoded key back into its raw components.
+    pub fn decode(key: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let mut components = Vec::new();
+        let mut component = Vec::new();
+
+        let mut iter = key.iter();
+        while let Some(b) = iter.next() {
+            if *b != ESCAPE {
+                component.push(*b);
+                continue;
+            }
+
+            match *iter.next()? {
+                ESCAPED_ESCAPE => component.push(ESCAPE),
+                TERMINATOR => components.push(std::mem::take(&mut component)),
+                _ => return None,
+            }
+        }
+
+        if !component.is_empty() {
+            return None;
+        }
+
+        Some(components)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_u64_round_trip_and_order(a: u64, b: u64) {
+            prop_assert_eq!(decode_u64(&encode_u64(a)), Some(a));
+            prop_assert_eq!(a.cmp(&b), encode_u64(a).cmp(&encode_u64(b)));
+        }
+
+        #[test]
+        fn test_i64_round_trip_and_order(a: i64, b: i64) {
+            prop_assert_eq!(decode_i64(&encode_i64(a)), Some(a));
+            prop_assert_eq!(a.cmp(&b), encode_i64(a).cmp(&encode_i64(b)));
+        }
+
+        #[test]
+        fn test_composite_round_trip_and_order(
+            a in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..8), 0..4),
+            b in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..8), 0..4),
+        ) {
+            let encode = |components: &Vec<Vec<u8>>| {
+                components
+                    .iter()
+                    .fold(CompositeKey::new(), |key, c| key.push(c))
+                    .build()
+            };
+            let (ka, kb) = (encode(&a), encode(&b));
+
+            prop_assert_eq!(CompositeKey::decode(&ka), Some(a.clone()));
+            prop_assert_eq!(a.cmp(&b), ka.cmp(&kb));
+        }
+    }
+
+    #[test]
+    fn test_composite_decode_invalid() {
+        assert_eq!(CompositeKey::decode(b"abc"), None);
+        assert_eq!(CompositeKey::decode(&[b'a', ESCAPE, 0x02]), None);
+        assert_eq!(CompositeKey::decode(&[]), Some(vec![]));
+    }
+}
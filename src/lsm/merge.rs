@@ -0,0 +1,210 @@
+//! Merge Module.
+//!
+//! Read-modify-write of a key in one call, e.g. for counters, see
+//! `Lsm::merge`.
+//!
+//! A merge is logged as an operand, not as the value it makes: the WAL
+//! holds one entry flagged with `MERGE_FLAG` per operand, folded in the
+//! memtable into a single entry of every unflushed operand of its key.
+//! Reads apply them to the value below, as of the read.
+//!
+//! The keydir only knows the newest version of each key, so a flush
+//! settles the operands on the value they apply to, written along in
+//! the same entry. Compaction collapses such an entry into the value
+//! it makes, see `CompactMergeIter`.
+//!
+//! The value of an entry flagged with `MERGE_FLAG` is its base, a u8 tag
+//! `BASE_BELOW`, `BASE_ABSENT` or `BASE_VALUE` the latter followed by
+//! the u32 length of the value and the value, then each operand, oldest
+//! first, as its u32 length and the operand.
+
+use std::sync::Arc;
+
+use crate::disk::format::DiskEntry;
+use crate::error::{LSMLibError, Result};
+
+/// New value of `key` from its current value, if any, and `operand`,
+/// `None` deleting the key, see `OpenOptions::merge_operator`.
+pub type MergeOperatorFn =
+    Arc<dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+const BASE_BELOW: u8 = 0;
+const BASE_ABSENT: u8 = 1;
+const BASE_VALUE: u8 = 2;
+
+/// Value the operands of a merge entry apply to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Base<'a> {
+    /// the version of the key below the entry, whatever it is when read.
+    Below,
+    Absent,
+    Value(&'a [u8]),
+}
+
+/// Operands of a merge entry over their base, borrowed from its value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Operands<'a> {
+    pub(crate) base: Base<'a>,
+
+    /// operands, oldest first.
+    pub(crate) operands: Vec<&'a [u8]>,
+}
+
+impl<'a> Operands<'a> {
+    /// Operands encoded in the value of a merge entry.
+    pub(crate) fn decode(mut buf: &'a [u8]) -> Result<Self> {
+        let invalid = || LSMLibError::Custom("invalid merge operands".to_string());
+        let field = |buf: &mut &'a [u8]| -> Result<&'a [u8]> {
+            let len = buf
+                .get(..4)
+                .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+                .ok_or_else(invalid)?;
+            let field = buf.get(4..4 + len).ok_or_else(invalid)?;
+            *buf = &buf[4 + len..];
+            Ok(field)
+        };
+
+        let (tag, rest) = buf.split_first().ok_or_else(invalid)?;
+        buf = rest;
+        let base = match *tag {
+            BASE_BELOW => Base::Below,
+            BASE_ABSENT => Base::Absent,
+            BASE_VALUE => Base::Value(field(&mut buf)?),
+            _ => return Err(invalid()),
+        };
+        let mut operands = Vec::new();
+        while !buf.is_empty() {
+            operands.push(field(&mut buf)?);
+        }
+
+        Ok(Self { base, operands })
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let field = |buf: &mut Vec<u8>, field: &[u8]| {
+            buf.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            buf.extend_from_slice(field);
+        };
+
+        match self.base {
+            Base::Below => buf.push(BASE_BELOW),
+            Base::Absent => buf.push(BASE_ABSENT),
+            Base::Value(value) => {
+                buf.push(BASE_VALUE);
+                field(&mut buf, value);
+            }
+        }
+        for operand in &self.operands {
+            field(&mut buf, operand);
+        }
+        buf
+    }
+
+    /// Value of `key` once the operands are applied to their base,
+    /// `below` standing for `Base::Below`. `None`, or an empty value,
+    /// deletes the key.
+    pub(crate) fn resolve(
+        &self,
+        operator: &MergeOperatorFn,
+        key: &[u8],
+        below: Option<Vec<u8>>,
+    ) -> Option<Vec<u8>> {
+        let mut value = match self.base {
+            Base::Below => below,
+            Base::Absent => None,
+            Base::Value(value) => Some(value.to_vec()),
+        };
+        for operand in &self.operands {
+            value = operator(key, value.as_deref(), operand).filter(|v| !v.is_empty());
+        }
+        value
+    }
+}
+
+/// Merge entry of the unflushed operands of a key: `entry` folded into
+/// `older`, the version of its key it was logged over, if in memory.
+///
+/// Operands over `Base::Below` apply to `older`: they are appended to
+/// its operands, or take a put as their base, a tombstone as absent.
+/// The fold keeps the seq and timestamp of `entry`.
+pub(crate) fn fold(older: Option<&DiskEntry>, entry: DiskEntry) -> Result<DiskEntry> {
+    let operands = Operands::decode(&entry.value)?;
+    let older = match (operands.base, older) {
+        (Base::Below, Some(older)) if !older.is_touch() => older,
+        _ => return Ok(entry),
+    };
+
+    let folded = match older.is_merge() {
+        true => {
+            let mut folded = Operands::decode(&older.value)?;
+            folded.operands.extend(operands.operands);
+            folded
+        }
+        false => Operands {
+            base: match older.is_tombstone() {
+                true => Base::Absent,
+                false => Base::Value(&older.value),
+            },
+            operands: operands.operands,
+        },
+    };
+
+    Ok(
+        DiskEntry::merge(entry.key.clone(), folded.encode(), entry.timestamp())
+            .with_seq(entry.seq()),
+    )
+}
+
+/// Put of `value`, made by the operands of `entry`, in place of it, a
+/// tombstone if `None`. Keeps the seq, timestamp and expiry of `entry`.
+pub(crate) fn collapsed(entry: &DiskEntry, value: Option<Vec<u8>>) -> DiskEntry {
+    match value {
+        Some(value) => DiskEntry::stamped(entry.key.clone(), value, entry.timestamp())
+            .with_expiry(entry.expiry()),
+        None => DiskEntry::stamped(entry.key.clone(), Vec::new(), entry.timestamp()),
+    }
+    .with_seq(entry.seq())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fold() {
+        let concat: MergeOperatorFn =
+            Arc::new(|_, existing, operand| Some([existing.unwrap_or_default(), operand].concat()));
+        let operand = |operand: &[u8], seq| {
+            let operands = Operands {
+                base: Base::Below,
+                operands: vec![operand],
+            };
+            DiskEntry::merge(b"k".to_vec(), operands.encode(), 0).with_seq(seq)
+        };
+        let resolve = |entry: &DiskEntry, below: Option<&[u8]>| {
+            Operands::decode(&entry.value).unwrap().resolve(
+                &concat,
+                b"k",
+                below.map(<[u8]>::to_vec),
+            )
+        };
+
+        // over nothing in memory the operands stay over the value below.
+        let a = fold(None, operand(b"a", 1)).unwrap();
+        let ab = fold(Some(&a), operand(b"b", 2)).unwrap();
+        assert_eq!(ab.seq(), 2);
+        assert_eq!(resolve(&ab, Some(b"x")), Some(b"xab".to_vec()));
+        assert_eq!(resolve(&ab, None), Some(b"ab".to_vec()));
+
+        // a put or a tombstone in memory is their base.
+        let put = DiskEntry::new(b"k".to_vec(), b"p".to_vec());
+        let pb = fold(Some(&put), operand(b"b", 2)).unwrap();
+        assert_eq!(resolve(&pb, Some(b"x")), Some(b"pb".to_vec()));
+        let tombstone = DiskEntry::new(b"k".to_vec(), Vec::new());
+        let b = fold(Some(&tombstone), operand(b"b", 2)).unwrap();
+        assert_eq!(resolve(&b, Some(b"x")), Some(b"b".to_vec()));
+
+        assert!(Operands::decode(&[BASE_VALUE, 9, 0, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! Write Observer Module.
+//!
+//! Callback invoked for every mutation, see `OpenOptions::write_observer`.
+//!
+//! # Ordering
+//!
+//! A mutation is delivered once appended to the WAL, before the call
+//! writing it returns, so events follow the WAL commit order, which is
+//! the sequence number order. Flushes and compactions rewrite entries
+//! without delivering them again.
+//!
+//! # Recovery
+//!
+//! Events are at-most-once by default: a mutation appended to the WAL
+//! right before a crash may never be delivered. With
+//! `Config::replay_writes_on_open`, every mutation recovered from the
+//! WAL at open is delivered again, marked `recovered`, in WAL order,
+//! making events at-least-once: the observer must tolerate recovered
+//! events it already saw.
+
+use std::sync::Arc;
+
+use crate::disk::format::DiskEntry;
+use crate::lsm::merge::Operands;
+
+/// Mutation of a `WriteEvent`, keys as stored. A `Touch` moves the
+/// expiry of its key to `expiry`, 0 for never, see `Lsm::touch`. A
+/// `Merge` carries the operand of `Lsm::merge`, not the value it makes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WriteOp<'a> {
+    Put { key: &'a [u8], value: &'a [u8] },
+    Delete { key: &'a [u8] },
+    DeleteRange { start: &'a [u8], end: &'a [u8] },
+    Touch { key: &'a [u8], expiry: u32 },
+    Merge { key: &'a [u8], operand: &'a [u8] },
+}
+
+/// Mutation delivered to a write observer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WriteEvent<'a> {
+    pub op: WriteOp<'a>,
+
+    /// sequence number of the mutation.
+    pub seq: u64,
+
+    /// whether the mutation is replayed from the WAL at open.
+    pub recovered: bool,
+}
+
+/// Callback of every mutation, see `OpenOptions::write_observer`.
+pub type WriteObserverFn = Arc<dyn Fn(&WriteEvent<'_>) + Send + Sync>;
+
+/// Deliver the mutation of WAL `entry` to `observer`.
+pub(crate) fn notify(observer: &WriteObserverFn, entry: &DiskEntry, recovered: bool) {
+    let tombstone = entry.range_tombstone();
+    let op = match &tombstone {
+        Some(t) => WriteOp::DeleteRange {
+            start: &t.start,
+            end: &t.end,
+        },
+        None if entry.is_tombstone() => WriteOp::Delete { key: &entry.key },
+        None if entry.is_touch() => WriteOp::Touch {
+            key: &entry.key,
+            expiry: entry.expiry(),
+        },
+        // logged one operand per entry.
+        None if entry.is_merge() => WriteOp::Merge {
+            key: &entry.key,
+            operand: Operands::decode(&entry.value)
+                .ok()
+                .and_then(|o| o.operands.last().copied())
+                .unwrap_or_default(),
+        },
+        None => WriteOp::Put {
+            key: &entry.key,
+            value: &entry.value,
+        },
+    };
+
+    observer(&WriteEvent {
+        op,
+        seq: entry.seq(),
+        recovered,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    use tempdir::TempDir;
+
+    use crate::lsm::{KVStore, OpenOptions};
+
+    type Events = Arc<Mutex<Vec<(Vec<u8>, u64, bool)>>>;
+
+    fn recorder(events: &Events) -> WriteObserverFn {
+        let events = Arc::clone(events);
+        Arc::new(move |event: &WriteEvent<'_>| {
+            let key = match event.op {
+                WriteOp::Put { key, .. }
+                | WriteOp::Delete { key }
+                | WriteOp::Touch { key, .. }
+                | WriteOp::Merge { key, .. } => key,
+                WriteOp::DeleteRange { start, .. } => start,
+            };
+            events
+                .lock()
+                .unwrap()
+                .push((key.to_vec(), event.seq, event.recovered));
+        })
+    }
+
+    #[test]
+    fn test_replay_writes_on_open() {
+        let dir = TempDir::new("lsmlib").unwrap();
+
+        // the process dies after the WAL append of "b", before its event.
+        let armed = Arc::new(AtomicBool::new(true));
+        let observer: WriteObserverFn = {
+            let armed = Arc::clone(&armed);
+            Arc::new(move |event: &WriteEvent<'_>| {
+                if event.op
+                    == (WriteOp::Put {
+                        key: b"b",
+                        value: b"2",
+                    })
+                    && armed.swap(false, Ordering::SeqCst)
+                {
+                    panic!("crashed before delivery");
+                }
+            })
+        };
+        let mut lsm = OpenOptions::new()
+            .write_observer(observer)
+            .open(dir.path())
+            .unwrap();
+        lsm.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        let crashed =
+            panic::catch_unwind(AssertUnwindSafe(|| lsm.put(b"b".to_vec(), b"2".to_vec())));
+        assert!(crashed.is_err());
+        drop(lsm);
+
+        let events = Events::default();
+        let mut lsm = OpenOptions::new()
+            .write_observer(recorder(&events))
+            .replay_writes_on_open(true)
+            .open(dir.path())
+            .unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![(b"a".to_vec(), 1, true), (b"b".to_vec(), 2, true)]
+        );
+
+        lsm.delete(b"a").unwrap();
+        lsm.delete_range(b"c", b"d").unwrap();
+        lsm.flush().unwrap();
+        assert_eq!(
+            events.lock().unwrap()[2..],
+            [(b"a".to_vec(), 3, false), (b"c".to_vec(), 4, false)]
+        );
+        drop(lsm);
+
+        // flushed mutations are not replayed.
+        events.lock().unwrap().clear();
+        let _lsm = OpenOptions::new()
+            .write_observer(recorder(&events))
+            .replay_writes_on_open(true)
+            .open(dir.path())
+            .unwrap();
+        assert!(events.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+//! Published Snapshot Module.
+//!
+//! `Lsm::publish_snapshot` writes the live data of a snapshot into a
+//! fresh directory as a single sstable with its hint, the format
+//! version file and a `SNAPSHOT.json` manifest. The directory is a
+//! complete store, meant to be opened with `OpenOptions::read_only`
+//! by any number of readers.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::config;
+use crate::error::Result;
+use crate::lsm::identity;
+use crate::utils;
+
+/// Description of a published snapshot, also written as `SNAPSHOT.json`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotManifest {
+    /// number of live keys.
+    pub key_count: u64,
+
+    /// bytes of the sstable.
+    pub size: u64,
+
+    /// seconds since the unix epoch.
+    pub created_at: u64,
+
+    /// sequence number of the source store at snapshot.
+    pub source_seq: u64,
+
+    /// uuid and generation of the source store, see `Lsm::identity`.
+    pub source_uuid: u128,
+    pub source_generation: u64,
+}
+
+impl SnapshotManifest {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"key_count\":{},\"size\":{},\"created_at\":{},\"source_seq\":{},\
+             \"source_uuid\":\"{}\",\"source_generation\":{}}}",
+            self.key_count,
+            self.size,
+            self.created_at,
+            self.source_seq,
+            identity::format_uuid(self.source_uuid),
+            self.source_generation
+        )
+    }
+
+    /// Write the manifest to `dir` and sync it.
+    pub(crate) fn write_to(&self, dir: &Path, file_mode: Option<u32>) -> Result<()> {
+        let mut file = utils::open_with_mode(
+            fs::OpenOptions::new().write(true).create_new(true),
+            &dir.join(config::SNAPSHOT_MANIFEST_FILE),
+            file_mode,
+        )?;
+        writeln!(file, "{}", self.to_json())?;
+        file.sync_all()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::lsm::{KVStore, LSMLibError, OpenOptions};
+
+    #[test]
+    fn test_publish_snapshot() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = OpenOptions::new()
+            .max_log_length(256)
+            .bloom_bits_per_key(Some(10))
+            .open(dir.path())
+            .unwrap();
+
+        // flushed, overwritten, deleted and in the memtable.
+        for i in 0..50u8 {
+            lsm.put(vec![i], vec![i; 16]).unwrap();
+        }
+        lsm.put(vec![1], vec![100; 16]).unwrap();
+        lsm.delete(&[2]).unwrap();
+
+        let target = dir.path().join("published");
+        let manifest = lsm.publish_snapshot(&target).unwrap();
+        assert_eq!(manifest.key_count, 49);
+        assert_eq!(manifest.source_seq, 52);
+        assert_eq!(manifest.source_uuid, lsm.identity().uuid);
+        assert_eq!(
+            fs::read_to_string(target.join(config::SNAPSHOT_MANIFEST_FILE)).unwrap(),
+            format!("{}\n", manifest.to_json())
+        );
+        assert!(utils::format_bloom_path(&target, 1).exists());
+        assert!(utils::format_lineage_path(&target, 1).exists());
+
+        // the source goes on, the published copy does not follow.
+        lsm.put(vec![3], vec![0; 16]).unwrap();
+
+        let readers: Vec<_> = (0..2)
+            .map(|_| OpenOptions::new().read_only(true).open(&target).unwrap())
+            .collect();
+        for reader in &readers {
+            assert_eq!(reader.list_keys().unwrap().len(), 49);
+            assert_eq!(reader.get(&[1]).unwrap(), Some(vec![100; 16]));
+            assert_eq!(reader.get(&[2]).unwrap(), None);
+            assert_eq!(reader.get(&[3]).unwrap(), Some(vec![3; 16]));
+        }
+
+        let mut reader = readers.into_iter().next().unwrap();
+        assert!(matches!(
+            reader.put(vec![1], vec![1]),
+            Err(LSMLibError::ReadOnly)
+        ));
+        assert!(matches!(
+            reader.publish_snapshot(dir.path().join("again")),
+            Err(LSMLibError::ReadOnly)
+        ));
+        assert!(!dir.path().join("again").exists());
+        assert!(!target.join("LOCK").exists());
+        assert!(!utils::format_wal_path(&target, 0).exists());
+
+        assert!(lsm.publish_snapshot(&target).is_err());
+        assert!(OpenOptions::new()
+            .read_only(true)
+            .open(dir.path().join("missing"))
+            .is_err());
+    }
+}
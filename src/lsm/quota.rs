@@ -0,0 +1,400 @@
+//! Quota Module.
+//!
+//! Limits on the live keys sharing a prefix, see `Lsm::set_prefix_quota`.
+//! Every write is checked against the `Lsm::namespace_usage` of the
+//! prefixes of its keys which have a quota, and fails with
+//! `QuotaExceeded` rather than take one over its limit.
+//!
+//! The usage is counted as it changes rather than scanned: the keydir
+//! counts the flushed live keys of each quota prefix, see
+//! `Keydir::count_prefix`, and `UnflushedUsage` what the memtable and
+//! the unflushed range tombstones change of it.
+//!
+//! Quotas live in the `QUOTAS` metadata file of the store dir, see
+//! `MetaFileWriter`: a record per prefix of a byte of flags telling
+//! which limits are set, the max keys and max bytes as u64, little
+//! endian, then the prefix. A file of the former text lines is still
+//! read.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::config;
+use crate::disk::format::{MetaFile, MetaFileReader, MetaFileWriter};
+use crate::error::{LSMLibError, Result};
+use crate::keydir::PrefixCount;
+
+/// Limits of the live keys sharing a prefix, see `Lsm::set_prefix_quota`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// max number of live keys, `None` for no limit.
+    pub max_keys: Option<u64>,
+
+    /// max bytes of the live entries, counted as
+    /// `PrefixStats::live_bytes`, `None` for no limit.
+    pub max_bytes: Option<u64>,
+}
+
+impl Quota {
+    /// Whether the quota limits nothing.
+    pub fn is_unlimited(&self) -> bool {
+        self.max_keys.is_none() && self.max_bytes.is_none()
+    }
+}
+
+/// Live keys sharing a prefix with a quota, see `Lsm::namespace_usage`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct NamespaceUsage {
+    /// number of live keys.
+    pub keys: u64,
+
+    /// bytes of the live entries, counted as `PrefixStats::live_bytes`.
+    pub bytes: u64,
+}
+
+impl NamespaceUsage {
+    /// Usage of the flushed `count` of a prefix changed by `delta`.
+    pub(crate) fn of(count: PrefixCount, delta: UsageDelta) -> Self {
+        Self {
+            keys: count.keys.saturating_add_signed(delta.keys),
+            bytes: count.bytes.saturating_add_signed(delta.bytes),
+        }
+    }
+}
+
+/// Change of the live keys of a prefix and of their bytes.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub(crate) struct UsageDelta {
+    pub(crate) keys: i64,
+    pub(crate) bytes: i64,
+}
+
+impl UsageDelta {
+    /// Change from a live entry of `from` bytes to one of `to` bytes,
+    /// `None` for no live entry.
+    pub(crate) fn between(from: Option<u64>, to: Option<u64>) -> Self {
+        Self {
+            keys: to.is_some() as i64 - from.is_some() as i64,
+            bytes: to.unwrap_or(0) as i64 - from.unwrap_or(0) as i64,
+        }
+    }
+
+    pub(crate) fn add(&mut self, other: Self) {
+        self.keys += other.keys;
+        self.bytes += other.bytes;
+    }
+}
+
+/// What the memtable and the unflushed range tombstones change of the
+/// keydir counts of each quota prefix.
+///
+/// Compaction and indexing change keydir entries the memtable shadows
+/// without the writer knowing, so the deltas only hold as of the
+/// `DiskStorage::rewrites` they were counted at.
+#[derive(Debug, Default)]
+pub(crate) struct UnflushedUsage {
+    /// rewrites of the store as of the count.
+    pub(crate) rewrites: u64,
+
+    /// delta by quota prefix.
+    pub(crate) deltas: BTreeMap<Vec<u8>, UsageDelta>,
+}
+
+impl UnflushedUsage {
+    /// Add `delta` of `key` to the prefixes of it.
+    pub(crate) fn add(&mut self, key: &[u8], delta: UsageDelta) {
+        for (prefix, d) in self.deltas.iter_mut() {
+            if key.starts_with(prefix) {
+                d.add(delta);
+            }
+        }
+    }
+}
+
+/// Limit of a `Quota` a put would exceed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QuotaLimit {
+    Keys,
+    Bytes,
+}
+
+impl fmt::Display for QuotaLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Keys => write!(f, "max keys"),
+            Self::Bytes => write!(f, "max bytes"),
+        }
+    }
+}
+
+const QUOTAS_MAGIC: &[u8; 4] = b"QUOT";
+const QUOTAS_VERSION: u32 = 1;
+
+/// Flags of the limits a quota record holds.
+const MAX_KEYS_FLAG: u8 = 1;
+const MAX_BYTES_FLAG: u8 = 2;
+
+/// Quotas of the store at `dir` by prefix, none if never set.
+pub(crate) fn read(dir: &Path) -> Result<BTreeMap<Vec<u8>, Quota>> {
+    let path = dir.join(config::QUOTAS_FILE);
+    let mut reader = match MetaFileReader::open_or_legacy(&path, QUOTAS_MAGIC, QUOTAS_VERSION)? {
+        Some(MetaFile::Framed(reader)) => reader,
+        Some(MetaFile::Legacy(text)) => return parse_legacy(&path, &text),
+        None => return Ok(BTreeMap::new()),
+    };
+
+    let mut quotas = BTreeMap::new();
+    while let Some(record) = reader.next_record() {
+        if record.len() < 17 || record[0] & !(MAX_KEYS_FLAG | MAX_BYTES_FLAG) != 0 {
+            return Err(reader.invalid());
+        }
+        let limit = |flag: u8, at: usize| {
+            (record[0] & flag != 0)
+                .then(|| u64::from_le_bytes(record[at..at + 8].try_into().unwrap()))
+        };
+        let quota = Quota {
+            max_keys: limit(MAX_KEYS_FLAG, 1),
+            max_bytes: limit(MAX_BYTES_FLAG, 9),
+        };
+        quotas.insert(record[17..].to_vec(), quota);
+    }
+
+    Ok(quotas)
+}
+
+/// Quotas in the text format of before the framing, one line
+/// `<max keys> <max bytes> <hex prefix>` per prefix, `-` for no limit.
+fn parse_legacy(path: &Path, text: &str) -> Result<BTreeMap<Vec<u8>, Quota>> {
+    let invalid = || LSMLibError::Custom(format!("invalid quotas file {}", path.display()));
+    let limit = |field: &str| match field {
+        "-" => Ok(None),
+        field => field.parse().map(Some),
+    };
+
+    let mut quotas = BTreeMap::new();
+    for line in text.lines() {
+        let mut fields = line.splitn(3, ' ');
+        let (Some(max_keys), Some(max_bytes), Some(prefix)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid());
+        };
+        let quota = Quota {
+            max_keys: limit(max_keys)?,
+            max_bytes: limit(max_bytes)?,
+        };
+        quotas.insert(decode_hex(prefix).ok_or_else(invalid)?, quota);
+    }
+
+    Ok(quotas)
+}
+
+/// Write `quotas` as the quotas of the store at `dir`, replacing any.
+/// The dir is not synced here, the caller syncs it.
+pub(crate) fn write(
+    dir: &Path,
+    quotas: &BTreeMap<Vec<u8>, Quota>,
+    file_mode: Option<u32>,
+) -> Result<()> {
+    let path = dir.join(config::QUOTAS_FILE);
+    let mut writer = MetaFileWriter::create(&path, QUOTAS_MAGIC, QUOTAS_VERSION, file_mode)?;
+
+    for (prefix, quota) in quotas {
+        let mut record = Vec::with_capacity(17 + prefix.len());
+        record.push(
+            quota.max_keys.map_or(0, |_| MAX_KEYS_FLAG)
+                | quota.max_bytes.map_or(0, |_| MAX_BYTES_FLAG),
+        );
+        record.extend_from_slice(&quota.max_keys.unwrap_or(0).to_le_bytes());
+        record.extend_from_slice(&quota.max_bytes.unwrap_or(0).to_le_bytes());
+        record.extend_from_slice(prefix);
+        writer.append(&record)?;
+    }
+
+    writer.finish_unsynced_dir()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::lsm::{KVStore, Lsm, MergeOperatorFn, OpenOptions, WriteBatch};
+
+    #[test]
+    fn test_prefix_quota() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+        lsm.put(b"a:1".to_vec(), vec![0; 10]).unwrap();
+        lsm.flush().unwrap();
+
+        let quota = Quota {
+            max_keys: Some(2),
+            max_bytes: None,
+        };
+        lsm.set_prefix_quota(b"a:", quota).unwrap();
+        lsm.put(b"a:2".to_vec(), vec![0; 10]).unwrap();
+
+        let put = lsm.put(b"a:3".to_vec(), vec![0; 10]);
+        assert!(matches!(
+            put,
+            Err(LSMLibError::QuotaExceeded { ref prefix, which: QuotaLimit::Keys }) if prefix == b"a:"
+        ));
+        assert_eq!(lsm.get(b"a:3").unwrap(), None);
+
+        // overwrites and other prefixes go through, deletes free room.
+        lsm.put(b"a:1".to_vec(), vec![1; 10]).unwrap();
+        lsm.put(b"b:1".to_vec(), vec![0; 10]).unwrap();
+        lsm.delete(b"a:2").unwrap();
+        lsm.put(b"a:3".to_vec(), vec![0; 10]).unwrap();
+        assert_eq!(lsm.prefix_stats(b"a:").unwrap().keys, 2);
+
+        // quotas survive a reopen.
+        drop(lsm);
+        let mut lsm = Lsm::open(dir.path()).unwrap();
+        assert_eq!(lsm.prefix_quota(b"a:"), Some(quota));
+        assert!(lsm.put(b"a:4".to_vec(), vec![0; 10]).is_err());
+
+        let live_bytes = lsm.prefix_stats(b"a:").unwrap().live_bytes;
+        lsm.set_prefix_quota(
+            b"a:",
+            Quota {
+                max_keys: None,
+                max_bytes: Some(live_bytes),
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            lsm.put(b"a:1".to_vec(), vec![1; 11]),
+            Err(LSMLibError::QuotaExceeded {
+                which: QuotaLimit::Bytes,
+                ..
+            })
+        ));
+        lsm.put(b"a:1".to_vec(), vec![1; 9]).unwrap();
+
+        lsm.set_prefix_quota(b"a:", Quota::default()).unwrap();
+        assert_eq!(lsm.prefix_quota(b"a:"), None);
+        lsm.put(b"a:4".to_vec(), vec![0; 10]).unwrap();
+    }
+
+    #[test]
+    fn test_namespace_usage() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let concat: MergeOperatorFn =
+            Arc::new(|_, existing, operand| Some([existing.unwrap_or_default(), operand].concat()));
+        let open = || {
+            OpenOptions::new()
+                .merge_operator(Arc::clone(&concat))
+                .open(dir.path())
+                .unwrap()
+        };
+        // the counted usage is the scanned one.
+        let check = |lsm: &Lsm| {
+            let stats = lsm.prefix_stats(b"a:").unwrap();
+            let usage = lsm.namespace_usage(b"a:").unwrap().unwrap();
+            assert_eq!((usage.keys, usage.bytes), (stats.keys, stats.live_bytes));
+            lsm.check_invariants().unwrap();
+            usage
+        };
+
+        let mut lsm = open();
+        lsm.put(b"a:1".to_vec(), vec![0; 10]).unwrap();
+        lsm.put(b"a:2".to_vec(), vec![0; 10]).unwrap();
+        lsm.put(b"b:1".to_vec(), vec![0; 10]).unwrap();
+        lsm.flush().unwrap();
+        assert_eq!(lsm.namespace_usage(b"a:").unwrap(), None);
+
+        let quota = Quota {
+            max_keys: Some(4),
+            max_bytes: None,
+        };
+        lsm.set_prefix_quota(b"a:", quota).unwrap();
+        assert_eq!(check(&lsm).keys, 2);
+
+        lsm.put(b"a:3".to_vec(), vec![0; 10]).unwrap();
+        lsm.delete(b"a:1").unwrap();
+        lsm.put(b"a:2".to_vec(), vec![0; 20]).unwrap();
+        assert_eq!(check(&lsm).keys, 2);
+        lsm.flush().unwrap();
+        check(&lsm);
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"a:4".to_vec(), vec![0; 10]);
+        batch.delete(b"a:3".to_vec());
+        lsm.apply_batch(batch).unwrap();
+        lsm.merge(b"a:2".to_vec(), b"xyz").unwrap();
+        lsm.merge(b"a:5".to_vec(), b"xyz").unwrap();
+        assert_eq!(check(&lsm).keys, 3);
+
+        lsm.delete_range(b"a:2", b"a:5").unwrap();
+        assert_eq!(check(&lsm).keys, 1);
+        lsm.put(b"a:3".to_vec(), vec![0; 10]).unwrap();
+        check(&lsm);
+        lsm.compact_now().unwrap();
+        let usage = check(&lsm);
+
+        drop(lsm);
+        let mut lsm = open();
+        assert_eq!(check(&lsm), usage);
+
+        // a batch is held back as a whole, net of its deletes.
+        let mut batch = WriteBatch::new();
+        batch.put(b"a:6".to_vec(), vec![0; 10]);
+        batch.put(b"a:7".to_vec(), vec![0; 10]);
+        batch.put(b"a:8".to_vec(), vec![0; 10]);
+        assert!(matches!(
+            lsm.apply_batch(batch),
+            Err(LSMLibError::QuotaExceeded {
+                which: QuotaLimit::Keys,
+                ..
+            })
+        ));
+        assert_eq!(check(&lsm), usage);
+        let mut batch = WriteBatch::new();
+        batch.put(b"a:6".to_vec(), vec![0; 10]);
+        batch.put(b"a:7".to_vec(), vec![0; 10]);
+        batch.delete(b"a:3".to_vec());
+        batch.put(b"a:8".to_vec(), vec![0; 10]);
+        lsm.apply_batch(batch).unwrap();
+        assert_eq!(check(&lsm).keys, 4);
+
+        // so are merges, checked with the value they make.
+        assert!(lsm.merge(b"a:9".to_vec(), b"xyz").is_err());
+        let usage = check(&lsm);
+        lsm.set_prefix_quota(
+            b"a:",
+            Quota {
+                max_keys: None,
+                max_bytes: Some(usage.bytes + 2),
+            },
+        )
+        .unwrap();
+        lsm.merge(b"a:6".to_vec(), b"xy").unwrap();
+        assert!(matches!(
+            lsm.merge(b"a:6".to_vec(), b"z"),
+            Err(LSMLibError::QuotaExceeded {
+                which: QuotaLimit::Bytes,
+                ..
+            })
+        ));
+        assert_eq!(
+            lsm.get(b"a:6").unwrap(),
+            Some([vec![0; 10], b"xy".to_vec()].concat())
+        );
+        check(&lsm);
+    }
+}
@@ -0,0 +1,144 @@
+//! Replication Module.
+//!
+//! Consuming side of a changefeed: `Lsm::apply_changes` applies
+//! sequenced mutations from another store and records, per named
+//! replication slot, the last sequence number applied, so a replica
+//! resumes where it stopped after a crash.
+//!
+//! A slot lives in the `REPLICATION-<slot>` metadata file of the store
+//! dir, a single record of the sequence number as u64, little endian,
+//! see `MetaFileWriter`. A file of the former decimal sequence number
+//! is still read.
+
+use std::path::{Path, PathBuf};
+
+use crate::config;
+use crate::disk::format::{MetaFile, MetaFileReader, MetaFileWriter};
+use crate::error::{LSMLibError, Result};
+
+const SLOT_MAGIC: &[u8; 4] = b"REPL";
+const SLOT_VERSION: u32 = 1;
+
+/// What `Lsm::apply_changes` did.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ApplyReport {
+    /// mutations written.
+    pub applied: u64,
+
+    /// mutations at or below the slot position, already applied.
+    pub skipped: u64,
+
+    /// slot position once done.
+    pub last_seq: u64,
+}
+
+fn slot_path(dir: &Path, slot: &str) -> Result<PathBuf> {
+    let valid = !slot.is_empty()
+        && slot
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if !valid {
+        return Err(LSMLibError::Custom(format!(
+            "invalid replication slot name '{}'",
+            slot
+        )));
+    }
+
+    Ok(dir.join(format!("{}{}", config::REPLICATION_SLOT_PREFIX, slot)))
+}
+
+/// Last sequence number applied through `slot`, `None` for a new slot.
+pub(crate) fn read_slot(dir: &Path, slot: &str) -> Result<Option<u64>> {
+    let path = slot_path(dir, slot)?;
+    match MetaFileReader::open_or_legacy(&path, SLOT_MAGIC, SLOT_VERSION)? {
+        Some(MetaFile::Framed(mut reader)) => {
+            let record = reader.record()?;
+            Ok(Some(u64::from_le_bytes(
+                record.try_into().map_err(|_| reader.invalid())?,
+            )))
+        }
+        Some(MetaFile::Legacy(text)) => Ok(Some(text.trim().parse()?)),
+        None => Ok(None),
+    }
+}
+
+/// Durably record `seq` as the last sequence number applied through `slot`.
+///
+/// The dir is not synced here, the caller syncs it.
+pub(crate) fn write_slot(dir: &Path, slot: &str, seq: u64, file_mode: Option<u32>) -> Result<()> {
+    let path = slot_path(dir, slot)?;
+    let mut writer = MetaFileWriter::create(&path, SLOT_MAGIC, SLOT_VERSION, file_mode)?;
+    writer.append(&seq.to_le_bytes())?;
+    writer.finish_unsynced_dir()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use tempdir::TempDir;
+
+    use crate::lsm::{KVStore, Lsm, OpenOptions};
+
+    #[test]
+    fn test_apply_changes_resumes_after_crash() {
+        // the changefeed: writes over 100 keys, every 7th one a delete.
+        let changes: Vec<(u64, Vec<u8>, Option<Vec<u8>>)> = (1..=3000u64)
+            .map(|seq| {
+                let key = (seq % 100).to_be_bytes().to_vec();
+                let value = (seq % 7 != 0).then(|| seq.to_be_bytes().to_vec());
+                (seq, key, value)
+            })
+            .collect();
+
+        let source_dir = TempDir::new("lsmlib").unwrap();
+        let mut source = Lsm::open(source_dir.path()).unwrap();
+        for (_, key, value) in &changes {
+            match value {
+                Some(value) => source.put(key.clone(), value.clone()).unwrap(),
+                None => source.delete(key).unwrap(),
+            }
+        }
+
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut replica = OpenOptions::new()
+            .max_log_length(16 * 1024)
+            .open(dir.path())
+            .unwrap();
+        assert_eq!(replica.replication_slot("main").unwrap(), None);
+
+        // the replica dies while applying the 2500th change.
+        let crashed = panic::catch_unwind(AssertUnwindSafe(|| {
+            let stream = changes.iter().cloned().inspect(|(seq, _, _)| {
+                if *seq == 2500 {
+                    panic!("replica crashed");
+                }
+            });
+            replica.apply_changes("main", stream)
+        }));
+        assert!(crashed.is_err());
+        drop(replica);
+
+        // none of the batch the crash cut short was logged.
+        let mut replica = Lsm::open(dir.path()).unwrap();
+        assert_eq!(replica.replication_slot("main").unwrap(), Some(2048));
+        assert_eq!(replica.seq, 2048);
+
+        let report = replica
+            .apply_changes("main", changes.iter().cloned())
+            .unwrap();
+        assert_eq!(
+            (report.skipped, report.applied, report.last_seq),
+            (2048, 952, 3000)
+        );
+
+        for (_, key, _) in &changes[..100] {
+            assert_eq!(replica.get(key).unwrap(), source.get(key).unwrap());
+        }
+
+        // replaying again changes nothing.
+        let report = replica.apply_changes("main", changes.into_iter()).unwrap();
+        assert_eq!((report.skipped, report.applied), (3000, 0));
+        assert!(replica.replication_slot("../x").is_err());
+    }
+}
@@ -0,0 +1,14 @@
+//! SSTable Module.
+//!
+//! Building sstables outside of a live store, e.g. to ingest them with
+//! `Lsm::ingest_sstable` or to publish them as test fixtures.
+//!
+//! An sstable is the sequence of its entries in key order, each checked
+//! by its crc, there is no footer nor bloom filter. The hint file
+//! indexes the entries, so a store opens without reading the data.
+//! `CompactMergeIter` merges sstables as compaction does.
+
+pub(crate) use crate::disk::sstable::{intact_len, verify_sstable};
+pub use crate::disk::sstable::{
+    read_sstable, CompactMergeIter, SSTable, SSTableMeta, SSTableWriter, SSTableWriterOptions,
+};
@@ -0,0 +1,124 @@
+//! Key Transform Module.
+//!
+//! Keys normalized before they reach the store, e.g. lowercased for
+//! case-insensitive lookups, see `OpenOptions::key_transform`.
+//!
+//! The identity of the transform lives in the `KEY_TRANSFORM` metadata
+//! file of the store dir, a single record of the UTF-8 id, see
+//! `MetaFileWriter`, so the store is never opened with another
+//! transform than the one its keys were written with. A file of the
+//! former single line of the id is still read.
+
+use std::borrow::Cow;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::config;
+use crate::disk::format::{MetaFile, MetaFileReader, MetaFileWriter};
+use crate::error::{LSMLibError, Result};
+
+/// Function normalizing keys, see `OpenOptions::key_transform`.
+pub type KeyTransformFn = Arc<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+/// Key transform and the identity it is recorded under.
+#[derive(Clone)]
+pub(crate) struct KeyTransform {
+    id: String,
+    f: KeyTransformFn,
+}
+
+impl KeyTransform {
+    pub(crate) fn new(id: String, f: KeyTransformFn) -> Self {
+        Self { id, f }
+    }
+
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// `key` as stored, transformed if there is a transform.
+pub(crate) fn apply<'a>(transform: Option<&KeyTransform>, key: &'a [u8]) -> Cow<'a, [u8]> {
+    match transform {
+        Some(t) => Cow::Owned((t.f)(key)),
+        None => Cow::Borrowed(key),
+    }
+}
+
+/// Owned `key` as stored, transformed if there is a transform.
+pub(crate) fn apply_owned(transform: Option<&KeyTransform>, key: Vec<u8>) -> Vec<u8> {
+    match transform {
+        Some(t) => (t.f)(&key),
+        None => key,
+    }
+}
+
+/// Bounds of `range` as stored, transformed if there is a transform.
+pub(crate) fn apply_bounds<R>(
+    transform: Option<&KeyTransform>,
+    range: &R,
+) -> (Bound<Vec<u8>>, Bound<Vec<u8>>)
+where
+    R: RangeBounds<Vec<u8>>,
+{
+    let map = |bound: Bound<&Vec<u8>>| match bound {
+        Bound::Included(key) => Bound::Included(apply(transform, key).into_owned()),
+        Bound::Excluded(key) => Bound::Excluded(apply(transform, key).into_owned()),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    (map(range.start_bound()), map(range.end_bound()))
+}
+
+const KEY_TRANSFORM_MAGIC: &[u8; 4] = b"KTRF";
+const KEY_TRANSFORM_VERSION: u32 = 1;
+
+fn read_id(dir: &Path) -> Result<Option<String>> {
+    let path = dir.join(config::KEY_TRANSFORM_FILE);
+    match MetaFileReader::open_or_legacy(&path, KEY_TRANSFORM_MAGIC, KEY_TRANSFORM_VERSION)? {
+        Some(MetaFile::Framed(mut reader)) => {
+            let record = reader.record()?;
+            String::from_utf8(record)
+                .map(Some)
+                .map_err(|_| reader.invalid())
+        }
+        Some(MetaFile::Legacy(text)) => Ok(Some(text.trim_end_matches('\n').to_string())),
+        None => Ok(None),
+    }
+}
+
+fn write_id(dir: &Path, id: &str, file_mode: Option<u32>) -> Result<()> {
+    let path = dir.join(config::KEY_TRANSFORM_FILE);
+    let mut writer =
+        MetaFileWriter::create(&path, KEY_TRANSFORM_MAGIC, KEY_TRANSFORM_VERSION, file_mode)?;
+    writer.append(id.as_bytes())?;
+    writer.finish()
+}
+
+/// Check the store at `dir` was written with `transform`.
+///
+/// An `empty` store takes the transform it is opened with, recorded
+/// unless `read_only`.
+pub(crate) fn check(
+    dir: &Path,
+    transform: Option<&KeyTransform>,
+    empty: bool,
+    file_mode: Option<u32>,
+    read_only: bool,
+) -> Result<()> {
+    let stored = read_id(dir)?;
+    let requested = transform.map(|t| t.id().to_string());
+    if stored == requested {
+        return Ok(());
+    }
+
+    match (&stored, &requested) {
+        (None, Some(id)) if empty => {
+            if !read_only {
+                write_id(dir, id, file_mode)?;
+            }
+            Ok(())
+        }
+        _ => Err(LSMLibError::KeyTransformMismatch { stored, requested }),
+    }
+}
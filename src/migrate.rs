@@ -0,0 +1,500 @@
+//! Migrate Module.
+//!
+//! Upgrades store directories written by older on-disk formats
+//! to `format::FORMAT_VERSION`.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use crate::config;
+use crate::disk::format::{
+    self, HintEntry, MetaFile, MetaFileReader, MetaFileWriter, COMPRESSION_FORMAT_VERSION,
+    EXPIRY_FORMAT_VERSION, FORMAT_VERSION, MERGE_FORMAT_VERSION, RANGE_TOMBSTONE_FORMAT_VERSION,
+    TOUCH_FORMAT_VERSION,
+};
+use crate::disk::{hint::HintFile, sstable::SSTable};
+use crate::error::{LSMLibError, Result};
+use crate::storage::Lockfile;
+use crate::utils;
+
+const VERSION_MAGIC: &[u8; 4] = b"VERS";
+const VERSION_VERSION: u32 = 1;
+
+const JOURNAL_MAGIC: &[u8; 4] = b"MIGR";
+const JOURNAL_VERSION: u32 = 1;
+
+/// Options of `migrate`.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct MigrateOptions {
+    /// Only detect the format version, do not rewrite anything.
+    pub dry_run: bool,
+
+    /// Permissions of rewritten files, see `Config::file_mode`.
+    pub file_mode: Option<u32>,
+}
+
+/// What `migrate` did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrateReport {
+    /// format version found in the directory, `None` for an empty one.
+    pub from: Option<u32>,
+
+    /// format version of the directory after migration.
+    pub to: u32,
+
+    /// number of sstables rewritten.
+    pub sstables_rewritten: u64,
+
+    /// number of entries rewritten, WAL included.
+    pub entries_rewritten: u64,
+
+    /// whether an interrupted migration was resumed.
+    pub resumed: bool,
+}
+
+/// Detect the format version of the store at `path`.
+///
+/// Returns `None` for a directory without any data yet. Data written
+/// before the `VERSION` file existed is format version 1.
+pub(crate) fn detect_format_version(path: &Path) -> Result<Option<u32>> {
+    let version_path = path.join(config::VERSION_FILE);
+    match MetaFileReader::open_or_legacy(&version_path, VERSION_MAGIC, VERSION_VERSION)? {
+        Some(MetaFile::Framed(mut reader)) => {
+            let record = reader.record()?;
+            let version = record.try_into().map_err(|_| reader.invalid())?;
+            return Ok(Some(u32::from_le_bytes(version)));
+        }
+        Some(MetaFile::Legacy(text)) => return Ok(Some(text.trim().parse::<u32>()?)),
+        None => {}
+    }
+
+    for suffix in [
+        config::DATA_FILE_SUFFIX,
+        config::HINT_FILE_SUFFIX,
+        config::WAL_FILE_SUFFIX,
+    ] {
+        let pattern = format!("{}/*{}", path.display(), suffix);
+        for file in glob::glob(&pattern)? {
+            if fs::metadata(file?)?.len() > 0 {
+                return Ok(Some(1));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Check the store at `path` uses the current format version,
+/// stamping the version into a directory without data unless `read_only`.
+pub(crate) fn check_format_version(
+    path: &Path,
+    file_mode: Option<u32>,
+    read_only: bool,
+) -> Result<()> {
+    match detect_format_version(path)? {
+        None if read_only => Ok(()),
+        None => write_format_version(path, FORMAT_VERSION, file_mode),
+        Some(
+            FORMAT_VERSION
+            | RANGE_TOMBSTONE_FORMAT_VERSION
+            | EXPIRY_FORMAT_VERSION
+            | COMPRESSION_FORMAT_VERSION
+            | TOUCH_FORMAT_VERSION
+            | MERGE_FORMAT_VERSION,
+        ) => Ok(()),
+        Some(from) => Err(LSMLibError::NeedsMigration {
+            from,
+            to: FORMAT_VERSION,
+        }),
+    }
+}
+
+/// Stamp the store at `path` with format `version`, unless stamped with
+/// it or a later one.
+pub(crate) fn require_format_version(
+    path: &Path,
+    version: u32,
+    file_mode: Option<u32>,
+) -> Result<()> {
+    if detect_format_version(path)?.is_some_and(|v| v >= version) {
+        return Ok(());
+    }
+    write_format_version(path, version, file_mode)
+}
+
+/// Stamp the store at `path` with format `version`, in the `VERSION`
+/// metadata file of a single record of the version as u32, little
+/// endian, see `MetaFileWriter`. A file of the former decimal version
+/// is still read.
+pub(crate) fn write_format_version(
+    path: &Path,
+    version: u32,
+    file_mode: Option<u32>,
+) -> Result<()> {
+    let version_path = path.join(config::VERSION_FILE);
+    let mut writer =
+        MetaFileWriter::create(&version_path, VERSION_MAGIC, VERSION_VERSION, file_mode)?;
+    writer.append(&version.to_le_bytes())?;
+    writer.finish()
+}
+
+/// Upgrade the store at `path` to the current on-disk format.
+///
+/// Every file is rewritten through the normal sstable writer. Progress
+/// is journaled in the `MIGRATION` file, so an interrupted migration
+/// resumes where it stopped when run again. Fails with `AlreadyLocked`
+/// while the store is open.
+pub fn migrate(path: impl AsRef<Path>, options: MigrateOptions) -> Result<MigrateReport> {
+    let path = path.as_ref();
+
+    let _lock = Lockfile::lock(path.join("LOCK"), options.file_mode, None)
+        .or(Err(LSMLibError::AlreadyLocked))?;
+
+    let from = detect_format_version(path)?;
+    let mut report = MigrateReport {
+        from,
+        to: from.unwrap_or(FORMAT_VERSION),
+        ..MigrateReport::default()
+    };
+
+    let version = match from {
+        Some(version) if version < FORMAT_VERSION => version,
+        Some(
+            RANGE_TOMBSTONE_FORMAT_VERSION
+            | EXPIRY_FORMAT_VERSION
+            | COMPRESSION_FORMAT_VERSION
+            | TOUCH_FORMAT_VERSION
+            | MERGE_FORMAT_VERSION,
+        ) => return Ok(report),
+        Some(version) if version > FORMAT_VERSION => {
+            return Err(LSMLibError::Custom(format!(
+                "store format version {} is newer than supported {}",
+                version, FORMAT_VERSION
+            )));
+        }
+        _ => return Ok(report),
+    };
+
+    if options.dry_run {
+        return Ok(report);
+    }
+
+    log::info!(
+        "migrating store {} from format version {} to {}",
+        path.display(),
+        version,
+        FORMAT_VERSION
+    );
+
+    // only version 1 is older than the current one.
+    migrate_v1_to_v2(path, &mut report, options.file_mode)?;
+
+    write_format_version(path, FORMAT_VERSION, options.file_mode)?;
+    fs::remove_file(path.join(config::MIGRATION_FILE))?;
+    File::open(path)?.sync_all()?;
+
+    report.to = FORMAT_VERSION;
+    log::info!("migration done: {:?}", report);
+
+    Ok(report)
+}
+
+/// Work journal of a migration, a `MIGRATION` metadata file of a record
+/// per finished file: its max seq as u64, little endian, then its name.
+/// A journal of the former text lines `<file name> <max seq>` is still
+/// read.
+struct Journal {
+    path: PathBuf,
+    done: BTreeMap<String, u64>,
+    file_mode: Option<u32>,
+}
+
+impl Journal {
+    fn open(dir: &Path, file_mode: Option<u32>) -> Result<Self> {
+        let path = dir.join(config::MIGRATION_FILE);
+
+        let mut done = BTreeMap::new();
+        match MetaFileReader::open_or_legacy(&path, JOURNAL_MAGIC, JOURNAL_VERSION)? {
+            Some(MetaFile::Framed(mut reader)) => {
+                while let Some(record) = reader.next_record() {
+                    let name = record
+                        .get(8..)
+                        .and_then(|name| String::from_utf8(name.to_vec()).ok())
+                        .ok_or_else(|| reader.invalid())?;
+                    let seq = u64::from_le_bytes(record[..8].try_into().unwrap());
+                    done.insert(name, seq);
+                }
+            }
+            Some(MetaFile::Legacy(text)) => {
+                for line in text.lines() {
+                    let mut fields = line.split(' ');
+                    if let (Some(name), Some(seq)) = (fields.next(), fields.next()) {
+                        done.insert(name.to_string(), seq.parse::<u64>()?);
+                    }
+                }
+            }
+            None => {}
+        }
+
+        Ok(Self {
+            path,
+            done,
+            file_mode,
+        })
+    }
+
+    fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    fn max_seq(&self) -> u64 {
+        self.done.values().copied().max().unwrap_or(0)
+    }
+
+    /// Record `name` as finished, rewriting the journal.
+    fn record(&mut self, name: &str, seq: u64) -> Result<()> {
+        self.done.insert(name.to_string(), seq);
+
+        let mut writer =
+            MetaFileWriter::create(&self.path, JOURNAL_MAGIC, JOURNAL_VERSION, self.file_mode)?;
+        for (name, seq) in &self.done {
+            let mut record = seq.to_le_bytes().to_vec();
+            record.extend_from_slice(name.as_bytes());
+            writer.append(&record)?;
+        }
+        writer.finish()
+    }
+}
+
+/// Path the original file is kept at while it is being rewritten.
+fn backup_path(path: &Path, version: u32) -> PathBuf {
+    let mut name = path.file_name().unwrap().to_os_string();
+    name.push(format!("-v{}", version));
+    path.with_file_name(name)
+}
+
+/// Rewrite sstables, hints and WAL with sequence numbers, assigned in
+/// write order: sstables by id, then the WAL.
+fn migrate_v1_to_v2(path: &Path, report: &mut MigrateReport, file_mode: Option<u32>) -> Result<()> {
+    let mut journal = Journal::open(path, file_mode)?;
+    report.resumed = journal.exists();
+
+    let pattern = format!("{}/*{}", path.display(), config::DATA_FILE_SUFFIX);
+    let mut files = Vec::new();
+    for file in glob::glob(&pattern)? {
+        files.push(file?);
+    }
+    files.sort();
+    files.push(utils::format_wal_path(path, 0));
+
+    let mut seq = journal.max_seq();
+    for file in files {
+        let name = file.file_name().unwrap().to_string_lossy().to_string();
+        let backup = backup_path(&file, 1);
+
+        if journal.done.contains_key(&name) {
+            if backup.exists() {
+                fs::remove_file(&backup)?;
+            }
+            continue;
+        }
+
+        // the backup, once there, is the original file.
+        if !backup.exists() {
+            if !file.exists() {
+                continue;
+            }
+            fs::rename(&file, &backup)?;
+            File::open(path)?.sync_all()?;
+        }
+
+        let is_wal = name.ends_with(config::WAL_FILE_SUFFIX);
+        let entries = rewrite_v1_file(&backup, &file, is_wal, &mut seq, file_mode)?;
+
+        journal.record(&name, seq)?;
+        fs::remove_file(&backup)?;
+
+        report.entries_rewritten += entries;
+        if !is_wal {
+            report.sstables_rewritten += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// Rewrite version 1 file `src` into `dst`, with a hint unless it is a WAL.
+fn rewrite_v1_file(
+    src: &Path,
+    dst: &Path,
+    is_wal: bool,
+    seq: &mut u64,
+    file_mode: Option<u32>,
+) -> Result<u64> {
+    let dir = dst.parent().expect("store file must have a parent");
+    let id = utils::parse_file_id(dst).expect("store file must have a file id");
+
+    let tmp_path = if is_wal {
+        utils::format_wal_tmp_path(dir, id)
+    } else {
+        utils::format_sstable_tmp_path(dir, id)
+    };
+    let hint_tmp_path = utils::format_hint_tmp_path(dir, id);
+
+    for tmp in [&tmp_path, &hint_tmp_path] {
+        if tmp.exists() {
+            fs::remove_file(tmp)?;
+        }
+    }
+
+    let mut sstable = SSTable::create(&tmp_path, file_mode)?;
+    let mut hint = if is_wal {
+        None
+    } else {
+        Some(HintFile::create(&hint_tmp_path, file_mode)?)
+    };
+
+    let mut reader = File::open(src)?;
+    let mut offset = 0;
+    let mut entries = 0;
+    loop {
+        let entry = match format::read_entry_v1(&mut reader, offset) {
+            Ok(Some(entry)) if entry.is_validate() => entry,
+            Ok(None) => break,
+            _ => {
+                log::warn!("torn tail in {} at offset {}", src.display(), offset);
+                break;
+            }
+        };
+        offset += (format::HEADER_SIZE_V1 + entry.key.len() + entry.value.len()) as u64;
+
+        *seq += 1;
+        let disk_entry = sstable.write_entry(entry.with_seq(*seq))?;
+        if let Some(hint) = hint.as_mut() {
+            hint.write_entry(HintEntry::from(&disk_entry))?;
+        }
+        entries += 1;
+    }
+
+    sstable.sync()?;
+    if let Some(mut hint) = hint {
+        hint.sync()?;
+        fs::rename(&hint_tmp_path, utils::format_hint_path(dir, id))?;
+    }
+    fs::rename(&tmp_path, dst)?;
+    File::open(dir)?.sync_all()?;
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use tempdir::TempDir;
+
+    use crate::disk::format::DiskEntry;
+    use crate::lsm::{KVStore, Lsm};
+
+    /// Write `items` as version 1 entries to `path`.
+    fn write_v1_file(path: &Path, items: &[(&[u8], &[u8])]) {
+        let mut file = File::create(path).unwrap();
+        for (key, value) in items {
+            let entry = DiskEntry::new(key.to_vec(), value.to_vec());
+            file.write_all(&entry.crc().to_le_bytes()).unwrap();
+            file.write_all(&entry.timestamp().to_le_bytes()).unwrap();
+            file.write_all(&(key.len() as u32).to_le_bytes()).unwrap();
+            file.write_all(&(value.len() as u32).to_le_bytes()).unwrap();
+            file.write_all(key).unwrap();
+            file.write_all(value).unwrap();
+        }
+    }
+
+    fn create_v1_store(dir: &Path) {
+        write_v1_file(
+            &utils::format_sstable_path(dir, 1),
+            &[(b"a", b"1"), (b"b", b"1")],
+        );
+        write_v1_file(
+            &utils::format_sstable_path(dir, 2),
+            &[(b"a", b"2"), (b"c", b"")],
+        );
+        write_v1_file(&utils::format_wal_path(dir, 0), &[(b"b", b"3")]);
+    }
+
+    fn assert_migrated(dir: &Path) {
+        let lsm = Lsm::open(dir).unwrap();
+        assert_eq!(lsm.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(lsm.get(b"b").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(lsm.get(b"c").unwrap(), None);
+    }
+
+    #[test]
+    fn test_open_needs_migration() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        create_v1_store(dir.path());
+
+        match Lsm::open(dir.path()) {
+            Err(LSMLibError::NeedsMigration { from: 1, to: 2 }) => {}
+            other => panic!("unexpected open result: {:?}", other.err()),
+        }
+
+        let report = migrate(
+            dir.path(),
+            MigrateOptions {
+                dry_run: true,
+                ..MigrateOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(report.from, Some(1));
+        assert_eq!(report.to, 1);
+
+        let report = migrate(dir.path(), MigrateOptions::default()).unwrap();
+        assert_eq!(report.from, Some(1));
+        assert_eq!(report.to, FORMAT_VERSION);
+        assert_eq!(report.sstables_rewritten, 2);
+        assert_eq!(report.entries_rewritten, 5);
+        assert!(!report.resumed);
+
+        assert_migrated(dir.path());
+
+        // nothing left to do once migrated.
+        let report = migrate(dir.path(), MigrateOptions::default()).unwrap();
+        assert_eq!(report.sstables_rewritten, 0);
+    }
+
+    #[test]
+    fn test_migrate_resume() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        create_v1_store(dir.path());
+
+        // interrupted while rewriting sstable 1: original moved to its
+        // backup, output only partially written.
+        let path = utils::format_sstable_path(dir.path(), 1);
+        fs::rename(&path, backup_path(&path, 1)).unwrap();
+        fs::write(&path, b"partial").unwrap();
+        fs::write(dir.path().join(config::MIGRATION_FILE), b"").unwrap();
+
+        let report = migrate(dir.path(), MigrateOptions::default()).unwrap();
+        assert!(report.resumed);
+        assert_eq!(report.sstables_rewritten, 2);
+        assert!(!backup_path(&path, 1).exists());
+
+        assert_migrated(dir.path());
+    }
+
+    #[test]
+    fn test_migrate_locked() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let _lsm = Lsm::open(dir.path()).unwrap();
+
+        assert!(matches!(
+            migrate(dir.path(), MigrateOptions::default()),
+            Err(LSMLibError::AlreadyLocked)
+        ));
+    }
+}
@@ -0,0 +1,366 @@
+//! Snapshot Module.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use crate::disk::format::{DiskEntry, RangeTombstone};
+use crate::disk::sstable::{SSTable, ValueReader};
+use crate::error::{LSMLibError, Result};
+use crate::keydir::KeydirEntry;
+use crate::lsm::merge::{self, Base, Operands};
+use crate::lsm::transform::{self, KeyTransform};
+use crate::storage::Store;
+
+/// Sstables read by live snapshots, see `DiskStorage::pin_sstables`.
+///
+/// Pins count per instance of an sstable id rather than per id, as the
+/// merged sstable of a compaction takes the id of its newest input.
+/// Compaction hands the pinned instances it replaces to `retire`, which
+/// keeps them open until the last snapshot reading them is released.
+#[derive(Debug, Default)]
+pub(crate) struct SSTablePins {
+    /// pinned instance of the current sstable of each id.
+    current: HashMap<u64, u64>,
+
+    /// number of live snapshots pinning each instance.
+    counts: HashMap<u64, usize>,
+
+    /// instances replaced while pinned, with the path their file was
+    /// moved to, if not overwritten by the merge.
+    retired: HashMap<u64, (SSTable, Option<PathBuf>)>,
+
+    /// last instance given.
+    last_instance: u64,
+}
+
+impl SSTablePins {
+    /// Pin the current sstable of `id`, returning its instance.
+    pub(crate) fn pin(&mut self, id: u64) -> u64 {
+        let last_instance = &mut self.last_instance;
+        let instance = *self.current.entry(id).or_insert_with(|| {
+            *last_instance += 1;
+            *last_instance
+        });
+        *self.counts.entry(instance).or_default() += 1;
+        instance
+    }
+
+    /// Release a pin of `instance` of `id`, returning the retired sstable
+    /// and its path once no snapshot pins it.
+    pub(crate) fn unpin(&mut self, id: u64, instance: u64) -> Option<(SSTable, Option<PathBuf>)> {
+        let count = self.counts.get_mut(&instance)?;
+        *count -= 1;
+        if *count > 0 {
+            return None;
+        }
+
+        self.counts.remove(&instance);
+        if self.current.get(&id) == Some(&instance) {
+            self.current.remove(&id);
+        }
+        self.retired.remove(&instance)
+    }
+
+    /// Whether a snapshot reads the current sstable of `id`.
+    pub(crate) fn is_pinned(&self, id: u64) -> bool {
+        self.current.contains_key(&id)
+    }
+
+    /// Keep `sstable`, the current one of the pinned `id` replaced by
+    /// compaction, until released, its file moved to `path` if any.
+    pub(crate) fn retire(&mut self, id: u64, sstable: SSTable, path: Option<PathBuf>) {
+        if let Some(instance) = self.current.remove(&id) {
+            self.retired.insert(instance, (sstable, path));
+        }
+    }
+
+    /// Retired `instance`, if retired.
+    pub(crate) fn retired(&self, instance: u64) -> Option<&SSTable> {
+        self.retired.get(&instance).map(|(sstable, _)| sstable)
+    }
+}
+
+/// Point-in-time read only view of the store.
+///
+/// A snapshot sees every write with a sequence number up to its
+/// watermark and none after, across flushes and compactions. It holds
+/// a copy of the memtable and of the live keydir entries when taken,
+/// and pins the sstables they point to: compaction leaves their files
+/// until the snapshot is dropped. So a snapshot costs memory in
+/// proportion to the keys of the store when taken, not to the writes
+/// made after, and disk space for the sstables compacted meanwhile.
+/// A snapshot keeps the store directory locked until dropped.
+pub struct Snapshot {
+    /// sequence number watermark.
+    seq: u64,
+
+    /// memtable at snapshot time.
+    memtable: BTreeMap<Vec<u8>, DiskEntry>,
+
+    /// range tombstones not flushed at snapshot time.
+    range_tombstones: Vec<RangeTombstone>,
+
+    /// snapshot time, values expired by then read as deleted.
+    now: u32,
+
+    /// live keydir entries at snapshot time.
+    keydir: BTreeMap<Vec<u8>, KeydirEntry>,
+
+    /// pinned instance of each sstable by id.
+    pins: HashMap<u64, u64>,
+
+    /// Disk Storage handler.
+    store: Arc<RwLock<Store>>,
+
+    /// normalization of the keys looked up, if any.
+    key_transform: Option<KeyTransform>,
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        // a poisoned store has no compaction to wait for the pins.
+        if let Ok(mut store) = self.store.write() {
+            store.unpin_sstables(&self.pins);
+        }
+    }
+}
+
+impl Snapshot {
+    /// Snapshot at `seq` of `store` and `memtable`, pinning its sstables.
+    pub(crate) fn new(
+        seq: u64,
+        memtable: BTreeMap<Vec<u8>, DiskEntry>,
+        range_tombstones: Vec<RangeTombstone>,
+        now: u32,
+        store: Arc<RwLock<Store>>,
+        key_transform: Option<KeyTransform>,
+    ) -> Self {
+        let (keydir, pins) = store.write().unwrap().pin_sstables();
+        Self {
+            seq,
+            memtable,
+            range_tombstones,
+            now,
+            keydir,
+            pins,
+            store,
+            key_transform,
+        }
+    }
+
+    /// Sequence number of the last write visible to the snapshot.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Get value of the key as it was when the snapshot was taken.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_stored(&transform::apply(self.key_transform.as_ref(), key))
+    }
+
+    /// `get` of a key as stored, already transformed.
+    fn get_stored(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_expiring(key)?.map(|(value, _)| value))
+    }
+
+    /// `get_stored`, with the expiry of the value, 0 if never.
+    fn get_expiring(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u32)>> {
+        if let Some(entry) = self.memtable.get(key) {
+            if entry.is_merge() {
+                return self.get_merged(entry);
+            }
+            if entry.value.is_empty() || entry.is_expired(self.now) {
+                return Ok(None);
+            }
+            return Ok(Some((entry.value.clone(), entry.expiry())));
+        }
+
+        self.get_flushed(key)
+    }
+
+    /// Keydir entry of `key` at snapshot time, unless deleted or expired.
+    fn flushed_entry(&self, key: &[u8]) -> Option<&KeydirEntry> {
+        // unflushed range tombstones are newer than any flushed version.
+        if self.range_tombstones.iter().any(|t| t.contains(key)) {
+            return None;
+        }
+        self.keydir.get(key).filter(|e| e.is_live(self.now))
+    }
+
+    /// `get_expiring` of a key the memtable of the snapshot lacks.
+    fn get_flushed(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u32)>> {
+        let Some(entry) = self.flushed_entry(key) else {
+            return Ok(None);
+        };
+        let store = self.store.read().unwrap();
+        let value = store.read_pinned(key, entry, self.pins[&entry.file_id])?;
+        Ok(value.map(|value| (value, entry.expiry)))
+    }
+
+    /// `get_expiring` of the merge operands `entry` of the memtable, over
+    /// the version below it, see `Lsm::merge`.
+    fn get_merged(&self, entry: &DiskEntry) -> Result<Option<(Vec<u8>, u32)>> {
+        let operator = self.store.read().unwrap().merge_operator().cloned();
+        let operator = operator.ok_or(LSMLibError::NoMergeOperator)?;
+        let operands = Operands::decode(&entry.value)?;
+        let below = match operands.base {
+            Base::Below => self.get_flushed(&entry.key)?.map(|(value, _)| value),
+            _ => None,
+        };
+
+        let entry = merge::collapsed(entry, operands.resolve(&operator, &entry.key, below));
+        if entry.is_tombstone() || entry.is_expired(self.now) {
+            return Ok(None);
+        }
+        let expiry = entry.expiry();
+        Ok(Some((entry.value, expiry)))
+    }
+
+    /// `get_stored`, but a value still in an sstable is handed as a
+    /// reader of it rather than read whole.
+    pub(crate) fn open_stored(&self, key: &[u8]) -> Result<Option<SnapshotValue<'_>>> {
+        if let Some(entry) = self.memtable.get(key) {
+            if entry.is_merge() {
+                return Ok(self
+                    .get_merged(entry)?
+                    .map(|(v, _)| SnapshotValue::Loaded(Cow::Owned(v))));
+            }
+            if entry.value.is_empty() || entry.is_expired(self.now) {
+                return Ok(None);
+            }
+            return Ok(Some(SnapshotValue::Loaded(Cow::Borrowed(&entry.value))));
+        }
+
+        let Some(entry) = self.flushed_entry(key) else {
+            return Ok(None);
+        };
+        // merge operands are applied to the value whole.
+        if entry.merge {
+            return Ok(self
+                .get_flushed(key)?
+                .map(|(v, _)| SnapshotValue::Loaded(Cow::Owned(v))));
+        }
+
+        let store = self.store.read().unwrap();
+        let reader = store.pinned_value_reader(entry, self.pins[&entry.file_id])?;
+        Ok(Some(SnapshotValue::Stored(reader)))
+    }
+}
+
+/// Value of a key of a snapshot, see `Snapshot::open_stored`.
+pub(crate) enum SnapshotValue<'a> {
+    /// value held in memory.
+    Loaded(Cow<'a, [u8]>),
+
+    /// value in an sstable, read as it goes.
+    Stored(ValueReader),
+}
+
+impl Snapshot {
+    /// Iterate the key/value pairs of the snapshot in key order.
+    ///
+    /// The iterator sees the same point-in-time view as `get`, never
+    /// writes made after the snapshot, and stays valid across flushes
+    /// and compactions: values are read from the sstables the snapshot
+    /// pins when yielded. The keys are collected up front, the values
+    /// only when yielded.
+    pub fn iter(&self) -> SnapshotIter<'_> {
+        self.range(..)
+    }
+
+    /// Iterate the key/value pairs of the snapshot within `range` in
+    /// key order, see `iter`.
+    pub fn range<R>(&self, range: R) -> SnapshotIter<'_>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        SnapshotIter {
+            snapshot: self,
+            keys: self.range_keys(range).into_iter(),
+        }
+    }
+
+    /// `iter`, with the expiry of each value, 0 if never.
+    pub(crate) fn iter_expiring(
+        &self,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>, u32)>> + '_ {
+        self.range_keys(..).into_iter().filter_map(|key| {
+            self.get_expiring(&key)
+                .transpose()
+                .map(|found| found.map(|(value, expiry)| (key, value, expiry)))
+        })
+    }
+
+    /// Keys as stored of the snapshot which may be live within `range`,
+    /// in key order.
+    pub(crate) fn range_keys<R>(&self, range: R) -> BTreeSet<Vec<u8>>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let range = transform::apply_bounds(self.key_transform.as_ref(), &range);
+        let bounds = (range.start_bound(), range.end_bound());
+        let mut keys: BTreeSet<Vec<u8>> = self
+            .memtable
+            .range::<Vec<u8>, _>(bounds)
+            .map(|(k, _)| k.clone())
+            .collect();
+        keys.extend(
+            self.keydir
+                .range::<Vec<u8>, _>(bounds)
+                .map(|(k, _)| k.clone()),
+        );
+
+        keys
+    }
+
+    /// Live keys of the snapshot in key order.
+    ///
+    /// Values are only read for keys holding merge operands, to tell
+    /// whether they delete the key.
+    pub fn list_keys(&self) -> Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        for key in self.range_keys(..) {
+            if self.is_live(&key)? {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Whether `key`, as stored, is live in the snapshot.
+    fn is_live(&self, key: &[u8]) -> Result<bool> {
+        match self.memtable.get(key) {
+            Some(entry) if entry.is_merge() => Ok(self.get_merged(entry)?.is_some()),
+            Some(entry) => Ok(!entry.value.is_empty() && !entry.is_expired(self.now)),
+            None => match self.flushed_entry(key) {
+                Some(entry) if entry.merge => Ok(self.get_flushed(key)?.is_some()),
+                Some(_) => Ok(true),
+                None => Ok(false),
+            },
+        }
+    }
+}
+
+/// Iterator over the key/value pairs of a `Snapshot`, see `Snapshot::iter`.
+pub struct SnapshotIter<'a> {
+    snapshot: &'a Snapshot,
+    keys: std::collections::btree_set::IntoIter<Vec<u8>>,
+}
+
+impl Iterator for SnapshotIter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for key in self.keys.by_ref() {
+            match self.snapshot.get_stored(&key) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
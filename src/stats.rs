@@ -1,10 +1,24 @@
 //! Stats Module.
 
-use std::sync::atomic::AtomicU64;
+use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::error::Result;
 
 pub struct WorkerStats {
     pub read_bytes: AtomicU64,
     pub written_bytes: AtomicU64,
+    pub reads_over_file_limit: AtomicU64,
+
+    /// reads by `ReadSource`, in declaration order.
+    pub reads_by_source: [AtomicU64; 5],
+
+    /// values `Lsm::scan_by_value_prefix` read, and skipped unread.
+    pub value_scan_reads: AtomicU64,
+    pub value_scan_reads_avoided: AtomicU64,
 }
 
 impl WorkerStats {
@@ -12,17 +26,491 @@ impl WorkerStats {
         Self {
             read_bytes: 0.into(),
             written_bytes: 0.into(),
+            reads_over_file_limit: 0.into(),
+            reads_by_source: Default::default(),
+            value_scan_reads: 0.into(),
+            value_scan_reads_avoided: 0.into(),
         }
     }
+
+    pub(crate) fn record_read(&self, source: ReadSource) {
+        let index = match source {
+            ReadSource::Memtable => 0,
+            ReadSource::MemtableTombstone => 1,
+            ReadSource::ReadCache => 2,
+            ReadSource::SSTable { .. } => 3,
+            ReadSource::NotFound { .. } => 4,
+        };
+        self.reads_by_source[index].fetch_add(1, Ordering::Relaxed);
+    }
 }
 
+/// Overview of the size and amplification of a store, see `Lsm::stats`.
 #[derive(Debug, Copy, Clone, Default)]
 pub struct Stats {
+    /// bytes of the live entries: those the keydir points to and the
+    /// memtable ones.
     pub resident_bytes: u64,
+
+    /// bytes of the sstables and the WAL.
     pub on_disk_bytes: u64,
+
+    /// bytes written to the WAL since the last flush.
     pub logged_bytes: u64,
+
+    /// bytes read and written by foreground `get` and `put`.
     pub read_bytes: u64,
     pub written_bytes: u64,
+
+    /// `on_disk_bytes / resident_bytes`, 0 for an empty store.
     pub space_amp: f64,
+
+    /// see `SyncStats::write_amplification`.
     pub write_amp: f64,
+
+    pub slow_syncs: u64,
+}
+
+/// Counters of the compactor, shared with the store handle.
+#[derive(Debug, Default)]
+pub(crate) struct CompactionCounters {
+    pub(crate) runs: AtomicU64,
+    pub(crate) tombstones_dropped: AtomicU64,
+    pub(crate) tombstones_retained_by_grace: AtomicU64,
+    pub(crate) range_tombstones_dropped: AtomicU64,
+    pub(crate) range_deleted_dropped: AtomicU64,
+    pub(crate) expired: AtomicU64,
+    pub(crate) last: Mutex<Option<CompactionOutcome>>,
+}
+
+impl CompactionCounters {
+    pub(crate) fn record(&self, outcome: &CompactionOutcome) {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        self.tombstones_dropped
+            .fetch_add(outcome.tombstones_dropped, Ordering::Relaxed);
+        self.tombstones_retained_by_grace
+            .fetch_add(outcome.tombstones_retained_by_grace, Ordering::Relaxed);
+        self.range_tombstones_dropped
+            .fetch_add(outcome.range_tombstones_dropped, Ordering::Relaxed);
+        self.range_deleted_dropped
+            .fetch_add(outcome.range_deleted_dropped, Ordering::Relaxed);
+        self.expired.fetch_add(outcome.expired, Ordering::Relaxed);
+        *self.last.lock().unwrap() = Some(outcome.clone());
+    }
+
+    pub(crate) fn last(&self) -> Option<CompactionOutcome> {
+        self.last.lock().unwrap().clone()
+    }
+
+    pub(crate) fn stats(&self) -> CompactionStats {
+        CompactionStats {
+            runs: self.runs.load(Ordering::Relaxed),
+            tombstones_dropped: self.tombstones_dropped.load(Ordering::Relaxed),
+            tombstones_retained_by_grace: self.tombstones_retained_by_grace.load(Ordering::Relaxed),
+            range_tombstones_dropped: self.range_tombstones_dropped.load(Ordering::Relaxed),
+            range_deleted_dropped: self.range_deleted_dropped.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Statistics of the background compactions.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// sstable runs merged.
+    pub runs: u64,
+
+    /// tombstones dropped by merges.
+    pub tombstones_dropped: u64,
+
+    /// tombstones, range ones included, a merge could have dropped
+    /// but kept, being younger than `Config::tombstone_grace`.
+    pub tombstones_retained_by_grace: u64,
+
+    /// range tombstones dropped by merges.
+    pub range_tombstones_dropped: u64,
+
+    /// entries dropped by merges as deleted by a range tombstone.
+    pub range_deleted_dropped: u64,
+
+    /// expired entries merges turned into tombstones.
+    pub expired: u64,
+}
+
+/// What a compaction did, see `Lsm::compact`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionOutcome {
+    /// id and size of the merged sstables, empty when nothing was merged.
+    pub inputs: Vec<(u64, u64)>,
+
+    /// id and size of the merged sstable.
+    pub output: Option<(u64, u64)>,
+
+    /// entries written to the merged sstable, tombstones included.
+    pub entries_written: u64,
+
+    pub tombstones_dropped: u64,
+    pub tombstones_retained_by_grace: u64,
+    pub range_tombstones_dropped: u64,
+    pub range_deleted_dropped: u64,
+
+    /// expired entries turned into tombstones, see `Lsm::put_with_ttl`.
+    pub expired: u64,
+
+    /// input bytes minus output bytes.
+    pub bytes_reclaimed: u64,
+
+    pub duration: Duration,
+}
+
+/// What a memtable flush did, see `Lsm::flush`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct FlushOutcome {
+    /// whether an sstable was written, `false` for an empty memtable.
+    pub flushed: bool,
+
+    /// id of the new sstable, the last one of a partitioned flush.
+    pub sstable_id: Option<u64>,
+
+    /// sstables written, see `Config::flush_partitions`.
+    pub sstables: u64,
+
+    /// size of the new sstables in bytes.
+    pub size: u64,
+
+    /// entries written, tombstones included.
+    pub entries: u64,
+
+    /// tombstones written, tombstones of keys no sstable holds excluded.
+    pub tombstones: u64,
+
+    pub range_tombstones: u64,
+
+    pub duration: Duration,
+}
+
+/// What a rewrite did, see `Lsm::rewrite_into`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct RewriteReport {
+    /// flush of the memtable before the copy.
+    pub flush: FlushOutcome,
+
+    /// live keys copied and bytes of the rewritten sstable.
+    pub keys: u64,
+    pub bytes: u64,
+    pub copy_duration: Duration,
+
+    /// open of the rewritten store.
+    pub open_duration: Duration,
+
+    /// time writes waited, the whole rewrite.
+    pub pause: Duration,
+}
+
+/// Bytes a store takes on disk, see `Lsm::disk_usage`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// bytes of the sstables, hint files and WAL.
+    pub bytes: u64,
+
+    /// `Config::max_database_bytes`.
+    pub max_bytes: Option<u64>,
+
+    /// whether `bytes` is past the soft limit, see
+    /// `Config::database_soft_limit_percent`.
+    pub nearly_full: bool,
+}
+
+/// Statistics of the memtable flushes.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct FlushStats {
+    /// memtables flushed to sstables.
+    pub flushes: u64,
+
+    /// tombstones of keys no sstable holds, not written as deleting nothing.
+    pub skipped_tombstones: u64,
+}
+
+/// Bytes read and written by foreground `get` and `put`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct IoStats {
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+
+    /// reads needing more sstables than `Config::max_files_per_read`,
+    /// failed or clamped, a sign the store needs compaction.
+    pub reads_over_file_limit: u64,
+
+    /// gets by what answered them, see `ReadSource`.
+    pub reads_from_memtable: u64,
+    pub reads_from_memtable_tombstone: u64,
+    pub reads_from_cache: u64,
+    pub reads_from_sstable: u64,
+    pub reads_not_found: u64,
+
+    /// flushed values `Lsm::scan_by_value_prefix` read, and skipped
+    /// unread thanks to `Config::value_prefix_index_bytes`.
+    pub value_scan_reads: u64,
+    pub value_scan_reads_avoided: u64,
+}
+
+impl IoStats {
+    /// Share of gets answered by the memtable, tombstones included.
+    pub fn memtable_hit_rate(&self) -> f64 {
+        let memtable = self.reads_from_memtable + self.reads_from_memtable_tombstone;
+        let total =
+            memtable + self.reads_from_cache + self.reads_from_sstable + self.reads_not_found;
+        if total == 0 {
+            return 0.0;
+        }
+        memtable as f64 / total as f64
+    }
+}
+
+impl WorkerStats {
+    pub(crate) fn io_stats(&self) -> IoStats {
+        let reads = |i: usize| self.reads_by_source[i].load(Ordering::Relaxed);
+        IoStats {
+            read_bytes: self.read_bytes.load(Ordering::Relaxed),
+            written_bytes: self.written_bytes.load(Ordering::Relaxed),
+            reads_over_file_limit: self.reads_over_file_limit.load(Ordering::Relaxed),
+            reads_from_memtable: reads(0),
+            reads_from_memtable_tombstone: reads(1),
+            reads_from_cache: reads(2),
+            reads_from_sstable: reads(3),
+            reads_not_found: reads(4),
+            value_scan_reads: self.value_scan_reads.load(Ordering::Relaxed),
+            value_scan_reads_avoided: self.value_scan_reads_avoided.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What answered a get, see `Lsm::get_traced`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReadSource {
+    /// a value in the memtable.
+    Memtable,
+
+    /// a tombstone or range tombstone in the memtable.
+    MemtableTombstone,
+
+    /// the negative cache, knowing the key absent.
+    ReadCache,
+
+    /// the version of the key in sstable `file_id`, value or tombstone.
+    /// The keydir points at it, no bloom filter is checked:
+    /// `bloom_checked` is always `false`.
+    SSTable { file_id: u64, bloom_checked: bool },
+
+    /// no version of the key anywhere. `bloom_rejected` if the bloom
+    /// filters of the sstables a partial open has yet to index ruled
+    /// the key out, see `Config::bloom_bits_per_key`.
+    NotFound { bloom_rejected: bool },
+}
+
+/// Statistics of the negative lookup cache.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct NegativeCacheStats {
+    /// lookups answered by the cache.
+    pub hits: u64,
+
+    /// all lookups which reached the cache.
+    pub lookups: u64,
+}
+
+impl NegativeCacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        if self.lookups == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / self.lookups as f64
+    }
+}
+
+/// Statistics of the live keys sharing a prefix.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct PrefixStats {
+    /// number of live keys.
+    pub keys: u64,
+
+    /// bytes of the latest entries of live keys, on disk or in memtable.
+    pub live_bytes: u64,
+
+    /// number of writes (tombstones included) not flushed yet.
+    pub pending: u64,
+}
+
+/// Kind of file a sync is issued on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileClass {
+    Wal,
+    SSTable,
+    Hint,
+    Dir,
+}
+
+/// Number of buckets of the sync latency histogram, bucket `i` counts
+/// syncs taking less than `2^i` microseconds, the last one the rest.
+pub const SYNC_HISTOGRAM_BUCKETS: usize = 24;
+
+#[derive(Debug, Default)]
+struct SyncCounters {
+    count: AtomicU64,
+    slow: AtomicU64,
+    bytes: AtomicU64,
+    last_at_micros: AtomicU64,
+    last_duration_micros: AtomicU64,
+    histogram: [AtomicU64; SYNC_HISTOGRAM_BUCKETS],
+}
+
+impl SyncCounters {
+    fn stats(&self) -> SyncClassStats {
+        let last_at = self.last_at_micros.load(Ordering::Relaxed);
+        SyncClassStats {
+            count: self.count.load(Ordering::Relaxed),
+            slow: self.slow.load(Ordering::Relaxed),
+            bytes_synced: self.bytes.load(Ordering::Relaxed),
+            last_sync: (last_at > 0).then(|| UNIX_EPOCH + Duration::from_micros(last_at)),
+            last_duration: Duration::from_micros(self.last_duration_micros.load(Ordering::Relaxed)),
+            latency_histogram: self
+                .histogram
+                .iter()
+                .map(|b| b.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+}
+
+/// Times every fsync of a store and reports the slow ones.
+#[derive(Debug)]
+pub(crate) struct SyncMonitor {
+    /// syncs taking longer are logged and counted as slow.
+    slow_threshold: Duration,
+
+    /// counters by `FileClass`.
+    classes: [SyncCounters; 4],
+}
+
+impl SyncMonitor {
+    pub(crate) fn new(slow_threshold: Duration) -> Self {
+        Self {
+            slow_threshold,
+            classes: Default::default(),
+        }
+    }
+
+    /// Run `sync` on file `path` of `class`, `bytes` written since its last sync.
+    pub(crate) fn sync<F>(&self, class: FileClass, path: &Path, bytes: u64, sync: F) -> Result<()>
+    where
+        F: FnOnce() -> std::io::Result<()>,
+    {
+        let start = Instant::now();
+        let result = sync();
+        let elapsed = start.elapsed();
+
+        let counters = &self.classes[class as usize];
+        let micros = elapsed.as_micros() as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+
+        counters.count.fetch_add(1, Ordering::Relaxed);
+        counters.bytes.fetch_add(bytes, Ordering::Relaxed);
+        counters.histogram[bucket.min(SYNC_HISTOGRAM_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        counters
+            .last_duration_micros
+            .store(micros, Ordering::Relaxed);
+        if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+            counters
+                .last_at_micros
+                .store(now.as_micros() as u64, Ordering::Relaxed);
+        }
+
+        if elapsed > self.slow_threshold {
+            counters.slow.fetch_add(1, Ordering::Relaxed);
+            log::warn!(
+                "slow sync: class={:?} path={} duration={:?} bytes_since_last_sync={}",
+                class,
+                path.display(),
+                elapsed,
+                bytes
+            );
+        }
+
+        Ok(result?)
+    }
+
+    /// Sync directory `path`, so created or renamed files in it are durable.
+    pub(crate) fn sync_dir(&self, path: &Path) -> Result<()> {
+        let dir = File::open(path)?;
+        self.sync(FileClass::Dir, path, 0, || dir.sync_all())
+    }
+
+    pub(crate) fn stats(&self) -> SyncStats {
+        SyncStats {
+            wal: self.classes[FileClass::Wal as usize].stats(),
+            sstable: self.classes[FileClass::SSTable as usize].stats(),
+            hint: self.classes[FileClass::Hint as usize].stats(),
+            dir: self.classes[FileClass::Dir as usize].stats(),
+            unsynced_age: None,
+            user_bytes: 0,
+        }
+    }
+}
+
+/// Sync statistics of one `FileClass`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncClassStats {
+    /// number of syncs.
+    pub count: u64,
+
+    /// number of syncs slower than `Config::slow_sync_warn_threshold`.
+    pub slow: u64,
+
+    /// bytes written to the files since their previous sync, summed
+    /// over the syncs, so bytes written and made durable.
+    pub bytes_synced: u64,
+
+    /// when the most recent sync ended.
+    pub last_sync: Option<SystemTime>,
+
+    /// duration of the most recent sync.
+    pub last_duration: Duration,
+
+    /// sync latencies, see `SYNC_HISTOGRAM_BUCKETS`.
+    pub latency_histogram: Vec<u64>,
+}
+
+/// Sync statistics by `FileClass`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncStats {
+    pub wal: SyncClassStats,
+    pub sstable: SyncClassStats,
+    pub hint: SyncClassStats,
+    pub dir: SyncClassStats,
+
+    /// age of the oldest WAL entry not synced yet, see `Lsm::unsynced_age`.
+    pub unsynced_age: Option<Duration>,
+
+    /// key and value bytes put by the user, see `IoStats::written_bytes`.
+    pub user_bytes: u64,
+}
+
+impl SyncStats {
+    /// Number of slow syncs of all classes.
+    pub fn slow_syncs(&self) -> u64 {
+        self.wal.slow + self.sstable.slow + self.hint.slow + self.dir.slow
+    }
+
+    /// Bytes written to the WAL, sstables and hint files and synced.
+    pub fn bytes_synced(&self) -> u64 {
+        self.wal.bytes_synced + self.sstable.bytes_synced + self.hint.bytes_synced
+    }
+
+    /// Bytes synced per user byte put, 0 before any put. Compactions
+    /// and entry headers take it past 1.
+    pub fn write_amplification(&self) -> f64 {
+        if self.user_bytes == 0 {
+            return 0.0;
+        }
+        self.bytes_synced() as f64 / self.user_bytes as f64
+    }
 }
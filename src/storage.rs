@@ -1,26 +1,141 @@
 //! Storage Module.
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::Write;
+use std::io::BufReader;
 use std::path::{Path, PathBuf};
-
-use crate::config::{self, Config};
-use crate::disk::format::DiskEntry;
-use crate::disk::{format::HintEntry, hint::HintFile, sstable::SSTable};
+use std::sync::{Arc, Mutex};
+
+use crate::bloomfilter::BloomFilter;
+use crate::clock::ClockFn;
+use crate::config::{self, Config, VerifyOnOpen};
+use crate::disk::format::{
+    DiskEntry, MetaFile, MetaFileReader, MetaFileWriter, RangeTombstone, COMPRESSION_FORMAT_VERSION,
+};
+use crate::disk::{
+    bloom,
+    format::HintEntry,
+    hint::HintFile,
+    lineage::Lineage,
+    sstable::{self, SSTable, SSTableWriter, SSTableWriterOptions, ValueReader},
+};
 use crate::error::{LSMLibError, Result};
-use crate::keydir::{HashmapKeydir, Keydir, KeydirEntry};
+use crate::keydir::{AnyKeydir, Keydir, KeydirEntry};
+use crate::lsm::merge::{Base, MergeOperatorFn, Operands};
+use crate::migrate;
+use crate::snapshot::SSTablePins;
+use crate::stats::{FileClass, FlushStats, SyncMonitor};
 use crate::utils;
+use crate::worker::index::IndexProgress;
+
+/// Store of an `Lsm`, its keydir ordered if `Config::ordered_keydir`.
+pub type Store = DiskStorage<AnyKeydir>;
 
-pub type Store = DiskStorage<HashmapKeydir>;
+/// Files removed and cut when the store opened.
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct OpenRepairs {
+    /// hint, lineage and bloom filter files removed without their sstable.
+    pub(crate) orphan_files_removed: u64,
+
+    /// sstables whose torn tail was cut off.
+    pub(crate) sstables_truncated: u64,
+}
+
+/// Size of every sstable and hint file in `dir` by path, skipping
+/// those removed meanwhile.
+pub(crate) fn list_sstable_files(dir: &Path) -> Result<BTreeMap<PathBuf, u64>> {
+    let mut files = BTreeMap::new();
+    for suffix in [config::DATA_FILE_SUFFIX, config::HINT_FILE_SUFFIX] {
+        let pattern = format!("{}/*{}", dir.display(), suffix);
+        for path in glob::glob(&pattern)? {
+            let path = path?;
+            match fs::metadata(&path) {
+                Ok(metadata) => files.insert(path, metadata.len()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+        }
+    }
+    Ok(files)
+}
+
+/// Seq of a version and the first bytes of its value.
+type IndexedPrefix = (u64, Box<[u8]>);
+
+/// Id of the sstable holding a version and its value, `None` for a
+/// tombstone, see `DiskStorage::lookup`.
+pub(crate) type Found = (u64, Option<Vec<u8>>);
+
+/// First bytes of the value of each key, with the seq of the version
+/// they belong to: an entry is stale once the keydir holds another.
+struct ValuePrefixIndex {
+    width: usize,
+    prefixes: Mutex<HashMap<Vec<u8>, IndexedPrefix>>,
+}
+
+impl ValuePrefixIndex {
+    fn new(width: u8) -> Self {
+        Self {
+            width: width.into(),
+            prefixes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// First `width` bytes of `value`, all of it if shorter.
+    fn prefix(&self, value: &[u8]) -> Box<[u8]> {
+        value[..value.len().min(self.width)].into()
+    }
+
+    /// Index the prefix of `value`, version `seq` of `key`, unless
+    /// a newer version is indexed.
+    fn insert(&self, key: &[u8], seq: u64, value: &[u8]) {
+        let mut prefixes = self.prefixes.lock().unwrap();
+        if prefixes.get(key).is_some_and(|(indexed, _)| *indexed > seq) {
+            return;
+        }
+        prefixes.insert(key.to_vec(), (seq, self.prefix(value)));
+    }
+
+    /// Whether a value whose indexed prefix is `stored` may start with
+    /// `prefix`, a stored prefix shorter than `width` being the whole value.
+    fn may_match(&self, stored: &[u8], prefix: &[u8]) -> bool {
+        if stored.len() < self.width {
+            return stored.starts_with(prefix);
+        }
+        stored.starts_with(&prefix[..prefix.len().min(self.width)])
+    }
+}
+
+/// Result of `DiskStorage::scan_by_value_prefix`.
+#[derive(Debug, Default)]
+pub(crate) struct ValueScan {
+    pub found: Vec<(Vec<u8>, Vec<u8>)>,
+    pub reads: u64,
+    pub reads_avoided: u64,
+}
 
 /// Store implementation methods.
 pub trait Storage {
+    /// Handle of a flush in progress, see `begin_flush`.
+    type Flush<'a>: FlushHandle
+    where
+        Self: 'a;
+
     /// Get value by key from the store.
     fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>>;
 
+    /// Start flushing entries to a new sstable, streamed one at a time
+    /// through the returned handle.
+    fn begin_flush(&mut self) -> Result<Self::Flush<'_>>;
+
     /// Flush memtable to sstable file in store.
-    fn set(&mut self, items: &BTreeMap<Vec<u8>, DiskEntry>) -> Result<(u64, u64)>;
+    fn set(&mut self, items: &BTreeMap<Vec<u8>, DiskEntry>) -> Result<(u64, u64)> {
+        let mut flush = self.begin_flush()?;
+        for (key, entry) in items {
+            flush.write(key, entry)?;
+        }
+        flush.finish()
+    }
 
     /// List all keys in the store.
     fn keys(&self) -> Result<Vec<Vec<u8>>>;
@@ -50,11 +165,42 @@ pub trait Storage {
     fn flush(&mut self) -> Result<()>;
 }
 
+/// Flush in progress, writing entries to a new sstable.
+///
+/// Entries are written in increasing key order, each key at most once.
+/// They become visible to readers of the store only once `finish`
+/// returns, a flush dropped before leaves the store as it was.
+pub trait FlushHandle {
+    /// Write `entry` of `key` to the sstable.
+    fn write(&mut self, key: &[u8], entry: &DiskEntry) -> Result<()>;
+
+    /// Write a range tombstone to the sstable, before any entry.
+    ///
+    /// Stores which cannot hold range tombstones keep the default,
+    /// failing the flush.
+    fn write_range_tombstone(&mut self, tombstone: &RangeTombstone) -> Result<()> {
+        let _ = tombstone;
+        Err(LSMLibError::Custom(
+            "range tombstones are not supported by this store".to_string(),
+        ))
+    }
+
+    /// Make the written entries durable and visible, returning the id
+    /// and size of the new sstable.
+    fn finish(self) -> Result<(u64, u64)>;
+}
+
 /// Keydir update methods.
 pub trait KeydirUpdate {
     fn compact_and_merge(&mut self, sstable_ids: &[u64]) -> Result<(u64, u64)>;
 }
 
+/// Canonical paths of the lockfiles held by this process.
+///
+/// The lockfile alone excludes other processes, this also rejects a
+/// second open within the process whatever the filesystem does.
+static HELD_LOCKS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
 /// A simple lockfile for `DistStorage`.
 #[derive(Debug)]
 pub struct Lockfile {
@@ -63,29 +209,51 @@ pub struct Lockfile {
 }
 
 impl Lockfile {
-    /// Creates a lock at the provided `path`. Fails if lock is already exists.
-    pub fn lock(path: impl AsRef<Path>) -> Result<Self> {
+    /// Creates a lock at the provided `path`. Fails if lock is already exists,
+    /// or is already held by this process under any spelling of `path`.
+    ///
+    /// The lock file gets permissions `file_mode`, its missing parent
+    /// directory `dir_mode`, if given.
+    pub fn lock(
+        path: impl AsRef<Path>,
+        file_mode: Option<u32>,
+        dir_mode: Option<u32>,
+    ) -> Result<Self> {
         let path = path.as_ref();
 
         let dir_path = path.parent().expect("lock file must have a parent");
-        fs::create_dir_all(dir_path)?;
+        if !dir_path.exists() {
+            utils::create_dir_all(dir_path, dir_mode)?;
+        }
+        let path = fs::canonicalize(dir_path)?.join(path.file_name().expect("lock file name"));
+
+        if !HELD_LOCKS.lock().unwrap().insert(path.clone()) {
+            return Err(LSMLibError::AlreadyLocked);
+        }
 
         let mut lockfile_opts = fs::OpenOptions::new();
         lockfile_opts.read(true).write(true).create_new(true);
 
-        let lockfile = lockfile_opts.open(path)?;
-
-        Ok(Self {
-            handle: Some(lockfile),
-            path: path.to_path_buf(),
-        })
+        match utils::open_with_mode(&mut lockfile_opts, &path, file_mode) {
+            Ok(lockfile) => Ok(Self {
+                handle: Some(lockfile),
+                path,
+            }),
+            Err(e) => {
+                HELD_LOCKS.lock().unwrap().remove(&path);
+                Err(e.into())
+            }
+        }
     }
 }
 
 impl Drop for Lockfile {
     fn drop(&mut self) {
         self.handle.take();
-        fs::remove_file(&self.path).expect("lock already dropped.");
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::error!("failed to remove lock {}: {}", self.path.display(), e);
+        }
+        HELD_LOCKS.lock().unwrap().remove(&self.path);
     }
 }
 
@@ -97,8 +265,8 @@ where
     /// directory for datastore.
     path: PathBuf,
 
-    /// lock for database directory.
-    _lock: Lockfile,
+    /// lock for database directory, none when read only.
+    _lock: Option<Lockfile>,
 
     /// holds a bunch of sstable files.
     sstables: BTreeMap<u64, SSTable>,
@@ -106,6 +274,49 @@ where
     /// Keydir maintains key value index for fast query.
     keydir: K,
 
+    /// sstables live snapshots read.
+    pins: SSTablePins,
+
+    /// monitor of all syncs of the store.
+    sync_monitor: Arc<SyncMonitor>,
+
+    /// counters of the flushes.
+    flush_stats: FlushStats,
+
+    /// range tombstones by id of the sstable holding them.
+    range_tombstones: BTreeMap<u64, Vec<RangeTombstone>>,
+
+    /// highest sstable id ever given, see `Config::sstable_id_start`.
+    id_high_water: u64,
+
+    /// value prefixes by key, see `Config::value_prefix_index_bytes`.
+    value_prefixes: Option<ValuePrefixIndex>,
+
+    /// ids of the sstables a partial open left out of the keydir,
+    /// oldest first, see `Config::partial_open_sstables`.
+    unindexed: Vec<u64>,
+
+    /// sstables indexed out of those opened with.
+    index_progress: Arc<IndexProgress>,
+
+    /// seq and expiry of the newest touch of keys left to index, see
+    /// `apply_touch`.
+    pending_touches: HashMap<Vec<u8>, (u64, u32)>,
+
+    /// operator applying the merge operands of the entries read, see
+    /// `resolve`.
+    merge_operator: Option<MergeOperatorFn>,
+
+    /// clock of the expiry checks and lineage, see `set_clock`.
+    clock: ClockFn,
+
+    /// times compaction or indexing changed keydir entries behind the
+    /// back of the writer, see `rewrites`.
+    rewrites: u64,
+
+    /// what the open repaired.
+    open_repairs: OpenRepairs,
+
     /// config options.
     config: Config,
 }
@@ -130,40 +341,496 @@ where
     }
 
     pub fn open_with_options(path: impl AsRef<Path>, config: Config) -> Result<Self> {
+        Self::open_indexing(path, config, None)
+    }
+
+    /// Open the store indexing only its `newest` sstables if given,
+    /// leaving the older ones to `index_next`.
+    pub(crate) fn open_indexing(
+        path: impl AsRef<Path>,
+        config: Config,
+        newest: Option<u32>,
+    ) -> Result<Self> {
         let path = path.as_ref();
+        config.validate()?;
 
         log::info!("open store path: {}", path.display());
 
-        fs::create_dir_all(path)?;
-        fs::File::open(path)?.flush()?; // sync to disk.
+        let sync_monitor = Arc::new(SyncMonitor::new(config.slow_sync_warn_threshold));
 
-        let lock = Lockfile::lock(path.join("LOCK")).or(Err(LSMLibError::AlreadyLocked))?;
+        let lock = if config.read_only {
+            // fail on a missing store rather than open an empty one.
+            fs::read_dir(path)?;
+            None
+        } else {
+            utils::create_dir_all(path, config.dir_mode)?;
+            sync_monitor.sync_dir(path)?;
+
+            let lock = Lockfile::lock(path.join("LOCK"), config.file_mode, config.dir_mode)
+                .or(Err(LSMLibError::AlreadyLocked))?;
+            Some(lock)
+        };
+        // every file path derives from the same spelling of the dir.
+        let path = fs::canonicalize(path)?;
+        let path = path.as_path();
+
+        migrate::check_format_version(path, config.file_mode, config.read_only)?;
 
         let mut store = Self {
             path: path.to_path_buf(),
             _lock: lock,
             sstables: BTreeMap::new(),
-            keydir: K::default(),
+            keydir: K::new(&config),
+            pins: SSTablePins::default(),
+            sync_monitor,
+            flush_stats: FlushStats::default(),
+            range_tombstones: BTreeMap::new(),
+            id_high_water: read_id_high_water(path)?,
+            value_prefixes: config.value_prefix_index_bytes.map(ValuePrefixIndex::new),
+            unindexed: Vec::new(),
+            index_progress: Arc::default(),
+            pending_touches: HashMap::new(),
+            merge_operator: None,
+            clock: Arc::new(utils::now_secs),
+            rewrites: 0,
+            open_repairs: OpenRepairs::default(),
             config,
         };
 
         store.open_sstables()?;
-        store.build_keydir()?;
+        store.remove_orphan_files()?;
+        store.verify_on_open()?;
+        store.build_keydir(newest)?;
+
+        let max_id = store.sstables.keys().max().copied().unwrap_or(0);
+        store.id_high_water = store.id_high_water.max(max_id);
 
         Ok(store)
     }
 
+    /// Canonical path of the store directory.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn list_sstables(&self) -> BTreeMap<u64, u64> {
         self.sstables.iter().map(|s| (*s.0, s.1.size())).collect()
     }
 
+    /// Bytes of the sstables and their hint files.
+    pub(crate) fn disk_bytes(&self) -> Result<u64> {
+        let mut bytes = 0;
+        for (id, sstable) in &self.sstables {
+            bytes += sstable.size();
+            match fs::metadata(utils::format_hint_path(&self.path, *id)) {
+                Ok(metadata) => bytes += metadata.len(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Stamp the store with `COMPRESSION_FORMAT_VERSION` before writing
+    /// an sstable which may hold compressed values.
+    pub(crate) fn require_compression_format(&self) -> Result<()> {
+        if self.config.zstd_sstable_compression_level == 0 {
+            return Ok(());
+        }
+        migrate::require_format_version(
+            &self.path,
+            COMPRESSION_FORMAT_VERSION,
+            self.config.file_mode,
+        )
+    }
+
+    /// Record `id` as given, once its sstable is written.
+    fn raise_id_high_water(&mut self, id: u64) -> Result<()> {
+        write_id_high_water(&self.path, id, self.config.file_mode)?;
+        self.id_high_water = id;
+        Ok(())
+    }
+
+    /// Start flushing to sstable `id`, a reserved id above every id given.
+    ///
+    /// Fails with `SSTableIdUnavailable` for any other id.
+    pub(crate) fn begin_flush_as(&mut self, id: u64) -> Result<DiskFlush<'_, K>> {
+        if !self.config.is_reserved_id(id) || id <= self.id_high_water {
+            return Err(LSMLibError::SSTableIdUnavailable(id));
+        }
+        self.flush_to(id)
+    }
+
+    fn flush_to(&mut self, id: u64) -> Result<DiskFlush<'_, K>> {
+        self.require_compression_format()?;
+        let writer = SSTableWriter::create(
+            utils::format_sstable_path(&self.path, id),
+            SSTableWriterOptions::from_config(&self.config),
+        )?
+        .with_monitor(self.sync_monitor());
+
+        Ok(DiskFlush {
+            store: self,
+            id,
+            writer,
+            written: Vec::new(),
+            range_tombstones: Vec::new(),
+            finished: false,
+        })
+    }
+
+    /// Id of the sstable holding the version of `key` in the keydir,
+    /// with its value, `None` for a tombstone or a value expired at
+    /// `now`. `None` if the keydir knows no version.
+    pub(crate) fn lookup(&self, key: &[u8], now: u32) -> Result<Option<Found>> {
+        let keydir_entry = match self.keydir.get(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        log::trace!(
+            "found key `{}` in keydir, got value `{:?}`",
+            String::from_utf8_lossy(key),
+            &keydir_entry,
+        );
+
+        let file_id = keydir_entry.file_id;
+        if !keydir_entry.is_live(now) {
+            return Ok(Some((file_id, None)));
+        }
+
+        Ok(Some((file_id, self.read_value(key, keydir_entry)?)))
+    }
+
+    /// `lookup` of each of `keys`, in order. The values are read sstable
+    /// by sstable, in offset order.
+    pub(crate) fn lookup_many(&self, keys: &[&[u8]], now: u32) -> Result<Vec<Option<Found>>> {
+        let entries: Vec<_> = keys.iter().map(|key| self.keydir.get(key)).collect();
+
+        let mut order: Vec<usize> = (0..keys.len())
+            .filter(|&i| entries[i].is_some_and(|entry| entry.is_live(now)))
+            .collect();
+        order.sort_unstable_by_key(|&i| entries[i].map(|entry| (entry.file_id, entry.offset)));
+
+        let mut values = vec![None; keys.len()];
+        for i in order {
+            values[i] = match entries[i] {
+                Some(entry) => self.read_value(keys[i], entry)?,
+                None => None,
+            };
+        }
+
+        Ok(entries
+            .into_iter()
+            .zip(values)
+            .map(|(entry, value)| entry.map(|entry| (entry.file_id, value)))
+            .collect())
+    }
+
+    /// Value of the live version `keydir_entry` of `key`, `None` if its
+    /// merge operands delete it.
+    fn read_value(&self, key: &[u8], keydir_entry: &KeydirEntry) -> Result<Option<Vec<u8>>> {
+        let file_id = keydir_entry.file_id;
+        let sst = self.sstables.get(&file_id).unwrap_or_else(|| {
+            panic!("sstable file `{}` not found", file_id);
+        });
+
+        let disk_entry = sst
+            .read_sized(keydir_entry.offset, keydir_entry.size)
+            .map_err(|e| match e {
+                // tell a damaged file from one changed behind our back.
+                LSMLibError::ChecksumMismatch { .. } | LSMLibError::StaleKeydirEntry { .. } => {
+                    sst.verify_fingerprint().err().unwrap_or(e)
+                }
+                e => e,
+            })?;
+        self.resolve(key, disk_entry)
+    }
+
+    /// Whether `entry`, the version of `key` in the keydir, is live at
+    /// `now`, merge operands read to tell whether they delete the key.
+    pub(crate) fn is_live(&self, key: &[u8], entry: &KeydirEntry, now: u32) -> Result<bool> {
+        if !entry.is_live(now) || !entry.merge {
+            return Ok(entry.is_live(now));
+        }
+        Ok(self.read_value(key, entry)?.is_some())
+    }
+
+    /// Operator applying the merge operands of the entries read.
+    pub(crate) fn set_merge_operator(&mut self, operator: Option<MergeOperatorFn>) {
+        self.merge_operator = operator;
+    }
+
+    pub(crate) fn merge_operator(&self) -> Option<&MergeOperatorFn> {
+        self.merge_operator.as_ref()
+    }
+
+    /// Clock of the expiry checks and lineage, the system clock until
+    /// set, see `OpenOptions::clock`.
+    pub(crate) fn set_clock(&mut self, clock: ClockFn) {
+        self.clock = clock;
+    }
+
+    fn now(&self) -> u32 {
+        (self.clock)()
+    }
+
+    /// Value of `entry` of `key`, its merge operands applied, `None`
+    /// if they delete it. Fails with `NoMergeOperator` for operands
+    /// without an operator.
+    pub(crate) fn resolve(&self, key: &[u8], entry: DiskEntry) -> Result<Option<Vec<u8>>> {
+        if !entry.is_merge() {
+            return Ok(Some(entry.value));
+        }
+
+        let operator = self
+            .merge_operator
+            .as_ref()
+            .ok_or(LSMLibError::NoMergeOperator)?;
+        let operands = Operands::decode(&entry.value)?;
+        // a flush settles the operands on the value below.
+        if operands.base == Base::Below {
+            return Err(LSMLibError::Custom(format!(
+                "unsettled merge operands of key {:?} in sstable {:?}",
+                key, entry.file_id
+            )));
+        }
+        Ok(operands.resolve(operator, key, None))
+    }
+
+    /// Live keys whose value starts with `prefix`, with their value,
+    /// skipping the keys `shadowed` holds for with their entry.
+    ///
+    /// Values whose indexed prefix cannot match are skipped unread,
+    /// values not indexed yet are read and indexed.
+    pub(crate) fn scan_by_value_prefix<F>(&self, prefix: &[u8], shadowed: F) -> Result<ValueScan>
+    where
+        F: Fn(&[u8], &KeydirEntry) -> bool,
+    {
+        let index = self.value_prefixes.as_ref();
+        let mut prefixes = index.map(|index| index.prefixes.lock().unwrap());
+        let mut scan = ValueScan::default();
+
+        for (key, entry) in self.keydir.entries() {
+            if entry.tombstone || shadowed(key, entry) {
+                continue;
+            }
+
+            let may_match = index.zip(prefixes.as_ref()).and_then(|(index, prefixes)| {
+                prefixes
+                    .get(key)
+                    .filter(|(seq, _)| *seq == entry.seq)
+                    .map(|(_, stored)| index.may_match(stored, prefix))
+            });
+            if may_match == Some(false) {
+                scan.reads_avoided += 1;
+                continue;
+            }
+
+            let value = self.read_value(key, entry)?;
+            scan.reads += 1;
+            let Some(value) = value else {
+                continue;
+            };
+            if let (None, Some(index), Some(prefixes)) = (may_match, index, prefixes.as_mut()) {
+                prefixes.insert(key.to_vec(), (entry.seq, index.prefix(&value)));
+            }
+            if value.starts_with(prefix) {
+                scan.found.push((key.to_vec(), value));
+            }
+        }
+
+        Ok(scan)
+    }
+
+    /// Monitor of all syncs of the store.
+    pub(crate) fn sync_monitor(&self) -> Arc<SyncMonitor> {
+        Arc::clone(&self.sync_monitor)
+    }
+
+    /// Files removed and cut when the store opened.
+    pub(crate) fn open_repairs(&self) -> OpenRepairs {
+        self.open_repairs
+    }
+
+    /// Counters of the flushes since open.
+    pub(crate) fn flush_stats(&self) -> FlushStats {
+        self.flush_stats
+    }
+
+    /// Keydir of the store.
+    pub(crate) fn keydir(&self) -> &K {
+        &self.keydir
+    }
+
+    /// Keep counting the live keys starting with `prefix`, see
+    /// `Keydir::count_prefix`.
+    pub(crate) fn count_prefix(&mut self, prefix: &[u8]) {
+        self.keydir.count_prefix(prefix);
+    }
+
+    /// Stop counting the keys starting with `prefix`.
+    pub(crate) fn uncount_prefix(&mut self, prefix: &[u8]) {
+        self.keydir.uncount_prefix(prefix);
+    }
+
+    /// Times compaction merged sstables or indexing went on since open,
+    /// changing the keydir entries of keys the memtable may hold.
+    pub(crate) fn rewrites(&self) -> u64 {
+        self.rewrites
+    }
+
+    /// Max sequence number of all sstables.
+    pub fn max_seq(&self) -> u64 {
+        self.sstables
+            .values()
+            .map(|s| s.max_seq())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Pin every sstable for a snapshot, returning the live keydir
+    /// entries and the instance pinned of each sstable id.
+    pub(crate) fn pin_sstables(&mut self) -> (BTreeMap<Vec<u8>, KeydirEntry>, HashMap<u64, u64>) {
+        let keydir = self
+            .keydir
+            .entries()
+            .filter(|(_, e)| !e.tombstone)
+            .map(|(k, e)| (k.to_vec(), *e))
+            .collect();
+        let pins = self
+            .sstables
+            .keys()
+            .map(|id| (*id, self.pins.pin(*id)))
+            .collect();
+        (keydir, pins)
+    }
+
+    /// Release the sstables pinned by `pin_sstables`, deleting the files
+    /// compaction retired once no snapshot reads them.
+    pub(crate) fn unpin_sstables(&mut self, pins: &HashMap<u64, u64>) {
+        for (id, instance) in pins {
+            let Some((sstable, path)) = self.pins.unpin(*id, *instance) else {
+                continue;
+            };
+            drop(sstable);
+            if let Some(path) = path {
+                if let Err(e) = fs::remove_file(&path) {
+                    log::warn!("failed to remove {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+
+    /// Value of `key` of the keydir `entry` of a snapshot, read from
+    /// `instance` of its sstable, see `pin_sstables`.
+    pub(crate) fn read_pinned(
+        &self,
+        key: &[u8],
+        entry: &KeydirEntry,
+        instance: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        let disk_entry = self
+            .pinned_sstable(entry.file_id, instance)
+            .read_sized(entry.offset, entry.size)?;
+        self.resolve(key, disk_entry)
+    }
+
+    /// `read_pinned`, but the value is handed as a reader of it rather
+    /// than read whole, see `SSTable::value_reader`.
+    pub(crate) fn pinned_value_reader(
+        &self,
+        entry: &KeydirEntry,
+        instance: u64,
+    ) -> Result<ValueReader> {
+        self.pinned_sstable(entry.file_id, instance)
+            .value_reader(entry.offset, entry.size)
+    }
+
+    /// `instance` of sstable `id`, the current one unless retired.
+    fn pinned_sstable(&self, id: u64, instance: u64) -> &SSTable {
+        self.pins
+            .retired(instance)
+            .or_else(|| self.sstables.get(&id))
+            .unwrap_or_else(|| panic!("pinned sstable file `{}` not found", id))
+    }
+
+    /// Check none of `sstable_ids` was changed on disk since opened,
+    /// see `SSTable::verify_fingerprint`.
+    pub(crate) fn verify_fingerprints(&self, sstable_ids: &[u64]) -> Result<()> {
+        sstable_ids
+            .iter()
+            .filter_map(|id| self.sstables.get(id))
+            .try_for_each(SSTable::verify_fingerprint)
+    }
+
+    /// Check every keydir entry points within a known sstable, see
+    /// `Lsm::check_invariants`.
+    pub(crate) fn check_invariants(&self) -> std::result::Result<(), String> {
+        let sizes = self.list_sstables();
+        for (key, entry) in self.keydir.entries() {
+            let size = sizes.get(&entry.file_id).ok_or_else(|| {
+                format!(
+                    "keydir entry of key {:?} points to missing sstable {}, known: {:?}",
+                    key,
+                    entry.file_id,
+                    sizes.keys().collect::<Vec<_>>()
+                )
+            })?;
+            if entry.offset + entry.size > *size {
+                return Err(format!(
+                    "keydir entry of key {:?} at {}+{} is past the end of sstable {} of {} bytes",
+                    key, entry.offset, entry.size, entry.file_id, size
+                ));
+            }
+            let max_seq = self.sstables[&entry.file_id].max_seq();
+            if entry.seq > max_seq {
+                return Err(format!(
+                    "keydir entry of key {:?} has seq {} above the max seq {} of sstable {}",
+                    key, entry.seq, max_seq, entry.file_id
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Newest intact version of `key` older than its keydir entry, with
+    /// the id of the sstable holding it, scanning sstables `sstable_ids`.
+    pub(crate) fn find_older_version(
+        &mut self,
+        key: &[u8],
+        sstable_ids: &[u64],
+    ) -> Option<(u64, DiskEntry)> {
+        let seq = self.keydir.get(key)?.seq;
+
+        let mut found: Option<DiskEntry> = None;
+        for (_, sst) in self
+            .sstables
+            .iter_mut()
+            .filter(|(id, _)| sstable_ids.contains(id))
+        {
+            // a torn entry ends the scan, found up to it.
+            for entry in sst.iter().map_while(|e| e.ok()) {
+                if entry.key == key
+                    && !entry.is_touch()
+                    && entry.seq() < seq
+                    && entry.is_validate()
+                    && found.as_ref().is_none_or(|f| f.seq() < entry.seq())
+                {
+                    found = Some(entry);
+                }
+            }
+        }
+
+        found.and_then(|e| Some((e.file_id?, e)))
+    }
+
     /// Open sstable files(they are immutable).
     fn open_sstables(&mut self) -> Result<()> {
         let pattern = format!("{}/*{}", self.path.display(), config::DATA_FILE_SUFFIX);
         log::trace!("read sstable files with pattern {}", &pattern);
 
         for path in glob::glob(&pattern)? {
-            let sst = SSTable::new(path?.as_path(), false)?;
+            let sst = self.open_sstable(path?.as_path())?;
 
             self.sstables.insert(sst.id(), sst);
         }
@@ -172,121 +839,704 @@ where
         Ok(())
     }
 
-    /// Build keydir index from sstable or it's hint.
-    fn build_keydir(&mut self) -> Result<()> {
+    /// Open the sstable at `path` with its bloom filter, if any.
+    fn open_sstable(&self, path: &Path) -> Result<SSTable> {
+        let sst = SSTable::new(path, false)?;
+        let bloom = bloom::read(&utils::format_bloom_path(&self.path, sst.id()))?;
+        Ok(sst.with_bloom(bloom))
+    }
+
+    /// Remove the hint, lineage and bloom filter files left without their
+    /// sstable, e.g.
+    /// after the sstable was deleted by hand, so a later sstable of the
+    /// same id never gets a stale hint, and the compacted sstables left
+    /// for snapshots by a crash. Kept, but logged, by a read only store.
+    fn remove_orphan_files(&mut self) -> Result<()> {
+        let mut removed = 0;
+        for suffix in [
+            config::HINT_FILE_SUFFIX,
+            config::LINEAGE_FILE_SUFFIX,
+            config::BLOOM_FILE_SUFFIX,
+            config::RETIRED_FILE_SUFFIX,
+        ] {
+            let pattern = format!("{}/*{}", self.path.display(), suffix);
+            for path in glob::glob(&pattern)? {
+                let path = path?;
+                let orphan = suffix == config::RETIRED_FILE_SUFFIX
+                    || utils::parse_file_id(&path)
+                        .is_some_and(|id| !self.sstables.contains_key(&id));
+                if !orphan {
+                    continue;
+                }
+
+                if self.config.read_only {
+                    log::warn!("{} has no sstable", path.display());
+                    continue;
+                }
+                log::warn!("removing {} without sstable", path.display());
+                fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+
+        if removed > 0 {
+            self.sync_monitor.sync_dir(&self.path)?;
+        }
+        self.open_repairs.orphan_files_removed = removed;
+
+        Ok(())
+    }
+
+    /// Check the entries of the sstables `config.verify_on_open` asks for,
+    /// newest first, see `VerifyOnOpen`.
+    fn verify_on_open(&mut self) -> Result<()> {
+        let count = match self.config.verify_on_open {
+            VerifyOnOpen::None => return Ok(()),
+            VerifyOnOpen::NewestFiles(n) => n as usize,
+            VerifyOnOpen::All => self.sstables.len(),
+        };
+
+        let file_ids: Vec<u64> = self.sstables.keys().rev().take(count).copied().collect();
+        for file_id in file_ids {
+            self.verify_sstable_on_open(file_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Cut the torn tail of sstable `file_id` off, if any, and drop its
+    /// hint file if it points past the intact entries.
+    fn verify_sstable_on_open(&mut self, file_id: u64) -> Result<()> {
+        let path = utils::format_sstable_path(&self.path, file_id);
+        let hint_path = utils::format_hint_path(&self.path, file_id);
+        let torn = |reason: String| LSMLibError::VerificationFailed {
+            path: path.clone(),
+            reason,
+        };
+
+        let len = self.sstables[&file_id].size();
+        let intact = sstable::intact_len(&path)?;
+        if intact < len {
+            if self.config.read_only {
+                return Err(torn(format!("torn tail at offset {}", intact)));
+            }
+
+            log::warn!(
+                "torn tail detected, truncating {} bytes from {}",
+                len - intact,
+                path.display()
+            );
+            let file = fs::OpenOptions::new().write(true).open(&path)?;
+            file.set_len(intact)?;
+            self.sync_monitor
+                .sync(FileClass::SSTable, &path, 0, || file.sync_all())?;
+            self.sstables.insert(file_id, self.open_sstable(&path)?);
+            self.open_repairs.sstables_truncated += 1;
+        }
+
+        if !hint_path.exists() {
+            return Ok(());
+        }
+
+        let mut hint = BufReader::new(File::open(&hint_path)?);
+        let hint_intact = loop {
+            match HintEntry::read_next(&mut hint) {
+                Ok(None) => break true,
+                Ok(Some(entry)) if entry.offset() + entry.size() <= intact => {}
+                Ok(Some(_)) | Err(_) => break false,
+            }
+        };
+        if !hint_intact {
+            if self.config.read_only {
+                return Err(torn(format!(
+                    "hint file {} points past the intact entries",
+                    hint_path.display()
+                )));
+            }
+
+            log::warn!(
+                "hint file {} points past the intact entries, removing it",
+                hint_path.display()
+            );
+            fs::remove_file(&hint_path)?;
+            self.sync_monitor.sync_dir(&self.path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the hint file of every sstable lacking one, e.g. flushed
+    /// without `Config::hints_on_flush`, returning how many were written.
+    pub(crate) fn write_missing_hints(&mut self) -> Result<u64> {
+        let mut written = 0;
+        for (id, sstable) in self.sstables.iter_mut() {
+            let hint_path = utils::format_hint_path(&self.path, *id);
+            if hint_path.exists() {
+                continue;
+            }
+
+            // not the compaction tmp path, a merge may be writing it.
+            let mut tmp_path = hint_path.clone().into_os_string();
+            tmp_path.push("-backfill");
+            let mut hint = HintFile::create(&tmp_path, self.config.file_mode)?
+                .with_monitor(Arc::clone(&self.sync_monitor));
+            for entry in sstable.iter() {
+                hint.write_entry(HintEntry::from(&entry?))?;
+            }
+            hint.sync()?;
+            fs::rename(&tmp_path, &hint_path)?;
+            written += 1;
+        }
+
+        if written > 0 {
+            self.sync_monitor.sync_dir(&self.path)?;
+        }
+
+        Ok(written)
+    }
+
+    /// Build keydir index from sstable or it's hint, of the `newest`
+    /// sstables only if given.
+    fn build_keydir(&mut self, newest: Option<u32>) -> Result<()> {
         let mut file_ids: Vec<u64> = self.sstables.keys().cloned().collect();
         file_ids.sort();
 
+        let total = file_ids.len() as u64;
+        if let Some(newest) = newest {
+            let split = file_ids.len().saturating_sub(newest as usize);
+            self.unindexed = file_ids.drain(..split).collect();
+        }
+        self.index_progress = Arc::new(IndexProgress::new(file_ids.len() as u64, total));
+
         for file_id in file_ids {
-            let hint_file_path = utils::format_hint_path(&self.path, file_id);
-            if hint_file_path.exists() {
-                self.build_keydir_from_hint(hint_file_path.as_path())?;
-            } else {
-                self.build_keydir_from_sstable(file_id)?;
+            self.index_sstable(file_id, false)?;
+        }
+
+        let tombstones: Vec<RangeTombstone> =
+            self.range_tombstones.values().flatten().cloned().collect();
+        for tombstone in &tombstones {
+            self.apply_range_tombstone(tombstone);
+        }
+
+        log::info!(
+            "build keydir done, got {} keys, {} sstables left to index",
+            self.keydir.len(),
+            self.unindexed.len()
+        );
+
+        Ok(())
+    }
+
+    /// Index sstable `file_id` from its hint file or its data, see
+    /// `index_next` for `behind`.
+    fn index_sstable(&mut self, file_id: u64, behind: bool) -> Result<()> {
+        let hint_file_path = utils::format_hint_path(&self.path, file_id);
+        if hint_file_path.exists() {
+            self.build_keydir_from_hint(hint_file_path.as_path(), behind)
+        } else {
+            self.build_keydir_from_sstable(file_id, behind)
+        }
+    }
+
+    /// Index the newest sstable a partial open left out, returning
+    /// whether any is left.
+    ///
+    /// The sstable is older than any the keydir knows, so its entries
+    /// go in `behind`: only for keys the keydir lacks a newer version of,
+    /// and unless a range tombstone covers them.
+    pub(crate) fn index_next(&mut self) -> Result<bool> {
+        let Some(file_id) = self.unindexed.pop() else {
+            return Ok(false);
+        };
+
+        self.rewrites += 1;
+        self.index_sstable(file_id, true)?;
+        let tombstones = self
+            .range_tombstones
+            .get(&file_id)
+            .cloned()
+            .unwrap_or_default();
+        for tombstone in &tombstones {
+            self.apply_range_tombstone(tombstone);
+        }
+        self.index_progress.advance();
+        if self.unindexed.is_empty() {
+            self.pending_touches.clear();
+        }
+        log::debug!("indexed sstable {}, {} left", file_id, self.unindexed.len());
+
+        Ok(!self.unindexed.is_empty())
+    }
+
+    /// Bloom filters of the sstables older than `id`, `None` if any
+    /// has none.
+    pub(crate) fn blooms_below(&self, id: u64) -> Option<Vec<Arc<BloomFilter>>> {
+        self.sstables
+            .range(..id)
+            .map(|(_, sst)| sst.bloom())
+            .collect()
+    }
+
+    /// Whether any sstable a partial open left out may hold a version
+    /// of `key`, see `SSTable::may_contain`.
+    pub(crate) fn unindexed_may_contain(&self, key: &[u8]) -> bool {
+        self.unindexed
+            .iter()
+            .any(|id| self.sstables[id].may_contain(key))
+    }
+
+    /// Whether sstables a partial open left out are still unindexed.
+    pub(crate) fn indexing(&self) -> bool {
+        !self.unindexed.is_empty()
+    }
+
+    pub(crate) fn index_progress(&self) -> Arc<IndexProgress> {
+        Arc::clone(&self.index_progress)
+    }
+
+    /// Range tombstones of the store, by id of the sstable holding them.
+    pub(crate) fn range_tombstones(&self) -> &BTreeMap<u64, Vec<RangeTombstone>> {
+        &self.range_tombstones
+    }
+
+    /// Mark the keydir entries `tombstone` covers as tombstones.
+    ///
+    /// The entries stay in the keydir until compaction drops them, like
+    /// those of point tombstones.
+    fn apply_range_tombstone(&mut self, tombstone: &RangeTombstone) {
+        let covered: Vec<(Vec<u8>, KeydirEntry)> = self
+            .keydir
+            .entries()
+            .filter(|(k, e)| !e.tombstone && tombstone.covers(k, e.seq))
+            .map(|(k, e)| (k.to_vec(), *e))
+            .collect();
+
+        for (key, mut entry) in covered {
+            entry.tombstone = true;
+            self.keydir.put(key, entry);
+        }
+    }
+
+    /// Move the expiry of the version of `key` the touch at `seq`
+    /// applies to, the older one in the keydir, to `expiry`, see
+    /// `Lsm::touch`.
+    ///
+    /// A key the keydir lacks while sstables are left to index gets the
+    /// expiry once indexed, see `touched`.
+    pub(crate) fn apply_touch(&mut self, key: &[u8], seq: u64, expiry: u32) {
+        let Some(mut entry) = self.keydir.get(key).copied() else {
+            if self.indexing() {
+                let pending = self
+                    .pending_touches
+                    .entry(key.to_vec())
+                    .or_insert((seq, expiry));
+                if pending.0 < seq {
+                    *pending = (seq, expiry);
+                }
+            }
+            return;
+        };
+        if entry.tombstone || entry.seq >= seq {
+            return;
+        }
+
+        entry.expiry = expiry;
+        self.keydir.put(key.to_vec(), entry);
+    }
+
+    /// Read the range tombstone recorded at `offset` of sstable `file_id`.
+    fn read_range_tombstone(&self, file_id: u64, offset: u64, size: u64) -> Result<RangeTombstone> {
+        let entry = self.sstables[&file_id].read_sized(offset, size)?;
+        entry.range_tombstone().ok_or_else(|| {
+            LSMLibError::Custom(format!(
+                "entry at offset {} of file {} is not a range tombstone",
+                offset, file_id
+            ))
+        })
+    }
+
+    /// Point keydir entries still in `sstable_ids` at the merged sstable `merged_id`.
+    ///
+    /// Entries flushed to other sstables during the merge are newer and
+    /// left alone. Entries of the run missing from the merged sstable are
+    /// tombstones compaction dropped. Entries keep the expiry of the
+    /// keydir, which newer touches may have moved.
+    fn apply_merged(&mut self, merged_id: u64, sstable_ids: &[u64]) -> Result<()> {
+        self.rewrites += 1;
+        let hint_path = utils::format_hint_path(&self.path, merged_id);
+        let merged: Vec<(Vec<u8>, KeydirEntry, bool)> = if hint_path.exists() {
+            HintFile::new(&hint_path, false)?
+                .iter()
+                .map(|e| Ok((e.key.clone(), KeydirEntry::try_from(&e)?, e.is_touch())))
+                .collect::<Result<_>>()?
+        } else {
+            self.sstables
+                .get_mut(&merged_id)
+                .unwrap()
+                .iter()
+                .map(|e| {
+                    let e = e?;
+                    Ok((e.key.clone(), KeydirEntry::try_from(&e)?, e.is_touch()))
+                })
+                .collect::<Result<_>>()?
+        };
+
+        for id in sstable_ids {
+            self.range_tombstones.remove(id);
+        }
+
+        let mut max_seq = 0;
+        let mut applied = HashSet::new();
+        for (key, mut entry, touch) in merged {
+            max_seq = max_seq.max(entry.seq);
+            // a touch left unfolded is no version of its key.
+            if touch {
+                continue;
+            }
+
+            if key.is_empty() {
+                let tombstone = self.read_range_tombstone(merged_id, entry.offset, entry.size)?;
+                self.range_tombstones
+                    .entry(merged_id)
+                    .or_default()
+                    .push(tombstone);
+                continue;
+            }
+
+            let Some(old) = self.keydir.get(&key) else {
+                continue;
+            };
+            if sstable_ids.contains(&old.file_id) {
+                if old.seq == entry.seq && !entry.tombstone {
+                    entry.expiry = old.expiry;
+                }
+                self.keydir.put(key.clone(), entry);
+                applied.insert(key);
             }
         }
 
-        log::info!("build keydir done, got {} keys", self.keydir.len());
+        self.keydir
+            .retain(|k, e| !sstable_ids.contains(&e.file_id) || applied.contains(k));
+
+        // merged entries keep their seq, drop versions compacted away.
+        if let Some(index) = self.value_prefixes.as_mut() {
+            let keydir = &self.keydir;
+            index
+                .prefixes
+                .get_mut()
+                .unwrap()
+                .retain(|k, (seq, _)| keydir.get(k).is_some_and(|e| e.seq == *seq && !e.tombstone));
+        }
+
+        if let Some(sst) = self.sstables.get_mut(&merged_id) {
+            sst.update_max_seq(max_seq);
+        }
 
         Ok(())
     }
 
-    fn build_keydir_from_hint(&mut self, path: &Path) -> Result<()> {
+    fn build_keydir_from_hint(&mut self, path: &Path, behind: bool) -> Result<()> {
         log::trace!("build keydir from hint file {}", path.display());
         let mut hint_file = HintFile::new(path, false)?;
-        let _hint_file_id = hint_file.id();
-
-        for entry in hint_file.iter() {
-            if entry.value_sz() != 0 {
-                let keydir_entry = KeydirEntry::try_from(&entry)?;
-                self.keydir.put(entry.key, keydir_entry);
-            } else {
-                self.keydir.remove(&entry.key);
+        let hint_file_id = hint_file.id();
+
+        let mut max_seq = 0;
+        let mut touches = Vec::new();
+        // may be gone already, compacted away by a live writer.
+        for entry in hint_file.try_iter()? {
+            max_seq = max_seq.max(entry.seq());
+            if entry.key.is_empty() {
+                let tombstone =
+                    self.read_range_tombstone(hint_file_id, entry.offset(), entry.size())?;
+                self.range_tombstones
+                    .entry(hint_file_id)
+                    .or_default()
+                    .push(tombstone);
+                continue;
+            }
+            // one entry per key, the version a touch applies to is older.
+            if entry.is_touch() {
+                touches.push((entry.seq(), entry.expiry(), entry.key));
+                continue;
             }
+            if behind
+                && shadowed(
+                    &self.keydir,
+                    &self.range_tombstones,
+                    &entry.key,
+                    entry.seq(),
+                )
+            {
+                continue;
+            }
+            let keydir_entry = touched(
+                &self.pending_touches,
+                &entry.key,
+                KeydirEntry::try_from(&entry)?,
+            );
+            self.keydir.put(entry.key, keydir_entry);
+        }
+
+        if let Some(sst) = self.sstables.get_mut(&hint_file_id) {
+            sst.update_max_seq(max_seq);
+        }
+        for (seq, expiry, key) in touches {
+            self.apply_touch(&key, seq, expiry);
         }
 
         Ok(())
     }
 
-    fn build_keydir_from_sstable(&mut self, file_id: u64) -> Result<()> {
+    fn build_keydir_from_sstable(&mut self, file_id: u64, behind: bool) -> Result<()> {
         let sst = self.sstables.get_mut(&file_id).unwrap();
         log::info!("build keydir from data file {}", sst.path().display());
 
+        let mut max_seq = 0;
+        let mut touches = Vec::new();
         for entry in sst.iter() {
+            let entry = entry?;
+            max_seq = max_seq.max(entry.seq());
+            if let Some(tombstone) = entry.range_tombstone() {
+                self.range_tombstones
+                    .entry(file_id)
+                    .or_default()
+                    .push(tombstone);
+                continue;
+            }
+            // one entry per key, the version a touch applies to is older.
+            if entry.is_touch() {
+                touches.push((entry.seq(), entry.expiry(), entry.key));
+                continue;
+            }
+            if behind
+                && shadowed(
+                    &self.keydir,
+                    &self.range_tombstones,
+                    &entry.key,
+                    entry.seq(),
+                )
+            {
+                continue;
+            }
             if entry.value.is_empty() {
                 log::trace!("{} is a remove tomestone", &entry);
-
-                self.keydir.remove(&entry.key);
-                continue;
             }
-            let keydir_entry = KeydirEntry::try_from(&entry)?;
+            if let Some(index) = self
+                .value_prefixes
+                .as_ref()
+                .filter(|_| !entry.is_tombstone())
+            {
+                index.insert(&entry.key, entry.seq(), &entry.value);
+            }
+            let keydir_entry = touched(
+                &self.pending_touches,
+                &entry.key,
+                KeydirEntry::try_from(&entry)?,
+            );
             let _ = self.keydir.put(entry.key, keydir_entry);
         }
+        sst.update_max_seq(max_seq);
+        for (seq, expiry, key) in touches {
+            self.apply_touch(&key, seq, expiry);
+        }
 
         Ok(())
     }
 }
 
-impl<K> Storage for DiskStorage<K>
+/// Whether the version of `key` at `seq`, from an sstable indexed behind
+/// the others, is shadowed by a newer one in `keydir` or a range tombstone.
+fn shadowed<K: Keydir>(
+    keydir: &K,
+    range_tombstones: &BTreeMap<u64, Vec<RangeTombstone>>,
+    key: &[u8],
+    seq: u64,
+) -> bool {
+    keydir.get(key).is_some_and(|e| e.seq >= seq)
+        || range_tombstones
+            .values()
+            .flatten()
+            .any(|t| t.covers(key, seq))
+}
+
+/// `entry` of `key`, indexed behind, with the expiry of a newer touch
+/// among `pending_touches`, see `DiskStorage::apply_touch`.
+fn touched(
+    pending_touches: &HashMap<Vec<u8>, (u64, u32)>,
+    key: &[u8],
+    mut entry: KeydirEntry,
+) -> KeydirEntry {
+    if let Some((seq, expiry)) = pending_touches.get(key) {
+        if *seq > entry.seq && !entry.tombstone {
+            entry.expiry = *expiry;
+        }
+    }
+    entry
+}
+
+const SSTABLE_ID_MAGIC: &[u8; 4] = b"SSID";
+const SSTABLE_ID_VERSION: u32 = 1;
+
+/// Highest sstable id given by the store at `dir`, 0 if none recorded.
+///
+/// A `SSTABLE_ID` metadata file of a single record of the id as u64,
+/// little endian, see `MetaFileWriter`.
+fn read_id_high_water(dir: &Path) -> Result<u64> {
+    let path = dir.join(config::SSTABLE_ID_FILE);
+    match MetaFileReader::open_or_legacy(&path, SSTABLE_ID_MAGIC, SSTABLE_ID_VERSION)? {
+        Some(MetaFile::Framed(mut reader)) => {
+            let record = reader.record()?;
+            Ok(u64::from_le_bytes(
+                record.try_into().map_err(|_| reader.invalid())?,
+            ))
+        }
+        Some(MetaFile::Legacy(text)) => Ok(text.trim_end_matches('\n').parse()?),
+        None => Ok(0),
+    }
+}
+
+fn write_id_high_water(dir: &Path, id: u64, file_mode: Option<u32>) -> Result<()> {
+    let path = dir.join(config::SSTABLE_ID_FILE);
+    let mut writer =
+        MetaFileWriter::create(&path, SSTABLE_ID_MAGIC, SSTABLE_ID_VERSION, file_mode)?;
+    writer.append(&id.to_le_bytes())?;
+    writer.finish()
+}
+
+/// Flush of a `DiskStorage`, see `FlushHandle`.
+///
+/// The keydir is only updated by `finish`, the written entries are
+/// kept until then, keys but not values.
+pub struct DiskFlush<'a, K>
 where
     K: Keydir + Default,
 {
-    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        if let Some(keydir_entry) = self.keydir.get(&key) {
-            log::trace!(
-                "found key `{}` in keydir, got value `{:?}`",
-                String::from_utf8_lossy(key),
-                &keydir_entry,
-            );
+    store: &'a mut DiskStorage<K>,
+    id: u64,
+    writer: SSTableWriter,
+    written: Vec<(Vec<u8>, KeydirEntry)>,
+    range_tombstones: Vec<RangeTombstone>,
+    finished: bool,
+}
 
-            let sst = self
-                .sstables
-                .get_mut(&keydir_entry.file_id)
-                .unwrap_or_else(|| {
-                    panic!("sstable file `{}` not found", keydir_entry.file_id);
-                });
+impl<K> FlushHandle for DiskFlush<'_, K>
+where
+    K: Keydir + Default,
+{
+    fn write(&mut self, key: &[u8], entry: &DiskEntry) -> Result<()> {
+        // the keydir knows every key any sstable still holds, tombstones
+        // included, so a tombstone of a key it lacks deletes nothing.
+        // Not so while sstables are left to index.
+        if entry.is_tombstone() && !self.store.indexing() && self.store.keydir.get(key).is_none() {
+            self.store.flush_stats.skipped_tombstones += 1;
+            return Ok(());
+        }
 
-            if let Some(disk_entry) = sst.read(keydir_entry.offset)? {
-                return Ok(disk_entry.value.into());
-            }
+        // write sstable and hint files.
+        let disk_entry = self.writer.write_entry(entry.clone())?;
+        // the keydir got the expiry of a touch when logged.
+        if entry.is_touch() {
+            return Ok(());
         }
 
-        Ok(None)
+        // unused until the keydir holds this version, operands are
+        // indexed once resolved by a scan.
+        if let Some(index) = self
+            .store
+            .value_prefixes
+            .as_ref()
+            .filter(|_| !entry.is_tombstone() && !entry.is_merge())
+        {
+            index.insert(key, entry.seq(), &entry.value);
+        }
+
+        self.written
+            .push((key.to_vec(), KeydirEntry::try_from(&disk_entry)?));
+
+        Ok(())
     }
 
-    fn set(&mut self, items: &BTreeMap<Vec<u8>, DiskEntry>) -> Result<(u64, u64)> {
-        let next_sstable_id = self.sstables.keys().max().copied().unwrap_or(0) + 1;
+    fn write_range_tombstone(&mut self, tombstone: &RangeTombstone) -> Result<()> {
+        self.writer.write_entry(tombstone.to_entry())?;
+        self.range_tombstones.push(tombstone.clone());
 
-        let sstable_path = utils::format_sstable_path(&self.path, next_sstable_id);
-        let hint_path = utils::format_hint_path(&self.path, next_sstable_id);
+        Ok(())
+    }
 
-        let mut sstable = SSTable::new(&sstable_path, true)?;
-        let mut hint = HintFile::new(&hint_path, true)?;
+    fn finish(mut self) -> Result<(u64, u64)> {
+        let meta = self.writer.seal()?;
+        // synced along with the id high water.
+        Lineage::flushed(self.store.now()).write(
+            &self.store.path,
+            self.id,
+            self.store.config.file_mode,
+        )?;
+        self.store.raise_id_high_water(self.id)?;
+
+        let mut flushed = self.store.open_sstable(&meta.path)?;
+        flushed.update_max_seq(meta.max_seq);
+
+        let store = &mut *self.store;
+        store.sstables.insert(self.id, flushed);
+        store.flush_stats.flushes += 1;
+        self.finished = true;
+
+        for tombstone in std::mem::take(&mut self.range_tombstones) {
+            store.apply_range_tombstone(&tombstone);
+            store
+                .range_tombstones
+                .entry(self.id)
+                .or_default()
+                .push(tombstone);
+        }
 
-        for (k, entry) in items {
-            // write sstable file.
-            let disk_entry = sstable.write_entry(entry.clone())?;
+        for (key, entry) in std::mem::take(&mut self.written) {
+            // update keydir, tombstones included.
+            store.keydir.put(key, entry);
+        }
 
-            // write hint file.
-            hint.write_entry(HintEntry::from(&disk_entry))?;
+        Ok((self.id, meta.size))
+    }
+}
 
-            // not hint
-            if disk_entry.value.is_empty() {
-                self.keydir.remove(&k);
-            } else {
-                // update keydir.
-                self.keydir
-                    .put(k.to_vec(), KeydirEntry::try_from(&disk_entry)?);
-            }
+impl<K> Drop for DiskFlush<'_, K>
+where
+    K: Keydir + Default,
+{
+    fn drop(&mut self) {
+        if self.finished {
+            return;
         }
 
-        sstable.sync()?;
-        hint.sync()?;
+        // nothing points at the unfinished files, ignore errors.
+        log::warn!("dropping unfinished flush of sstable {}", self.id);
+        let _ = fs::remove_file(self.writer.path());
+        if let Some(hint) = self.writer.hint_path() {
+            let _ = fs::remove_file(hint);
+        }
+        if let Some(bloom) = self.writer.bloom_path() {
+            let _ = fs::remove_file(bloom);
+        }
+        let _ = fs::remove_file(utils::format_lineage_path(&self.store.path, self.id));
+    }
+}
 
-        self.sstables
-            .insert(next_sstable_id, SSTable::new(&sstable_path, false)?);
+impl<K> Storage for DiskStorage<K>
+where
+    K: Keydir + Default,
+{
+    type Flush<'a>
+        = DiskFlush<'a, K>
+    where
+        Self: 'a;
 
-        Ok((next_sstable_id, sstable.size()))
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.lookup(key, self.now())?.and_then(|(_, value)| value))
+    }
+
+    fn begin_flush(&mut self) -> Result<DiskFlush<'_, K>> {
+        let id = self.config.next_sstable_id(self.id_high_water);
+        self.flush_to(id)
     }
 
     fn contains_key(&self, key: &[u8]) -> bool {
@@ -305,15 +1555,30 @@ where
         self.len() == 0
     }
 
-    fn for_each<F>(&self, _f: &mut F) -> Result<()>
+    /// Visits keys in no particular order, reading each value when visited.
+    /// The caller holds the store, so no compaction moves them meanwhile.
+    fn for_each<F>(&self, f: &mut F) -> Result<()>
     where
         F: FnMut(&[u8], &[u8]) -> Result<bool>,
     {
-        todo!()
+        for (key, entry) in self.keydir.entries() {
+            if entry.tombstone {
+                continue;
+            }
+            let Some(value) = self.read_value(key, entry)? else {
+                continue;
+            };
+            if !f(key, &value)? {
+                break;
+            }
+        }
+        Ok(())
     }
 
+    /// Writes reach the store through flush handles, durable once
+    /// finished, so nothing is pending but the directory entries.
     fn flush(&mut self) -> Result<()> {
-        todo!()
+        self.sync_monitor.sync_dir(&self.path)
     }
 }
 
@@ -335,45 +1600,109 @@ where
 
         let merge_tmp_path = utils::format_sstable_tmp_path(&self.path, max_sstable_id);
         let merge_hint_tmp_path = utils::format_hint_tmp_path(&self.path, max_sstable_id);
+        let merge_bloom_tmp_path = utils::format_bloom_tmp_path(&self.path, max_sstable_id);
 
         let merge_path = utils::format_sstable_path(&self.path, max_sstable_id);
         let merge_hint_path = utils::format_hint_path(&self.path, max_sstable_id);
+        let merge_bloom_path = utils::format_bloom_path(&self.path, max_sstable_id);
 
-        fs::rename(&merge_tmp_path, &merge_path)?;
+        // lineage is advisory, an unreadable one is unknown.
+        let inputs: Vec<(u64, u64)> = sstable_ids
+            .iter()
+            .map(|id| match Lineage::read(&self.path, *id) {
+                Ok(lineage) => (*id, lineage.created_at),
+                Err(e) => {
+                    log::warn!("ignoring lineage of sstable {}: {}", id, e);
+                    (*id, 0)
+                }
+            })
+            .collect();
+
+        // never replace or delete inputs changed behind our back,
+        // the merge may have read garbage from them.
+        let installed = self.verify_fingerprints(sstable_ids).and_then(|()| {
+            // the filter of the newest input must not outlive it, it
+            // would rule out keys of the merged sstable taking its id.
+            if merge_bloom_path.exists() {
+                fs::remove_file(&merge_bloom_path)?;
+            }
+            fs::rename(&merge_tmp_path, &merge_path)?;
+            Ok(())
+        });
+        if let Err(e) = installed {
+            // a merge left behind would be taken for a finished one.
+            for path in [&merge_tmp_path, &merge_hint_tmp_path, &merge_bloom_tmp_path] {
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        log::warn!("failed to remove {}: {}", path.display(), e);
+                    }
+                    _ => {}
+                }
+            }
+            return Err(e);
+        }
         fs::rename(&merge_hint_tmp_path, &merge_hint_path)?;
-        fs::File::open(&self.path)?.flush()?; // sync to disk.
+        if merge_bloom_tmp_path.exists() {
+            fs::rename(&merge_bloom_tmp_path, &merge_bloom_path)?;
+        }
+        if let Err(e) = Lineage::compacted(inputs, self.now()).write(
+            &self.path,
+            max_sstable_id,
+            self.config.file_mode,
+        ) {
+            log::warn!(
+                "failed to record lineage of sstable {}: {}",
+                max_sstable_id,
+                e
+            );
+        }
+        self.sync_monitor.sync_dir(&self.path)?;
 
         for sstable_id in sstable_ids {
             if max_sstable_id == *sstable_id {
                 continue;
             }
 
-            // remove compacted sstable file.
+            // remove compacted sstable file, or move it aside while
+            // snapshots still read it.
             let path = utils::format_sstable_path(&self.path, *sstable_id);
-            fs::remove_file(path)?;
-
-            self.sstables
+            let sstable = self
+                .sstables
                 .remove(sstable_id)
                 .expect("compacted sstable not persent in sstables");
+            if self.pins.is_pinned(*sstable_id) {
+                let retired_path = utils::format_retired_path(&self.path, *sstable_id);
+                fs::rename(path, &retired_path)?;
+                self.pins.retire(*sstable_id, sstable, Some(retired_path));
+            } else {
+                fs::remove_file(path)?;
+            }
 
-            // remove compacted hint file.
-            let hint_path = utils::format_hint_path(&self.path, *sstable_id);
-            if hint_path.exists() {
-                fs::remove_file(hint_path)?;
+            // remove compacted hint, lineage and bloom filter files.
+            for path in [
+                utils::format_hint_path(&self.path, *sstable_id),
+                utils::format_lineage_path(&self.path, *sstable_id),
+                utils::format_bloom_path(&self.path, *sstable_id),
+            ] {
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
             }
         }
 
-        let merge_sstable = SSTable::new(&merge_path, false)?;
+        let merge_sstable = self.open_sstable(&merge_path)?;
         let merge_sstable_size = merge_sstable.size();
 
-        self.sstables.insert(max_sstable_id, merge_sstable);
-
-        if merge_hint_path.exists() {
-            self.build_keydir_from_hint(&merge_hint_path)?;
-        } else {
-            self.build_keydir_from_sstable(max_sstable_id)?;
+        // the merge took the place of the newest input on disk, snapshots
+        // read it through its open file.
+        if let Some(replaced) = self.sstables.insert(max_sstable_id, merge_sstable) {
+            if self.pins.is_pinned(max_sstable_id) {
+                self.pins.retire(max_sstable_id, replaced, None);
+            }
         }
 
+        self.apply_merged(max_sstable_id, sstable_ids)?;
+
         log::debug!(
             "keydir updated for compact and merge to: {}",
             max_sstable_id
@@ -382,3 +1711,450 @@ where
         Ok((max_sstable_id, merge_sstable_size))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    fn flush(store: &mut Store, key: &[u8], value: &[u8], seq: u64) {
+        let entry = DiskEntry::new(key.to_vec(), value.to_vec()).with_seq(seq);
+        store.set(&BTreeMap::from([(key.to_vec(), entry)])).unwrap();
+    }
+
+    fn assert_deleted(store: &mut Store, key: &[u8]) {
+        assert_eq!(store.get(key).unwrap(), None);
+        assert!(!store.contains_key(key));
+        assert!(!store.keys().unwrap().contains(&key.to_vec()));
+        assert!(store.keydir().get(key).unwrap().is_tombstone());
+    }
+
+    #[test]
+    fn test_tombstone_survives_reindex() {
+        let dir = TempDir::new("lsmlib").unwrap();
+
+        let mut store = Store::open(dir.path()).unwrap();
+        flush(&mut store, b"k", b"v", 1);
+        flush(&mut store, b"k", b"", 2);
+        flush(&mut store, b"x", b"v", 3);
+        assert_deleted(&mut store, b"k");
+        assert_eq!(store.len(), 1);
+
+        // re-index the sstable holding the older version.
+        store.build_keydir_from_sstable(1, false).unwrap();
+        assert_deleted(&mut store, b"k");
+        store
+            .build_keydir_from_hint(&utils::format_hint_path(dir.path(), 1), false)
+            .unwrap();
+        assert_deleted(&mut store, b"k");
+        drop(store);
+
+        // rebuild from hints.
+        let mut store = Store::open(dir.path()).unwrap();
+        assert_deleted(&mut store, b"k");
+        assert_eq!(store.len(), 1);
+        drop(store);
+
+        // rebuild from sstables.
+        for id in 1..=3 {
+            fs::remove_file(utils::format_hint_path(dir.path(), id)).unwrap();
+        }
+        let mut store = Store::open(dir.path()).unwrap();
+        assert_deleted(&mut store, b"k");
+        assert_eq!(store.get(b"x").unwrap(), Some(b"v".to_vec()));
+    }
+
+    #[test]
+    fn test_for_each() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut store = Store::open(dir.path()).unwrap();
+
+        let entries = (0..100u64)
+            .map(|i| {
+                let key = i.to_be_bytes().to_vec();
+                (
+                    key.clone(),
+                    DiskEntry::new(key, b"v".to_vec()).with_seq(i + 1),
+                )
+            })
+            .collect();
+        store.set(&entries).unwrap();
+        for i in 0..10u64 {
+            flush(&mut store, &i.to_be_bytes(), b"", 101 + i);
+        }
+
+        let mut seen = HashSet::new();
+        store
+            .for_each(&mut |key, value| {
+                assert_eq!(value, b"v");
+                assert!(u64::from_be_bytes(key.try_into().unwrap()) >= 10);
+                seen.insert(key.to_vec());
+                Ok(true)
+            })
+            .unwrap();
+        assert_eq!(seen.len(), 90);
+
+        let mut visited = 0;
+        store
+            .for_each(&mut |_, _| {
+                visited += 1;
+                Ok(visited < 5)
+            })
+            .unwrap();
+        assert_eq!(visited, 5);
+
+        let failed = store.for_each(&mut |_, _| Err(LSMLibError::EmptyKey));
+        assert!(matches!(failed, Err(LSMLibError::EmptyKey)));
+
+        store.flush().unwrap();
+        assert_eq!(store.list_sstables().len(), 11);
+    }
+
+    fn assert_absent(store: &mut Store, key: &[u8]) {
+        assert_eq!(store.get(key).unwrap(), None);
+        assert!(store.keydir().get(key).is_none());
+    }
+
+    #[test]
+    fn test_skip_noop_tombstones() {
+        let dir = TempDir::new("lsmlib").unwrap();
+
+        let mut store = Store::open(dir.path()).unwrap();
+        flush(&mut store, b"k", b"v", 1);
+
+        // "n" was put and deleted within the memtable, "k" lives in sstable 1.
+        let items = BTreeMap::from([
+            (
+                b"k".to_vec(),
+                DiskEntry::new(b"k".to_vec(), vec![]).with_seq(3),
+            ),
+            (
+                b"n".to_vec(),
+                DiskEntry::new(b"n".to_vec(), vec![]).with_seq(2),
+            ),
+        ]);
+        store.set(&items).unwrap();
+        assert_eq!(
+            store.flush_stats(),
+            FlushStats {
+                flushes: 2,
+                skipped_tombstones: 1
+            }
+        );
+        assert_deleted(&mut store, b"k");
+        assert_absent(&mut store, b"n");
+
+        let written =
+            crate::disk::sstable::read_sstable(&utils::format_sstable_path(dir.path(), 2)).unwrap();
+        assert_eq!(written.keys().collect::<Vec<_>>(), vec![b"k"]);
+
+        // the kept tombstone still shadows sstable 1, by hints and by sstables.
+        drop(store);
+        let mut store = Store::open(dir.path()).unwrap();
+        assert_deleted(&mut store, b"k");
+        assert_absent(&mut store, b"n");
+        drop(store);
+
+        for id in 1..=2 {
+            fs::remove_file(utils::format_hint_path(dir.path(), id)).unwrap();
+        }
+        let mut store = Store::open(dir.path()).unwrap();
+        assert_deleted(&mut store, b"k");
+        assert_absent(&mut store, b"n");
+    }
+
+    #[test]
+    fn test_lock_path_spellings() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let path = dir.path().join("db");
+        let store = Store::open(&path).unwrap();
+        assert_eq!(store.path(), fs::canonicalize(&path).unwrap());
+
+        let mut spellings = vec![dir.path().join(".").join("db"), path.join("..").join("db")];
+        #[cfg(unix)]
+        {
+            let link = dir.path().join("link");
+            std::os::unix::fs::symlink(&path, &link).unwrap();
+            spellings.push(link);
+        }
+        for spelling in &spellings {
+            assert!(matches!(
+                Store::open(spelling),
+                Err(LSMLibError::AlreadyLocked)
+            ));
+        }
+
+        // a failed open does not release the lock of the open store.
+        assert!(matches!(
+            Store::open(&path),
+            Err(LSMLibError::AlreadyLocked)
+        ));
+        drop(store);
+        for spelling in &spellings {
+            drop(Store::open(spelling).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_verify_on_open() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let open = |verify_on_open, read_only| {
+            let config = Config {
+                verify_on_open,
+                read_only,
+                ..Config::default()
+            };
+            Store::open_with_options(dir.path(), config)
+        };
+
+        let mut store = Store::open(dir.path()).unwrap();
+        for (seq, keys) in [(1, [b"a", b"b"]), (3, [b"c", b"d"])] {
+            let items = keys
+                .iter()
+                .zip(seq..)
+                .map(|(k, seq)| {
+                    (
+                        k.to_vec(),
+                        DiskEntry::new(k.to_vec(), vec![1; 8]).with_seq(seq),
+                    )
+                })
+                .collect();
+            store.set(&items).unwrap();
+        }
+        drop(store);
+
+        // damage `a` in the middle of sstable 1, tear `d` off sstable 2.
+        let path = utils::format_sstable_path(dir.path(), 1);
+        let mut data = fs::read(&path).unwrap();
+        data[30] ^= 0xFF;
+        fs::write(&path, data).unwrap();
+        let path = utils::format_sstable_path(dir.path(), 2);
+        let len = fs::metadata(&path).unwrap().len();
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 3).unwrap();
+        drop(file);
+
+        // a read only store cannot cut the tail off.
+        assert!(matches!(
+            open(VerifyOnOpen::NewestFiles(1), true),
+            Err(LSMLibError::VerificationFailed { .. })
+        ));
+        assert_eq!(fs::metadata(&path).unwrap().len(), len - 3);
+
+        // only the newest sstable is checked.
+        let mut store = open(VerifyOnOpen::NewestFiles(1), false).unwrap();
+        assert_eq!(store.open_repairs().sstables_truncated, 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len / 2);
+        assert!(!utils::format_hint_path(dir.path(), 2).exists());
+        assert_eq!(store.get(b"c").unwrap(), Some(vec![1; 8]));
+        assert_eq!(store.get(b"d").unwrap(), None);
+        assert!(store.get(b"a").is_err());
+        drop(store);
+
+        assert!(open(VerifyOnOpen::NewestFiles(1), true).is_ok());
+        assert!(matches!(
+            open(VerifyOnOpen::All, false),
+            Err(LSMLibError::VerificationFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_dropped_flush() {
+        let dir = TempDir::new("lsmlib").unwrap();
+
+        let mut store = Store::open(dir.path()).unwrap();
+        flush(&mut store, b"k", b"v1", 1);
+
+        let mut dropped = store.begin_flush().unwrap();
+        let entry = DiskEntry::new(b"k".to_vec(), b"v2".to_vec()).with_seq(2);
+        dropped.write(b"k", &entry).unwrap();
+        drop(dropped);
+
+        assert_eq!(store.get(b"k").unwrap(), Some(b"v1".to_vec()));
+        assert!(!utils::format_sstable_path(dir.path(), 2).exists());
+        assert!(!utils::format_hint_path(dir.path(), 2).exists());
+
+        let mut streamed = store.begin_flush().unwrap();
+        streamed.write(b"k", &entry).unwrap();
+        assert_eq!(streamed.finish().unwrap().0, 2);
+        assert_eq!(store.get(b"k").unwrap(), Some(b"v2".to_vec()));
+    }
+
+    #[test]
+    fn test_sstable_writer() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let (flushed, written) = (dir.path().join("flushed"), dir.path().join("written"));
+        fs::create_dir(&written).unwrap();
+
+        let items: BTreeMap<Vec<u8>, DiskEntry> = (0..100u32)
+            .map(|i| {
+                let key = format!("key{:03}", i).into_bytes();
+                let entry =
+                    DiskEntry::new(key.clone(), vec![i as u8; i as usize + 1]).with_timestamp(i);
+                (key, entry)
+            })
+            .collect();
+        Store::open(&flushed).unwrap().set(&items).unwrap();
+
+        let path = utils::format_sstable_path(&written, 1);
+        let mut writer = SSTableWriter::create(&path, SSTableWriterOptions::default()).unwrap();
+        for entry in items.values() {
+            writer
+                .add(&entry.key, &entry.value, entry.timestamp())
+                .unwrap();
+        }
+        writer.add_tombstone(b"key999", 0).unwrap();
+
+        // rejected entries leave the writer as it was.
+        let options = SSTableWriterOptions::default();
+        let too_long = vec![0; options.max_key_size as usize + 1];
+        let too_large = vec![0; options.max_value_size as usize + 1];
+        for (key, value) in [
+            (&b"key500"[..], &b"v"[..]),
+            (b"key999", b"v"),
+            (b"", b"v"),
+            (&too_long, b"v"),
+            (b"zzz", &too_large),
+        ] {
+            assert!(matches!(
+                writer.add(key, value, 0),
+                Err(LSMLibError::OutOfOrderKey(_)
+                    | LSMLibError::EmptyKey
+                    | LSMLibError::KeyIsTooLarge
+                    | LSMLibError::ValueIsTooLarge)
+            ));
+        }
+
+        let meta = writer.finish().unwrap();
+        assert_eq!((meta.entries, meta.tombstones), (101, 1));
+        assert_eq!(meta.first_key.as_deref(), Some(&b"key000"[..]));
+        assert_eq!(meta.last_key.as_deref(), Some(&b"key999"[..]));
+        assert_eq!(meta.hint_path, Some(utils::format_hint_path(&written, 1)));
+
+        // byte identical to a flush, up to the extra tombstone.
+        let tombstone_size = DiskEntry::entry_size(b"key999", b"");
+        let sst = fs::read(&path).unwrap();
+        assert_eq!(meta.size, sst.len() as u64);
+        assert_eq!(
+            sst[..sst.len() - tombstone_size as usize],
+            fs::read(utils::format_sstable_path(&flushed, 1)).unwrap()
+        );
+        let hint = fs::read(utils::format_hint_path(&written, 1)).unwrap();
+        let flushed_hint = fs::read(utils::format_hint_path(&flushed, 1)).unwrap();
+        assert_eq!(hint[..flushed_hint.len()], flushed_hint);
+
+        let read = sstable::read_sstable(&path).unwrap();
+        assert_eq!(read.len(), 101);
+        assert_eq!(read[&b"key042".to_vec()], vec![42; 43]);
+
+        // keydir rebuilt from the hint, then from the sstable.
+        migrate::write_format_version(&written, crate::disk::format::FORMAT_VERSION, None).unwrap();
+        for remove_hint in [false, true] {
+            if remove_hint {
+                fs::remove_file(utils::format_hint_path(&written, 1)).unwrap();
+            }
+            let mut store = Store::open(&written).unwrap();
+            assert_eq!(store.len(), 100);
+            assert_eq!(store.get(b"key042").unwrap(), Some(vec![42; 43]));
+            assert_deleted(&mut store, b"key999");
+        }
+
+        // files of an unfinished writer are removed.
+        let path = utils::format_sstable_path(&written, 2);
+        let mut writer = SSTableWriter::create(&path, SSTableWriterOptions::default()).unwrap();
+        writer.add(b"k", b"v", 0).unwrap();
+        drop(writer);
+        assert!(!path.exists());
+        assert!(!utils::format_hint_path(&written, 2).exists());
+
+        assert!(matches!(
+            SSTableWriter::create(written.join("data.sst"), SSTableWriterOptions::default()),
+            Err(LSMLibError::InvalidFileName(_))
+        ));
+    }
+
+    #[test]
+    fn test_sstable_id_allocation() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let config = Config {
+            sstable_id_start: 10,
+            reserved_id_ranges: vec![25..30, 11..20],
+            ..Config::default()
+        };
+
+        let mut store = Store::open_with_options(dir.path(), config.clone()).unwrap();
+        for seq in 1..=3 {
+            flush(&mut store, b"k", b"v", seq);
+        }
+        assert_eq!(
+            store.list_sstables().into_keys().collect::<Vec<_>>(),
+            vec![10, 20, 21]
+        );
+        drop(store);
+
+        // the newest sstable gone, its id is not given again.
+        fs::remove_file(utils::format_sstable_path(dir.path(), 21)).unwrap();
+        fs::remove_file(utils::format_hint_path(dir.path(), 21)).unwrap();
+        let mut store = Store::open_with_options(dir.path(), config.clone()).unwrap();
+        flush(&mut store, b"k", b"v", 4);
+        assert!(store.list_sstables().contains_key(&22));
+
+        // flushes skip reserved ids, kept ids must be reserved and above them.
+        for seq in 5..=7 {
+            flush(&mut store, b"k", b"v", seq);
+        }
+        assert_eq!(
+            store.list_sstables().into_keys().collect::<Vec<_>>(),
+            vec![10, 20, 22, 23, 24, 30]
+        );
+        for id in [15, 26, 31] {
+            assert!(matches!(
+                store.begin_flush_as(id),
+                Err(LSMLibError::SSTableIdUnavailable(_))
+            ));
+        }
+        drop(store);
+
+        for reserved_id_ranges in [vec![5..5, 6..7], vec![0..3, 5..6], vec![1..10, 9..12]] {
+            let config = Config {
+                reserved_id_ranges,
+                ..Config::default()
+            };
+            assert!(matches!(
+                Store::open_with_options(dir.path(), config),
+                Err(LSMLibError::InvalidConfig(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_orphan_hints() {
+        let dir = TempDir::new("lsmlib").unwrap();
+
+        let mut store = Store::open(dir.path()).unwrap();
+        flush(&mut store, b"a", b"1", 1);
+        flush(&mut store, b"b", b"2", 2);
+        drop(store);
+
+        // sstable 2 deleted by hand, its hint left behind.
+        fs::remove_file(utils::format_sstable_path(dir.path(), 2)).unwrap();
+        let hint_path = utils::format_hint_path(dir.path(), 2);
+
+        let config = Config {
+            read_only: true,
+            ..Config::default()
+        };
+        let mut store = Store::open_with_options(dir.path(), config).unwrap();
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert!(hint_path.exists());
+        drop(store);
+
+        let mut store = Store::open(dir.path()).unwrap();
+        // the hint and the bloom filter.
+        assert_eq!(store.open_repairs().orphan_files_removed, 2);
+        assert!(!hint_path.exists());
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+    }
+}
@@ -1,5 +1,8 @@
 //! utils Module.
 
+use std::fs::{self, File};
+use std::io;
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
 
 use crate::config;
@@ -29,6 +32,139 @@ pub(crate) fn format_hint_tmp_path(dir: &Path, id: u64) -> PathBuf {
     dir.join(format!("{:012}{}-tmp", id, config::HINT_FILE_SUFFIX))
 }
 
+pub(crate) fn format_lineage_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:012}{}", id, config::LINEAGE_FILE_SUFFIX))
+}
+
+pub(crate) fn format_bloom_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:012}{}", id, config::BLOOM_FILE_SUFFIX))
+}
+
+pub(crate) fn format_bloom_tmp_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:012}{}-tmp", id, config::BLOOM_FILE_SUFFIX))
+}
+
+pub(crate) fn format_retired_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:012}{}", id, config::RETIRED_FILE_SUFFIX))
+}
+
 pub(crate) fn format_wal_path(dir: &Path, id: u64) -> PathBuf {
     dir.join(format!("{:012}{}", id, config::WAL_FILE_SUFFIX))
 }
+
+pub(crate) fn format_wal_tmp_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:012}{}-tmp", id, config::WAL_FILE_SUFFIX))
+}
+
+/// Smallest key greater than every key starting with `prefix`,
+/// `None` when there is none (empty or all `0xFF` prefix).
+pub(crate) fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xFF {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Key range covering every key starting with `prefix`.
+pub(crate) fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let end = match prefix_upper_bound(prefix) {
+        Some(end) => Bound::Excluded(end),
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix.to_vec()), end)
+}
+
+/// Merge the sorted keys `a` and `b`, none in both, into one sorted list.
+pub(crate) fn merge_sorted(a: Vec<Vec<u8>>, b: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let mut merged = Vec::with_capacity(a.len() + b.len());
+    let (mut a, mut b) = (a.into_iter().peekable(), b.into_iter().peekable());
+    loop {
+        let next = match (a.peek(), b.peek()) {
+            (Some(x), Some(y)) if x > y => b.next(),
+            (Some(_), _) => a.next(),
+            (None, _) => b.next(),
+        };
+        match next {
+            Some(key) => merged.push(key),
+            None => return merged,
+        }
+    }
+}
+
+/// Current time in seconds since the unix epoch, as in entry timestamps.
+///
+/// Clamped to the `u32` range, a clock before the epoch reads 0.
+pub(crate) fn now_secs() -> u32 {
+    chrono::Utc::now().timestamp().clamp(0, u32::MAX.into()) as u32
+}
+
+/// Whether `key` lies within `range`, without allocating a `Vec`.
+pub(crate) fn range_contains<R: RangeBounds<Vec<u8>>>(range: &R, key: &[u8]) -> bool {
+    let after_start = match range.start_bound() {
+        Bound::Included(start) => key >= start.as_slice(),
+        Bound::Excluded(start) => key > start.as_slice(),
+        Bound::Unbounded => true,
+    };
+    let before_end = match range.end_bound() {
+        Bound::Included(end) => key <= end.as_slice(),
+        Bound::Excluded(end) => key < end.as_slice(),
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+/// Open `path` with `options`, setting its permissions to `mode`
+/// whatever the process umask. `mode` is ignored on non-unix platforms.
+pub(crate) fn open_with_mode(
+    options: &mut fs::OpenOptions,
+    path: &Path,
+    mode: Option<u32>,
+) -> io::Result<File> {
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        let file = options.mode(mode).open(path)?;
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+        return Ok(file);
+    }
+
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    options.open(path)
+}
+
+/// Hard link `src` to `dst`, copying it where linking fails,
+/// e.g. across filesystems.
+pub(crate) fn link_or_copy(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::hard_link(src, dst).or_else(|e| {
+        log::debug!("failed to link {}: {}, copying", src.display(), e);
+        fs::copy(src, dst).map(|_| ())
+    })
+}
+
+/// Create directory `path` and its missing parents, setting the permissions
+/// of `path` to `mode` whatever the process umask. `mode` is ignored on
+/// non-unix platforms.
+pub(crate) fn create_dir_all(path: &Path, mode: Option<u32>) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+        builder.mode(mode).create(path)?;
+        return fs::set_permissions(path, fs::Permissions::from_mode(mode));
+    }
+
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    builder.create(path)
+}
@@ -1,25 +1,115 @@
 //! Compactor Module.
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
 use std::sync::{mpsc, Arc, RwLock};
+use std::time::Instant;
 
+use crate::cache::NegativeCache;
+use crate::clock::ClockFn;
 use crate::config::Config;
 use crate::disk::{
-    format::HintEntry,
-    hint::HintFile,
-    sstable::{self, SSTable},
+    format::{DiskEntry, RangeTombstone},
+    sstable::{self, SSTable, SSTableWriter, SSTableWriterOptions},
 };
-use crate::error::Result;
+use crate::error::{LSMLibError, Result};
+use crate::keydir::Keydir;
+use crate::stats::{CompactionCounters, CompactionOutcome};
 use crate::storage::{KeydirUpdate, Store};
 use crate::utils;
 
 pub enum CompactorMessage {
-    NewSSTable { id: u64, size: u64 },
+    /// id and size of the sstables of a flush.
+    NewSSTables(Vec<(u64, u64)>),
+    /// merge every sstable into one, see `Lsm::compact`.
+    Compact(mpsc::Sender<Result<CompactionOutcome>>),
     Stop(mpsc::Sender<()>),
     HeartBeat(mpsc::Sender<()>),
 }
 
+/// Gate consulted before the compactor starts a merge.
+///
+/// Lets applications pause compaction (e.g. under peak traffic)
+/// without disabling it.
+pub trait CompactionGate: Send + Sync {
+    /// Return `false` to defer compacting `candidate_ids` to the next tick.
+    fn allow(&self, candidate_ids: &[u64]) -> bool;
+}
+
+/// Why the compactor found a run eligible, see `CandidateRun`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CandidateKind {
+    /// too many small sstables, see `Config::small_file_merge_threshold`.
+    SmallFiles,
+
+    /// the store is past its soft size limit, the run holds the most
+    /// garbage, see `Config::database_soft_limit_percent`.
+    Garbage,
+
+    /// sstables of similar sizes, see `Config::merge_ratio`.
+    SizeTiered,
+}
+
+/// Run of adjacent sstables the compactor may merge, see `CompactionPolicy`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CandidateRun {
+    pub kind: CandidateKind,
+
+    /// id and size of the sstables, oldest first.
+    pub sstables: Vec<(u64, u64)>,
+
+    /// share of the bytes of the run the keydir no longer points to.
+    pub garbage_ratio: f64,
+}
+
+impl CandidateRun {
+    pub fn ids(&self) -> Vec<u64> {
+        self.sstables.iter().map(|(id, _)| *id).collect()
+    }
+}
+
+/// Chooses the run the compactor merges, among those it found eligible.
+///
+/// Lets applications defer or reorder merges, e.g. leave the sstables
+/// of hot keys alone until off-peak hours, without pausing compaction
+/// wholesale. The gate still applies to the run chosen.
+pub trait CompactionPolicy: Send + Sync {
+    /// Index of the run of `candidates` to merge, `None` to merge
+    /// nothing this tick. Candidates come in the order the built-in
+    /// selection prefers them, never empty.
+    fn select(&self, candidates: &[CandidateRun]) -> Option<usize>;
+}
+
+/// The built-in selection, the first candidate.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct DefaultCompactionPolicy;
+
+impl CompactionPolicy for DefaultCompactionPolicy {
+    fn select(&self, candidates: &[CandidateRun]) -> Option<usize> {
+        (!candidates.is_empty()).then_some(0)
+    }
+}
+
+/// Oldest run of adjacent sstables each at least 1/`merge_ratio` the
+/// size of the first one, at least `merge_window` long, or 2 long while
+/// there are fewer sstables than `merge_window`. The run extends as far
+/// as its sstables qualify.
+fn size_tiered_run(sstables: &BTreeMap<u64, u64>, config: &Config) -> Option<Vec<u64>> {
+    let window = (config.merge_window as usize).max(2);
+    let min_len = if sstables.len() < window { 2 } else { window };
+    let sstables: Vec<(u64, u64)> = sstables.iter().map(|(id, size)| (*id, *size)).collect();
+
+    (0..sstables.len()).find_map(|start| {
+        let first = sstables[start].1;
+        let run: Vec<u64> = sstables[start..]
+            .iter()
+            .take_while(|(_, size)| size * config.merge_ratio as u64 >= first)
+            .map(|(id, _)| *id)
+            .collect();
+        (run.len() >= min_len).then_some(run)
+    })
+}
+
 pub struct Compactor {
     /// Dir of the Datastore.
     pub(crate) path: PathBuf,
@@ -33,12 +123,36 @@ pub struct Compactor {
     /// Inbox of message.
     pub(crate) inbox: mpsc::Receiver<CompactorMessage>,
 
+    /// Gate which may veto compaction.
+    pub(crate) gate: Option<Arc<dyn CompactionGate>>,
+
+    /// Policy choosing among eligible runs, `DefaultCompactionPolicy`
+    /// if unset.
+    pub(crate) policy: Option<Arc<dyn CompactionPolicy>>,
+
+    /// Negative lookup cache, cleared after each compaction.
+    pub(crate) negative_cache: Option<Arc<NegativeCache>>,
+
     /// config of the Datastore.
     pub(crate) config: Config,
+
+    /// counters shared with the store handle.
+    pub(crate) stats: Arc<CompactionCounters>,
+
+    /// clock in seconds since the unix epoch, for the tombstone grace.
+    pub(crate) now: ClockFn,
+
+    /// called between merged sstable written and keydir updated.
+    #[cfg(test)]
+    pub(crate) merge_hook: Option<fn(&Compactor)>,
 }
 
 impl Compactor {
     pub fn run(mut self) {
+        if let Some(nice) = self.config.compactor_nice {
+            super::set_thread_nice(nice);
+        }
+
         while self.tick() {}
         log::info!("Compactor worker quitting...");
     }
@@ -66,13 +180,44 @@ impl Compactor {
             );
         }
 
-        return true;
+        if cfg!(feature = "strict-invariants") {
+            if let Err(e) = self.check_invariants() {
+                panic!("invariant violated after compaction: {}", e);
+            }
+        }
+
+        true
+    }
+
+    /// Check every sstable the compactor knows is in the store with the
+    /// same size, and the store invariants, see `Lsm::check_invariants`.
+    ///
+    /// The store may hold newer sstables the compactor is not told of yet.
+    pub(crate) fn check_invariants(&self) -> std::result::Result<(), String> {
+        let store = self.store.read().unwrap();
+        let sstables = store.list_sstables();
+        for (id, size) in &self.sstables {
+            match sstables.get(id) {
+                Some(actual) if actual == size => {}
+                actual => {
+                    return Err(format!(
+                        "compactor sstable {} of {} bytes is {:?} in the store, store: {:?}, compactor: {:?}",
+                        id, size, actual, sstables, self.sstables
+                    ))
+                }
+            }
+        }
+        store.check_invariants()
     }
 
     fn handle_message(&mut self, msg: CompactorMessage) -> bool {
         match msg {
-            CompactorMessage::NewSSTable { id, size } => {
-                self.sstables.insert(id, size);
+            CompactorMessage::NewSSTables(sstables) => {
+                self.sstables.extend(sstables);
+                true
+            }
+            CompactorMessage::Compact(reply) => {
+                let _ = reply.send(self.compact_all());
                 true
             }
             CompactorMessage::Stop(dropper) => {
@@ -87,40 +232,191 @@ impl Compactor {
     }
 
     fn sstable_maintenance(&mut self) -> Result<()> {
+        // merging needs the whole keydir, wait for the indexor.
+        if self.config.read_only || self.store.read().unwrap().indexing() {
+            return Ok(());
+        }
+
         let on_disk_size: u64 = self.sstables.values().sum();
 
         log::debug!("disk size: {}", on_disk_size);
-        if self.sstables.len() < self.config.merge_window.max(2) as usize {
-            log::debug!("sstable files less 2, pass compacting...");
+
+        let candidates = self.candidate_runs()?;
+        if candidates.is_empty() {
             return Ok(());
         }
 
-        for window in self
+        let choice = match &self.policy {
+            Some(policy) => policy.select(&candidates),
+            None => DefaultCompactionPolicy.select(&candidates),
+        };
+        let Some(run) = choice.and_then(|i| candidates.get(i)) else {
+            log::debug!(
+                "compaction policy skipped {} candidate runs",
+                candidates.len()
+            );
+            return Ok(());
+        };
+
+        log::debug!("merging {:?} sstables {:?}", run.kind, run.ids());
+        self.try_compact_sstable_run(&run.ids())
+    }
+
+    /// Runs eligible for a merge, the built-in choice first: small
+    /// sstables, then garbage when the store is nearly full, then
+    /// size tiers.
+    ///
+    /// Garbage ratios take a scan of the keydir, only made for a
+    /// `policy`, 0 otherwise.
+    fn candidate_runs(&self) -> Result<Vec<CandidateRun>> {
+        let mut runs = Vec::new();
+        if let Some(run) = self.small_file_run() {
+            runs.push((CandidateKind::SmallFiles, run));
+        }
+        if let Some(run) = self.garbage_heavy_run()? {
+            runs.push((CandidateKind::Garbage, run));
+        }
+        if let Some(run) = size_tiered_run(&self.sstables, &self.config) {
+            runs.push((CandidateKind::SizeTiered, run));
+        }
+
+        let live = (self.policy.is_some() && !runs.is_empty()).then(|| self.live_bytes());
+        Ok(runs
+            .into_iter()
+            .map(|(kind, ids)| {
+                let sstables: Vec<(u64, u64)> =
+                    ids.iter().map(|id| (*id, self.sstables[id])).collect();
+                let bytes: u64 = sstables.iter().map(|(_, size)| size).sum();
+                let garbage_ratio = match &live {
+                    Some(live) if bytes > 0 => {
+                        let live: u64 = ids.iter().filter_map(|id| live.get(id)).sum();
+                        bytes.saturating_sub(live) as f64 / bytes as f64
+                    }
+                    _ => 0.0,
+                };
+                CandidateRun {
+                    kind,
+                    sstables,
+                    garbage_ratio,
+                }
+            })
+            .collect())
+    }
+
+    /// Bytes of the entries the keydir points to, by sstable.
+    fn live_bytes(&self) -> HashMap<u64, u64> {
+        let mut live: HashMap<u64, u64> = HashMap::new();
+        for (_, entry) in self.store.read().unwrap().keydir().entries() {
+            *live.entry(entry.file_id()).or_default() += entry.size();
+        }
+        live
+    }
+
+    /// Longest run of adjacent small sstables, when there are too many of them.
+    ///
+    /// Runs must not skip over sstables: a merged run takes the id of its
+    /// newest sstable, so an older entry skipping ahead of a newer
+    /// tombstone would come back on reopen.
+    fn small_file_run(&self) -> Option<Vec<u64>> {
+        let small_file_size = self.config.small_file_size;
+
+        let small_files = self
             .sstables
-            .iter()
-            .collect::<Vec<_>>()
-            .windows(self.config.merge_window.max(2) as usize)
-        {
-            if window
-                .iter()
-                .skip(1)
-                .all(|w| *w.1 * self.config.merge_ratio as u64 > *window[0].1)
+            .values()
+            .filter(|size| **size < small_file_size)
+            .count();
+        if small_files <= self.config.small_file_merge_threshold as usize {
+            return None;
+        }
+
+        let mut runs: Vec<(Vec<u64>, u64)> = vec![(Vec::new(), 0)];
+        for (&id, &size) in &self.sstables {
+            let (run, run_bytes) = runs.last_mut().unwrap();
+            if size >= small_file_size {
+                if !run.is_empty() {
+                    runs.push((Vec::new(), 0));
+                }
+            } else if !run.is_empty() && *run_bytes + size > self.config.small_file_merge_max_bytes
             {
-                let run_to_compact: Vec<u64> = window.into_iter().map(|(id, _sum)| **id).collect();
+                runs.push((vec![id], size));
+            } else {
+                run.push(id);
+                *run_bytes += size;
+            }
+        }
+
+        runs.into_iter()
+            .map(|(run, _)| run)
+            .filter(|run| run.len() >= 2)
+            .max_by_key(|run| run.len())
+    }
+
+    /// Window of adjacent sstables holding the most bytes the keydir no
+    /// longer points to, when the store is past its soft size limit.
+    fn garbage_heavy_run(&self) -> Result<Option<Vec<u64>>> {
+        let Some(soft_limit) = self.config.database_soft_limit() else {
+            return Ok(None);
+        };
+        if self.sstables.len() < 2 {
+            return Ok(None);
+        }
+
+        if self.store.read().unwrap().disk_bytes()? <= soft_limit {
+            return Ok(None);
+        }
+        let live = self.live_bytes();
+
+        let garbage: Vec<(u64, u64)> = self
+            .sstables
+            .iter()
+            .map(|(id, size)| (*id, size.saturating_sub(*live.get(id).unwrap_or(&0))))
+            .collect();
+        let window = (self.config.merge_window.max(2) as usize).min(garbage.len());
 
-                self.compact_sstable_run(&run_to_compact)?;
+        Ok(garbage
+            .windows(window)
+            .max_by_key(|w| w.iter().map(|(_, bytes)| bytes).sum::<u64>())
+            .filter(|w| w.iter().any(|(_, bytes)| *bytes > 0))
+            .map(|w| w.iter().map(|(id, _)| *id).collect()))
+    }
+
+    /// Compact `run_to_compact` unless the gate defers it.
+    fn try_compact_sstable_run(&mut self, run_to_compact: &[u64]) -> Result<()> {
+        if let Some(gate) = &self.gate {
+            if !gate.allow(run_to_compact) {
+                log::debug!("compacting {:?} deferred by gate", run_to_compact);
                 return Ok(());
             }
         }
 
-        Ok(())
+        self.compact_sstable_run(run_to_compact).map(|_| ())
+    }
+
+    /// Merge every sstable into one, whatever the gate.
+    fn compact_all(&mut self) -> Result<CompactionOutcome> {
+        if self.config.read_only {
+            return Err(LSMLibError::ReadOnly);
+        }
+        if self.store.read().unwrap().indexing() {
+            return Err(LSMLibError::Custom(
+                "cannot compact while indexing sstables".to_string(),
+            ));
+        }
+
+        let sstable_ids: Vec<u64> = self.sstables.keys().copied().collect();
+        if sstable_ids.len() < 2 {
+            return Ok(CompactionOutcome::default());
+        }
+
+        Ok(self.compact_sstable_run(&sstable_ids)?.unwrap_or_default())
     }
 
     // This function must be able to crash at any point without
     // leaving the system in an unrecoverable state, or without
     // losing data. This function must be nullpotent from the
     // external API surface's perspective.
-    fn compact_sstable_run(&mut self, sstable_ids: &[u64]) -> Result<()> {
+    fn compact_sstable_run(&mut self, sstable_ids: &[u64]) -> Result<Option<CompactionOutcome>> {
+        let started = Instant::now();
         log::debug!(
             "trying to compact sstable_ids: {:?}",
             sstable_ids
@@ -141,9 +437,11 @@ impl Compactor {
                 "compact sstable_ids: {:?} already finished, waiting for keydir to be updated",
                 sstable_ids
             );
-            return Ok(());
+            return Ok(None);
         }
 
+        self.store.read().unwrap().require_compression_format()?;
+
         let mut sstables = Vec::new();
         for sstable_id in sstable_ids.iter() {
             let path = utils::format_sstable_path(&self.path, *sstable_id);
@@ -151,31 +449,169 @@ impl Compactor {
             sstables.push(sstable.iter());
         }
 
-        // let merge_tmp_path = utils::format_sstable_tmp_path(&self.path, max_sstable_id);
-        let mut merge_sstable = SSTable::new(&merge_tmp_path, true)?;
-
+        // an unfinished merge is removed when the writer is dropped.
         let merge_hint_tmp_path = utils::format_hint_tmp_path(&self.path, max_sstable_id);
-        let mut merge_hint = HintFile::new(&merge_hint_tmp_path, true)?;
+        let mut merge_writer = SSTableWriter::create_at(
+            &merge_tmp_path,
+            Some(&merge_hint_tmp_path),
+            &utils::format_bloom_tmp_path(&self.path, max_sstable_id),
+            SSTableWriterOptions::from_config(&self.config),
+        )?
+        .with_monitor(self.store.read().unwrap().sync_monitor());
+
+        // no older sstable may hold a version shadowed by a tombstone
+        // when the run starts at the oldest one, so tombstones can go.
+        let drop_tombstones = self.sstables.keys().next() == sstable_ids.iter().min();
+        // elsewhere, a tombstone can go if the bloom filters of the
+        // older sstables rule out any version of its key.
+        let older_blooms = match drop_tombstones {
+            true => None,
+            false => {
+                let min_sstable_id = sstable_ids.iter().min().copied().unwrap_or_default();
+                self.store.read().unwrap().blooms_below(min_sstable_id)
+            }
+        };
+        let unshadowed = |key: &[u8]| {
+            drop_tombstones
+                || older_blooms
+                    .as_ref()
+                    .is_some_and(|blooms| blooms.iter().all(|b| !b.may_contain(key)))
+        };
+        let grace = self.config.tombstone_grace.as_secs();
+        let clock = (self.now)();
+        let now = u64::from(clock);
+        let expired = |timestamp: u32| grace == 0 || u64::from(timestamp) + grace < now;
+
+        // versions deleted by a range tombstone of the store can go
+        // whatever the run: the tombstone stays until merged into the
+        // oldest sstable, along with every version it covers.
+        let (range_tombstones, run_range_tombstones) = {
+            let store = self.store.read().unwrap();
+            let all: Vec<RangeTombstone> = store
+                .range_tombstones()
+                .values()
+                .flatten()
+                .cloned()
+                .collect();
+            let run: Vec<RangeTombstone> = sstable_ids
+                .iter()
+                .filter_map(|id| store.range_tombstones().get(id))
+                .flatten()
+                .cloned()
+                .collect();
+            (all, run)
+        };
+
+        let mut outcome = CompactionOutcome {
+            inputs: sstable_ids
+                .iter()
+                .map(|id| (*id, self.sstables.get(id).copied().unwrap_or_default()))
+                .collect(),
+            ..CompactionOutcome::default()
+        };
+        for tombstone in &run_range_tombstones {
+            if drop_tombstones {
+                if expired(tombstone.timestamp()) {
+                    outcome.range_tombstones_dropped += 1;
+                    continue;
+                }
+                outcome.tombstones_retained_by_grace += 1;
+            }
 
-        let ms_iter = sstable::CompactMergeIter::new(sstables);
+            merge_writer.write_entry(tombstone.to_entry())?;
+            outcome.entries_written += 1;
+        }
+
+        let merge_operator = self.store.read().unwrap().merge_operator().cloned();
+        let ms_iter = sstable::CompactMergeIter::new(sstables).with_merge_operator(merge_operator);
         for entry in ms_iter {
-            // write to merge sstable.
-            let disk_entry = merge_sstable.write_entry(entry)?;
+            // an input which cannot be read through fails the merge, the
+            // writer removing what it wrote.
+            let entry = entry?;
+
+            // range tombstones of the run are written above.
+            if entry.key.is_empty() {
+                continue;
+            }
+
+            if range_tombstones
+                .iter()
+                .any(|t| t.covers(&entry.key, entry.seq()))
+            {
+                outcome.range_deleted_dropped += 1;
+                continue;
+            }
+
+            // a touch after the run may have moved the expiry of its
+            // version, the keydir holds the latest.
+            let touched = match entry.is_touch() || entry.is_tombstone() {
+                true => None,
+                false => self
+                    .store
+                    .read()
+                    .unwrap()
+                    .keydir()
+                    .get(&entry.key)
+                    .filter(|e| e.seq == entry.seq() && e.expiry != entry.expiry())
+                    .map(|e| e.expiry),
+            };
+            let entry = match touched {
+                Some(expiry) => entry.with_expiry(expiry),
+                None => entry,
+            };
+
+            // an expired value goes, its key deleted as of the expiry.
+            let entry = if entry.is_expired(clock) {
+                outcome.expired += 1;
+                let (seq, expiry) = (entry.seq(), entry.expiry());
+                DiskEntry::stamped(entry.key, Vec::new(), expiry).with_seq(seq)
+            } else {
+                entry
+            };
+
+            // no older version left for a touch to apply to.
+            if entry.is_touch() && unshadowed(&entry.key) {
+                continue;
+            }
 
-            // write hint file.
-            merge_hint.write_entry(HintEntry::from(&disk_entry))?;
+            if entry.is_tombstone() && unshadowed(&entry.key) {
+                if expired(entry.timestamp()) {
+                    outcome.tombstones_dropped += 1;
+                    continue;
+                }
+                outcome.tombstones_retained_by_grace += 1;
+            }
+
+            // write to merge sstable and its hint file.
+            merge_writer.write_entry(entry)?;
+            outcome.entries_written += 1;
         }
 
         // sync all write.
-        merge_sstable.sync()?;
+        merge_writer.seal()?;
 
         log::debug!("compacting file generated...");
 
+        #[cfg(test)]
+        if let Some(hook) = self.merge_hook {
+            hook(self);
+        }
+
         // to updating keydir.
         let (sstable_id, size) = self.store.write().unwrap().compact_and_merge(sstable_ids)?;
 
         self.sstables.insert(sstable_id, size);
 
+        let input_bytes: u64 = outcome.inputs.iter().map(|(_, size)| size).sum();
+        outcome.output = Some((sstable_id, size));
+        outcome.bytes_reclaimed = input_bytes.saturating_sub(size);
+        outcome.duration = started.elapsed();
+        self.stats.record(&outcome);
+
+        if let Some(cache) = &self.negative_cache {
+            cache.clear();
+        }
+
         for sstable_id in sstable_ids {
             if max_sstable_id == *sstable_id {
                 continue;
@@ -186,8 +622,517 @@ impl Compactor {
                 .expect("compacted sstable not persent in sstables");
         }
 
-        log::debug!("compacting finished...");
+        log::debug!("compacting finished: {:?}", outcome);
+
+        Ok(Some(outcome))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(())
+    use tempdir::TempDir;
+
+    use crate::storage::{FlushHandle, Storage};
+
+    #[test]
+    fn test_size_tiered_run() {
+        let config = |merge_ratio, merge_window| Config {
+            merge_ratio,
+            merge_window,
+            ..Config::default()
+        };
+        // sstable sizes by increasing id from 1, config, run selected.
+        type Case<'a> = (&'a [u64], Config, Option<&'a [u64]>);
+        let cases: [Case; 9] = [
+            (&[], config(3, 10), None),
+            (&[100], config(3, 10), None),
+            // fewer sstables than the window, any run of 2 qualifies.
+            (&[100, 1, 1], config(3, 10), Some(&[2, 3])),
+            (&[100, 10, 1], config(3, 10), None),
+            // at least 1/merge_ratio of the first one, boundary included.
+            (&[90, 30, 31, 29], config(3, 2), Some(&[1, 2, 3])),
+            (&[90, 29, 60, 60], config(3, 2), Some(&[2, 3, 4])),
+            // merge_window is a minimum length, the run extends past it.
+            (&[10, 10, 10, 10, 10], config(3, 3), Some(&[1, 2, 3, 4, 5])),
+            (&[10, 10, 1, 10, 0], config(3, 3), None),
+            (&[100, 10, 10, 10, 1, 10], config(3, 3), Some(&[2, 3, 4])),
+        ];
+
+        for (sizes, config, expected) in cases {
+            let sstables: BTreeMap<u64, u64> = (1..).zip(sizes.iter().copied()).collect();
+            assert_eq!(
+                size_tiered_run(&sstables, &config).as_deref(),
+                expected,
+                "sizes {:?}, merge_ratio {}, merge_window {}",
+                sizes,
+                config.merge_ratio,
+                config.merge_window
+            );
+        }
+    }
+
+    #[test]
+    fn test_compaction_tombstones() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut store = Store::open(dir.path()).unwrap();
+
+        let writes: [(&[u8], &[u8]); 4] = [(b"k", b"v"), (b"x", b"v"), (b"k", b""), (b"y", b"v")];
+        for (seq, (key, value)) in writes.into_iter().enumerate() {
+            let entry = DiskEntry::new(key.to_vec(), value.to_vec()).with_seq(seq as u64 + 1);
+            store.set(&BTreeMap::from([(key.to_vec(), entry)])).unwrap();
+        }
+
+        let (_tx, rx) = mpsc::channel();
+        let mut compactor = Compactor {
+            path: dir.path().to_path_buf(),
+            sstables: store.list_sstables(),
+            store: Arc::new(RwLock::new(store)),
+            inbox: rx,
+            gate: None,
+            policy: None,
+            negative_cache: None,
+            config: Config::default(),
+            stats: Arc::default(),
+            now: Arc::new(utils::now_secs),
+            merge_hook: None,
+        };
+
+        // sstable 1 still holds the older version, keep the tombstone.
+        compactor.compact_sstable_run(&[2, 3]).unwrap();
+        {
+            let mut store = compactor.store.write().unwrap();
+            assert_eq!(store.get(b"k").unwrap(), None);
+            assert!(store.keydir().get(b"k").unwrap().is_tombstone());
+        }
+        let merged = sstable::read_sstable(&utils::format_sstable_path(dir.path(), 3)).unwrap();
+        assert_eq!(merged.get(b"k".as_slice()), Some(&vec![]));
+
+        // the run starts at the oldest sstable, drop the tombstone.
+        compactor.compact_sstable_run(&[1, 3]).unwrap();
+        {
+            let mut store = compactor.store.write().unwrap();
+            assert_eq!(store.get(b"k").unwrap(), None);
+            assert!(store.keydir().get(b"k").is_none());
+            assert_eq!(store.get(b"x").unwrap(), Some(b"v".to_vec()));
+            assert_eq!(store.len(), 2);
+        }
+        let merged = sstable::read_sstable(&utils::format_sstable_path(dir.path(), 3)).unwrap();
+        assert!(!merged.contains_key(b"k".as_slice()));
+    }
+
+    #[test]
+    fn test_compaction_tombstones_bloom() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let config = Config {
+            bloom_bits_per_key: Some(10),
+            ..Config::default()
+        };
+        let mut store = Store::open_with_options(dir.path(), config.clone()).unwrap();
+
+        let writes: [(&[u8], &[u8]); 4] = [(b"k", b"v"), (b"y", b"v"), (b"k", b""), (b"y", b"")];
+        for (seq, (key, value)) in writes.into_iter().enumerate() {
+            let entry = DiskEntry::new(key.to_vec(), value.to_vec()).with_seq(seq as u64 + 1);
+            store.set(&BTreeMap::from([(key.to_vec(), entry)])).unwrap();
+        }
+        assert!((1..=4).all(|id| utils::format_bloom_path(dir.path(), id).exists()));
+
+        let (_tx, rx) = mpsc::channel();
+        let mut compactor = Compactor {
+            path: dir.path().to_path_buf(),
+            sstables: store.list_sstables(),
+            store: Arc::new(RwLock::new(store)),
+            inbox: rx,
+            gate: None,
+            policy: None,
+            negative_cache: None,
+            config,
+            stats: Arc::default(),
+            now: Arc::new(utils::now_secs),
+            merge_hook: None,
+        };
+
+        // the filter of sstable 1 rules out `y` but not `k`.
+        let outcome = compactor.compact_sstable_run(&[2, 3, 4]).unwrap().unwrap();
+        assert_eq!(outcome.tombstones_dropped, 1);
+        let merged = sstable::read_sstable(&utils::format_sstable_path(dir.path(), 4)).unwrap();
+        assert_eq!(merged.get(b"k".as_slice()), Some(&vec![]));
+        assert!(!merged.contains_key(b"y".as_slice()));
+        {
+            let mut store = compactor.store.write().unwrap();
+            assert_eq!(store.get(b"k").unwrap(), None);
+            assert!(store.keydir().get(b"y").is_none());
+        }
+
+        // the merged sstable got a filter of its own, the inputs' are gone.
+        assert!(!utils::format_bloom_path(dir.path(), 2).exists());
+        assert!(!utils::format_bloom_path(dir.path(), 3).exists());
+        drop(compactor);
+        let store = Store::open(dir.path()).unwrap();
+        assert_eq!(store.blooms_below(5).map(|b| b.len()), Some(2));
+        assert!(store.blooms_below(5).unwrap()[1].may_contain(b"k"));
+    }
+
+    #[test]
+    fn test_compaction_corrupt_input() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut store = Store::open(dir.path()).unwrap();
+        let entry = |key: &[u8], seq| {
+            let entry = DiskEntry::new(key.to_vec(), b"v".to_vec()).with_seq(seq);
+            (key.to_vec(), entry)
+        };
+        store
+            .set(&BTreeMap::from([
+                entry(b"a", 1),
+                entry(b"b", 2),
+                entry(b"c", 3),
+            ]))
+            .unwrap();
+        store.set(&BTreeMap::from([entry(b"d", 4)])).unwrap();
+
+        // the key size of `b` runs past the end of the file.
+        let offset = store.keydir().get(b"b").unwrap().offset;
+        drop(store);
+        let path = utils::format_sstable_path(dir.path(), 1);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[offset as usize + 8..offset as usize + 12]
+            .copy_from_slice(&1_000_000u32.to_le_bytes());
+        std::fs::write(&path, bytes).unwrap();
+
+        let store = Store::open(dir.path()).unwrap();
+        let (_tx, rx) = mpsc::channel();
+        let mut compactor = Compactor {
+            path: dir.path().to_path_buf(),
+            sstables: store.list_sstables(),
+            store: Arc::new(RwLock::new(store)),
+            inbox: rx,
+            gate: None,
+            policy: None,
+            negative_cache: None,
+            config: Config::default(),
+            stats: Arc::default(),
+            now: Arc::new(utils::now_secs),
+            merge_hook: None,
+        };
+
+        // the merge fails, again on a retry, leaving inputs and keydir.
+        for _ in 0..2 {
+            assert!(compactor.compact_sstable_run(&[1, 2]).is_err());
+            assert!(!utils::format_sstable_tmp_path(dir.path(), 2).exists());
+        }
+        assert!(path.exists());
+        let mut store = compactor.store.write().unwrap();
+        assert_eq!(store.list_sstables().len(), 2);
+        for key in [b"a", b"c", b"d"] {
+            assert_eq!(store.get(key).unwrap(), Some(b"v".to_vec()));
+        }
+        assert!(store.keydir().get(b"b").is_some());
+    }
+
+    /// Policy answering from a script, recording the candidates.
+    #[derive(Default)]
+    struct ScriptedPolicy {
+        script: std::sync::Mutex<Vec<Option<usize>>>,
+        seen: std::sync::Mutex<Vec<Vec<CandidateRun>>>,
+    }
+
+    impl CompactionPolicy for ScriptedPolicy {
+        fn select(&self, candidates: &[CandidateRun]) -> Option<usize> {
+            self.seen.lock().unwrap().push(candidates.to_vec());
+            self.script.lock().unwrap().remove(0)
+        }
+    }
+
+    #[test]
+    fn test_compaction_policy() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut store = Store::open(dir.path()).unwrap();
+
+        // `a` overwritten, so sstable 1 is garbage.
+        for (seq, key) in [b"a", b"a", b"c", b"d"].into_iter().enumerate() {
+            let entry = DiskEntry::new(key.to_vec(), b"v".to_vec()).with_seq(seq as u64 + 1);
+            store.set(&BTreeMap::from([(key.to_vec(), entry)])).unwrap();
+        }
+        let size = store.list_sstables()[&1];
+
+        let policy = Arc::new(ScriptedPolicy {
+            script: std::sync::Mutex::new(vec![None, Some(1)]),
+            ..ScriptedPolicy::default()
+        });
+        let (_tx, rx) = mpsc::channel();
+        let mut compactor = Compactor {
+            path: dir.path().to_path_buf(),
+            sstables: store.list_sstables(),
+            store: Arc::new(RwLock::new(store)),
+            inbox: rx,
+            gate: None,
+            policy: Some(policy.clone()),
+            negative_cache: None,
+            config: Config {
+                merge_window: 2,
+                small_file_merge_threshold: 2,
+                small_file_merge_max_bytes: 2 * size,
+                ..Config::default()
+            },
+            stats: Arc::default(),
+            now: Arc::new(utils::now_secs),
+            merge_hook: None,
+        };
+
+        // skipped, then the second candidate merged.
+        compactor.sstable_maintenance().unwrap();
+        assert_eq!(compactor.sstables.len(), 4);
+        compactor.sstable_maintenance().unwrap();
+        assert_eq!(compactor.sstables.keys().collect::<Vec<_>>(), vec![&4]);
+        assert_eq!(compactor.stats.stats().runs, 1);
+
+        let seen = policy.seen.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[0], seen[1]);
+        let summary: Vec<_> = seen[0].iter().map(|run| (run.kind, run.ids())).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (CandidateKind::SmallFiles, vec![3, 4]),
+                (CandidateKind::SizeTiered, vec![1, 2, 3, 4]),
+            ]
+        );
+        assert_eq!(seen[0][0].garbage_ratio, 0.0);
+        assert_eq!(seen[0][1].garbage_ratio, 0.25);
+        assert!(policy.script.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tombstone_grace() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut store = Store::open(dir.path()).unwrap();
+
+        let writes: [(&[u8], &[u8]); 3] = [(b"k", b"v"), (b"x", b"v"), (b"k", b"")];
+        for (seq, (key, value)) in writes.into_iter().enumerate() {
+            let entry = DiskEntry::new(key.to_vec(), value.to_vec()).with_seq(seq as u64 + 1);
+            store.set(&BTreeMap::from([(key.to_vec(), entry)])).unwrap();
+        }
+
+        let (_tx, rx) = mpsc::channel();
+        let mut compactor = Compactor {
+            path: dir.path().to_path_buf(),
+            sstables: store.list_sstables(),
+            store: Arc::new(RwLock::new(store)),
+            inbox: rx,
+            gate: None,
+            policy: None,
+            negative_cache: None,
+            config: Config {
+                tombstone_grace: std::time::Duration::from_secs(3600),
+                ..Config::default()
+            },
+            stats: Arc::default(),
+            now: Arc::new(utils::now_secs),
+            merge_hook: None,
+        };
+
+        // bottom-most, but the tombstone is within its grace.
+        compactor.compact_sstable_run(&[1, 2, 3]).unwrap();
+        assert!(compactor
+            .store
+            .read()
+            .unwrap()
+            .keydir()
+            .get(b"k")
+            .unwrap()
+            .is_tombstone());
+        let stats = compactor.stats.stats();
+        assert_eq!(
+            (stats.tombstones_dropped, stats.tombstones_retained_by_grace),
+            (0, 1)
+        );
+
+        let entry = DiskEntry::new(b"y".to_vec(), b"v".to_vec()).with_seq(4);
+        let (id, size) = {
+            let mut store = compactor.store.write().unwrap();
+            store
+                .set(&BTreeMap::from([(b"y".to_vec(), entry)]))
+                .unwrap()
+        };
+        compactor.sstables.insert(id, size);
+
+        // two hours later the grace is over.
+        compactor.now = Arc::new(|| utils::now_secs() + 7200);
+        compactor.compact_sstable_run(&[3, 4]).unwrap();
+        {
+            let mut store = compactor.store.write().unwrap();
+            assert!(store.keydir().get(b"k").is_none());
+            assert_eq!(store.get(b"x").unwrap(), Some(b"v".to_vec()));
+        }
+        let stats = compactor.stats.stats();
+        assert_eq!(stats.runs, 2);
+        assert_eq!(
+            (stats.tombstones_dropped, stats.tombstones_retained_by_grace),
+            (1, 1)
+        );
+    }
+
+    #[test]
+    fn test_compaction_range_tombstones() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut store = Store::open(dir.path()).unwrap();
+
+        for (seq, key) in [b"a", b"b", b"c", b"d"].into_iter().enumerate() {
+            let entry = DiskEntry::new(key.to_vec(), b"v".to_vec()).with_seq(seq as u64 + 1);
+            store.set(&BTreeMap::from([(key.to_vec(), entry)])).unwrap();
+        }
+
+        // delete [b, d), then write c again.
+        let mut flush = store.begin_flush().unwrap();
+        flush
+            .write_range_tombstone(&RangeTombstone::new(
+                b"b".to_vec(),
+                b"d".to_vec(),
+                5,
+                utils::now_secs(),
+            ))
+            .unwrap();
+        let entry = DiskEntry::new(b"c".to_vec(), b"new".to_vec()).with_seq(6);
+        flush.write(b"c", &entry).unwrap();
+        flush.finish().unwrap();
+
+        let (_tx, rx) = mpsc::channel();
+        let mut compactor = Compactor {
+            path: dir.path().to_path_buf(),
+            sstables: store.list_sstables(),
+            store: Arc::new(RwLock::new(store)),
+            inbox: rx,
+            gate: None,
+            policy: None,
+            negative_cache: None,
+            config: Config::default(),
+            stats: Arc::default(),
+            now: Arc::new(utils::now_secs),
+            merge_hook: None,
+        };
+
+        // b is dropped, the tombstone stays for the older sstables.
+        compactor.compact_sstable_run(&[2, 3, 4, 5]).unwrap();
+        {
+            let mut store = compactor.store.write().unwrap();
+            assert_eq!(store.get(b"b").unwrap(), None);
+            assert_eq!(store.get(b"c").unwrap(), Some(b"new".to_vec()));
+            assert_eq!(store.range_tombstones().len(), 1);
+        }
+        let stats = compactor.stats.stats();
+        assert_eq!(
+            (stats.range_deleted_dropped, stats.range_tombstones_dropped),
+            (1, 0)
+        );
+
+        // the run starts at the oldest sstable, drop the tombstone.
+        compactor.compact_sstable_run(&[1, 5]).unwrap();
+        let mut store = compactor.store.write().unwrap();
+        assert!(store.range_tombstones().is_empty());
+        assert_eq!(store.get(b"a").unwrap(), Some(b"v".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(store.get(b"d").unwrap(), Some(b"v".to_vec()));
+        assert_eq!(store.len(), 3);
+        let stats = compactor.stats.stats();
+        assert_eq!(
+            (stats.range_deleted_dropped, stats.range_tombstones_dropped),
+            (1, 1)
+        );
+    }
+
+    #[test]
+    fn test_flush_during_merge() {
+        let dir = TempDir::new("lsmlib").unwrap();
+        let mut store = Store::open(dir.path()).unwrap();
+
+        for (seq, key) in [b"k", b"x"].into_iter().enumerate() {
+            let entry = DiskEntry::new(key.to_vec(), b"old".to_vec()).with_seq(seq as u64 + 1);
+            store.set(&BTreeMap::from([(key.to_vec(), entry)])).unwrap();
+        }
+
+        let (_tx, rx) = mpsc::channel();
+        let mut compactor = Compactor {
+            path: dir.path().to_path_buf(),
+            sstables: store.list_sstables(),
+            store: Arc::new(RwLock::new(store)),
+            inbox: rx,
+            gate: None,
+            policy: None,
+            negative_cache: None,
+            config: Config::default(),
+            stats: Arc::default(),
+            now: Arc::new(utils::now_secs),
+            merge_hook: Some(|compactor| {
+                // overwrite k and delete x while the merge is in flight.
+                let items = BTreeMap::from([
+                    (
+                        b"k".to_vec(),
+                        DiskEntry::new(b"k".to_vec(), b"new".to_vec()).with_seq(3),
+                    ),
+                    (
+                        b"x".to_vec(),
+                        DiskEntry::new(b"x".to_vec(), vec![]).with_seq(4),
+                    ),
+                ]);
+                compactor.store.write().unwrap().set(&items).unwrap();
+            }),
+        };
+
+        compactor.compact_sstable_run(&[1, 2]).unwrap();
+
+        let mut store = compactor.store.write().unwrap();
+        assert_eq!(store.get(b"k").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(store.keydir().get(b"k").unwrap().file_id(), 3);
+        assert_eq!(store.get(b"x").unwrap(), None);
+        assert!(store.keydir().get(b"x").unwrap().is_tombstone());
+    }
+
+    #[test]
+    fn test_flush_and_compaction_outputs_match() {
+        let entry = |key: &[u8], seq| {
+            let entry = DiskEntry::new(key.to_vec(), b"v".to_vec())
+                .with_seq(seq)
+                .with_timestamp(1);
+            (key.to_vec(), entry)
+        };
+        let all = BTreeMap::from([entry(b"a", 1), entry(b"b", 2), entry(b"c", 3)]);
+
+        let flushed = TempDir::new("lsmlib").unwrap();
+        Store::open(flushed.path()).unwrap().set(&all).unwrap();
+
+        // the same entries flushed in two sstables, then merged.
+        let merged = TempDir::new("lsmlib").unwrap();
+        let mut store = Store::open(merged.path()).unwrap();
+        store
+            .set(&BTreeMap::from([entry(b"a", 1), entry(b"c", 3)]))
+            .unwrap();
+        store.set(&BTreeMap::from([entry(b"b", 2)])).unwrap();
+
+        let (_tx, rx) = mpsc::channel();
+        let mut compactor = Compactor {
+            path: merged.path().to_path_buf(),
+            sstables: store.list_sstables(),
+            store: Arc::new(RwLock::new(store)),
+            inbox: rx,
+            gate: None,
+            policy: None,
+            negative_cache: None,
+            config: Config::default(),
+            stats: Arc::default(),
+            now: Arc::new(utils::now_secs),
+            merge_hook: None,
+        };
+        compactor.compact_sstable_run(&[1, 2]).unwrap();
+
+        let read = |path: PathBuf| std::fs::read(path).unwrap();
+        assert_eq!(
+            read(utils::format_sstable_path(flushed.path(), 1)),
+            read(utils::format_sstable_path(merged.path(), 2))
+        );
+        assert_eq!(
+            read(utils::format_hint_path(flushed.path(), 1)),
+            read(utils::format_hint_path(merged.path(), 2))
+        );
     }
 }
@@ -1,6 +1,12 @@
 //! Indexor Module.
+//!
+//! Indexes the sstables a partial open left out of the keydir, newest
+//! first, see `Config::partial_open_sstables`.
 
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock};
+
+use crate::error::{LSMLibError, Result};
+use crate::storage::Store;
 
 pub enum IndexorMessage {
     NewSSTableUpdate(u64),
@@ -9,4 +15,105 @@ pub enum IndexorMessage {
     HeartBeat(mpsc::Sender<()>),
 }
 
-pub struct Indexor {}
+#[derive(Debug, Default)]
+struct IndexState {
+    indexed: u64,
+    total: u64,
+
+    /// why indexing stopped short, if it did.
+    failed: Option<String>,
+}
+
+/// Sstables indexed out of those the store opened with.
+#[derive(Debug, Default)]
+pub(crate) struct IndexProgress {
+    state: Mutex<IndexState>,
+    done: Condvar,
+}
+
+impl IndexProgress {
+    pub(crate) fn new(indexed: u64, total: u64) -> Self {
+        Self {
+            state: Mutex::new(IndexState {
+                indexed,
+                total,
+                failed: None,
+            }),
+            done: Condvar::new(),
+        }
+    }
+
+    /// `(indexed, total)` sstables.
+    pub(crate) fn get(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.indexed, state.total)
+    }
+
+    pub(crate) fn advance(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.indexed += 1;
+        if state.indexed == state.total {
+            self.done.notify_all();
+        }
+    }
+
+    fn fail(&self, reason: String) {
+        self.state.lock().unwrap().failed = Some(reason);
+        self.done.notify_all();
+    }
+
+    /// Block until every sstable is indexed, failing if indexing failed.
+    pub(crate) fn wait(&self) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        let state = self
+            .done
+            .wait_while(state, |s| s.indexed < s.total && s.failed.is_none())
+            .unwrap();
+        match &state.failed {
+            Some(reason) => Err(LSMLibError::Custom(format!(
+                "background indexing failed: {}",
+                reason
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct Indexor {
+    /// Disk Storage.
+    pub(crate) store: Arc<RwLock<Store>>,
+
+    /// Inbox of message.
+    pub(crate) inbox: mpsc::Receiver<IndexorMessage>,
+
+    pub(crate) progress: Arc<IndexProgress>,
+}
+
+impl Indexor {
+    /// Index one sstable at a time, holding the store lock only for
+    /// that one, until every sstable is indexed or told to stop.
+    pub fn run(self) {
+        loop {
+            match self.inbox.try_recv() {
+                Ok(IndexorMessage::Stop(dropper)) => {
+                    drop(dropper);
+                    break;
+                }
+                Ok(IndexorMessage::HeartBeat(dropper)) => drop(dropper),
+                Ok(_) | Err(mpsc::TryRecvError::Empty) => {}
+                Err(mpsc::TryRecvError::Disconnected) => break,
+            }
+
+            match self.store.write().unwrap().index_next() {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    log::error!("error while indexing sstables in the background: {:?}", e);
+                    self.progress.fail(e.to_string());
+                    break;
+                }
+            }
+        }
+        log::info!("Indexor worker quitting...");
+    }
+}
@@ -1,4 +1,48 @@
 //! Worker Module.
 
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
 pub mod compact;
 pub mod index;
+
+/// Background thread of a store, see `Lsm::worker_info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerInfo {
+    /// kind of worker, e.g. `compactor`.
+    pub kind: &'static str,
+
+    /// name of the thread, as in panics and debuggers.
+    pub thread_name: String,
+}
+
+/// Name of the `kind` worker thread of the store at `path`, e.g.
+/// `lsmlib-compactor-1a2b3c4d`, the same for every open of the store.
+///
+/// Linux shows only the first 15 bytes in `/proc` and `top`, the full
+/// name is kept by the thread handle.
+pub(crate) fn thread_name(kind: &str, path: &Path) -> String {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    format!("lsmlib-{}-{:08x}", kind, hasher.finish() as u32)
+}
+
+/// Set the nice value of the calling thread, logging failures.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_thread_nice(nice: i32) {
+    // on linux `who == 0` is the calling thread, not the process.
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+        log::warn!(
+            "failed to set worker thread nice to {}: {}",
+            nice,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn set_thread_nice(nice: i32) {
+    log::debug!("ignoring worker thread nice {}, unsupported platform", nice);
+}
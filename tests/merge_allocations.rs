@@ -0,0 +1,114 @@
+//! Allocations of `CompactMergeIter`, in a binary of its own as the
+//! counting allocator applies to every test of the binary.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use slmlib::lsm::sstable::{CompactMergeIter, SSTable};
+use slmlib::lsm::{CompactionGate, KVStore};
+use slmlib::OpenOptions;
+use tempdir::TempDir;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+fn allocations() -> u64 {
+    ALLOCATIONS.load(Ordering::Relaxed)
+}
+
+/// Gate keeping the flushed sstables apart.
+struct NoCompaction;
+
+impl CompactionGate for NoCompaction {
+    fn allow(&self, _: &[u64]) -> bool {
+        false
+    }
+}
+
+#[test]
+fn test_merge_allocations() {
+    const SSTABLES: u64 = 16;
+    const KEYS: u64 = 4096;
+
+    let dir = TempDir::new("lsmlib").unwrap();
+
+    // interleaved keys, the newer sstables holding the smaller ones,
+    // and every 4th key overwritten by each sstable.
+    let mut lsm = OpenOptions::new()
+        .compaction_gate(Arc::new(NoCompaction))
+        .open(dir.path())
+        .unwrap();
+    for id in 1..=SSTABLES {
+        for key in (0..KEYS).filter(|k| k % 4 == 0 || k % SSTABLES == SSTABLES - id) {
+            lsm.put(key.to_be_bytes().to_vec(), id.to_le_bytes().to_vec())
+                .unwrap();
+        }
+        lsm.flush().unwrap();
+    }
+    drop(lsm);
+
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "data"))
+        .collect();
+    paths.sort();
+    assert_eq!(paths.len() as u64, SSTABLES);
+
+    let read_allocations = |paths: &[PathBuf]| {
+        let before = allocations();
+        let mut entries = 0;
+        for path in paths {
+            entries += SSTable::new(path, false).unwrap().iter().count() as u64;
+        }
+        (allocations() - before, entries)
+    };
+    let (reads, inputs) = read_allocations(&paths);
+
+    let mut sstables: Vec<SSTable> = paths
+        .iter()
+        .map(|p| SSTable::new(p, false).unwrap())
+        .collect();
+    let before = allocations();
+    let mut outputs = 0;
+    for entry in CompactMergeIter::new(sstables.iter_mut().map(SSTable::iter).collect()) {
+        let entry = entry.unwrap();
+        // the newest version of each key, in key order.
+        let key = u64::from_be_bytes(entry.key().try_into().unwrap());
+        assert_eq!(key, outputs);
+        if key % 4 == 0 {
+            assert_eq!(entry.value(), SSTABLES.to_le_bytes());
+        }
+        outputs += 1;
+    }
+    let merge = allocations() - before;
+    assert_eq!(outputs, KEYS);
+
+    // reading the inputs and little else, whatever the comparisons.
+    assert!(inputs > 2 * outputs);
+    assert!(
+        merge <= reads + outputs,
+        "{} allocations merging {} entries into {}, {} reading them",
+        merge,
+        inputs,
+        outputs,
+        reads
+    );
+}